@@ -1,4 +1,4 @@
-use std::{arch::x86_64::_CMP_FALSE_OQ, collections::VecDeque, net::{SocketAddr, UdpSocket}, sync::{atomic::{AtomicBool, Ordering}, mpsc, Arc, Mutex, OnceLock}, thread::JoinHandle};
+use std::{arch::x86_64::_CMP_FALSE_OQ, collections::VecDeque, net::{IpAddr, SocketAddr, UdpSocket}, sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, mpsc, Arc, Mutex, OnceLock}, thread::JoinHandle};
 use arc_swap::ArcSwapAny;
 use windows_capture::{monitor::Monitor, settings::{ColorFormat, CursorCaptureSettings, DirtyRegionSettings, DrawBorderSettings, MinimumUpdateIntervalSettings, SecondaryWindowSettings, Settings}}; 
 use tauri::State;
@@ -8,13 +8,28 @@ use tauri::{Manager};
 
 mod models;
 use crate::models::structs::app_core::AppCore;
+use crate::models::structs::http_access_log::{AccessLog, AccessLogConfig, LogFormat};
+use crate::models::structs::http_auth::{Auth, BasicAuth};
+use crate::models::structs::http_cors::Cors;
+use crate::models::structs::http_middleware::{MiddlewareChain, Timing};
+use crate::models::structs::http_response::HttpResponse;
+use crate::models::structs::http_router::Router;
+use crate::models::structs::http_server::{HttpServer, HttpServerConfig};
+use crate::models::structs::http_sse::{EventBroadcaster, SseConfig};
+use crate::models::structs::http_static::StaticFiles;
+use crate::models::structs::http_tls::{TlsCertificate, TlsConfig};
+use crate::models::structs::stream_stats::StreamStats;
+use crate::models::structs::websocket::WebSocket;
 
 //Global usable variables
 static MAX_UDP_PACKET_SIZE: usize = 50000;
 static CLIENT_NUMBER_SENDER: OnceLock<Mutex<mpsc::Sender<usize>>> = OnceLock::new();
 static CLIENT_NUMBER_RECEIVER: OnceLock<Mutex<mpsc::Receiver<usize>>> = OnceLock::new();
+static ENCODED_FRAME_COUNTER: AtomicUsize = AtomicUsize::new(0);
 static GLOBAL_QUEUE: Lazy<Arc<Mutex<VecDeque<Vec<u8>>>>> = 
     Lazy::new(|| Arc::new(Mutex::new(VecDeque::new())));
+// Stream telemetry served on /events, the last 256 events are kept for resuming clients
+static STREAM_EVENTS: Lazy<EventBroadcaster> = Lazy::new(|| EventBroadcaster::new(256));
 
 
 type SingletonType = Arc<RwLock<AppCore>>;
@@ -124,6 +139,151 @@ async fn off_thread_capture(thread_supervisor: State<'_, Arc<Mutex<ThreadSupervi
     }
 }
 
+#[tauri::command]
+async fn start_http_server(
+    address: String,
+    port: u16,
+    static_root: Option<String>,
+    tls_cert_path: Option<String>,
+    tls_key_path: Option<String>,
+    access_log_path: Option<String>,
+    cors_origins: Option<Vec<String>>,
+    auth_users_path: Option<String>,
+    http_server: State<'_, Arc<Mutex<Option<HttpServer>>>>,
+    clients: State<'_, Arc<arc_swap::ArcSwapAny<Arc<Vec<SocketAddr>>>>>
+) -> Result<String, String> {
+    let address: IpAddr = match address.parse() {
+        Ok(address) => address,
+        Err(err) => {
+            println!("Invalid http server address {:?}", err);
+            return Err("Invalid http server address".to_string())
+        }
+    };
+
+    let mut locked_http_server = match http_server.lock() {
+        Ok(locked_http_server) => locked_http_server,
+        Err(err) => {
+            println!("Error while locking http server {:?}", err);
+            return Err("Error while locking http server".to_string())
+        }
+    };
+    if locked_http_server.is_some() {
+        return Err("Http server already running".to_string())
+    }
+
+    // HTTPS when both PEM files are given, the files are watched for renewed certificates
+    let tls = match (tls_cert_path, tls_key_path) {
+        (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+            certificates: vec![TlsCertificate {
+                server_names: Vec::new(),
+                cert_path: cert_path.into(),
+                key_path: key_path.into(),
+            }],
+            reload_interval: Some(std::time::Duration::from_secs(10)),
+        }),
+        (None, None) => None,
+        _ => return Err("Both tls certificate and key are required".to_string())
+    };
+
+    // Combined Log Format, rotated at 10 MiB
+    let access_log = match access_log_path {
+        Some(access_log_path) => match AccessLog::open(AccessLogConfig::new(access_log_path.into(), LogFormat::Combined)) {
+            Ok(access_log) => Some(Arc::new(access_log)),
+            Err(err) => {
+                println!("Error while opening access log {:?}", err);
+                return Err("Error while opening access log".to_string())
+            }
+        },
+        None => None,
+    };
+
+    let config = HttpServerConfig {
+        address,
+        port,
+        tls,
+        access_log,
+        ..HttpServerConfig::default()
+    };
+
+    let stats_clients = clients.inner().clone();
+    let mut router = Router::new()
+        .get("/health", |_request, _params| HttpResponse::json(200, &serde_json::json!({ "status": "ok" })))
+        .get("/ws/stats", move |request, _params| {
+            let clients = stats_clients.clone();
+            WebSocket::upgrade(request, move |websocket| StreamStats::push_to_websocket(websocket, clients))
+        })
+        .get("/events", |request, _params| STREAM_EVENTS.stream(request, &SseConfig::default()));
+
+    // Directory served under /files, e.g. recorded streams or a web viewer
+    if let Some(static_root) = static_root {
+        match StaticFiles::new(std::path::Path::new(&static_root)) {
+            Ok(static_files) => {
+                let static_files = Arc::new(static_files.with_directory_listing(true));
+                router = router.get("/files/*path", move |request, params| {
+                    static_files.serve(request, params.get("path").unwrap_or_default())
+                });
+            },
+            Err(err) => {
+                println!("Invalid static root {:?}", err);
+                return Err("Invalid static root".to_string())
+            }
+        }
+    }
+
+    //Latency of every request announced with Server-Timing
+    let mut middlewares = MiddlewareChain::new().with(Timing::new());
+
+    // Origins of the browser viewers allowed to call the API, e.g. "https://viewer.example.com"
+    if let Some(cors_origins) = cors_origins {
+        let cors = cors_origins.iter().fold(Cors::new(), |cors, origin| cors.with_origin(origin));
+        middlewares = middlewares.with(cors);
+    }
+
+    // Basic authentication against a "name:hash" file (argon2 or bcrypt), every route but /health
+    if let Some(auth_users_path) = auth_users_path {
+        match BasicAuth::from_file("rust_web_server", std::path::Path::new(&auth_users_path)) {
+            Ok(basic_auth) => middlewares = middlewares.with(Auth::new().with(basic_auth).with_public_path("/health")),
+            Err(err) => {
+                println!("Error while reading auth users {:?}", err);
+                return Err("Error while reading auth users".to_string())
+            }
+        }
+    }
+
+    match HttpServer::start(config, middlewares.into_handler(router.into_handler())) {
+        Ok(server) => {
+            let local_addr = server.local_addr.to_string();
+            *locked_http_server = Some(server);
+            Ok(local_addr)
+        },
+        Err(err) => {
+            println!("Error while starting http server {:?}", err);
+            Err("Error while starting http server".to_string())
+        }
+    }
+}
+
+#[tauri::command]
+async fn stop_http_server(http_server: State<'_, Arc<Mutex<Option<HttpServer>>>>) -> Result<bool, String> {
+    match http_server.lock() {
+        Ok(mut locked_http_server) => {
+            if let Some(mut server) = locked_http_server.take() {
+                //Event streams never end by themselves, the workers serving them would not stop
+                STREAM_EVENTS.disconnect_all();
+                server.stop()?;
+                Ok(true)
+            }
+            else {
+                Err("No http server running".to_string())
+            }
+        },
+        Err(err) => {
+            println!("Error while locking http server {:?}", err);
+            Err("Error while locking http server".to_string())
+        }
+    }
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
@@ -146,10 +306,15 @@ pub fn run() {
             let socket: Arc<UdpSocket> = Arc::new(UdpSocket::bind("0.0.0.0:0").unwrap());
             app.manage(socket);
 
+            // Http server, started on demand
+            let http_server: Arc<Mutex<Option<HttpServer>>> = Arc::new(Mutex::new(None));
+            app.manage(http_server);
+
             Ok(())
         })
         .plugin(tauri_plugin_opener::init())
-        .invoke_handler(tauri::generate_handler![greet, run_capture_thread, off_thread_capture])
+        .invoke_handler(tauri::generate_handler![greet, run_capture_thread, off_thread_capture,
+            start_http_server, stop_http_server])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
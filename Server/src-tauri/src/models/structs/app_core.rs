@@ -1,4 +1,4 @@
-use std::{collections::VecDeque, net::{SocketAddr, UdpSocket}, sync::{atomic::{AtomicBool, Ordering}, mpsc, Arc, Mutex, OnceLock}, thread::{self, JoinHandle}, time::{Duration, SystemTime}};
+use std::{collections::VecDeque, net::{SocketAddr, UdpSocket}, sync::{atomic::{AtomicBool, Ordering}, mpsc, Arc, Mutex, OnceLock}, thread::{self, JoinHandle}, time::{Duration, Instant, SystemTime}};
 use std::time::{UNIX_EPOCH};
 
 use once_cell::sync::Lazy;
@@ -6,7 +6,9 @@ use tokio::io::Join;
 use windows_capture::{capture::GraphicsCaptureApiHandler, monitor::Monitor, settings::Settings};
 
 use crate::models::structs::screen_capture::ScreenCapture;
+use crate::models::structs::stream_events::StreamEvent;
 use crate::CLIENT_NUMBER_SENDER;
+use crate::ENCODED_FRAME_COUNTER;
 use crate::GLOBAL_QUEUE;
 use crate::MAX_UDP_PACKET_SIZE;
 
@@ -43,6 +45,13 @@ impl AppCore {
 
         let handler = thread::spawn(move ||{
             println!("Udp thread spawned");
+
+            // Throughput since the last report
+            let mut report_start = Instant::now();
+            let mut report_frames = ENCODED_FRAME_COUNTER.load(Ordering::Relaxed);
+            let mut sent_bytes: usize = 0;
+            let mut sent_packets: usize = 0;
+
             loop {
                 if should_stop.load(Ordering::Relaxed) {
                     break
@@ -56,6 +65,7 @@ impl AppCore {
                                 new_clients.push(client_addr);
                                 let clients_len = new_clients.len();
                                 client_copy.store(Arc::new(new_clients));
+                                StreamEvent::SubscriberJoined { subscribers: clients_len }.publish();
 
                                 if let Some(client_number_mutex) = CLIENT_NUMBER_SENDER.get() {     
                                     if let Ok(client_number) = client_number_mutex.lock() {
@@ -72,6 +82,7 @@ impl AppCore {
                                 new_clients.remove(client_position);
                                 let clients_len = new_clients.len();
                                 client_copy.store(Arc::new(new_clients));
+                                StreamEvent::SubscriberLeft { subscribers: clients_len }.publish();
                                 
                                 if let Some(client_number_mutex) = CLIENT_NUMBER_SENDER.get() {     
                                     if let Ok(client_number) = client_number_mutex.lock() {
@@ -133,16 +144,36 @@ impl AppCore {
                             println!("Size chunk - {}", chunk.len());
                             println!("Timestamp chunk - {}", timestamp);
 
-                            Self::send_to_clients(&socket, (**clients.load()).clone(), chunk);
+                            let (packets, bytes) = Self::send_to_clients(&socket, (**clients.load()).clone(), chunk);
+                            sent_packets += packets;
+                            sent_bytes += bytes;
                         }
                     }
                     else {
-                        Self::send_to_clients(&socket, (**clients.load()).clone(), timestamped_data);
+                        let (packets, bytes) = Self::send_to_clients(&socket, (**clients.load()).clone(), timestamped_data);
+                        sent_packets += packets;
+                        sent_bytes += bytes;
                     }
 
 
                 };
-            
+
+                let elapsed = report_start.elapsed();
+                if elapsed >= Duration::from_secs(1) {
+                    let frames = ENCODED_FRAME_COUNTER.load(Ordering::Relaxed);
+                    let per_second = |count: usize| (count as f64 / elapsed.as_secs_f64()).round() as u64;
+                    StreamEvent::Throughput {
+                        bytes_per_second: per_second(sent_bytes),
+                        packets_per_second: per_second(sent_packets),
+                        frames_per_second: per_second(frames.saturating_sub(report_frames)),
+                        subscribers: clients.load().len(),
+                    }.publish();
+
+                    report_start = Instant::now();
+                    report_frames = frames;
+                    sent_bytes = 0;
+                    sent_packets = 0;
+                }
 
                 thread::sleep(Duration::from_millis(10));
             }
@@ -150,9 +181,16 @@ impl AppCore {
         handler
     }
 
-    pub fn send_to_clients(socket: &UdpSocket, clients: Vec<SocketAddr>, data: Vec<u8>) {
-       for client in clients {
-            let _ = socket.send_to(&data, client);
+    // Returns the number of datagrams and bytes sent
+    pub fn send_to_clients(socket: &UdpSocket, clients: Vec<SocketAddr>, data: Vec<u8>) -> (usize, usize) {
+        let mut sent_packets: usize = 0;
+        let mut sent_bytes: usize = 0;
+        for client in clients {
+            if let Ok(nb_bytes_sent) = socket.send_to(&data, client) {
+                sent_packets += 1;
+                sent_bytes += nb_bytes_sent;
+            }
         }
+        (sent_packets, sent_bytes)
     }
 }
\ No newline at end of file
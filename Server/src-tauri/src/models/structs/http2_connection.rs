@@ -0,0 +1,1392 @@
+use std::{collections::HashMap, io::{self, Write}, net::SocketAddr, sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, mpsc::{self, Receiver, SyncSender, TryRecvError}, Arc}, thread, time::{Duration, Instant, SystemTime}};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine};
+
+use crate::models::structs::http2_error::{ErrorCode, Http2Error};
+use crate::models::structs::http2_frame::{Frame, FrameType, Http2Settings, FLAG_ACK, FLAG_END_HEADERS, FLAG_END_STREAM, MAX_WINDOW_SIZE, PREFACE};
+use crate::models::structs::http2_hpack::{encode_header_block, HpackDecoder};
+use crate::models::structs::http_access_log::AccessLogEntry;
+use crate::models::structs::http_date::format_http_date;
+use crate::models::structs::http_headers::HeaderMap;
+use crate::models::structs::http_message::HttpMessage;
+use crate::models::structs::http_method::Method;
+use crate::models::structs::http_middleware::Extensions;
+use crate::models::structs::http_response::HttpResponse;
+use crate::models::structs::http_server::{Handler, HttpServer, HttpServerConfig};
+use crate::models::structs::http_stream::HttpStream;
+use crate::models::structs::http_target::RequestTarget;
+use crate::models::structs::http_tls::TlsServerName;
+use crate::models::structs::http_version::Version;
+
+// How long the connection waits for frames before looking at the responses produced by the handlers
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+// Body writes buffered per stream before the handler waits for the flow control window
+const STREAM_CHANNEL_BOUND: usize = 8;
+// Fields tied to a HTTP/1.1 connection, not allowed in HTTP/2 (RFC 9113 8.2.2)
+const CONNECTION_SPECIFIC_FIELDS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+#[derive(Clone, Debug)]
+pub struct Http2Config {
+    pub max_concurrent_streams: u32,
+    // Receive window of each stream, the connection window is opened as wide
+    pub initial_window_size: u32,
+    pub max_frame_size: u32,
+    // Dynamic table size allowed to the client's HPACK encoder
+    pub header_table_size: u32,
+    // Accept HTTP/2 over cleartext (prior knowledge and Upgrade: h2c), HTTPS always negotiates it with ALPN
+    pub h2c: bool,
+    // Open streams the client may reset each second, above it the connection is closed with ENHANCE_YOUR_CALM
+    // Protects against rapid reset, streams opened and cancelled at once to keep the server starting handlers
+    pub max_resets_per_second: u32,
+}
+
+impl Default for Http2Config {
+    fn default() -> Self {
+        Http2Config {
+            max_concurrent_streams: 100,
+            initial_window_size: 1024 * 1024,
+            max_frame_size: 16384,
+            header_table_size: 4096,
+            h2c: true,
+            max_resets_per_second: 100,
+        }
+    }
+}
+
+// Produced by the thread running the handler of a stream
+enum StreamOutput {
+    // Response fields and END_STREAM when there is no body
+    Headers(Vec<(String, String)>, bool),
+    Data(Vec<u8>),
+    End,
+    Reset(ErrorCode),
+}
+
+// Body writer of a response stream, each write is handed to the connection thread
+struct StreamWriter {
+    sender: SyncSender<StreamOutput>,
+    // Body bytes handed so far, for the access log
+    bytes_sent: u64,
+}
+
+impl Write for StreamWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        match self.sender.send(StreamOutput::Data(data.to_vec())) {
+            Ok(()) => {
+                self.bytes_sent += data.len() as u64;
+                Ok(data.len())
+            },
+            Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "Stream reset by peer")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Http2Stream {
+    // Request being received, handed to the handler once END_STREAM is received
+    request: Option<HttpMessage>,
+    // END_STREAM received from the client
+    remote_closed: bool,
+    receive_window: i64,
+    send_window: i64,
+    output: Option<Receiver<StreamOutput>>,
+    // Response body waiting for flow control window
+    pending_data: Vec<u8>,
+    // END_STREAM to send once pending_data is sent
+    pending_end: bool,
+    // Since when pending_data waits for the client to open its window
+    blocked_since: Option<Instant>,
+}
+
+// Counts a handler thread as running until dropped, even if the handler panics
+struct HandlerSlot(Arc<AtomicUsize>);
+
+impl HandlerSlot {
+    fn new(running_handlers: &Arc<AtomicUsize>) -> Self {
+        running_handlers.fetch_add(1, Ordering::SeqCst);
+        HandlerSlot(running_handlers.clone())
+    }
+}
+
+impl Drop for HandlerSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Server side of a HTTP/2 connection (RFC 9113)
+// Frames are read and written by the connection thread, each request is handled on its own thread
+pub struct Http2Connection {
+    stream: Box<dyn HttpStream>,
+    peer_addr: Option<SocketAddr>,
+    server_name: Option<String>,
+    buffer: Vec<u8>,
+    config: Arc<HttpServerConfig>,
+    http2: Http2Config,
+    handler: Handler,
+    should_stop: Arc<AtomicBool>,
+    local_settings: Http2Settings,
+    remote_settings: Http2Settings,
+    settings_received: bool,
+    decoder: HpackDecoder,
+    streams: HashMap<u32, Http2Stream>,
+    // Highest stream identifier opened by the client
+    last_stream_id: u32,
+    // Handler threads still running, a reset stream keeps its thread until the handler returns
+    running_handlers: Arc<AtomicUsize>,
+    // Open streams reset by the client since reset_window_start
+    resets: u32,
+    reset_window_start: Instant,
+    send_window: i64,
+    receive_window: i64,
+    // Header block split over CONTINUATION frames : stream, END_STREAM of the HEADERS frame, fragments
+    continuation: Option<(u32, bool, Vec<u8>)>,
+    goaway_sent: bool,
+    goaway_received: bool,
+    // Frames written at the end of each iteration
+    output: Vec<u8>,
+}
+
+impl Http2Connection {
+    // buffer holds bytes already received on the stream
+    pub fn new(stream: Box<dyn HttpStream>,
+        buffer: Vec<u8>,
+        config: Arc<HttpServerConfig>,
+        handler: Handler,
+        should_stop: Arc<AtomicBool>) -> Self {
+
+        let http2 = config.http2.clone().unwrap_or_default();
+        let local_settings = Http2Settings {
+            header_table_size: http2.header_table_size,
+            enable_push: false,
+            max_concurrent_streams: Some(http2.max_concurrent_streams),
+            initial_window_size: http2.initial_window_size,
+            max_frame_size: http2.max_frame_size,
+            max_header_list_size: Some((config.limits.max_headers * config.limits.max_header_size) as u32),
+        };
+
+        Http2Connection {
+            peer_addr: stream.peer_addr().ok(),
+            server_name: stream.server_name(),
+            stream,
+            buffer,
+            decoder: HpackDecoder::new(http2.header_table_size as usize).with_max_list_size(config.limits.max_headers * config.limits.max_header_size),
+            config,
+            http2,
+            handler,
+            should_stop,
+            local_settings,
+            remote_settings: Http2Settings::default(),
+            settings_received: false,
+            streams: HashMap::new(),
+            last_stream_id: 0,
+            running_handlers: Arc::new(AtomicUsize::new(0)),
+            resets: 0,
+            reset_window_start: Instant::now(),
+            send_window: Http2Settings::default().initial_window_size as i64,
+            receive_window: Http2Settings::default().initial_window_size as i64,
+            continuation: None,
+            goaway_sent: false,
+            goaway_received: false,
+            output: Vec::new(),
+        }
+    }
+
+    // Connection selected with ALPN or prior knowledge, the client starts with the preface
+    pub fn serve(self) -> io::Result<()> {
+        self.run(None)
+    }
+
+    // Connection upgraded from HTTP/1.1 (RFC 7540 3.2), the upgraded request is answered on stream 1
+    // http2_settings is the HTTP2-Settings field of the request
+    pub fn serve_upgraded(mut self, mut request: HttpMessage, http2_settings: &str) -> io::Result<()> {
+        let payload = match BASE64_URL.decode(http2_settings.trim_end_matches('=')) {
+            Ok(payload) => payload,
+            Err(err) => {
+                println!("Invalid HTTP2-Settings {:?}", err);
+                return Ok(())
+            }
+        };
+        if let Err(err) = self.remote_settings.apply(&payload) {
+            println!("Invalid HTTP2-Settings {}", err);
+            return Ok(())
+        }
+
+        //Handlers see the request as received on HTTP/2, without the fields of the upgrade
+        request.version = Version::Http2;
+        for name in ["Connection", "Upgrade", "HTTP2-Settings"] {
+            request.headers.remove(name);
+        }
+        self.run(Some(request))
+    }
+
+    fn run(mut self, upgraded_request: Option<HttpMessage>) -> io::Result<()> {
+        self.stream.set_write_timeout(Some(self.config.write_timeout))?;
+
+        //Server preface, then the connection window is opened as wide as the stream windows
+        self.queue_frame(Frame::new(FrameType::Settings, 0, 0, self.local_settings.to_payload()));
+        let increment = self.http2.initial_window_size as i64 - self.receive_window;
+        if increment > 0 {
+            self.queue_frame(Frame::window_update(0, increment as u32));
+            self.receive_window += increment;
+        }
+
+        if let Some(request) = upgraded_request {
+            self.last_stream_id = 1;
+            self.open_stream(1, None, true);
+            self.dispatch(1, request);
+        }
+
+        match self.serve_frames() {
+            Ok(()) => {},
+            Err(Http2Error::Connection(code, reason)) => {
+                println!("Http2 connection error {:?} : {}", code, reason);
+                self.queue_frame(Frame::goaway(self.last_stream_id, code, &reason));
+                let _ = self.flush_output();
+            },
+            Err(Http2Error::Stream(stream_id, code, reason)) => {
+                println!("Http2 stream {} error {:?} : {}", stream_id, code, reason);
+            },
+            Err(Http2Error::Io(err)) => return Err(err),
+        }
+
+        self.stream.close();
+        Ok(())
+    }
+
+    fn serve_frames(&mut self) -> Result<(), Http2Error> {
+        self.read_preface()?;
+        let mut chunk = [0u8; 16 * 1024];
+        let mut last_activity = Instant::now();
+
+        loop {
+            while let Some(frame) = Frame::parse(&mut self.buffer, self.local_settings.max_frame_size)? {
+                match self.handle_frame(frame) {
+                    Ok(()) => {},
+                    Err(Http2Error::Stream(stream_id, code, reason)) => {
+                        println!("Http2 stream {} error {:?} : {}", stream_id, code, reason);
+                        self.reset_stream(stream_id, code);
+                    },
+                    Err(err) => return Err(err),
+                }
+            }
+
+            self.send_responses();
+            self.reset_stalled_streams();
+            self.flush_output()?;
+
+            //Streams already opened are answered before closing
+            if self.should_stop.load(Ordering::Relaxed) && !self.goaway_sent {
+                self.queue_frame(Frame::goaway(self.last_stream_id, ErrorCode::NoError, ""));
+                self.goaway_sent = true;
+                self.flush_output()?;
+            }
+            if (self.goaway_sent || self.goaway_received) && self.streams.is_empty() {
+                return Ok(())
+            }
+
+            //Poll while handlers produce responses, otherwise wait for the client
+            //A stream blocked on flow control only moves on with a WINDOW_UPDATE, there is nothing to poll
+            let producing = self.streams.values().any(|stream| {
+                stream.blocked_since.is_none() && (stream.output.is_some() || !stream.pending_data.is_empty() || stream.pending_end)
+            });
+            let deadline = if self.streams.is_empty() { self.config.idle_timeout } else { self.config.read_timeout };
+            if !producing && last_activity.elapsed() >= deadline {
+                self.queue_frame(Frame::goaway(self.last_stream_id, ErrorCode::NoError, "Idle"));
+                self.flush_output()?;
+                return Ok(())
+            }
+            let mut timeout = if producing { POLL_INTERVAL } else { deadline - last_activity.elapsed() };
+            //Wake up in time to reset the streams blocked for too long
+            if let Some(blocked_since) = self.streams.values().filter_map(|stream| stream.blocked_since).min() {
+                timeout = timeout.min(self.config.write_timeout.saturating_sub(blocked_since.elapsed()));
+            }
+            self.stream.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Ok(()),
+                Ok(nb_bytes_read) => {
+                    self.buffer.extend_from_slice(&chunk[..nb_bytes_read]);
+                    last_activity = Instant::now();
+                },
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => {},
+                Err(err) if err.kind() == io::ErrorKind::ConnectionReset => return Ok(()),
+                Err(err) => return Err(Http2Error::Io(err)),
+            }
+        }
+    }
+
+    fn read_preface(&mut self) -> Result<(), Http2Error> {
+        self.stream.set_read_timeout(Some(self.config.read_timeout))?;
+        let mut chunk = [0u8; 1024];
+        while self.buffer.len() < PREFACE.len() {
+            let nb_bytes_read = self.stream.read(&mut chunk)?;
+            if nb_bytes_read == 0 {
+                return Err(Http2Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed before the preface")));
+            }
+            self.buffer.extend_from_slice(&chunk[..nb_bytes_read]);
+        }
+
+        if !self.buffer.starts_with(PREFACE) {
+            return Err(Http2Error::Connection(ErrorCode::ProtocolError, "Invalid connection preface".to_string()));
+        }
+        self.buffer.drain(..PREFACE.len());
+        Ok(())
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> Result<(), Http2Error> {
+        //A header block can't be interleaved with other frames (RFC 9113 6.10)
+        if let Some((stream_id, _, _)) = &self.continuation {
+            if frame.kind != FrameType::Continuation || frame.stream_id != *stream_id {
+                return Err(Http2Error::Connection(ErrorCode::ProtocolError, "Expected CONTINUATION".to_string()));
+            }
+        }
+        //The client preface ends with its SETTINGS
+        if !self.settings_received && frame.kind != FrameType::Settings {
+            return Err(Http2Error::Connection(ErrorCode::ProtocolError, "Expected SETTINGS".to_string()));
+        }
+
+        match frame.kind {
+            FrameType::Data => self.on_data(frame),
+            FrameType::Headers => self.on_headers(frame),
+            FrameType::Continuation => self.on_continuation(frame),
+            FrameType::Priority => {
+                //Deprecated, only checked
+                if frame.stream_id == 0 {
+                    return Err(Http2Error::Connection(ErrorCode::ProtocolError, "PRIORITY on stream 0".to_string()));
+                }
+                if frame.payload.len() != 5 {
+                    return Err(Http2Error::Stream(frame.stream_id, ErrorCode::FrameSizeError, "Invalid PRIORITY length".to_string()));
+                }
+                Ok(())
+            },
+            FrameType::RstStream => self.on_rst_stream(frame),
+            FrameType::Settings => self.on_settings(frame),
+            FrameType::PushPromise => Err(Http2Error::Connection(ErrorCode::ProtocolError, "PUSH_PROMISE from a client".to_string())),
+            FrameType::Ping => self.on_ping(frame),
+            FrameType::Goaway => {
+                if frame.stream_id != 0 {
+                    return Err(Http2Error::Connection(ErrorCode::ProtocolError, "GOAWAY on a stream".to_string()));
+                }
+                self.goaway_received = true;
+                Ok(())
+            },
+            FrameType::WindowUpdate => self.on_window_update(frame),
+            FrameType::Unknown(_) => Ok(()),
+        }
+    }
+
+    fn on_settings(&mut self, frame: Frame) -> Result<(), Http2Error> {
+        if frame.stream_id != 0 {
+            return Err(Http2Error::Connection(ErrorCode::ProtocolError, "SETTINGS on a stream".to_string()));
+        }
+        if frame.has_flag(FLAG_ACK) {
+            if !frame.payload.is_empty() {
+                return Err(Http2Error::Connection(ErrorCode::FrameSizeError, "SETTINGS acknowledgment with a payload".to_string()));
+            }
+            return Ok(());
+        }
+
+        let previous_window = self.remote_settings.initial_window_size as i64;
+        self.remote_settings.apply(&frame.payload)?;
+        self.settings_received = true;
+
+        //A new initial window size applies to the streams already open (RFC 9113 6.9.2)
+        let delta = self.remote_settings.initial_window_size as i64 - previous_window;
+        for stream in self.streams.values_mut() {
+            stream.send_window += delta;
+            if stream.send_window > MAX_WINDOW_SIZE {
+                return Err(Http2Error::Connection(ErrorCode::FlowControlError, "Stream window too large".to_string()));
+            }
+        }
+
+        self.queue_frame(Frame::new(FrameType::Settings, FLAG_ACK, 0, Vec::new()));
+        Ok(())
+    }
+
+    fn on_ping(&mut self, frame: Frame) -> Result<(), Http2Error> {
+        if frame.stream_id != 0 {
+            return Err(Http2Error::Connection(ErrorCode::ProtocolError, "PING on a stream".to_string()));
+        }
+        if frame.payload.len() != 8 {
+            return Err(Http2Error::Connection(ErrorCode::FrameSizeError, "Invalid PING length".to_string()));
+        }
+        if !frame.has_flag(FLAG_ACK) {
+            self.queue_frame(Frame::new(FrameType::Ping, FLAG_ACK, 0, frame.payload));
+        }
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, frame: Frame) -> Result<(), Http2Error> {
+        if frame.payload.len() != 4 {
+            return Err(Http2Error::Connection(ErrorCode::FrameSizeError, "Invalid WINDOW_UPDATE length".to_string()));
+        }
+        let increment = (u32::from_be_bytes([frame.payload[0], frame.payload[1], frame.payload[2], frame.payload[3]]) & 0x7FFF_FFFF) as i64;
+
+        if frame.stream_id == 0 {
+            if increment == 0 {
+                return Err(Http2Error::Connection(ErrorCode::ProtocolError, "Window increment of 0".to_string()));
+            }
+            self.send_window += increment;
+            if self.send_window > MAX_WINDOW_SIZE {
+                return Err(Http2Error::Connection(ErrorCode::FlowControlError, "Connection window too large".to_string()));
+            }
+            return Ok(());
+        }
+
+        if frame.stream_id > self.last_stream_id {
+            return Err(Http2Error::Connection(ErrorCode::ProtocolError, "WINDOW_UPDATE on an idle stream".to_string()));
+        }
+        if increment == 0 {
+            return Err(Http2Error::Stream(frame.stream_id, ErrorCode::ProtocolError, "Window increment of 0".to_string()));
+        }
+        //Closed streams may still receive window updates, they are ignored
+        if let Some(stream) = self.streams.get_mut(&frame.stream_id) {
+            stream.send_window += increment;
+            if stream.send_window > MAX_WINDOW_SIZE {
+                return Err(Http2Error::Stream(frame.stream_id, ErrorCode::FlowControlError, "Stream window too large".to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    fn on_rst_stream(&mut self, frame: Frame) -> Result<(), Http2Error> {
+        if frame.stream_id == 0 || frame.stream_id > self.last_stream_id {
+            return Err(Http2Error::Connection(ErrorCode::ProtocolError, "RST_STREAM on an idle stream".to_string()));
+        }
+        if frame.payload.len() != 4 {
+            return Err(Http2Error::Connection(ErrorCode::FrameSizeError, "Invalid RST_STREAM length".to_string()));
+        }
+
+        //Dropping the receiver makes the handler's next write fail
+        if self.streams.remove(&frame.stream_id).is_none() {
+            return Ok(());
+        }
+
+        if self.reset_window_start.elapsed() >= Duration::from_secs(1) {
+            self.reset_window_start = Instant::now();
+            self.resets = 0;
+        }
+        self.resets += 1;
+        if self.resets > self.http2.max_resets_per_second {
+            return Err(Http2Error::Connection(ErrorCode::EnhanceYourCalm, "Too many stream resets".to_string()));
+        }
+        Ok(())
+    }
+
+    fn on_data(&mut self, frame: Frame) -> Result<(), Http2Error> {
+        if frame.stream_id == 0 || frame.stream_id > self.last_stream_id {
+            return Err(Http2Error::Connection(ErrorCode::ProtocolError, "DATA on an idle stream".to_string()));
+        }
+
+        //The whole payload counts for flow control, padding included (RFC 9113 6.9.1)
+        let length = frame.payload.len() as i64;
+        if length > self.receive_window {
+            return Err(Http2Error::Connection(ErrorCode::FlowControlError, "Connection window exceeded".to_string()));
+        }
+        self.receive_window -= length;
+        if self.receive_window < self.http2.initial_window_size as i64 / 2 {
+            let increment = self.http2.initial_window_size as i64 - self.receive_window;
+            self.queue_frame(Frame::window_update(0, increment as u32));
+            self.receive_window += increment;
+        }
+
+        let content = frame.content()?;
+        let end_stream = frame.has_flag(FLAG_END_STREAM);
+        let max_body_size = self.config.limits.max_body_size;
+        let initial_window_size = self.http2.initial_window_size as i64;
+
+        let stream = match self.streams.get_mut(&frame.stream_id) {
+            Some(stream) if !stream.remote_closed => stream,
+            _ => return Err(Http2Error::Stream(frame.stream_id, ErrorCode::StreamClosed, "DATA on a closed stream".to_string())),
+        };
+        if length > stream.receive_window {
+            return Err(Http2Error::Stream(frame.stream_id, ErrorCode::FlowControlError, "Stream window exceeded".to_string()));
+        }
+        stream.receive_window -= length;
+
+        let mut too_large = false;
+        if let Some(request) = &mut stream.request {
+            if request.body.len() + content.len() > max_body_size {
+                too_large = true;
+            }
+            else {
+                request.body.extend_from_slice(content);
+            }
+        }
+
+        if end_stream {
+            stream.remote_closed = true;
+        }
+        else if stream.receive_window < initial_window_size / 2 {
+            let increment = initial_window_size - stream.receive_window;
+            stream.receive_window += increment;
+            self.queue_frame(Frame::window_update(frame.stream_id, increment as u32));
+        }
+
+        if too_large {
+            self.reject(frame.stream_id, 413);
+        }
+        else if end_stream {
+            self.complete_request(frame.stream_id)?;
+        }
+        Ok(())
+    }
+
+    fn on_headers(&mut self, frame: Frame) -> Result<(), Http2Error> {
+        //Client streams have odd identifiers
+        if frame.stream_id == 0 || frame.stream_id.is_multiple_of(2) {
+            return Err(Http2Error::Connection(ErrorCode::ProtocolError, "Invalid stream identifier".to_string()));
+        }
+
+        let block = frame.content()?.to_vec();
+        let end_stream = frame.has_flag(FLAG_END_STREAM);
+        if frame.has_flag(FLAG_END_HEADERS) {
+            return self.on_header_block(frame.stream_id, end_stream, block);
+        }
+
+        self.check_header_block_size(&block)?;
+        self.continuation = Some((frame.stream_id, end_stream, block));
+        Ok(())
+    }
+
+    fn on_continuation(&mut self, frame: Frame) -> Result<(), Http2Error> {
+        let (stream_id, end_stream, mut block) = match self.continuation.take() {
+            Some(continuation) => continuation,
+            None => return Err(Http2Error::Connection(ErrorCode::ProtocolError, "CONTINUATION without HEADERS".to_string())),
+        };
+
+        block.extend_from_slice(&frame.payload);
+        self.check_header_block_size(&block)?;
+        if frame.has_flag(FLAG_END_HEADERS) {
+            return self.on_header_block(stream_id, end_stream, block);
+        }
+
+        self.continuation = Some((stream_id, end_stream, block));
+        Ok(())
+    }
+
+    // Same bound as the header section of a HTTP/1.1 request
+    fn check_header_block_size(&self, block: &[u8]) -> Result<(), Http2Error> {
+        let limits = &self.config.limits;
+        if block.len() > limits.max_request_line + limits.max_headers * limits.max_header_size {
+            return Err(Http2Error::Connection(ErrorCode::EnhanceYourCalm, "Header block too large".to_string()));
+        }
+        Ok(())
+    }
+
+    fn on_header_block(&mut self, stream_id: u32, end_stream: bool, block: Vec<u8>) -> Result<(), Http2Error> {
+        let fields = self.decoder.decode(&block)?;
+
+        //Trailer section of a request already open
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            if stream.remote_closed {
+                return Err(Http2Error::Stream(stream_id, ErrorCode::StreamClosed, "HEADERS on a closed stream".to_string()));
+            }
+            if !end_stream || fields.iter().any(|(name, _)| name.starts_with(':')) {
+                return Err(Http2Error::Stream(stream_id, ErrorCode::ProtocolError, "Invalid trailer section".to_string()));
+            }
+            stream.remote_closed = true;
+            if let Some(request) = &mut stream.request {
+                for (name, value) in &fields {
+                    request.trailers.append(name, value);
+                }
+            }
+            return self.complete_request(stream_id);
+        }
+
+        //Stream identifiers only grow (RFC 9113 5.1.1)
+        if stream_id <= self.last_stream_id {
+            return Err(Http2Error::Connection(ErrorCode::ProtocolError, "Stream identifier reused".to_string()));
+        }
+        self.last_stream_id = stream_id;
+        //Streams opened after GOAWAY are ignored, the client retries them on a new connection
+        if self.goaway_sent {
+            return Ok(());
+        }
+        //Handlers of reset streams still count, they run until they return
+        let max_concurrent_streams = self.http2.max_concurrent_streams as usize;
+        if self.streams.len() >= max_concurrent_streams || self.running_handlers.load(Ordering::SeqCst) >= max_concurrent_streams {
+            return Err(Http2Error::Stream(stream_id, ErrorCode::RefusedStream, "Too many concurrent streams".to_string()));
+        }
+
+        let limits = &self.config.limits;
+        if fields.len() > limits.max_headers || fields.iter().any(|(name, value)| name.len() + value.len() + 2 > limits.max_header_size) {
+            self.open_stream(stream_id, None, end_stream);
+            self.reject(stream_id, 431);
+            return Ok(());
+        }
+
+        let mut request = Self::build_request(stream_id, fields)?;
+        request.peer_addr = self.peer_addr;
+        if let Some(server_name) = &self.server_name {
+            request.extensions.insert(TlsServerName(server_name.clone()));
+        }
+        if request.content_length().is_some_and(|content_length| content_length > limits.max_body_size) {
+            self.open_stream(stream_id, None, end_stream);
+            self.reject(stream_id, 413);
+            return Ok(());
+        }
+
+        self.open_stream(stream_id, Some(request), end_stream);
+        if end_stream {
+            self.complete_request(stream_id)?;
+        }
+        Ok(())
+    }
+
+    // Same request type as HTTP/1.1, pseudo-header fields become the request-line and Host
+    fn build_request(stream_id: u32, fields: Vec<(String, String)>) -> Result<HttpMessage, Http2Error> {
+        let protocol_error = |reason: &str| Http2Error::Stream(stream_id, ErrorCode::ProtocolError, reason.to_string());
+
+        let mut method: Option<String> = None;
+        let mut scheme: Option<String> = None;
+        let mut authority: Option<String> = None;
+        let mut path: Option<String> = None;
+        let mut headers = HeaderMap::new();
+        let mut cookies: Vec<String> = Vec::new();
+
+        for (name, value) in fields {
+            if value.bytes().any(|byte| byte == b'\r' || byte == b'\n' || byte == 0) {
+                return Err(protocol_error("Invalid field value"));
+            }
+
+            if let Some(pseudo_header) = name.strip_prefix(':') {
+                if !headers.is_empty() || !cookies.is_empty() {
+                    return Err(protocol_error("Pseudo-header after regular fields"));
+                }
+                let slot = match pseudo_header {
+                    "method" => &mut method,
+                    "scheme" => &mut scheme,
+                    "authority" => &mut authority,
+                    "path" => &mut path,
+                    _ => return Err(protocol_error("Unknown pseudo-header")),
+                };
+                if slot.is_some() {
+                    return Err(protocol_error("Duplicated pseudo-header"));
+                }
+                *slot = Some(value);
+                continue;
+            }
+
+            //Field names are lowercase in HTTP/2 (RFC 9113 8.2.1)
+            if name.is_empty() || !name.bytes().all(|byte| Method::is_tchar(byte) && !byte.is_ascii_uppercase()) {
+                return Err(protocol_error("Invalid field name"));
+            }
+            if CONNECTION_SPECIFIC_FIELDS.contains(&name.as_str()) || (name == "te" && !value.eq_ignore_ascii_case("trailers")) {
+                return Err(protocol_error("Connection-specific field"));
+            }
+            //Cookie may be split in several fields for better compression (RFC 9113 8.2.3)
+            if name == "cookie" {
+                cookies.push(value);
+                continue;
+            }
+            headers.append(&name, &value);
+        }
+        if !cookies.is_empty() {
+            headers.append("cookie", &cookies.join("; "));
+        }
+
+        let method = match method {
+            Some(method) => Method::parse(&method).map_err(|_| protocol_error("Invalid :method"))?,
+            None => return Err(protocol_error("Missing :method")),
+        };
+        //CONNECT only carries the authority (RFC 9113 8.5)
+        let target = if method == Method::Connect {
+            match (&authority, &scheme, &path) {
+                (Some(authority), None, None) => authority.clone(),
+                _ => return Err(protocol_error("Invalid CONNECT pseudo-headers")),
+            }
+        }
+        else {
+            match (&scheme, path) {
+                (Some(_), Some(path)) if !path.is_empty() => path,
+                _ => return Err(protocol_error("Missing :scheme or :path")),
+            }
+        };
+        let target = RequestTarget::parse(&target).map_err(|_| protocol_error("Invalid :path"))?;
+
+        //Handlers read the authority from Host like for HTTP/1.1
+        if let Some(authority) = &authority {
+            if !headers.contains("host") {
+                headers.append("host", authority);
+            }
+        }
+
+        Ok(HttpMessage {
+            method,
+            target,
+            version: Version::Http2,
+            headers,
+            body: Vec::new(),
+            trailers: HeaderMap::new(),
+            extensions: Extensions::new(),
+            peer_addr: None,
+        })
+    }
+
+    fn open_stream(&mut self, stream_id: u32, request: Option<HttpMessage>, remote_closed: bool) {
+        self.streams.insert(stream_id, Http2Stream {
+            request,
+            remote_closed,
+            receive_window: self.http2.initial_window_size as i64,
+            send_window: self.remote_settings.initial_window_size as i64,
+            output: None,
+            pending_data: Vec::new(),
+            pending_end: false,
+            blocked_since: None,
+        });
+    }
+
+    // The request is fully received, check its length and hand it to the handler
+    fn complete_request(&mut self, stream_id: u32) -> Result<(), Http2Error> {
+        let request = match self.streams.get_mut(&stream_id).and_then(|stream| stream.request.take()) {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+
+        //Content-Length must match the DATA frames (RFC 9113 8.1.1)
+        if request.content_length().is_some_and(|content_length| content_length != request.body.len()) {
+            return Err(Http2Error::Stream(stream_id, ErrorCode::ProtocolError, "Content-Length does not match the body".to_string()));
+        }
+
+        self.dispatch(stream_id, request);
+        Ok(())
+    }
+
+    fn dispatch(&mut self, stream_id: u32, mut request: HttpMessage) {
+        let (sender, receiver) = mpsc::sync_channel::<StreamOutput>(STREAM_CHANNEL_BOUND);
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.output = Some(receiver);
+        }
+
+        let handler = self.handler.clone();
+        let config = self.config.clone();
+        let slot = HandlerSlot::new(&self.running_handlers);
+        thread::spawn(move || {
+            let started = Instant::now();
+            let mut response = HttpServer::respond(&handler, &mut request, &config);
+            let include_body = request.method != Method::Head && HttpResponse::allows_body(response.status);
+            let bytes_sent = Self::send_response(&sender, &mut response, include_body);
+            drop(slot);
+
+            if let Some(access_log) = &config.access_log {
+                access_log.log(&AccessLogEntry::new(&request, response.status, bytes_sent, started.elapsed()));
+            }
+        });
+    }
+
+    // Hand the response to the connection thread, returns the number of body bytes sent
+    fn send_response(sender: &SyncSender<StreamOutput>, response: &mut HttpResponse, include_body: bool) -> u64 {
+        let fields = Self::response_fields(response);
+        let body_stream = response.body_stream.take();
+        let has_body = include_body && (body_stream.is_some() || !response.body.is_empty());
+
+        if sender.send(StreamOutput::Headers(fields, !has_body)).is_err() || !has_body {
+            return 0;
+        }
+
+        let bytes_sent = match body_stream {
+            Some(body_stream) => {
+                let mut writer = StreamWriter { sender: sender.clone(), bytes_sent: 0 };
+                if let Err(err) = body_stream(&mut writer) {
+                    println!("Error while streaming http2 response {:?}", err);
+                    let _ = sender.send(StreamOutput::Reset(ErrorCode::InternalError));
+                    return writer.bytes_sent;
+                }
+                writer.bytes_sent
+            },
+            None => {
+                let body = std::mem::take(&mut response.body);
+                let body_length = body.len() as u64;
+                if sender.send(StreamOutput::Data(body)).is_err() {
+                    return 0;
+                }
+                body_length
+            },
+        };
+        let _ = sender.send(StreamOutput::End);
+        bytes_sent
+    }
+
+    // Answer with an error status without calling the handler, the rest of the request is discarded
+    fn reject(&mut self, stream_id: u32, status: u16) {
+        let response = HttpResponse::error(status);
+        let fields = Self::response_fields(&response);
+        self.queue_headers(stream_id, fields, false);
+
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.request = None;
+            stream.output = None;
+            stream.pending_data = response.body;
+            stream.pending_end = true;
+        }
+    }
+
+    // :status and the response fields, fields tied to HTTP/1.1 are dropped
+    fn response_fields(response: &HttpResponse) -> Vec<(String, String)> {
+        let mut fields: Vec<(String, String)> = vec![(":status".to_string(), response.status.to_string())];
+        for (name, value) in response.headers.iter() {
+            let name = name.to_ascii_lowercase();
+            if CONNECTION_SPECIFIC_FIELDS.contains(&name.as_str()) || name == "content-length" || name == "date" {
+                continue;
+            }
+            fields.push((name, value.to_string()));
+        }
+
+        fields.push(("date".to_string(), format_http_date(SystemTime::now())));
+        let content_length = match &response.body_stream {
+            Some(_) => response.stream_length,
+            None => Some(response.body.len() as u64),
+        };
+        if let (true, Some(content_length)) = (HttpResponse::allows_body(response.status), content_length) {
+            fields.push(("content-length".to_string(), content_length.to_string()));
+        }
+        fields
+    }
+
+    // Move the output of the handlers to frames, as far as the flow control windows allow
+    fn send_responses(&mut self) {
+        let mut stream_ids: Vec<u32> = self.streams.keys().copied().collect();
+        stream_ids.sort();
+
+        for stream_id in stream_ids {
+            self.send_stream_output(stream_id);
+        }
+    }
+
+    fn send_stream_output(&mut self, stream_id: u32) {
+        loop {
+            let max_frame_size = self.remote_settings.max_frame_size as usize;
+            let connection_window = self.send_window;
+            let stream = match self.streams.get_mut(&stream_id) {
+                Some(stream) => stream,
+                None => return,
+            };
+
+            if !stream.pending_data.is_empty() || stream.pending_end {
+                let window = stream.send_window.min(connection_window).max(0) as usize;
+                let length = stream.pending_data.len().min(window).min(max_frame_size);
+                if length == 0 && !stream.pending_data.is_empty() {
+                    //Blocked until the client sends WINDOW_UPDATE
+                    stream.blocked_since.get_or_insert_with(Instant::now);
+                    return;
+                }
+                stream.blocked_since = None;
+
+                let data: Vec<u8> = stream.pending_data.drain(..length).collect();
+                let end_stream = stream.pending_data.is_empty() && stream.pending_end;
+                stream.send_window -= length as i64;
+                self.send_window -= length as i64;
+                self.queue_frame(Frame::new(FrameType::Data, if end_stream { FLAG_END_STREAM } else { 0 }, stream_id, data));
+
+                if end_stream {
+                    self.finish_stream(stream_id);
+                    return;
+                }
+                continue;
+            }
+
+            //Next output only once the previous body write is sent, the handler waits meanwhile
+            let output = match stream.output.as_ref().map(|receiver| receiver.try_recv()) {
+                Some(Ok(output)) => output,
+                Some(Err(TryRecvError::Empty)) | None => return,
+                Some(Err(TryRecvError::Disconnected)) => {
+                    //The handler thread ended without finishing its response
+                    self.reset_stream(stream_id, ErrorCode::InternalError);
+                    return;
+                },
+            };
+
+            match output {
+                StreamOutput::Headers(fields, end_stream) => {
+                    self.queue_headers(stream_id, fields, end_stream);
+                    if end_stream {
+                        self.finish_stream(stream_id);
+                        return;
+                    }
+                },
+                StreamOutput::Data(data) => stream.pending_data = data,
+                StreamOutput::End => {
+                    stream.output = None;
+                    stream.pending_end = true;
+                },
+                StreamOutput::Reset(code) => {
+                    self.reset_stream(stream_id, code);
+                    return;
+                },
+            }
+        }
+    }
+
+    // A client that never opens its window would hold the stream and its handler forever
+    // Same bound as a blocked write on a HTTP/1.1 connection
+    fn reset_stalled_streams(&mut self) {
+        let write_timeout = self.config.write_timeout;
+        let stalled: Vec<u32> = self.streams.iter()
+            .filter(|(_, stream)| stream.blocked_since.is_some_and(|blocked_since| blocked_since.elapsed() >= write_timeout))
+            .map(|(stream_id, _)| *stream_id)
+            .collect();
+
+        for stream_id in stalled {
+            println!("Http2 stream {} blocked on flow control for {:?}, reset", stream_id, write_timeout);
+            self.reset_stream(stream_id, ErrorCode::Cancel);
+        }
+    }
+
+    // HEADERS followed by CONTINUATION frames when the block is larger than a frame
+    fn queue_headers(&mut self, stream_id: u32, fields: Vec<(String, String)>, end_stream: bool) {
+        let block = encode_header_block(&fields);
+        let max_frame_size = self.remote_settings.max_frame_size as usize;
+
+        let fragments: Vec<&[u8]> = if block.is_empty() { vec![&block[..]] } else { block.chunks(max_frame_size).collect() };
+        let last_index = fragments.len() - 1;
+        for (index, fragment) in fragments.into_iter().enumerate() {
+            let mut flags = if index == last_index { FLAG_END_HEADERS } else { 0 };
+            let kind = if index == 0 {
+                if end_stream {
+                    flags |= FLAG_END_STREAM;
+                }
+                FrameType::Headers
+            }
+            else {
+                FrameType::Continuation
+            };
+            self.queue_frame(Frame::new(kind, flags, stream_id, fragment.to_vec()));
+        }
+    }
+
+    // END_STREAM sent, a request still being received is cancelled as its response is complete
+    fn finish_stream(&mut self, stream_id: u32) {
+        if let Some(stream) = self.streams.remove(&stream_id) {
+            if !stream.remote_closed {
+                self.queue_frame(Frame::rst_stream(stream_id, ErrorCode::NoError));
+            }
+        }
+    }
+
+    fn reset_stream(&mut self, stream_id: u32, code: ErrorCode) {
+        self.streams.remove(&stream_id);
+        self.queue_frame(Frame::rst_stream(stream_id, code));
+    }
+
+    fn queue_frame(&mut self, frame: Frame) {
+        self.output.extend_from_slice(&frame.to_bytes());
+    }
+
+    fn flush_output(&mut self) -> io::Result<()> {
+        if self.output.is_empty() {
+            return Ok(());
+        }
+        self.stream.write_all(&self.output)?;
+        self.output.clear();
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Read, sync::{mpsc::{RecvTimeoutError, Sender}, Mutex}, thread::JoinHandle};
+
+    // One end of an in-memory connection, what one end writes the other reads
+    struct MemoryStream {
+        incoming: Receiver<Vec<u8>>,
+        outgoing: Sender<Vec<u8>>,
+        pending: Vec<u8>,
+        read_timeout: Mutex<Option<Duration>>,
+    }
+
+    impl MemoryStream {
+        fn pair() -> (MemoryStream, MemoryStream) {
+            let (first_sender, first_receiver) = mpsc::channel();
+            let (second_sender, second_receiver) = mpsc::channel();
+            (MemoryStream::new(first_receiver, second_sender), MemoryStream::new(second_receiver, first_sender))
+        }
+
+        fn new(incoming: Receiver<Vec<u8>>, outgoing: Sender<Vec<u8>>) -> Self {
+            MemoryStream {
+                incoming,
+                outgoing,
+                pending: Vec::new(),
+                read_timeout: Mutex::new(None),
+            }
+        }
+    }
+
+    impl Read for MemoryStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pending.is_empty() {
+                let read_timeout = *self.read_timeout.lock().unwrap();
+                let received = match read_timeout {
+                    Some(read_timeout) => self.incoming.recv_timeout(read_timeout),
+                    None => self.incoming.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok(data) => self.pending = data,
+                    Err(RecvTimeoutError::Timeout) => return Err(io::Error::new(io::ErrorKind::WouldBlock, "Read timed out")),
+                    Err(RecvTimeoutError::Disconnected) => return Ok(0),
+                }
+            }
+            let length = buf.len().min(self.pending.len());
+            buf[..length].copy_from_slice(&self.pending[..length]);
+            self.pending.drain(..length);
+            Ok(length)
+        }
+    }
+
+    impl Write for MemoryStream {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            if !data.is_empty() {
+                self.outgoing.send(data.to_vec()).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Other end closed"))?;
+            }
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl HttpStream for MemoryStream {
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            *self.read_timeout.lock().unwrap() = timeout;
+            Ok(())
+        }
+
+        fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            Ok(SocketAddr::from(([127, 0, 0, 1], 40000)))
+        }
+    }
+
+    // Client end of a connection served on its own thread
+    struct Client {
+        stream: MemoryStream,
+        buffer: Vec<u8>,
+        connection: JoinHandle<io::Result<()>>,
+    }
+
+    impl Client {
+        fn start(config: HttpServerConfig, handler: Handler, should_stop: Arc<AtomicBool>) -> Self {
+            let (stream, server_stream) = MemoryStream::pair();
+            let connection = thread::spawn(move || {
+                Http2Connection::new(Box::new(server_stream), Vec::new(), Arc::new(config), handler, should_stop).serve()
+            });
+            Client { stream, buffer: Vec::new(), connection }
+        }
+
+        // Preface and SETTINGS, returns once the server acknowledged them
+        fn connect(config: HttpServerConfig, handler: Handler, parameters: &[(u16, u32)]) -> Self {
+            let mut client = Self::start(config, handler, Arc::new(AtomicBool::new(false)));
+            client.stream.write_all(PREFACE).unwrap();
+            client.send(settings(parameters));
+            while !client.expect(FrameType::Settings).has_flag(FLAG_ACK) {}
+            client
+        }
+
+        fn send(&mut self, frame: Frame) {
+            self.stream.write_all(&frame.to_bytes()).unwrap();
+        }
+
+        // None when no frame comes within timeout
+        fn next_frame(&mut self, timeout: Duration) -> Option<Frame> {
+            let deadline = Instant::now() + timeout;
+            loop {
+                if let Some(frame) = Frame::parse(&mut self.buffer, 16_777_215).unwrap() {
+                    return Some(frame);
+                }
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return None;
+                }
+                self.stream.set_read_timeout(Some(remaining)).unwrap();
+                let mut chunk = [0u8; 16384];
+                match self.stream.read(&mut chunk) {
+                    Ok(0) | Err(_) => return None,
+                    Ok(nb_bytes_read) => self.buffer.extend_from_slice(&chunk[..nb_bytes_read]),
+                }
+            }
+        }
+
+        // Next frame of this kind, the others are skipped
+        fn expect(&mut self, kind: FrameType) -> Frame {
+            loop {
+                match self.next_frame(Duration::from_secs(5)) {
+                    Some(frame) if frame.kind == kind => return frame,
+                    Some(_) => continue,
+                    None => panic!("No {:?} frame received", kind),
+                }
+            }
+        }
+
+        // Body of DATA frames up to END_STREAM
+        fn read_body(&mut self) -> Vec<u8> {
+            let mut body: Vec<u8> = Vec::new();
+            loop {
+                let data = self.expect(FrameType::Data);
+                body.extend_from_slice(&data.payload);
+                if data.has_flag(FLAG_END_STREAM) {
+                    return body;
+                }
+            }
+        }
+
+        fn request(&mut self, stream_id: u32, path: &str, end_stream: bool) {
+            let flags = FLAG_END_HEADERS | if end_stream { FLAG_END_STREAM } else { 0 };
+            self.send(Frame::new(FrameType::Headers, flags, stream_id, encode_header_block(&request_fields(path))));
+        }
+
+        // The connection ends once the client is gone
+        fn close(self) -> io::Result<()> {
+            drop(self.stream);
+            self.connection.join().unwrap()
+        }
+    }
+
+    fn request_fields(path: &str) -> Vec<(String, String)> {
+        [(":method", "GET"), (":scheme", "http"), (":authority", "a"), (":path", path)].iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn settings(parameters: &[(u16, u32)]) -> Frame {
+        let payload = parameters.iter().flat_map(|(identifier, value)| [&identifier.to_be_bytes()[..], &value.to_be_bytes()[..]].concat()).collect();
+        Frame::new(FrameType::Settings, 0, 0, payload)
+    }
+
+    fn status(frame: &Frame) -> String {
+        let fields = HpackDecoder::new(4096).decode(frame.content().unwrap()).unwrap();
+        fields.into_iter().find(|(name, _)| name == ":status").unwrap().1
+    }
+
+    // Error code of a RST_STREAM or GOAWAY frame
+    fn error_code(frame: &Frame) -> u32 {
+        let offset = if frame.kind == FrameType::Goaway { 4 } else { 0 };
+        u32::from_be_bytes(frame.payload[offset..offset + 4].try_into().unwrap())
+    }
+
+    // "/stream" writes until the stream is reset, any other path gets a body of 25 bytes
+    fn handler() -> Handler {
+        Arc::new(|request: &mut HttpMessage| {
+            if request.target.path == "/stream" {
+                return HttpResponse::stream(200, "text/plain", |writer| loop {
+                    writer.write_all(b"tick")?;
+                    thread::sleep(Duration::from_millis(5));
+                });
+            }
+            HttpResponse::text(200, "0123456789012345678901234")
+        })
+    }
+
+    fn http2_config(http2: Http2Config) -> HttpServerConfig {
+        HttpServerConfig { http2: Some(http2), ..HttpServerConfig::default() }
+    }
+
+    #[test]
+    fn exchanges_preface_and_settings() {
+        let mut client = Client::start(HttpServerConfig::default(), handler(), Arc::new(AtomicBool::new(false)));
+        client.stream.write_all(PREFACE).unwrap();
+        client.send(settings(&[(0x4, 1000)]));
+
+        let server_settings = client.next_frame(Duration::from_secs(5)).unwrap();
+        assert_eq!(server_settings.kind, FrameType::Settings);
+        assert!(!server_settings.has_flag(FLAG_ACK));
+        let mut announced = Http2Settings::default();
+        announced.apply(&server_settings.payload).unwrap();
+        assert_eq!(announced.max_concurrent_streams, Some(100));
+        assert!(!announced.enable_push);
+        assert_eq!(announced.initial_window_size, 1024 * 1024);
+
+        let window_update = client.next_frame(Duration::from_secs(5)).unwrap();
+        assert_eq!((window_update.kind, window_update.stream_id), (FrameType::WindowUpdate, 0));
+        let acknowledgment = client.next_frame(Duration::from_secs(5)).unwrap();
+        assert_eq!(acknowledgment.kind, FrameType::Settings);
+        assert!(acknowledgment.has_flag(FLAG_ACK) && acknowledgment.payload.is_empty());
+
+        client.send(Frame::new(FrameType::Ping, 0, 0, b"12345678".to_vec()));
+        let pong = client.expect(FrameType::Ping);
+        assert!(pong.has_flag(FLAG_ACK));
+        assert_eq!(pong.payload, b"12345678");
+        client.close().unwrap();
+
+        //Not a HTTP/2 preface, or no SETTINGS first
+        let mut client = Client::start(HttpServerConfig::default(), handler(), Arc::new(AtomicBool::new(false)));
+        client.stream.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        assert_eq!(error_code(&client.expect(FrameType::Goaway)), ErrorCode::ProtocolError as u32);
+        client.close().unwrap();
+
+        let mut client = Client::start(HttpServerConfig::default(), handler(), Arc::new(AtomicBool::new(false)));
+        client.stream.write_all(PREFACE).unwrap();
+        client.send(Frame::new(FrameType::Ping, 0, 0, b"12345678".to_vec()));
+        assert_eq!(error_code(&client.expect(FrameType::Goaway)), ErrorCode::ProtocolError as u32);
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn sends_within_flow_control_windows() {
+        let mut client = Client::connect(HttpServerConfig::default(), handler(), &[(0x4, 10)]);
+        client.request(1, "/", true);
+        assert_eq!(status(&client.expect(FrameType::Headers)), "200");
+
+        let data = client.expect(FrameType::Data);
+        assert_eq!(data.payload, b"0123456789");
+        assert!(!data.has_flag(FLAG_END_STREAM));
+        assert!(client.next_frame(Duration::from_millis(100)).is_none());
+
+        client.send(Frame::window_update(1, 10));
+        assert_eq!(client.expect(FrameType::Data).payload, b"0123456789");
+        client.send(Frame::window_update(1, 100));
+        assert_eq!(client.read_body(), b"01234");
+
+        //DATA above the window announced by the server
+        let config = http2_config(Http2Config { initial_window_size: 100, ..Http2Config::default() });
+        let mut client = Client::connect(config, handler(), &[]);
+        client.request(3, "/", false);
+        client.send(Frame::new(FrameType::Data, FLAG_END_STREAM, 3, vec![b'a'; 150]));
+        let reset = client.expect(FrameType::RstStream);
+        assert_eq!((reset.stream_id, error_code(&reset)), (3, ErrorCode::FlowControlError as u32));
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn resets_streams_blocked_on_flow_control() {
+        let config = HttpServerConfig { write_timeout: Duration::from_millis(200), ..HttpServerConfig::default() };
+        let mut client = Client::connect(config, handler(), &[(0x4, 0)]);
+        client.request(1, "/", true);
+        assert_eq!(status(&client.expect(FrameType::Headers)), "200");
+
+        let reset = client.expect(FrameType::RstStream);
+        assert_eq!((reset.stream_id, error_code(&reset)), (1, ErrorCode::Cancel as u32));
+        client.send(Frame::new(FrameType::Ping, 0, 0, b"12345678".to_vec()));
+        assert!(client.expect(FrameType::Ping).has_flag(FLAG_ACK));
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn stops_streams_reset_by_the_client() {
+        let config = http2_config(Http2Config { max_resets_per_second: 3, ..Http2Config::default() });
+        let mut client = Client::connect(config, handler(), &[]);
+        client.request(1, "/stream", true);
+        client.expect(FrameType::Data);
+        client.send(Frame::rst_stream(1, ErrorCode::Cancel));
+        //Frames already sent are still received
+        while client.next_frame(Duration::from_millis(100)).is_some() {}
+
+        client.request(3, "/", true);
+        let headers = client.expect(FrameType::Headers);
+        assert_eq!((headers.stream_id, status(&headers)), (3, "200".to_string()));
+
+        //Rapid reset, streams opened and cancelled at once
+        for stream_id in [5, 7, 9] {
+            client.request(stream_id, "/stream", true);
+            client.send(Frame::rst_stream(stream_id, ErrorCode::Cancel));
+        }
+        assert_eq!(error_code(&client.expect(FrameType::Goaway)), ErrorCode::EnhanceYourCalm as u32);
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn refuses_streams_over_the_concurrency_limit() {
+        let (release, released) = mpsc::channel::<()>();
+        let released = Arc::new(Mutex::new(released));
+        let handler: Handler = Arc::new(move |request: &mut HttpMessage| {
+            if request.target.path == "/wait" {
+                let _ = released.lock().unwrap().recv();
+            }
+            HttpResponse::text(200, "done")
+        });
+        let config = http2_config(Http2Config { max_concurrent_streams: 1, ..Http2Config::default() });
+        let mut client = Client::connect(config, handler, &[]);
+
+        client.request(1, "/wait", true);
+        client.request(3, "/", true);
+        let reset = client.expect(FrameType::RstStream);
+        assert_eq!((reset.stream_id, error_code(&reset)), (3, ErrorCode::RefusedStream as u32));
+
+        //A reset stream still counts while its handler runs
+        client.send(Frame::rst_stream(1, ErrorCode::Cancel));
+        client.request(5, "/", true);
+        let reset = client.expect(FrameType::RstStream);
+        assert_eq!((reset.stream_id, error_code(&reset)), (5, ErrorCode::RefusedStream as u32));
+
+        release.send(()).unwrap();
+        let started = Instant::now();
+        let mut stream_id = 7;
+        loop {
+            client.request(stream_id, "/", true);
+            match client.next_frame(Duration::from_secs(5)) {
+                Some(frame) if frame.kind == FrameType::Headers => break,
+                Some(frame) if frame.kind == FrameType::RstStream && started.elapsed() < Duration::from_secs(5) => {
+                    thread::sleep(Duration::from_millis(10));
+                    stream_id += 2;
+                },
+                _ => panic!("Stream not accepted once the handler returned"),
+            }
+        }
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn closes_after_goaway() {
+        //From the client, open streams are answered first
+        let mut client = Client::connect(HttpServerConfig::default(), handler(), &[]);
+        client.request(1, "/", true);
+        client.send(Frame::goaway(0, ErrorCode::NoError, ""));
+        assert_eq!(client.read_body(), b"0123456789012345678901234");
+        assert!(client.next_frame(Duration::from_secs(5)).is_none());
+        client.close().unwrap();
+
+        //From the server when it stops, the request sent along with the preface is still answered
+        let should_stop = Arc::new(AtomicBool::new(true));
+        let mut client = Client::start(HttpServerConfig::default(), handler(), should_stop);
+        let mut opening = PREFACE.to_vec();
+        opening.extend_from_slice(&settings(&[]).to_bytes());
+        opening.extend_from_slice(&Frame::new(FrameType::Headers, FLAG_END_HEADERS | FLAG_END_STREAM, 1, encode_header_block(&request_fields("/"))).to_bytes());
+        client.stream.write_all(&opening).unwrap();
+        let mut goaway: Option<Frame> = None;
+        let mut body: Vec<u8> = Vec::new();
+        while let Some(frame) = client.next_frame(Duration::from_secs(5)) {
+            match frame.kind {
+                FrameType::Goaway => goaway = Some(frame),
+                FrameType::Data => body.extend_from_slice(&frame.payload),
+                _ => {},
+            }
+        }
+        let goaway = goaway.unwrap();
+        assert_eq!(u32::from_be_bytes(goaway.payload[..4].try_into().unwrap()), 1);
+        assert_eq!(error_code(&goaway), ErrorCode::NoError as u32);
+        assert_eq!(body, b"0123456789012345678901234");
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn keeps_header_blocks_contiguous() {
+        let mut client = Client::connect(HttpServerConfig::default(), handler(), &[]);
+        let block = encode_header_block(&request_fields("/split"));
+        let (first, second) = block.split_at(block.len() / 2);
+        client.send(Frame::new(FrameType::Headers, FLAG_END_STREAM, 1, first.to_vec()));
+        client.send(Frame::new(FrameType::Continuation, FLAG_END_HEADERS, 1, second.to_vec()));
+        assert_eq!(status(&client.expect(FrameType::Headers)), "200");
+
+        //Another frame inside a header block
+        client.send(Frame::new(FrameType::Headers, FLAG_END_STREAM, 3, first.to_vec()));
+        client.send(Frame::new(FrameType::Ping, 0, 0, b"12345678".to_vec()));
+        assert_eq!(error_code(&client.expect(FrameType::Goaway)), ErrorCode::ProtocolError as u32);
+        client.close().unwrap();
+
+        let mut client = Client::connect(HttpServerConfig::default(), handler(), &[]);
+        client.send(Frame::new(FrameType::Continuation, FLAG_END_HEADERS, 1, second.to_vec()));
+        assert_eq!(error_code(&client.expect(FrameType::Goaway)), ErrorCode::ProtocolError as u32);
+        client.close().unwrap();
+    }
+}
@@ -0,0 +1,48 @@
+use std::{fmt::Display, io};
+
+// Error codes of RST_STREAM and GOAWAY frames (RFC 9113 7)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    NoError = 0x0,
+    ProtocolError = 0x1,
+    InternalError = 0x2,
+    FlowControlError = 0x3,
+    SettingsTimeout = 0x4,
+    StreamClosed = 0x5,
+    FrameSizeError = 0x6,
+    RefusedStream = 0x7,
+    Cancel = 0x8,
+    CompressionError = 0x9,
+    ConnectError = 0xa,
+    EnhanceYourCalm = 0xb,
+    InadequateSecurity = 0xc,
+    Http11Required = 0xd,
+}
+
+// Errors raised while serving a HTTP/2 connection (RFC 9113 5.4)
+#[derive(Debug)]
+pub enum Http2Error {
+    // The whole connection is closed with a GOAWAY frame
+    Connection(ErrorCode, String),
+    // Only the stream is closed with a RST_STREAM frame
+    Stream(u32, ErrorCode, String),
+    Io(io::Error),
+}
+
+impl Display for Http2Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Http2Error::Connection(code, reason) => write!(f, "Connection error {:?} : {}", code, reason),
+            Http2Error::Stream(stream_id, code, reason) => write!(f, "Stream {} error {:?} : {}", stream_id, code, reason),
+            Http2Error::Io(err) => write!(f, "Io error : {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Http2Error {}
+
+impl From<io::Error> for Http2Error {
+    fn from(err: io::Error) -> Self {
+        Http2Error::Io(err)
+    }
+}
@@ -0,0 +1,305 @@
+use crate::models::structs::http2_error::{ErrorCode, Http2Error};
+
+// First bytes sent by a HTTP/2 client (RFC 9113 3.4)
+pub const PREFACE: &[u8; 24] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+pub const FRAME_HEADER_LENGTH: usize = 9;
+// Largest flow control window (RFC 9113 6.9.1)
+pub const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+
+pub const FLAG_END_STREAM: u8 = 0x1;
+pub const FLAG_ACK: u8 = 0x1;
+pub const FLAG_END_HEADERS: u8 = 0x4;
+pub const FLAG_PADDED: u8 = 0x8;
+pub const FLAG_PRIORITY: u8 = 0x20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameType {
+    Data,
+    Headers,
+    Priority,
+    RstStream,
+    Settings,
+    PushPromise,
+    Ping,
+    Goaway,
+    WindowUpdate,
+    Continuation,
+    // Unknown frame types are ignored (RFC 9113 4.1)
+    Unknown(u8),
+}
+
+impl FrameType {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0x0 => FrameType::Data,
+            0x1 => FrameType::Headers,
+            0x2 => FrameType::Priority,
+            0x3 => FrameType::RstStream,
+            0x4 => FrameType::Settings,
+            0x5 => FrameType::PushPromise,
+            0x6 => FrameType::Ping,
+            0x7 => FrameType::Goaway,
+            0x8 => FrameType::WindowUpdate,
+            0x9 => FrameType::Continuation,
+            _ => FrameType::Unknown(value),
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            FrameType::Data => 0x0,
+            FrameType::Headers => 0x1,
+            FrameType::Priority => 0x2,
+            FrameType::RstStream => 0x3,
+            FrameType::Settings => 0x4,
+            FrameType::PushPromise => 0x5,
+            FrameType::Ping => 0x6,
+            FrameType::Goaway => 0x7,
+            FrameType::WindowUpdate => 0x8,
+            FrameType::Continuation => 0x9,
+            FrameType::Unknown(value) => *value,
+        }
+    }
+}
+
+pub struct Frame {
+    pub kind: FrameType,
+    pub flags: u8,
+    pub stream_id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(kind: FrameType, flags: u8, stream_id: u32, payload: Vec<u8>) -> Self {
+        Frame {
+            kind,
+            flags,
+            stream_id,
+            payload,
+        }
+    }
+
+    pub fn rst_stream(stream_id: u32, code: ErrorCode) -> Self {
+        Self::new(FrameType::RstStream, 0, stream_id, (code as u32).to_be_bytes().to_vec())
+    }
+
+    pub fn window_update(stream_id: u32, increment: u32) -> Self {
+        Self::new(FrameType::WindowUpdate, 0, stream_id, increment.to_be_bytes().to_vec())
+    }
+
+    pub fn goaway(last_stream_id: u32, code: ErrorCode, debug: &str) -> Self {
+        let mut payload = last_stream_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&(code as u32).to_be_bytes());
+        payload.extend_from_slice(debug.as_bytes());
+        Self::new(FrameType::Goaway, 0, 0, payload)
+    }
+
+    // Parse the frame at the start of buf, None when it is not complete yet
+    // Format : length (24) type (8) flags (8) R (1) stream identifier (31) payload
+    pub fn parse(buf: &mut Vec<u8>, max_frame_size: u32) -> Result<Option<Frame>, Http2Error> {
+        if buf.len() < FRAME_HEADER_LENGTH {
+            return Ok(None);
+        }
+
+        let length = u32::from_be_bytes([0, buf[0], buf[1], buf[2]]);
+        if length > max_frame_size {
+            return Err(Http2Error::Connection(ErrorCode::FrameSizeError, format!("Frame of {} bytes", length)));
+        }
+        if buf.len() < FRAME_HEADER_LENGTH + length as usize {
+            return Ok(None);
+        }
+
+        let kind = FrameType::from_u8(buf[3]);
+        let flags = buf[4];
+        let stream_id = u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]) & 0x7FFF_FFFF;
+        let payload: Vec<u8> = buf.drain(..FRAME_HEADER_LENGTH + length as usize).skip(FRAME_HEADER_LENGTH).collect();
+
+        Ok(Some(Frame::new(kind, flags, stream_id, payload)))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(FRAME_HEADER_LENGTH + self.payload.len());
+        bytes.extend_from_slice(&(self.payload.len() as u32).to_be_bytes()[1..]);
+        bytes.push(self.kind.as_u8());
+        bytes.push(self.flags);
+        bytes.extend_from_slice(&(self.stream_id & 0x7FFF_FFFF).to_be_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    // Payload of DATA and HEADERS frames without the padding, and without the priority fields of HEADERS
+    pub fn content(&self) -> Result<&[u8], Http2Error> {
+        let mut content = &self.payload[..];
+
+        if self.has_flag(FLAG_PADDED) {
+            let padding_length = match content.first() {
+                Some(padding_length) => *padding_length as usize,
+                None => return Err(Http2Error::Connection(ErrorCode::FrameSizeError, "Missing padding length".to_string())),
+            };
+            if padding_length >= content.len() {
+                return Err(Http2Error::Connection(ErrorCode::ProtocolError, "Padding longer than the payload".to_string()));
+            }
+            content = &content[1..content.len() - padding_length];
+        }
+
+        if self.kind == FrameType::Headers && self.has_flag(FLAG_PRIORITY) {
+            //Exclusive flag, stream dependency and weight, deprecated and ignored
+            if content.len() < 5 {
+                return Err(Http2Error::Connection(ErrorCode::FrameSizeError, "Missing priority fields".to_string()));
+            }
+            content = &content[5..];
+        }
+
+        Ok(content)
+    }
+}
+
+// SETTINGS parameters of one endpoint (RFC 9113 6.5.2)
+#[derive(Clone, Copy, Debug)]
+pub struct Http2Settings {
+    pub header_table_size: u32,
+    pub enable_push: bool,
+    // None for no limit
+    pub max_concurrent_streams: Option<u32>,
+    pub initial_window_size: u32,
+    pub max_frame_size: u32,
+    pub max_header_list_size: Option<u32>,
+}
+
+impl Default for Http2Settings {
+    // Values in effect before any SETTINGS frame is received
+    fn default() -> Self {
+        Http2Settings {
+            header_table_size: 4096,
+            enable_push: true,
+            max_concurrent_streams: None,
+            initial_window_size: 65535,
+            max_frame_size: 16384,
+            max_header_list_size: None,
+        }
+    }
+}
+
+impl Http2Settings {
+    // Apply the parameters of a SETTINGS payload, unknown identifiers are ignored
+    pub fn apply(&mut self, payload: &[u8]) -> Result<(), Http2Error> {
+        if !payload.len().is_multiple_of(6) {
+            return Err(Http2Error::Connection(ErrorCode::FrameSizeError, "Invalid SETTINGS length".to_string()));
+        }
+
+        for parameter in payload.chunks(6) {
+            let identifier = u16::from_be_bytes([parameter[0], parameter[1]]);
+            let value = u32::from_be_bytes([parameter[2], parameter[3], parameter[4], parameter[5]]);
+            match identifier {
+                0x1 => self.header_table_size = value,
+                0x2 => {
+                    if value > 1 {
+                        return Err(Http2Error::Connection(ErrorCode::ProtocolError, "Invalid SETTINGS_ENABLE_PUSH".to_string()));
+                    }
+                    self.enable_push = value == 1;
+                },
+                0x3 => self.max_concurrent_streams = Some(value),
+                0x4 => {
+                    if value as i64 > MAX_WINDOW_SIZE {
+                        return Err(Http2Error::Connection(ErrorCode::FlowControlError, "Invalid SETTINGS_INITIAL_WINDOW_SIZE".to_string()));
+                    }
+                    self.initial_window_size = value;
+                },
+                0x5 => {
+                    if !(16384..=16_777_215).contains(&value) {
+                        return Err(Http2Error::Connection(ErrorCode::ProtocolError, "Invalid SETTINGS_MAX_FRAME_SIZE".to_string()));
+                    }
+                    self.max_frame_size = value;
+                },
+                0x6 => self.max_header_list_size = Some(value),
+                _ => {},
+            }
+        }
+
+        Ok(())
+    }
+
+    // Payload of the SETTINGS frame announcing these values, a server never pushes
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut parameters: Vec<(u16, u32)> = vec![
+            (0x1, self.header_table_size),
+            (0x2, 0),
+            (0x4, self.initial_window_size),
+            (0x5, self.max_frame_size),
+        ];
+        if let Some(max_concurrent_streams) = self.max_concurrent_streams {
+            parameters.push((0x3, max_concurrent_streams));
+        }
+        if let Some(max_header_list_size) = self.max_header_list_size {
+            parameters.push((0x6, max_header_list_size));
+        }
+
+        let mut payload: Vec<u8> = Vec::with_capacity(parameters.len() * 6);
+        for (identifier, value) in parameters {
+            payload.extend_from_slice(&identifier.to_be_bytes());
+            payload.extend_from_slice(&value.to_be_bytes());
+        }
+        payload
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_frames_once_complete() {
+        let frame = Frame::new(FrameType::Headers, FLAG_END_HEADERS | FLAG_END_STREAM, 3, b"block".to_vec());
+        let bytes = frame.to_bytes();
+        assert_eq!(&bytes[..9], &[0, 0, 5, 0x1, 0x5, 0, 0, 0, 3]);
+
+        //Split over two reads, the second one also holds the start of the next frame
+        let mut buf = bytes[..7].to_vec();
+        assert!(Frame::parse(&mut buf, 16384).unwrap().is_none());
+        buf.extend_from_slice(&bytes[7..]);
+        buf.extend_from_slice(&Frame::window_update(0, 1).to_bytes()[..4]);
+        let parsed = Frame::parse(&mut buf, 16384).unwrap().unwrap();
+        assert_eq!((parsed.kind, parsed.flags, parsed.stream_id, parsed.payload), (FrameType::Headers, 0x5, 3, b"block".to_vec()));
+        assert_eq!(buf.len(), 4);
+
+        //The reserved bit is ignored, unknown types are kept
+        let mut buf = vec![0, 0, 0, 0xfa, 0, 0x80, 0, 0, 1];
+        let parsed = Frame::parse(&mut buf, 16384).unwrap().unwrap();
+        assert_eq!((parsed.kind, parsed.stream_id), (FrameType::Unknown(0xfa), 1));
+
+        let mut buf = Frame::new(FrameType::Data, 0, 1, vec![0; 16385]).to_bytes();
+        assert!(matches!(Frame::parse(&mut buf, 16384), Err(Http2Error::Connection(ErrorCode::FrameSizeError, _))));
+    }
+
+    #[test]
+    fn strips_padding_and_priority() {
+        let padded = Frame::new(FrameType::Data, FLAG_PADDED, 1, vec![2, b'a', b'b', 0, 0]);
+        assert_eq!(padded.content().unwrap(), b"ab");
+        let with_priority = Frame::new(FrameType::Headers, FLAG_PADDED | FLAG_PRIORITY, 1, vec![1, 0, 0, 0, 3, 16, b'h', 0]);
+        assert_eq!(with_priority.content().unwrap(), b"h");
+
+        assert!(Frame::new(FrameType::Data, FLAG_PADDED, 1, vec![4, b'a', 0, 0]).content().is_err());
+        assert!(Frame::new(FrameType::Data, FLAG_PADDED, 1, Vec::new()).content().is_err());
+        assert!(Frame::new(FrameType::Headers, FLAG_PRIORITY, 1, vec![0, 0, 0]).content().is_err());
+    }
+
+    #[test]
+    fn applies_settings() {
+        let announced = Http2Settings { max_concurrent_streams: Some(10), max_header_list_size: Some(8192), ..Http2Settings::default() };
+        let mut received = Http2Settings::default();
+        received.apply(&announced.to_payload()).unwrap();
+        assert_eq!(received.max_concurrent_streams, Some(10));
+        assert_eq!(received.max_header_list_size, Some(8192));
+        assert!(!received.enable_push);
+
+        //Unknown identifiers are ignored
+        assert!(received.apply(&[0, 0x7f, 0, 0, 0, 1]).is_ok());
+        for invalid in [&[0, 0x2, 0, 0, 0, 2][..], &[0, 0x4, 0x80, 0, 0, 0], &[0, 0x5, 0, 0, 0x10, 0], &[0, 0x1, 0, 0]] {
+            assert!(received.apply(invalid).is_err());
+        }
+    }
+}
@@ -0,0 +1,549 @@
+use std::{collections::VecDeque, sync::OnceLock};
+
+use crate::models::structs::http2_error::{ErrorCode, Http2Error};
+
+// Static table (RFC 7541 Appendix A), index 1 is the first entry
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// (code, length in bits) of each byte value, the last one is EOS (RFC 7541 Appendix B)
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28),
+    (0xfffffe4, 28), (0xfffffe5, 28), (0xfffffe6, 28), (0xfffffe7, 28),
+    (0xfffffe8, 28), (0xffffea, 24), (0x3ffffffc, 30), (0xfffffe9, 28),
+    (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28), (0xfffffec, 28),
+    (0xfffffed, 28), (0xfffffee, 28), (0xfffffef, 28), (0xffffff0, 28),
+    (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28),
+    (0xffffff4, 28), (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28),
+    (0xffffff8, 28), (0xffffff9, 28), (0xffffffa, 28), (0xffffffb, 28),
+    (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12),
+    (0x1ff9, 13), (0x15, 6), (0xf8, 8), (0x7fa, 11),
+    (0x3fa, 10), (0x3fb, 10), (0xf9, 8), (0x7fb, 11),
+    (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6),
+    (0x0, 5), (0x1, 5), (0x2, 5), (0x19, 6),
+    (0x1a, 6), (0x1b, 6), (0x1c, 6), (0x1d, 6),
+    (0x1e, 6), (0x1f, 6), (0x5c, 7), (0xfb, 8),
+    (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10),
+    (0x1ffa, 13), (0x21, 6), (0x5d, 7), (0x5e, 7),
+    (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7),
+    (0x63, 7), (0x64, 7), (0x65, 7), (0x66, 7),
+    (0x67, 7), (0x68, 7), (0x69, 7), (0x6a, 7),
+    (0x6b, 7), (0x6c, 7), (0x6d, 7), (0x6e, 7),
+    (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7),
+    (0xfc, 8), (0x73, 7), (0xfd, 8), (0x1ffb, 13),
+    (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6),
+    (0x7ffd, 15), (0x3, 5), (0x23, 6), (0x4, 5),
+    (0x24, 6), (0x5, 5), (0x25, 6), (0x26, 6),
+    (0x27, 6), (0x6, 5), (0x74, 7), (0x75, 7),
+    (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5),
+    (0x2b, 6), (0x76, 7), (0x2c, 6), (0x8, 5),
+    (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7),
+    (0x79, 7), (0x7a, 7), (0x7b, 7), (0x7ffe, 15),
+    (0x7fc, 11), (0x3ffd, 14), (0x1ffd, 13), (0xffffffc, 28),
+    (0xfffe6, 20), (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20),
+    (0x3fffd3, 22), (0x3fffd4, 22), (0x3fffd5, 22), (0x7fffd9, 23),
+    (0x3fffd6, 22), (0x7fffda, 23), (0x7fffdb, 23), (0x7fffdc, 23),
+    (0x7fffdd, 23), (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23),
+    (0xffffec, 24), (0xffffed, 24), (0x3fffd7, 22), (0x7fffe0, 23),
+    (0xffffee, 24), (0x7fffe1, 23), (0x7fffe2, 23), (0x7fffe3, 23),
+    (0x7fffe4, 23), (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23),
+    (0x3fffd9, 22), (0x7fffe6, 23), (0x7fffe7, 23), (0xffffef, 24),
+    (0x3fffda, 22), (0x1fffdd, 21), (0xfffe9, 20), (0x3fffdb, 22),
+    (0x3fffdc, 22), (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21),
+    (0x7fffea, 23), (0x3fffdd, 22), (0x3fffde, 22), (0xfffff0, 24),
+    (0x1fffdf, 21), (0x3fffdf, 22), (0x7fffeb, 23), (0x7fffec, 23),
+    (0x1fffe0, 21), (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21),
+    (0x7fffed, 23), (0x3fffe1, 22), (0x7fffee, 23), (0x7fffef, 23),
+    (0xfffea, 20), (0x3fffe2, 22), (0x3fffe3, 22), (0x3fffe4, 22),
+    (0x7ffff0, 23), (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23),
+    (0x3ffffe0, 26), (0x3ffffe1, 26), (0xfffeb, 20), (0x7fff1, 19),
+    (0x3fffe7, 22), (0x7ffff2, 23), (0x3fffe8, 22), (0x1ffffec, 25),
+    (0x3ffffe2, 26), (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27),
+    (0x7ffffdf, 27), (0x3ffffe5, 26), (0xfffff1, 24), (0x1ffffed, 25),
+    (0x7fff2, 19), (0x1fffe3, 21), (0x3ffffe6, 26), (0x7ffffe0, 27),
+    (0x7ffffe1, 27), (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24),
+    (0x1fffe4, 21), (0x1fffe5, 21), (0x3ffffe8, 26), (0x3ffffe9, 26),
+    (0xffffffd, 28), (0x7ffffe3, 27), (0x7ffffe4, 27), (0x7ffffe5, 27),
+    (0xfffec, 20), (0xfffff3, 24), (0xfffed, 20), (0x1fffe6, 21),
+    (0x3fffe9, 22), (0x1fffe7, 21), (0x1fffe8, 21), (0x7ffff3, 23),
+    (0x3fffea, 22), (0x3fffeb, 22), (0x1ffffee, 25), (0x1ffffef, 25),
+    (0xfffff4, 24), (0xfffff5, 24), (0x3ffffea, 26), (0x7ffff4, 23),
+    (0x3ffffeb, 26), (0x7ffffe6, 27), (0x3ffffec, 26), (0x3ffffed, 26),
+    (0x7ffffe7, 27), (0x7ffffe8, 27), (0x7ffffe9, 27), (0x7ffffea, 27),
+    (0x7ffffeb, 27), (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27),
+    (0x7ffffee, 27), (0x7ffffef, 27), (0x7fffff0, 27), (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+const EOS: u16 = 256;
+// Marks a leaf of the decoding tree, the low bits hold the symbol
+const LEAF: u16 = 0x8000;
+// Size of a dynamic table entry counts 32 bytes of overhead (RFC 7541 4.1)
+const ENTRY_OVERHEAD: usize = 32;
+
+// Header block decoder of one connection, owns the dynamic table filled by the peer's encoder
+pub struct HpackDecoder {
+    table: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+    // SETTINGS_HEADER_TABLE_SIZE announced to the peer, upper bound of max_size
+    settings_max_size: usize,
+    // SETTINGS_MAX_HEADER_LIST_SIZE announced to the peer, None for no limit
+    max_list_size: Option<usize>,
+}
+
+impl HpackDecoder {
+    pub fn new(settings_max_size: usize) -> Self {
+        HpackDecoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: settings_max_size,
+            settings_max_size,
+            max_list_size: None,
+        }
+    }
+
+    pub fn with_max_list_size(mut self, max_list_size: usize) -> Self {
+        self.max_list_size = Some(max_list_size);
+        self
+    }
+
+    // Decode a complete header block into (name, value) fields in order
+    // Every block must be decoded, even for a refused stream, to keep the dynamic table in sync
+    // Decoding stops once the fields pass max_list_size, small references to large entries would expand without bound
+    pub fn decode(&mut self, block: &[u8]) -> Result<Vec<(String, String)>, Http2Error> {
+        let mut fields: Vec<(String, String)> = Vec::new();
+        let mut position: usize = 0;
+        let mut size_update_allowed = true;
+        //Counted like SETTINGS_MAX_HEADER_LIST_SIZE (RFC 9113 6.5.2)
+        let mut list_size: usize = 0;
+
+        while position < block.len() {
+            let byte = block[position];
+
+            if byte & 0x80 != 0 {
+                //Indexed field
+                let index = Self::decode_integer(block, &mut position, 7)?;
+                let field = self.entry(index)?;
+                fields.push(field);
+            }
+            else if byte & 0x40 != 0 {
+                //Literal with incremental indexing
+                let field = self.decode_literal(block, &mut position, 6)?;
+                self.insert(field.clone());
+                fields.push(field);
+            }
+            else if byte & 0x20 != 0 {
+                //Dynamic table size update, only at the start of a block
+                if !size_update_allowed {
+                    return Err(Self::error("Table size update after a field"));
+                }
+                let max_size = Self::decode_integer(block, &mut position, 5)?;
+                if max_size > self.settings_max_size {
+                    return Err(Self::error("Table size above the announced limit"));
+                }
+                self.max_size = max_size;
+                self.evict(0);
+                continue;
+            }
+            else {
+                //Literal without indexing or never indexed
+                let field = self.decode_literal(block, &mut position, 4)?;
+                fields.push(field);
+            }
+            size_update_allowed = false;
+
+            if let (Some(max_list_size), Some((name, value))) = (self.max_list_size, fields.last()) {
+                list_size += name.len() + value.len() + ENTRY_OVERHEAD;
+                if list_size > max_list_size {
+                    //The table can't be kept in sync once decoding stops, the connection is closed
+                    return Err(Http2Error::Connection(ErrorCode::EnhanceYourCalm, "Header list too large".to_string()));
+                }
+            }
+        }
+
+        Ok(fields)
+    }
+
+    fn decode_literal(&self, block: &[u8], position: &mut usize, prefix_bits: u8) -> Result<(String, String), Http2Error> {
+        let name_index = Self::decode_integer(block, position, prefix_bits)?;
+        let name = if name_index == 0 {
+            Self::decode_string(block, position)?
+        }
+        else {
+            self.entry(name_index)?.0
+        };
+        let value = Self::decode_string(block, position)?;
+
+        Ok((name, value))
+    }
+
+    fn entry(&self, index: usize) -> Result<(String, String), Http2Error> {
+        if index == 0 {
+            return Err(Self::error("Index 0"));
+        }
+        if index <= STATIC_TABLE.len() {
+            let (name, value) = STATIC_TABLE[index - 1];
+            return Ok((name.to_string(), value.to_string()));
+        }
+
+        match self.table.get(index - STATIC_TABLE.len() - 1) {
+            Some(field) => Ok(field.clone()),
+            None => Err(Self::error("Index out of the dynamic table")),
+        }
+    }
+
+    // Newest entries have the lowest index
+    fn insert(&mut self, field: (String, String)) {
+        let entry_size = field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+        //An entry larger than the table empties it (RFC 7541 4.4)
+        if entry_size > self.max_size {
+            self.table.clear();
+            self.size = 0;
+            return;
+        }
+
+        self.evict(entry_size);
+        self.size += entry_size;
+        self.table.push_front(field);
+    }
+
+    // Drop the oldest entries until entry_size more bytes fit
+    fn evict(&mut self, entry_size: usize) {
+        while self.size + entry_size > self.max_size {
+            match self.table.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + ENTRY_OVERHEAD,
+                None => break,
+            }
+        }
+    }
+
+    // Integer with a prefix of prefix_bits bits (RFC 7541 5.1)
+    fn decode_integer(block: &[u8], position: &mut usize, prefix_bits: u8) -> Result<usize, Http2Error> {
+        let mask: u8 = ((1u16 << prefix_bits) - 1) as u8;
+        let mut value = match block.get(*position) {
+            Some(byte) => (byte & mask) as usize,
+            None => return Err(Self::error("Truncated integer")),
+        };
+        *position += 1;
+        if value < mask as usize {
+            return Ok(value);
+        }
+
+        let mut shift: u32 = 0;
+        loop {
+            let byte = match block.get(*position) {
+                Some(byte) => *byte,
+                None => return Err(Self::error("Truncated integer")),
+            };
+            *position += 1;
+            //Nothing in a request needs more than 32 bits
+            if shift > 28 {
+                return Err(Self::error("Integer too large"));
+            }
+            value += ((byte & 0x7F) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    // String literal, Huffman encoded when the first bit is set (RFC 7541 5.2)
+    fn decode_string(block: &[u8], position: &mut usize) -> Result<String, Http2Error> {
+        let huffman = match block.get(*position) {
+            Some(byte) => byte & 0x80 != 0,
+            None => return Err(Self::error("Truncated string")),
+        };
+        let length = Self::decode_integer(block, position, 7)?;
+        if block.len() - *position < length {
+            return Err(Self::error("Truncated string"));
+        }
+        let data = &block[*position..*position + length];
+        *position += length;
+
+        let bytes = if huffman { huffman_decode(data)? } else { data.to_vec() };
+        Ok(String::from_utf8_lossy(&bytes).to_string())
+    }
+
+    fn error(reason: &str) -> Http2Error {
+        Http2Error::Connection(ErrorCode::CompressionError, reason.to_string())
+    }
+}
+
+// Encode a header block, fields are sent without indexing so no dynamic table has to be kept in sync
+pub fn encode_header_block(fields: &[(String, String)]) -> Vec<u8> {
+    let mut block: Vec<u8> = Vec::new();
+
+    for (name, value) in fields {
+        let name = name.to_ascii_lowercase();
+        if let Some(index) = STATIC_TABLE.iter().position(|(static_name, static_value)| *static_name == name && *static_value == value) {
+            encode_integer(&mut block, index + 1, 7, 0x80);
+            continue;
+        }
+
+        match STATIC_TABLE.iter().position(|(static_name, _)| *static_name == name) {
+            Some(index) => encode_integer(&mut block, index + 1, 4, 0x00),
+            None => {
+                block.push(0x00);
+                encode_string(&mut block, name.as_bytes());
+            }
+        }
+        encode_string(&mut block, value.as_bytes());
+    }
+
+    block
+}
+
+fn encode_integer(block: &mut Vec<u8>, value: usize, prefix_bits: u8, flags: u8) {
+    let mask: usize = (1 << prefix_bits) - 1;
+    if value < mask {
+        block.push(flags | value as u8);
+        return;
+    }
+
+    block.push(flags | mask as u8);
+    let mut rest = value - mask;
+    while rest >= 0x80 {
+        block.push((rest & 0x7F) as u8 | 0x80);
+        rest >>= 7;
+    }
+    block.push(rest as u8);
+}
+
+// Huffman only when it is shorter
+fn encode_string(block: &mut Vec<u8>, data: &[u8]) {
+    let encoded = huffman_encode(data);
+    if encoded.len() < data.len() {
+        encode_integer(block, encoded.len(), 7, 0x80);
+        block.extend_from_slice(&encoded);
+    }
+    else {
+        encode_integer(block, data.len(), 7, 0x00);
+        block.extend_from_slice(data);
+    }
+}
+
+pub fn huffman_encode(data: &[u8]) -> Vec<u8> {
+    let mut encoded: Vec<u8> = Vec::with_capacity(data.len());
+    let mut bits: u64 = 0;
+    let mut nb_bits: u32 = 0;
+
+    for byte in data {
+        let (code, length) = HUFFMAN_CODES[*byte as usize];
+        bits = (bits << length) | code as u64;
+        nb_bits += length as u32;
+        while nb_bits >= 8 {
+            nb_bits -= 8;
+            encoded.push((bits >> nb_bits) as u8);
+        }
+    }
+
+    //Padded with the most significant bits of EOS, all ones
+    if nb_bits > 0 {
+        let padding = 8 - nb_bits;
+        encoded.push(((bits << padding) | ((1 << padding) - 1)) as u8);
+    }
+    encoded
+}
+
+pub fn huffman_decode(data: &[u8]) -> Result<Vec<u8>, Http2Error> {
+    let tree = huffman_tree();
+    let mut decoded: Vec<u8> = Vec::with_capacity(data.len() * 8 / 5);
+    let mut node: usize = 0;
+    let mut pending_bits: u32 = 0;
+    let mut pending_all_ones = true;
+
+    for byte in data {
+        for shift in (0..8).rev() {
+            let bit = (byte >> shift) & 1;
+            pending_bits += 1;
+            pending_all_ones &= bit == 1;
+
+            let next = tree[node][bit as usize];
+            if next & LEAF != 0 {
+                let symbol = next & !LEAF;
+                if symbol == EOS {
+                    return Err(HpackDecoder::error("EOS in Huffman string"));
+                }
+                decoded.push(symbol as u8);
+                node = 0;
+                pending_bits = 0;
+                pending_all_ones = true;
+            }
+            else {
+                node = next as usize;
+            }
+        }
+    }
+
+    //Padding is at most 7 bits of the EOS code (RFC 7541 5.2)
+    if pending_bits > 7 || !pending_all_ones {
+        return Err(HpackDecoder::error("Invalid Huffman padding"));
+    }
+    Ok(decoded)
+}
+
+// Binary tree of the code, built once, node 0 is the root
+fn huffman_tree() -> &'static Vec<[u16; 2]> {
+    static TREE: OnceLock<Vec<[u16; 2]>> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut tree: Vec<[u16; 2]> = vec![[0, 0]];
+        for (symbol, (code, length)) in HUFFMAN_CODES.iter().enumerate() {
+            let mut node: usize = 0;
+            for shift in (0..*length).rev() {
+                let bit = ((code >> shift) & 1) as usize;
+                if shift == 0 {
+                    tree[node][bit] = LEAF | symbol as u16;
+                }
+                else {
+                    if tree[node][bit] == 0 {
+                        tree.push([0, 0]);
+                        tree[node][bit] = (tree.len() - 1) as u16;
+                    }
+                    node = tree[node][bit] as usize;
+                }
+            }
+        }
+        tree
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    fn hex(text: &str) -> Vec<u8> {
+        let digits: Vec<u8> = text.bytes().filter(|byte| byte.is_ascii_hexdigit()).collect();
+        digits.chunks(2).map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap()).collect()
+    }
+
+    // RFC 7541 C.4, requests with Huffman coding sharing one dynamic table
+    #[test]
+    fn decodes_rfc_request_examples() {
+        let mut decoder = HpackDecoder::new(4096);
+
+        let first = decoder.decode(&hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff")).unwrap();
+        assert_eq!(first, fields(&[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")]));
+
+        let second = decoder.decode(&hex("8286 84be 5886 a8eb 1064 9cbf")).unwrap();
+        assert_eq!(second, fields(&[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com"), ("cache-control", "no-cache")]));
+
+        let third = decoder.decode(&hex("8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf")).unwrap();
+        assert_eq!(third, fields(&[(":method", "GET"), (":scheme", "https"), (":path", "/index.html"), (":authority", "www.example.com"), ("custom-key", "custom-value")]));
+        assert_eq!(decoder.size, 164);
+    }
+
+    #[test]
+    fn evicts_oldest_entries() {
+        let mut decoder = HpackDecoder::new(64);
+        //Two literals with incremental indexing of 38 bytes each, only the last one fits
+        decoder.decode(&hex("4003 6162 6303 7878 78")).unwrap();
+        decoder.decode(&hex("4003 6465 6603 7979 79")).unwrap();
+        assert_eq!(decoder.decode(&hex("be")).unwrap(), fields(&[("def", "yyy")]));
+        assert!(decoder.decode(&hex("bf")).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_blocks() {
+        let mut decoder = HpackDecoder::new(4096);
+        assert!(decoder.decode(&hex("80")).is_err());
+        assert!(decoder.decode(&hex("ff80")).is_err());
+        assert!(decoder.decode(&hex("8220")).is_err());
+        assert!(decoder.decode(&hex("3fe21f")).is_err());
+        //Huffman padding of 8 bits
+        assert!(decoder.decode(&hex("0082 ffff 00")).is_err());
+    }
+
+    #[test]
+    fn stops_decoding_large_header_lists() {
+        //One literal of 4000 bytes indexed, then referenced by one byte at a time
+        let mut block = vec![0x40, 0x01, b'x', 0x7f, 0xa0, 0x1e];
+        block.extend_from_slice(&[b'a'; 3999][..]);
+        block.extend(std::iter::repeat_n(0xbe, 10_000));
+
+        let mut decoder = HpackDecoder::new(4096).with_max_list_size(64 * 1024);
+        match decoder.decode(&block) {
+            Err(Http2Error::Connection(code, _)) => assert_eq!(code, ErrorCode::EnhanceYourCalm),
+            _ => panic!("Header list not bounded"),
+        }
+        assert_eq!(HpackDecoder::new(4096).with_max_list_size(64 * 1024).decode(&block[..4010]).unwrap().len(), 6);
+    }
+
+    #[test]
+    fn encoded_blocks_decode_back() {
+        let response = fields(&[(":status", "200"), (":status", "302"), ("content-type", "text/html; charset=utf-8"), ("x-custom", "\u{e9}t\u{e9}"), ("content-length", "123456")]);
+        let block = encode_header_block(&response);
+        assert_eq!(block[0], 0x88);
+        assert_eq!(HpackDecoder::new(4096).decode(&block).unwrap(), response);
+
+        let all_bytes: Vec<u8> = (0..=255).collect();
+        assert_eq!(huffman_decode(&huffman_encode(&all_bytes)).unwrap(), all_bytes);
+        assert_eq!(huffman_encode(b"www.example.com"), hex("f1e3 c2e5 f23a 6ba0 ab90 f4ff"));
+    }
+}
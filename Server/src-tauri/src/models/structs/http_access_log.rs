@@ -0,0 +1,280 @@
+use std::{fs::{self, File, OpenOptions}, io::{self, Write}, path::{Path, PathBuf}, sync::Mutex, time::{Duration, Instant, SystemTime}};
+
+use serde::Serialize;
+
+use crate::models::structs::http_date::{format_log_date, format_rfc3339};
+use crate::models::structs::http_message::HttpMessage;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    // host ident authuser [date] "request-line" status bytes duration
+    Common,
+    // Common followed by "referer" "user-agent", before the duration
+    Combined,
+    // One JSON object per line
+    Json,
+}
+
+#[derive(Clone, Debug)]
+pub struct AccessLogConfig {
+    pub path: PathBuf,
+    pub format: LogFormat,
+    // Rotate once the file reaches this size
+    pub max_size: Option<u64>,
+    // Rotate once the file is this old
+    pub rotate_interval: Option<Duration>,
+    // Rotated files kept as path.1 (newest) to path.N, older ones are deleted
+    pub max_files: usize,
+}
+
+impl AccessLogConfig {
+    pub fn new(path: PathBuf, format: LogFormat) -> Self {
+        AccessLogConfig {
+            path,
+            format,
+            max_size: Some(10 * 1024 * 1024),
+            rotate_interval: None,
+            max_files: 5,
+        }
+    }
+}
+
+// What is logged for one request
+#[derive(Clone, Debug, Serialize)]
+pub struct AccessLogEntry {
+    #[serde(skip)]
+    pub time: SystemTime,
+    pub remote_addr: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub protocol: String,
+    pub status: u16,
+    // Body bytes sent, without the header section
+    pub bytes: u64,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    #[serde(skip)]
+    pub duration: Duration,
+}
+
+impl AccessLogEntry {
+    // duration is the time between the end of the request and the end of the response
+    pub fn new(request: &HttpMessage, status: u16, bytes: u64, duration: Duration) -> Self {
+        AccessLogEntry {
+            time: SystemTime::now(),
+            remote_addr: request.peer_addr.map(|peer_addr| peer_addr.ip().to_string()),
+            method: request.method.to_string(),
+            path: request.target.raw.clone(),
+            protocol: request.version.to_string(),
+            status,
+            bytes,
+            referer: request.headers.get("Referer").map(|referer| referer.to_string()),
+            user_agent: request.headers.get("User-Agent").map(|user_agent| user_agent.to_string()),
+            duration,
+        }
+    }
+
+    // Line written for the format, without the line feed
+    pub fn format(&self, format: LogFormat) -> String {
+        if format == LogFormat::Json {
+            return self.to_json();
+        }
+
+        let mut line = format!("{} - - [{}] \"{} {} {}\" {} {}",
+            self.remote_addr.as_deref().unwrap_or("-"),
+            format_log_date(self.time),
+            escape(&self.method),
+            escape(&self.path),
+            escape(&self.protocol),
+            self.status,
+            //CLF writes "-" for an empty body
+            if self.bytes == 0 { "-".to_string() } else { self.bytes.to_string() });
+
+        if format == LogFormat::Combined {
+            line.push_str(&format!(" \"{}\" \"{}\"",
+                escape(self.referer.as_deref().unwrap_or("-")),
+                escape(self.user_agent.as_deref().unwrap_or("-"))));
+        }
+
+        //Request time in seconds like nginx's $request_time
+        line.push_str(&format!(" {:.3}", self.duration.as_secs_f64()));
+        line
+    }
+
+    fn to_json(&self) -> String {
+        #[derive(Serialize)]
+        struct JsonEntry<'a> {
+            time: String,
+            #[serde(flatten)]
+            entry: &'a AccessLogEntry,
+            duration_ms: f64,
+        }
+
+        let json_entry = JsonEntry {
+            time: format_rfc3339(self.time),
+            entry: self,
+            duration_ms: (self.duration.as_secs_f64() * 1_000_000.0).round() / 1000.0,
+        };
+        serde_json::to_string(&json_entry).unwrap_or_default()
+    }
+}
+
+// Quote and backslash escaped, control and non-ASCII bytes as \xHH, so a client can't forge log lines
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'"' => escaped.push_str("\\\""),
+            b'\\' => escaped.push_str("\\\\"),
+            0x20..=0x7E => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    escaped
+}
+
+#[derive(Debug)]
+struct LogFile {
+    file: File,
+    size: u64,
+    opened_at: Instant,
+}
+
+// Access log file shared by the workers, lines are written whole under a lock
+#[derive(Debug)]
+pub struct AccessLog {
+    config: AccessLogConfig,
+    log_file: Mutex<LogFile>,
+}
+
+impl AccessLog {
+    // Open the file in append mode, the directory is created if missing
+    pub fn open(config: AccessLogConfig) -> io::Result<Self> {
+        if let Some(directory) = config.path.parent().filter(|directory| !directory.as_os_str().is_empty()) {
+            fs::create_dir_all(directory)?;
+        }
+        let log_file = Self::open_file(&config.path)?;
+
+        Ok(AccessLog {
+            config,
+            log_file: Mutex::new(log_file),
+        })
+    }
+
+    pub fn log(&self, entry: &AccessLogEntry) {
+        let mut line = entry.format(self.config.format);
+        line.push('\n');
+
+        let mut log_file = match self.log_file.lock() {
+            Ok(log_file) => log_file,
+            Err(_) => return,
+        };
+        if let Err(err) = self.rotate_if_needed(&mut log_file, line.len() as u64) {
+            println!("Error while rotating access log {:?}", err);
+        }
+
+        match log_file.file.write_all(line.as_bytes()) {
+            Ok(()) => log_file.size += line.len() as u64,
+            Err(err) => println!("Error while writing access log {:?}", err),
+        }
+    }
+
+    fn rotate_if_needed(&self, log_file: &mut LogFile, next_line_length: u64) -> io::Result<()> {
+        let too_large = self.config.max_size.is_some_and(|max_size| log_file.size > 0 && log_file.size + next_line_length > max_size);
+        let too_old = self.config.rotate_interval.is_some_and(|rotate_interval| log_file.opened_at.elapsed() >= rotate_interval);
+        if !too_large && !too_old {
+            return Ok(());
+        }
+
+        //path.N-1 becomes path.N ... path becomes path.1, the oldest one is dropped
+        let path = &self.config.path;
+        if self.config.max_files == 0 {
+            fs::remove_file(path)?;
+        }
+        else {
+            let _ = fs::remove_file(Self::rotated_path(path, self.config.max_files));
+            for index in (1..self.config.max_files).rev() {
+                let from = Self::rotated_path(path, index);
+                if from.exists() {
+                    fs::rename(&from, Self::rotated_path(path, index + 1))?;
+                }
+            }
+            fs::rename(path, Self::rotated_path(path, 1))?;
+        }
+
+        *log_file = Self::open_file(path)?;
+        Ok(())
+    }
+
+    fn open_file(path: &Path) -> io::Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(LogFile {
+            size: file.metadata()?.len(),
+            file,
+            opened_at: Instant::now(),
+        })
+    }
+
+    fn rotated_path(path: &Path, index: usize) -> PathBuf {
+        let mut rotated_path = path.as_os_str().to_owned();
+        rotated_path.push(format!(".{}", index));
+        PathBuf::from(rotated_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn entry() -> AccessLogEntry {
+        let mut request = HttpMessage::new(&b"GET /apache_pb.gif?a=1 HTTP/1.0\r\nReferer: http://www.example.com/start.html\r\nUser-Agent: Mozilla/4.08 \"x\"\r\n\r\n"[..]).unwrap();
+        request.peer_addr = Some("127.0.0.1:51234".parse().unwrap());
+
+        let mut entry = AccessLogEntry::new(&request, 200, 2326, Duration::from_micros(12345));
+        entry.time = UNIX_EPOCH + Duration::from_millis(971_186_136_042);
+        entry
+    }
+
+    #[test]
+    fn formats_common_and_combined() {
+        assert_eq!(entry().format(LogFormat::Common),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif?a=1 HTTP/1.0\" 200 2326 0.012");
+        assert_eq!(entry().format(LogFormat::Combined),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif?a=1 HTTP/1.0\" 200 2326 \"http://www.example.com/start.html\" \"Mozilla/4.08 \\\"x\\\"\" 0.012");
+    }
+
+    #[test]
+    fn formats_json() {
+        let json: serde_json::Value = serde_json::from_str(&entry().format(LogFormat::Json)).unwrap();
+        assert_eq!(json["time"], "2000-10-10T13:55:36.042Z");
+        assert_eq!(json["remote_addr"], "127.0.0.1");
+        assert_eq!(json["path"], "/apache_pb.gif?a=1");
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["bytes"], 2326);
+        assert_eq!(json["user_agent"], "Mozilla/4.08 \"x\"");
+        assert_eq!(json["duration_ms"], 12.345);
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let directory = std::env::temp_dir().join(format!("access_log_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let path = directory.join("access.log");
+        let config = AccessLogConfig { max_size: Some(200), max_files: 2, ..AccessLogConfig::new(path.clone(), LogFormat::Common) };
+        let access_log = AccessLog::open(config).unwrap();
+
+        //About 90 bytes per line, two lines per file
+        for _ in 0..7 {
+            access_log.log(&entry());
+        }
+
+        let line_count = |path: PathBuf| fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(line_count(path.clone()), 1);
+        assert_eq!(line_count(AccessLog::rotated_path(&path, 1)), 2);
+        assert_eq!(line_count(AccessLog::rotated_path(&path, 2)), 2);
+        assert!(!AccessLog::rotated_path(&path, 3).exists());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
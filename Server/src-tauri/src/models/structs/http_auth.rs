@@ -0,0 +1,515 @@
+use std::{collections::HashMap, fmt::Display, fs, io, path::Path, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::{engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD as BASE64_URL}, Engine};
+use hmac::{digest::KeyInit, Hmac, Mac};
+use serde_json::Value;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+
+use crate::models::structs::http_message::HttpMessage;
+use crate::models::structs::http_middleware::{Middleware, Next};
+use crate::models::structs::http_response::HttpResponse;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthScheme {
+    Basic,
+    ApiKey,
+    Bearer,
+}
+
+// Who made the request, attached to the request extensions by Auth
+// Read by the handlers with request.extensions.get::<Principal>()
+#[derive(Clone, Debug)]
+pub struct Principal {
+    // User name, name of the API key or subject of the token
+    pub name: String,
+    pub scheme: AuthScheme,
+    // Claims of a bearer token, Null for the other schemes
+    pub claims: Value,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuthError {
+    // Credentials not in the format of the scheme
+    Malformed,
+    // Unknown user or key, or wrong password
+    InvalidCredentials,
+    // Bad signature, expired, or not for this audience
+    InvalidToken(String),
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::Malformed => write!(f, "Malformed credentials"),
+            AuthError::InvalidCredentials => write!(f, "Invalid credentials"),
+            AuthError::InvalidToken(reason) => write!(f, "Invalid token : {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+// One way of authenticating requests, Auth tries them in order
+pub trait Authenticator: Send + Sync {
+    // None when the request carries no credentials for this scheme
+    fn authenticate(&self, request: &HttpMessage) -> Result<Option<Principal>, AuthError>;
+
+    // WWW-Authenticate value sent with 401, error is set when this scheme rejected the credentials
+    fn challenge(&self, error: Option<&AuthError>) -> String;
+}
+
+// Credentials of an Authorization field of the given scheme, the scheme is case-insensitive
+fn authorization<'a>(request: &'a HttpMessage, scheme: &str) -> Option<&'a str> {
+    let (request_scheme, credentials) = request.headers.get("Authorization")?.trim().split_once(' ')?;
+    request_scheme.eq_ignore_ascii_case(scheme).then(|| credentials.trim())
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0)
+}
+
+// Basic authentication (RFC 7617) against password hashes
+pub struct BasicAuth {
+    realm: String,
+    // Name and PHC string, argon2 ("$argon2id$...") or bcrypt ("$2b$...")
+    users: HashMap<String, String>,
+}
+
+impl BasicAuth {
+    pub fn new(realm: &str) -> Self {
+        BasicAuth {
+            realm: realm.to_string(),
+            users: HashMap::new(),
+        }
+    }
+
+    pub fn with_user(mut self, name: &str, password_hash: &str) -> Self {
+        self.users.insert(name.to_string(), password_hash.to_string());
+        self
+    }
+
+    // Format : one "name:hash" per line like htpasswd, # starts a comment
+    pub fn from_file(realm: &str, path: &Path) -> io::Result<Self> {
+        let mut basic_auth = Self::new(realm);
+        for (index, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(':') {
+                Some((name, password_hash)) if !name.is_empty() && !password_hash.is_empty() => {
+                    basic_auth = basic_auth.with_user(name, password_hash);
+                },
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid user at line {} of {}", index + 1, path.display()))),
+            }
+        }
+        Ok(basic_auth)
+    }
+
+    fn verify_password(password: &str, password_hash: &str) -> bool {
+        #[cfg(test)]
+        tests::VERIFICATIONS.with(|verifications| verifications.set(verifications.get() + 1));
+
+        if password_hash.starts_with("$argon2") {
+            return PasswordHash::new(password_hash)
+                .is_ok_and(|parsed_hash| Argon2::default().verify_password(password.as_bytes(), &parsed_hash).is_ok());
+        }
+        if password_hash.starts_with("$2") {
+            return bcrypt::verify(password, password_hash).unwrap_or(false);
+        }
+        false
+    }
+}
+
+impl Authenticator for BasicAuth {
+    fn authenticate(&self, request: &HttpMessage) -> Result<Option<Principal>, AuthError> {
+        let credentials = match authorization(request, "Basic") {
+            Some(credentials) => credentials,
+            None => return Ok(None),
+        };
+        let decoded = BASE64.decode(credentials).map_err(|_| AuthError::Malformed)?;
+        let decoded = String::from_utf8(decoded).map_err(|_| AuthError::Malformed)?;
+        //The user-id can't contain a colon, the password can
+        let (name, password) = decoded.split_once(':').ok_or(AuthError::Malformed)?;
+
+        match self.users.get(name) {
+            Some(password_hash) if Self::verify_password(password, password_hash) => Ok(Some(Principal {
+                name: name.to_string(),
+                scheme: AuthScheme::Basic,
+                claims: Value::Null,
+            })),
+            Some(_) => Err(AuthError::InvalidCredentials),
+            None => {
+                //An unknown name costs a hash verification too, or the answer time would tell which names exist
+                //Any stored hash has the cost of a real one, the result is ignored
+                if let Some(dummy_hash) = self.users.values().next() {
+                    let _ = Self::verify_password(password, dummy_hash);
+                }
+                Err(AuthError::InvalidCredentials)
+            },
+        }
+    }
+
+    fn challenge(&self, _error: Option<&AuthError>) -> String {
+        format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm)
+    }
+}
+
+// Static keys sent in a header field, e.g. "X-API-Key: 3f9c..."
+pub struct ApiKeyAuth {
+    header: String,
+    // SHA-256 of the key and its name, a lookup by digest doesn't leak the key through timing
+    keys: HashMap<Vec<u8>, String>,
+}
+
+impl ApiKeyAuth {
+    pub fn new(header: &str) -> Self {
+        ApiKeyAuth {
+            header: header.to_string(),
+            keys: HashMap::new(),
+        }
+    }
+
+    // name becomes the principal of the requests made with the key
+    pub fn with_key(mut self, key: &str, name: &str) -> Self {
+        self.keys.insert(Sha256::digest(key.as_bytes()).to_vec(), name.to_string());
+        self
+    }
+}
+
+impl Authenticator for ApiKeyAuth {
+    fn authenticate(&self, request: &HttpMessage) -> Result<Option<Principal>, AuthError> {
+        let key = match request.headers.get(&self.header) {
+            Some(key) => key.trim(),
+            None => return Ok(None),
+        };
+
+        match self.keys.get(Sha256::digest(key.as_bytes()).as_slice()) {
+            Some(name) => Ok(Some(Principal {
+                name: name.clone(),
+                scheme: AuthScheme::ApiKey,
+                claims: Value::Null,
+            })),
+            None => Err(AuthError::InvalidCredentials),
+        }
+    }
+
+    //No registered scheme for API keys, the challenge tells which field to send
+    fn challenge(&self, _error: Option<&AuthError>) -> String {
+        format!("ApiKey header=\"{}\"", self.header)
+    }
+}
+
+// Bearer tokens (RFC 6750) that are JWTs signed with HMAC (RFC 7519, HS256, HS384 or HS512)
+// exp is required, nbf, aud and iss are checked when present or configured
+pub struct JwtAuth {
+    realm: String,
+    secret: Vec<u8>,
+    // Required in aud when set
+    audience: Option<String>,
+    // Required as iss when set
+    issuer: Option<String>,
+    // Clock difference tolerated with the issuer
+    leeway: Duration,
+}
+
+impl JwtAuth {
+    pub fn new(realm: &str, secret: &[u8]) -> Self {
+        JwtAuth {
+            realm: realm.to_string(),
+            secret: secret.to_vec(),
+            audience: None,
+            issuer: None,
+            leeway: Duration::from_secs(30),
+        }
+    }
+
+    pub fn with_audience(mut self, audience: &str) -> Self {
+        self.audience = Some(audience.to_string());
+        self
+    }
+
+    pub fn with_issuer(mut self, issuer: &str) -> Self {
+        self.issuer = Some(issuer.to_string());
+        self
+    }
+
+    pub fn with_leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = leeway;
+        self
+    }
+
+    // HS256 token of the claims, e.g. issued by a login route
+    pub fn sign(&self, claims: &Value) -> String {
+        let header = BASE64_URL.encode(br#"{"alg":"HS256","typ":"JWT"}"#);
+        let payload = BASE64_URL.encode(claims.to_string());
+        let signed = format!("{}.{}", header, payload);
+
+        let mut mac = <Hmac<Sha256> as KeyInit>::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(signed.as_bytes());
+        format!("{}.{}", signed, BASE64_URL.encode(mac.finalize().into_bytes()))
+    }
+
+    // Claims of a valid token
+    pub fn verify(&self, token: &str) -> Result<Value, AuthError> {
+        let invalid = |reason: &str| AuthError::InvalidToken(reason.to_string());
+
+        //Format : base64url(header) "." base64url(claims) "." base64url(signature of what precedes)
+        let (signed, signature) = token.rsplit_once('.').ok_or(AuthError::Malformed)?;
+        let (header, payload) = signed.split_once('.').ok_or(AuthError::Malformed)?;
+        if payload.contains('.') {
+            return Err(AuthError::Malformed);
+        }
+        let decode_json = |part: &str| -> Result<Value, AuthError> {
+            let bytes = BASE64_URL.decode(part).map_err(|_| AuthError::Malformed)?;
+            serde_json::from_slice(&bytes).map_err(|_| AuthError::Malformed)
+        };
+        let header = decode_json(header)?;
+        let signature = BASE64_URL.decode(signature).map_err(|_| AuthError::Malformed)?;
+
+        //The algorithm is taken from the token but only HMAC ones are accepted, never "none"
+        let signature_valid = match header["alg"].as_str() {
+            Some("HS256") => self.verify_signature::<Hmac<Sha256>>(signed.as_bytes(), &signature),
+            Some("HS384") => self.verify_signature::<Hmac<Sha384>>(signed.as_bytes(), &signature),
+            Some("HS512") => self.verify_signature::<Hmac<Sha512>>(signed.as_bytes(), &signature),
+            _ => return Err(invalid("Unsupported algorithm")),
+        };
+        if !signature_valid {
+            return Err(invalid("Invalid signature"));
+        }
+
+        let claims = decode_json(payload)?;
+        let now = unix_time();
+        let leeway = self.leeway.as_secs();
+        match claims["exp"].as_u64() {
+            Some(expiration) if now <= expiration.saturating_add(leeway) => (),
+            Some(_) => return Err(invalid("The token expired")),
+            None => return Err(invalid("No expiration")),
+        }
+        if claims["nbf"].as_u64().is_some_and(|not_before| now.saturating_add(leeway) < not_before) {
+            return Err(invalid("The token is not valid yet"));
+        }
+        if let Some(audience) = &self.audience {
+            let audience_valid = match &claims["aud"] {
+                Value::String(aud) => aud == audience,
+                Value::Array(auds) => auds.iter().any(|aud| aud.as_str() == Some(audience.as_str())),
+                _ => false,
+            };
+            if !audience_valid {
+                return Err(invalid("The token is not for this audience"));
+            }
+        }
+        if let Some(issuer) = &self.issuer {
+            if claims["iss"].as_str() != Some(issuer.as_str()) {
+                return Err(invalid("Unknown issuer"));
+            }
+        }
+        Ok(claims)
+    }
+
+    fn verify_signature<M: Mac + KeyInit>(&self, signed: &[u8], signature: &[u8]) -> bool {
+        match <M as KeyInit>::new_from_slice(&self.secret) {
+            Ok(mut mac) => {
+                mac.update(signed);
+                //verify_slice compares in constant time
+                mac.verify_slice(signature).is_ok()
+            },
+            Err(_) => false,
+        }
+    }
+}
+
+impl Authenticator for JwtAuth {
+    fn authenticate(&self, request: &HttpMessage) -> Result<Option<Principal>, AuthError> {
+        let token = match authorization(request, "Bearer") {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+        let claims = self.verify(token)?;
+
+        Ok(Some(Principal {
+            name: claims["sub"].as_str().unwrap_or_default().to_string(),
+            scheme: AuthScheme::Bearer,
+            claims,
+        }))
+    }
+
+    fn challenge(&self, error: Option<&AuthError>) -> String {
+        match error {
+            Some(AuthError::InvalidToken(reason)) => format!("Bearer realm=\"{}\", error=\"invalid_token\", error_description=\"{}\"", self.realm, reason),
+            Some(_) => format!("Bearer realm=\"{}\", error=\"invalid_token\"", self.realm),
+            None => format!("Bearer realm=\"{}\"", self.realm),
+        }
+    }
+}
+
+// Require one of the authenticators to accept the request, 401 with their challenges otherwise
+// Place Cors before it in the chain, preflight requests carry no credentials
+#[derive(Clone, Default)]
+pub struct Auth {
+    authenticators: Vec<Arc<dyn Authenticator>>,
+    // Paths under these prefixes are served without authentication, e.g. "/health"
+    public_paths: Vec<String>,
+}
+
+impl Auth {
+    pub fn new() -> Self {
+        Auth {
+            authenticators: Vec::new(),
+            public_paths: Vec::new(),
+        }
+    }
+
+    pub fn with<A: Authenticator + 'static>(mut self, authenticator: A) -> Self {
+        self.authenticators.push(Arc::new(authenticator));
+        self
+    }
+
+    pub fn with_public_path(mut self, path: &str) -> Self {
+        self.public_paths.push(path.trim_end_matches('/').to_string());
+        self
+    }
+
+    fn is_public(&self, path: &str) -> bool {
+        self.public_paths.iter().any(|public_path| {
+            path.strip_prefix(public_path.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+    }
+
+    fn unauthorized(&self, rejected_by: Option<(usize, &AuthError)>) -> HttpResponse {
+        let mut response = HttpResponse::error(401);
+        for (index, authenticator) in self.authenticators.iter().enumerate() {
+            let error = rejected_by.filter(|(rejected_by, _)| *rejected_by == index).map(|(_, err)| err);
+            response.headers.append("WWW-Authenticate", &authenticator.challenge(error));
+        }
+        response
+    }
+}
+
+impl Middleware for Auth {
+    fn handle(&self, request: &mut HttpMessage, next: Next<'_>) -> HttpResponse {
+        if self.is_public(&request.target.path) {
+            return next.run(request);
+        }
+
+        for (index, authenticator) in self.authenticators.iter().enumerate() {
+            match authenticator.authenticate(request) {
+                Ok(Some(principal)) => {
+                    request.extensions.insert(principal);
+                    return next.run(request);
+                },
+                Ok(None) => (),
+                //Wrong credentials are not tried against the other schemes
+                Err(err) => return self.unauthorized(Some((index, &err))),
+            }
+        }
+        self.unauthorized(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::{password_hash::SaltString, PasswordHasher};
+    use serde_json::json;
+    use std::cell::Cell;
+    use crate::models::structs::http_middleware::MiddlewareChain;
+    use crate::models::structs::http_server::Handler;
+
+    thread_local! {
+        // Password hash verifications made by this thread
+        pub(super) static VERIFICATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn request(authorization: &str) -> HttpMessage {
+        HttpMessage::new(format!("GET /api HTTP/1.1\r\n{}\r\n", authorization).as_bytes()).unwrap()
+    }
+
+    fn basic(name: &str, password: &str) -> String {
+        format!("Authorization: Basic {}\r\n", BASE64.encode(format!("{}:{}", name, password)))
+    }
+
+    #[test]
+    fn checks_basic_credentials_from_file() {
+        let salt = SaltString::from_b64("c29tZXNhbHRzb21lc2FsdA").unwrap();
+        let argon2_hash = Argon2::default().hash_password(b"s3cret:pass", &salt).unwrap().to_string();
+        let bcrypt_hash = bcrypt::hash("hunter2", 4).unwrap();
+        let path = std::env::temp_dir().join(format!("auth_users_test_{}", std::process::id()));
+        fs::write(&path, format!("# users\nalice:{}\n\nbob:{}\n", argon2_hash, bcrypt_hash)).unwrap();
+        let basic_auth = BasicAuth::from_file("server", &path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(basic_auth.authenticate(&request(&basic("alice", "s3cret:pass"))).unwrap().unwrap().name, "alice");
+        assert_eq!(basic_auth.authenticate(&request(&basic("bob", "hunter2"))).unwrap().unwrap().scheme, AuthScheme::Basic);
+        assert_eq!(basic_auth.authenticate(&request(&basic("bob", "hunter3"))).unwrap_err(), AuthError::InvalidCredentials);
+        assert_eq!(basic_auth.authenticate(&request(&basic("carol", "hunter2"))).unwrap_err(), AuthError::InvalidCredentials);
+        assert_eq!(basic_auth.authenticate(&request("Authorization: Basic !!!\r\n")).unwrap_err(), AuthError::Malformed);
+        assert!(basic_auth.authenticate(&request("Authorization: Bearer abc\r\n")).unwrap().is_none());
+    }
+
+    #[test]
+    fn verifies_a_password_for_unknown_users() {
+        let basic_auth = BasicAuth::new("server").with_user("bob", &bcrypt::hash("hunter2", 4).unwrap());
+        let verifications = |name: &str| {
+            let before = VERIFICATIONS.with(Cell::get);
+            assert_eq!(basic_auth.authenticate(&request(&basic(name, "hunter3"))).unwrap_err(), AuthError::InvalidCredentials);
+            VERIFICATIONS.with(Cell::get) - before
+        };
+        //An unknown name goes through the same hash verification as a wrong password
+        assert_eq!(verifications("bob"), 1);
+        assert_eq!(verifications("carol"), 1);
+    }
+
+    #[test]
+    fn verifies_jwt_expiry_audience_and_signature() {
+        let jwt_auth = JwtAuth::new("api", b"secret").with_audience("viewer").with_leeway(Duration::ZERO);
+        let now = unix_time();
+        let valid = jwt_auth.sign(&json!({ "sub": "alice", "aud": ["viewer", "admin"], "exp": now + 60 }));
+
+        let principal = jwt_auth.authenticate(&request(&format!("Authorization: bearer {}\r\n", valid))).unwrap().unwrap();
+        assert_eq!(principal.name, "alice");
+        assert_eq!(principal.claims["aud"][1], "admin");
+
+        let reason = |token: &str| match jwt_auth.verify(token) {
+            Err(AuthError::InvalidToken(reason)) => reason,
+            other => panic!("invalid token expected, got {:?}", other),
+        };
+        assert_eq!(reason(&jwt_auth.sign(&json!({ "aud": "viewer", "exp": now - 1 }))), "The token expired");
+        assert_eq!(reason(&jwt_auth.sign(&json!({ "aud": "viewer" }))), "No expiration");
+        assert_eq!(reason(&jwt_auth.sign(&json!({ "aud": "other", "exp": now + 60 }))), "The token is not for this audience");
+        assert_eq!(reason(&JwtAuth::new("api", b"other").sign(&json!({ "aud": "viewer", "exp": now + 60 }))), "Invalid signature");
+
+        //alg "none" with the signature removed
+        let payload = valid.split('.').nth(1).unwrap();
+        assert_eq!(reason(&format!("{}.{}.", BASE64_URL.encode(br#"{"alg":"none"}"#), payload)), "Unsupported algorithm");
+        assert_eq!(jwt_auth.verify("abc.def"), Err(AuthError::Malformed));
+    }
+
+    #[test]
+    fn attaches_principal_or_answers_401() {
+        let jwt_auth = JwtAuth::new("api", b"secret");
+        let token = jwt_auth.sign(&json!({ "sub": "alice", "exp": unix_time() + 60 }));
+        let auth = Auth::new()
+            .with(ApiKeyAuth::new("X-API-Key").with_key("k3y", "dashboard"))
+            .with(jwt_auth)
+            .with_public_path("/health/");
+        let handler: Handler = MiddlewareChain::new().with(auth).into_handler(Arc::new(|request: &mut HttpMessage| {
+            let name = request.extensions.get::<Principal>().map(|principal| principal.name.clone()).unwrap_or_default();
+            HttpResponse::text(200, &format!("hello {}", name))
+        }));
+        let send = |raw: &str| handler(&mut HttpMessage::new(raw.as_bytes()).unwrap());
+
+        assert_eq!(send("GET /api HTTP/1.1\r\nX-API-Key: k3y\r\n\r\n").body, b"hello dashboard");
+        assert_eq!(send(&format!("GET /api HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n", token)).body, b"hello alice");
+        assert_eq!(send("GET /health HTTP/1.1\r\n\r\n").body, b"hello ");
+        assert_eq!(send("GET /healthz HTTP/1.1\r\n\r\n").status, 401);
+
+        let response = send("GET /api HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status, 401);
+        assert_eq!(response.headers.get_all("WWW-Authenticate"), vec!["ApiKey header=\"X-API-Key\"", "Bearer realm=\"api\""]);
+        let response = send("GET /api HTTP/1.1\r\nAuthorization: Bearer a.b.c\r\n\r\n");
+        assert_eq!(response.headers.get_all("WWW-Authenticate")[1], "Bearer realm=\"api\", error=\"invalid_token\"");
+        assert_eq!(send("GET /api HTTP/1.1\r\nX-API-Key: wrong\r\n\r\n").status, 401);
+    }
+}
@@ -0,0 +1,234 @@
+use std::io::{self, Write};
+
+use crate::models::structs::http_error::HttpError;
+use crate::models::structs::http_headers::HeaderMap;
+use crate::models::structs::http_message::HttpLimits;
+
+const CRLF: &[u8; 2] = b"\r\n";
+// chunk-size in hex plus chunk extensions
+const MAX_CHUNK_LINE: usize = 4 * 1024;
+
+enum ChunkedState {
+    // Waiting for chunk-size [ ; chunk-ext ] CRLF
+    Size,
+    // Bytes of the current chunk still to receive
+    Data(usize),
+    // CRLF closing the chunk data
+    DataEnd,
+    // Trailer fields after the last chunk, ends with an empty line
+    Trailers,
+}
+
+// Decode a chunked body (RFC 9112 7.1) without doing any I/O
+// Bytes are fed as they are received, the body and trailers are returned once complete
+pub struct ChunkedDecoder {
+    state: ChunkedState,
+    body: Vec<u8>,
+    trailers: HeaderMap,
+}
+
+impl ChunkedDecoder {
+    pub fn new() -> Self {
+        ChunkedDecoder {
+            state: ChunkedState::Size,
+            body: Vec::new(),
+            trailers: HeaderMap::new(),
+        }
+    }
+
+    // Consume the chunked data at the start of buf, None when more bytes are needed
+    // Bytes after the body are left in buf
+    pub fn decode(&mut self, buf: &mut Vec<u8>, limits: &HttpLimits) -> Result<Option<(Vec<u8>, HeaderMap)>, HttpError> {
+        loop {
+            match self.state {
+                ChunkedState::Size => {
+                    //Format : chunk-size [ ; chunk-ext ] CRLF chunk-data CRLF
+                    let line = match Self::take_line(buf, MAX_CHUNK_LINE)? {
+                        Some(line) => line,
+                        None => return Ok(None),
+                    };
+                    let size_part = match line.split(|byte| *byte == b';').next() {
+                        Some(size_part) => size_part,
+                        None => &line[..],
+                    };
+                    let size_str = String::from_utf8_lossy(size_part).trim_end_matches([' ', '\t']).to_string();
+                    if size_str.is_empty() || !size_str.bytes().all(|char| char.is_ascii_hexdigit()) {
+                        return Err(HttpError::Malformed("Invalid chunk size".to_string()));
+                    }
+                    let chunk_size = usize::from_str_radix(&size_str, 16).map_err(|_| HttpError::PayloadTooLarge)?;
+
+                    if chunk_size == 0 {
+                        self.state = ChunkedState::Trailers;
+                        continue;
+                    }
+                    if self.body.len().saturating_add(chunk_size) > limits.max_body_size {
+                        return Err(HttpError::PayloadTooLarge);
+                    }
+                    self.state = ChunkedState::Data(chunk_size);
+                },
+                ChunkedState::Data(remaining) => {
+                    let available = remaining.min(buf.len());
+                    self.body.extend(buf.drain(..available));
+                    if available < remaining {
+                        self.state = ChunkedState::Data(remaining - available);
+                        return Ok(None);
+                    }
+                    self.state = ChunkedState::DataEnd;
+                },
+                ChunkedState::DataEnd => {
+                    if buf.len() < CRLF.len() {
+                        return Ok(None);
+                    }
+                    if !buf.starts_with(CRLF) {
+                        return Err(HttpError::Malformed("Missing CRLF after chunk data".to_string()));
+                    }
+                    buf.drain(..CRLF.len());
+                    self.state = ChunkedState::Size;
+                },
+                ChunkedState::Trailers => {
+                    let line = match Self::take_line(buf, limits.max_header_size)? {
+                        Some(line) => line,
+                        None => return Ok(None),
+                    };
+                    if line.is_empty() {
+                        self.state = ChunkedState::Size;
+                        let body = std::mem::take(&mut self.body);
+                        let trailers = std::mem::replace(&mut self.trailers, HeaderMap::new());
+                        return Ok(Some((body, trailers)));
+                    }
+                    if self.trailers.len() == limits.max_headers {
+                        return Err(HttpError::TooManyHeaders);
+                    }
+                    let (name, value) = HeaderMap::parse_field(&String::from_utf8_lossy(&line))?;
+                    self.trailers.append(&name, &value);
+                },
+            }
+        }
+    }
+
+    // Body bytes decoded so far, for callers relaying the body as it arrives
+    // The body size limit then applies to the bytes not taken yet
+    pub fn take_body(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.body)
+    }
+
+    // Line up to CRLF removed from buf without its CRLF, None when no complete line was received
+    fn take_line(buf: &mut Vec<u8>, max_length: usize) -> Result<Option<Vec<u8>>, HttpError> {
+        match buf.windows(CRLF.len()).position(|window| window == CRLF) {
+            Some(position) => {
+                let line: Vec<u8> = buf.drain(..position).collect();
+                buf.drain(..CRLF.len());
+                Ok(Some(line))
+            },
+            None if buf.len() > max_length => Err(HttpError::HeaderTooLarge),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Default for ChunkedDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Encode everything written as chunks, finish() sends the last chunk
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        ChunkedWriter {
+            inner
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    // Each call sends one chunk, an empty buf would be read as the last chunk so it is skipped
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        self.inner.write_all(format!("{:X}\r\n", data.len()).as_bytes())?;
+        self.inner.write_all(data)?;
+        self.inner.write_all(CRLF)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"4;name=value\r\nWiki\r\n6\r\npedia \r\nE\r\nin \r\n\r\nchunks.\r\n0\r\nExpires: never\r\nX-Checksum: 1\r\n\r\nNEXT";
+
+    #[test]
+    fn encodes_each_write_as_a_chunk() {
+        let mut writer = ChunkedWriter::new(Vec::new());
+        writer.write_all(b"Wiki").unwrap();
+        assert_eq!(writer.write(b"").unwrap(), 0);
+        writer.write_all(&[b'x'; 26]).unwrap();
+        let encoded = writer.finish().unwrap();
+        assert_eq!(encoded, [b"4\r\nWiki\r\n1A\r\n".to_vec(), vec![b'x'; 26], b"\r\n0\r\n\r\n".to_vec()].concat());
+    }
+
+    #[test]
+    fn decodes_bodies_received_byte_by_byte() {
+        let mut decoder = ChunkedDecoder::new();
+        let mut buf: Vec<u8> = Vec::new();
+        let mut bytes = BODY.iter();
+        let mut decoded = None;
+        while decoded.is_none() {
+            buf.push(*bytes.next().unwrap());
+            decoded = decoder.decode(&mut buf, &HttpLimits::default()).unwrap();
+        }
+        //Complete at the empty line closing the trailers
+        assert_eq!(bytes.as_slice(), b"NEXT");
+        assert!(buf.is_empty());
+        assert_eq!(decoded.unwrap().0, b"Wikipedia in \r\n\r\nchunks.");
+
+        let mut buf = BODY.to_vec();
+        let (body, trailers) = ChunkedDecoder::new().decode(&mut buf, &HttpLimits::default()).unwrap().unwrap();
+        assert_eq!(body, b"Wikipedia in \r\n\r\nchunks.");
+        assert_eq!(trailers.get("Expires"), Some("never"));
+        assert_eq!(trailers.get("X-Checksum"), Some("1"));
+        assert_eq!(buf, b"NEXT");
+    }
+
+    #[test]
+    fn hands_out_the_body_as_it_is_decoded() {
+        let mut decoder = ChunkedDecoder::new();
+        let mut buf = b"4\r\nWiki\r\n6\r\npe".to_vec();
+        assert!(decoder.decode(&mut buf, &HttpLimits::default()).unwrap().is_none());
+        assert_eq!(decoder.take_body(), b"Wikipe");
+        let mut buf = b"dia \r\n0\r\n\r\n".to_vec();
+        assert_eq!(decoder.decode(&mut buf, &HttpLimits::default()).unwrap().unwrap().0, b"dia ");
+    }
+
+    #[test]
+    fn rejects_invalid_chunks() {
+        let decode = |bytes: &[u8], limits: &HttpLimits| ChunkedDecoder::new().decode(&mut bytes.to_vec(), limits);
+        let limits = HttpLimits::default();
+        for invalid_size in [&b"\r\n"[..], b"-4\r\nWiki\r\n0\r\n\r\n", b"+4\r\nWiki\r\n0\r\n\r\n", b"0x4\r\nWiki\r\n0\r\n\r\n", b"4 x\r\nWiki\r\n0\r\n\r\n"] {
+            assert!(matches!(decode(invalid_size, &limits), Err(HttpError::Malformed(_))), "{:?}", String::from_utf8_lossy(invalid_size));
+        }
+        assert!(matches!(decode(b"4\r\nWikiXX0\r\n\r\n", &limits), Err(HttpError::Malformed(_))));
+        assert!(matches!(decode(b"FFFFFFFFFFFFFFFFFF\r\n", &limits), Err(HttpError::PayloadTooLarge)));
+        let small_limits = HttpLimits { max_body_size: 8, ..HttpLimits::default() };
+        assert!(matches!(decode(b"4\r\nWiki\r\n5\r\n", &small_limits), Err(HttpError::PayloadTooLarge)));
+        assert!(matches!(decode(&[b'1'; MAX_CHUNK_LINE + 1], &limits), Err(HttpError::HeaderTooLarge)));
+    }
+}
@@ -0,0 +1,501 @@
+use std::{collections::HashMap, io::{self, Read, Write}, net::{TcpStream, ToSocketAddrs}, path::PathBuf, sync::Mutex, time::{Duration, Instant}};
+
+use serde::Serialize;
+
+use crate::models::structs::http_error::HttpError;
+use crate::models::structs::http_headers::HeaderMap;
+use crate::models::structs::http_message::HttpLimits;
+use crate::models::structs::http_method::Method;
+use crate::models::structs::http_parser::HttpParser;
+use crate::models::structs::http_response::HttpResponse;
+use crate::models::structs::http_stream::HttpStream;
+use crate::models::structs::http_tls::TlsConnector;
+
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct HttpClientConfig {
+    pub connect_timeout: Duration,
+    // Applied to each read and write, a server silent for that long fails the request
+    pub timeout: Duration,
+    // Redirections followed before a 3xx response is returned as is, 0 to never follow them
+    pub max_redirects: usize,
+    // Idle connections kept per origin for the next requests
+    pub max_idle_per_origin: usize,
+    // Idle connections older than this are closed instead of reused
+    pub idle_timeout: Duration,
+    // Limits on the responses
+    pub limits: HttpLimits,
+    pub user_agent: String,
+    // PEM file of the authorities trusted for https, the Mozilla root program's when None
+    pub root_certificates: Option<PathBuf>,
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        HttpClientConfig {
+            connect_timeout: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+            max_redirects: 5,
+            max_idle_per_origin: 4,
+            //Below the usual server keep-alive timeouts
+            idle_timeout: Duration::from_secs(4),
+            limits: HttpLimits::default(),
+            user_agent: "rust_web_server".to_string(),
+            root_certificates: None,
+        }
+    }
+}
+
+// Request sent by the client, Host, Content-Length and User-Agent are added when sending
+pub struct ClientRequest {
+    pub method: Method,
+    // Absolute http or https URL
+    pub url: String,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl ClientRequest {
+    pub fn new(method: Method, url: &str) -> Self {
+        ClientRequest {
+            method,
+            url: url.to_string(),
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn get(url: &str) -> Self {
+        Self::new(Method::Get, url)
+    }
+
+    pub fn post(url: &str) -> Self {
+        Self::new(Method::Post, url)
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+
+    pub fn with_json<T: Serialize>(self, value: &T) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => self.with_header("Content-Type", "application/json").with_body(body),
+            Err(err) => {
+                println!("Error while serializing json request {:?}", err);
+                self
+            }
+        }
+    }
+}
+
+// Parts of an absolute URL the client needs
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Url {
+    https: bool,
+    // Without brackets for IPv6 literals
+    host: String,
+    port: u16,
+    path_and_query: String,
+}
+
+impl Url {
+    // Format : http[s]://host[:port][/path][?query], user information is not supported
+    fn parse(url: &str) -> Result<Self, HttpError> {
+        let invalid = |reason: &str| HttpError::Malformed(format!("{} in url {}", reason, url));
+        if url.bytes().any(|byte| byte <= b' ' || byte >= 127) {
+            return Err(invalid("Invalid character"));
+        }
+
+        let (scheme, rest) = url.split_once("://").ok_or_else(|| invalid("No scheme"))?;
+        let https = match scheme.to_ascii_lowercase().as_str() {
+            "http" => false,
+            "https" => true,
+            _ => return Err(invalid("Unsupported scheme")),
+        };
+
+        let authority_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+        let (authority, path_and_query) = rest.split_at(authority_end);
+        if authority.contains('@') {
+            return Err(invalid("User information"));
+        }
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, after) = bracketed.split_once(']').ok_or_else(|| invalid("Invalid IPv6 host"))?;
+                (host, after.strip_prefix(':'))
+            },
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        if host.is_empty() {
+            return Err(invalid("No host"));
+        }
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| invalid("Invalid port"))?,
+            None if https => 443,
+            None => 80,
+        };
+
+        //Fragment is never sent
+        let path_and_query = path_and_query.split('#').next().unwrap_or_default();
+        Ok(Url {
+            https,
+            host: host.to_ascii_lowercase(),
+            port,
+            path_and_query: if path_and_query.starts_with('/') { path_and_query.to_string() } else { format!("/{}", path_and_query) },
+        })
+    }
+
+    // Pooled connections are shared by the requests of the same origin
+    fn origin(&self) -> String {
+        format!("{}://{}:{}", if self.https { "https" } else { "http" }, self.host, self.port)
+    }
+
+    // Host field value, the port is omitted when it is the scheme's default one
+    fn host_field(&self) -> String {
+        let host = if self.host.contains(':') { format!("[{}]", self.host) } else { self.host.clone() };
+        if self.port == if self.https { 443 } else { 80 } {
+            host
+        }
+        else {
+            format!("{}:{}", host, self.port)
+        }
+    }
+
+    // URL of a Location field, relative to this one
+    fn resolve(&self, location: &str) -> Result<Url, HttpError> {
+        if location.contains("://") {
+            return Url::parse(location);
+        }
+        if location.starts_with("//") {
+            return Url::parse(&format!("{}:{}", if self.https { "https" } else { "http" }, location));
+        }
+
+        let path_and_query = if location.starts_with('/') {
+            location.to_string()
+        }
+        else {
+            //Relative to the directory of the current path
+            let path = self.path_and_query.split('?').next().unwrap_or_default();
+            let directory = &path[..path.rfind('/').map(|index| index + 1).unwrap_or(0)];
+            format!("{}{}", directory, location)
+        };
+        Url::parse(&format!("{}{}", self.origin(), path_and_query))
+    }
+}
+
+struct IdleConnection {
+    stream: Box<dyn HttpStream>,
+    idle_since: Instant,
+}
+
+// Blocking HTTP/1.1 client over TCP or TLS, e.g. for webhooks
+// Connections are kept alive and reused per origin, redirections are followed and chunked bodies decoded
+pub struct HttpClient {
+    config: HttpClientConfig,
+    tls_connector: TlsConnector,
+    idle_connections: Mutex<HashMap<String, Vec<IdleConnection>>>,
+}
+
+impl HttpClient {
+    // Fails when root_certificates can't be read
+    pub fn new(config: HttpClientConfig) -> io::Result<Self> {
+        let tls_connector = TlsConnector::new(config.root_certificates.as_deref(), vec![b"http/1.1".to_vec()])?;
+        Ok(HttpClient {
+            config,
+            tls_connector,
+            idle_connections: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn get(&self, url: &str) -> Result<HttpResponse, HttpError> {
+        self.send(ClientRequest::get(url))
+    }
+
+    pub fn post_json<T: Serialize>(&self, url: &str, value: &T) -> Result<HttpResponse, HttpError> {
+        self.send(ClientRequest::post(url).with_json(value))
+    }
+
+    // Any status is a response, only I/O and protocol errors are errors
+    pub fn send(&self, mut request: ClientRequest) -> Result<HttpResponse, HttpError> {
+        let mut url = Url::parse(&request.url)?;
+        let mut redirects: usize = 0;
+
+        loop {
+            let response = self.send_to(&request, &url)?;
+            let location = match response.headers.get("Location") {
+                Some(location) if [301, 302, 303, 307, 308].contains(&response.status) && redirects < self.config.max_redirects => location,
+                _ => return Ok(response),
+            };
+
+            let next_url = url.resolve(location)?;
+            //303, and 301 or 302 after a POST, are followed with a GET like browsers do
+            if (response.status == 303 && request.method != Method::Head)
+                || ([301, 302].contains(&response.status) && request.method == Method::Post) {
+                request.method = Method::Get;
+                request.body.clear();
+                request.headers.remove("Content-Type");
+            }
+            //Credentials are not sent to another origin
+            if next_url.origin() != url.origin() {
+                request.headers.remove("Authorization");
+                request.headers.remove("Cookie");
+            }
+
+            url = next_url;
+            redirects += 1;
+        }
+    }
+
+    // One exchange, on a pooled connection when there is one
+    fn send_to(&self, request: &ClientRequest, url: &Url) -> Result<HttpResponse, HttpError> {
+        let head = self.request_head(request, url);
+
+        //The server may have closed the pooled connection meanwhile, the request is then sent on a new one
+        if let Some(stream) = self.take_idle_connection(url) {
+            match self.exchange(stream, &head, request, url) {
+                Err(err) if Self::is_closed_connection(&err) => (),
+                result => return result,
+            }
+        }
+
+        let stream = self.connect(url)?;
+        self.exchange(stream, &head, request, url)
+    }
+
+    fn request_head(&self, request: &ClientRequest, url: &Url) -> Vec<u8> {
+        let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", request.method, url.path_and_query, url.host_field());
+        for (name, value) in request.headers.iter() {
+            if ["Host", "Content-Length", "Transfer-Encoding", "Connection"].iter().any(|generated| generated.eq_ignore_ascii_case(name)) {
+                continue;
+            }
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+
+        if !request.headers.contains("User-Agent") {
+            head.push_str(&format!("User-Agent: {}\r\n", self.config.user_agent));
+        }
+        if !request.body.is_empty() || [Method::Post, Method::Put, Method::Patch].contains(&request.method) {
+            head.push_str(&format!("Content-Length: {}\r\n", request.body.len()));
+        }
+        head.push_str("\r\n");
+
+        head.into_bytes()
+    }
+
+    fn exchange(&self, mut stream: Box<dyn HttpStream>, head: &[u8], request: &ClientRequest, url: &Url) -> Result<HttpResponse, HttpError> {
+        stream.write_all(head)?;
+        stream.write_all(&request.body)?;
+        stream.flush()?;
+
+        let mut parser = HttpParser::for_response(&self.config.limits, &request.method);
+        let mut buf: Vec<u8> = Vec::new();
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        let mut received: usize = 0;
+        let mut closed = false;
+
+        loop {
+            if let Some(response) = parser.parse_response(&mut buf, closed)? {
+                //Interim responses, e.g. 100 Continue, are skipped
+                if response.status < 200 && response.status != 101 {
+                    continue;
+                }
+                if response.keep_alive && !closed && buf.is_empty() && response.status != 101 {
+                    self.release_connection(url, stream);
+                }
+                return Ok(response);
+            }
+
+            if closed {
+                if received == 0 {
+                    return Err(HttpError::ConnectionClosed);
+                }
+                return Err(HttpError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed before the end of the response")));
+            }
+
+            match stream.read(&mut chunk) {
+                Ok(0) => closed = true,
+                Ok(nb_bytes_read) => {
+                    received += nb_bytes_read;
+                    buf.extend_from_slice(&chunk[..nb_bytes_read]);
+                },
+                //TLS servers often close without close_notify
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => closed = true,
+                Err(err) => return Err(HttpError::from(err)),
+            }
+        }
+    }
+
+    fn connect(&self, url: &Url) -> Result<Box<dyn HttpStream>, HttpError> {
+        let mut last_err = io::Error::new(io::ErrorKind::NotFound, format!("Host {} not resolved", url.host));
+        for socket_addr in (url.host.as_str(), url.port).to_socket_addrs()? {
+            let tcp_stream = match TcpStream::connect_timeout(&socket_addr, self.config.connect_timeout) {
+                Ok(tcp_stream) => tcp_stream,
+                Err(err) => {
+                    last_err = err;
+                    continue;
+                }
+            };
+            tcp_stream.set_read_timeout(Some(self.config.timeout))?;
+            tcp_stream.set_write_timeout(Some(self.config.timeout))?;
+            tcp_stream.set_nodelay(true)?;
+
+            if url.https {
+                return Ok(self.tls_connector.connect(&url.host, tcp_stream)?);
+            }
+            return Ok(Box::new(tcp_stream));
+        }
+        Err(HttpError::from(last_err))
+    }
+
+    // Most recently used connection of the origin, expired ones are closed
+    fn take_idle_connection(&self, url: &Url) -> Option<Box<dyn HttpStream>> {
+        let mut idle_connections = self.idle_connections.lock().ok()?;
+        let connections = idle_connections.get_mut(&url.origin())?;
+        connections.retain(|connection| connection.idle_since.elapsed() < self.config.idle_timeout);
+        connections.pop().map(|connection| connection.stream)
+    }
+
+    fn release_connection(&self, url: &Url, stream: Box<dyn HttpStream>) {
+        if let Ok(mut idle_connections) = self.idle_connections.lock() {
+            let connections = idle_connections.entry(url.origin()).or_default();
+            if connections.len() < self.config.max_idle_per_origin {
+                connections.push(IdleConnection {
+                    stream,
+                    idle_since: Instant::now(),
+                });
+            }
+        }
+    }
+
+    // Nothing received, or the connection reset while sending
+    fn is_closed_connection(err: &HttpError) -> bool {
+        match err {
+            HttpError::ConnectionClosed => true,
+            HttpError::Io(err) => [io::ErrorKind::ConnectionReset, io::ErrorKind::ConnectionAborted, io::ErrorKind::BrokenPipe].contains(&err.kind()),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::{IpAddr, Ipv4Addr}, sync::Arc, thread};
+    use crate::models::structs::http_message::HttpMessage;
+    use crate::models::structs::http_server::{HttpServer, HttpServerConfig};
+
+    fn server(idle_timeout: Duration) -> HttpServer {
+        let config = HttpServerConfig { address: IpAddr::V4(Ipv4Addr::LOCALHOST), port: 0, workers: 2, idle_timeout, ..HttpServerConfig::default() };
+        HttpServer::start(config, Arc::new(|request: &mut HttpMessage| {
+            match request.target.path.as_str() {
+                "/stream" => HttpResponse::stream(200, "text/plain", |writer| {
+                    for i in 0..3 {
+                        write!(writer, "part{} ", i)?;
+                    }
+                    Ok(())
+                }),
+                "/old" => HttpResponse::redirect(302, "new?x=1"),
+                "/form" => HttpResponse::redirect(303, "/result"),
+                "/temporary" => HttpResponse::redirect(307, "/echo"),
+                "/loop" => HttpResponse::redirect(302, "/loop"),
+                "/slow" => {
+                    thread::sleep(Duration::from_millis(500));
+                    HttpResponse::text(200, "late")
+                },
+                _ => {
+                    //The client port tells which connection was used
+                    let port = request.peer_addr.map(|peer_addr| peer_addr.port()).unwrap_or_default();
+                    HttpResponse::text(200, &format!("{} {} {} {}", request.method, request.target, port, request.body_text().unwrap_or_default()))
+                }
+            }
+        })).unwrap()
+    }
+
+    fn body(response: &HttpResponse) -> &str {
+        std::str::from_utf8(&response.body).unwrap()
+    }
+
+    #[test]
+    fn parses_and_resolves_urls() {
+        let url = Url::parse("HTTPS://Example.com/a/b?x=1#top").unwrap();
+        assert_eq!(url, Url { https: true, host: "example.com".to_string(), port: 443, path_and_query: "/a/b?x=1".to_string() });
+        assert_eq!(url.host_field(), "example.com");
+        assert_eq!(Url::parse("http://[::1]:8080?q").unwrap().host_field(), "[::1]:8080");
+        assert_eq!(Url::parse("http://[::1]:8080?q").unwrap().path_and_query, "/?q");
+        assert!(Url::parse("ftp://example.com/").is_err());
+        assert!(Url::parse("http://user@example.com/").is_err());
+        assert!(Url::parse("http://example.com/a b").is_err());
+
+        assert_eq!(url.resolve("c").unwrap().path_and_query, "/a/c");
+        assert_eq!(url.resolve("/c?y").unwrap().path_and_query, "/c?y");
+        assert_eq!(url.resolve("//other.com/c").unwrap().origin(), "https://other.com:443");
+        assert_eq!(url.resolve("http://other.com:81/").unwrap().origin(), "http://other.com:81");
+    }
+
+    #[test]
+    fn reuses_connections_and_decodes_chunked_bodies() {
+        let server = server(Duration::from_secs(5));
+        let client = HttpClient::new(HttpClientConfig::default()).unwrap();
+        let url = format!("http://{}", server.local_addr);
+
+        let first = client.get(&format!("{}/a", url)).unwrap();
+        let second = client.post_json(&format!("{}/b", url), &serde_json::json!({ "stream": 1 })).unwrap();
+        let first_port = body(&first).split(' ').nth(2).unwrap().to_string();
+        assert_eq!(body(&second), format!("POST /b {} {{\"stream\":1}}", first_port));
+
+        let streamed = client.get(&format!("{}/stream", url)).unwrap();
+        assert_eq!(streamed.headers.get("Transfer-Encoding"), Some("chunked"));
+        assert_eq!(body(&streamed), "part0 part1 part2 ");
+
+        let head = client.send(ClientRequest::new(Method::Head, &format!("{}/a", url))).unwrap();
+        assert!(head.body.is_empty());
+        assert!(body(&client.get(&format!("{}/a", url)).unwrap()).ends_with(&format!("{} ", first_port)));
+    }
+
+    #[test]
+    fn retries_on_connection_closed_by_server() {
+        let server = server(Duration::from_millis(100));
+        let client = HttpClient::new(HttpClientConfig::default()).unwrap();
+        let url = format!("http://{}/a", server.local_addr);
+
+        let first = client.get(&url).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        let second = client.get(&url).unwrap();
+        assert_eq!(second.status, 200);
+        assert_ne!(body(&first), body(&second));
+    }
+
+    #[test]
+    fn follows_redirects() {
+        let server = server(Duration::from_secs(5));
+        let client = HttpClient::new(HttpClientConfig::default()).unwrap();
+        let url = format!("http://{}", server.local_addr);
+
+        assert!(body(&client.get(&format!("{}/old", url)).unwrap()).starts_with("GET /new?x=1 "));
+        let form = client.send(ClientRequest::post(&format!("{}/form", url)).with_body(b"a=1".to_vec())).unwrap();
+        assert!(body(&form).starts_with("GET /result "));
+        let temporary = client.send(ClientRequest::post(&format!("{}/temporary", url)).with_body(b"a=1".to_vec())).unwrap();
+        assert!(body(&temporary).starts_with("POST /echo ") && body(&temporary).ends_with("a=1"));
+
+        let response = client.get(&format!("{}/loop", url)).unwrap();
+        assert_eq!(response.status, 302);
+        let client = HttpClient::new(HttpClientConfig { max_redirects: 0, ..HttpClientConfig::default() }).unwrap();
+        assert_eq!(client.get(&format!("{}/old", url)).unwrap().status, 302);
+    }
+
+    #[test]
+    fn times_out() {
+        let server = server(Duration::from_secs(5));
+        let client = HttpClient::new(HttpClientConfig { timeout: Duration::from_millis(100), ..HttpClientConfig::default() }).unwrap();
+        assert!(matches!(client.get(&format!("http://{}/slow", server.local_addr)), Err(HttpError::Timeout)));
+    }
+}
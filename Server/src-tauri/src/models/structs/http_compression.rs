@@ -0,0 +1,280 @@
+use std::io::{self, Read, Write};
+
+use flate2::{read::{GzDecoder, ZlibDecoder}, write::{GzEncoder, ZlibEncoder}, Compression};
+
+use crate::models::structs::http_message::HttpMessage;
+use crate::models::structs::http_response::{BodyStream, HttpResponse};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentCoding {
+    Brotli,
+    Gzip,
+    // zlib format, as "deflate" is defined by RFC 9110 8.4.1.2
+    Deflate,
+}
+
+impl ContentCoding {
+    pub fn as_str(&self) -> &str {
+        match self {
+            ContentCoding::Brotli => "br",
+            ContentCoding::Gzip => "gzip",
+            ContentCoding::Deflate => "deflate",
+        }
+    }
+
+    pub fn parse(token: &str) -> Option<Self> {
+        match token.to_ascii_lowercase().as_str() {
+            "br" => Some(ContentCoding::Brotli),
+            "gzip" | "x-gzip" => Some(ContentCoding::Gzip),
+            "deflate" => Some(ContentCoding::Deflate),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CompressionConfig {
+    // Smaller bodies are sent as is, compression would not pay off
+    pub min_size: usize,
+    // gzip and deflate level, 0 to 9
+    pub level: u32,
+    // brotli quality, 0 to 11
+    pub brotli_quality: u32,
+    // Decode Content-Encoding of request bodies before the handler sees them
+    pub decompress_requests: bool,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            min_size: 1024,
+            level: 6,
+            brotli_quality: 5,
+            decompress_requests: true,
+        }
+    }
+}
+
+impl CompressionConfig {
+    // Compress the response body with the best coding accepted by the client
+    pub fn compress_response(&self, request: &HttpMessage, mut response: HttpResponse) -> HttpResponse {
+        if response.headers.contains("Content-Encoding") || !Self::is_compressible(response.headers.get("Content-Type")) {
+            return response;
+        }
+
+        //Caches must know the representation depends on Accept-Encoding, compressed or not
+        if !response.headers.contains_token("Vary", "Accept-Encoding") && !response.headers.contains_token("Vary", "*") {
+            response.headers.append("Vary", "Accept-Encoding");
+        }
+
+        //Partial responses keep the identity coding so ranges stay meaningful
+        if response.status != 200 {
+            return response;
+        }
+        let length = match (&response.body_stream, response.stream_length) {
+            (Some(_), stream_length) => stream_length,
+            (None, _) => Some(response.body.len() as u64),
+        };
+        if length.is_some_and(|length| length < self.min_size as u64) {
+            return response;
+        }
+
+        let coding = match request.headers.get("Accept-Encoding").and_then(Self::negotiate) {
+            Some(coding) => coding,
+            None => return response,
+        };
+
+        //HEAD gets the fields GET would, the stream is wrapped the same way but never run
+        if let Some(body_stream) = response.body_stream.take() {
+            //Files are compressed while read, the compressed length is only known at the end
+            response.body_stream = Some(self.compress_stream(coding, body_stream));
+            response.stream_length = None;
+        }
+        else {
+            match self.compress(coding, &response.body) {
+                Ok(compressed) => response.body = compressed,
+                Err(err) => {
+                    println!("Error while compressing response {:?}", err);
+                    return response;
+                }
+            }
+        }
+
+        //The compressed representation needs its own entity tag
+        if let Some(etag) = response.headers.get("ETag").map(|etag| etag.to_string()) {
+            if let Some(opaque) = etag.strip_suffix('"') {
+                response.headers.insert("ETag", &format!("{}-{}\"", opaque, coding.as_str()));
+            }
+        }
+        response.headers.insert("Content-Encoding", coding.as_str());
+        response
+    }
+
+    // Replace a Content-Encoding body by its decoded content, error holds the status to answer with
+    pub fn decompress_request(&self, request: &mut HttpMessage, max_size: usize) -> Result<(), u16> {
+        let codings: Vec<String> = request.headers.get_list("Content-Encoding").iter().map(|coding| coding.to_string()).collect();
+        if codings.is_empty() || !self.decompress_requests {
+            return Ok(());
+        }
+
+        //Codings are listed in the order they were applied
+        let mut body = std::mem::take(&mut request.body);
+        for coding in codings.iter().rev() {
+            if coding.eq_ignore_ascii_case("identity") {
+                continue;
+            }
+            let coding = ContentCoding::parse(coding).ok_or(415u16)?;
+            body = Self::decompress(coding, &body, max_size).map_err(|err| {
+                if err.kind() == io::ErrorKind::OutOfMemory { 413u16 } else { 400u16 }
+            })?;
+        }
+
+        request.body = body;
+        request.headers.remove("Content-Encoding");
+        request.headers.insert("Content-Length", &request.body.len().to_string());
+        Ok(())
+    }
+
+    // Best coding of the Accept-Encoding field (RFC 9110 12.5.3), br > gzip > deflate for equal weights
+    pub fn negotiate(accept_encoding: &str) -> Option<ContentCoding> {
+        let mut weights: Vec<(String, f32)> = Vec::new();
+        for element in accept_encoding.split(',') {
+            let mut parts = element.split(';');
+            let coding = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+            if coding.is_empty() {
+                continue;
+            }
+
+            let mut weight: f32 = 1.0;
+            for parameter in parts {
+                if let Some((name, value)) = parameter.split_once('=') {
+                    if name.trim().eq_ignore_ascii_case("q") {
+                        weight = value.trim().parse().unwrap_or(0.0);
+                    }
+                }
+            }
+            weights.push((coding, weight));
+        }
+
+        let weight_of = |coding: ContentCoding| -> f32 {
+            let exact = weights.iter().find(|(name, _)| ContentCoding::parse(name) == Some(coding));
+            let wildcard = weights.iter().find(|(name, _)| name == "*");
+            exact.or(wildcard).map(|(_, weight)| *weight).unwrap_or(0.0)
+        };
+
+        let mut best: Option<(ContentCoding, f32)> = None;
+        for coding in [ContentCoding::Brotli, ContentCoding::Gzip, ContentCoding::Deflate] {
+            let weight = weight_of(coding);
+            if weight > 0.0 && best.is_none_or(|(_, best_weight)| weight > best_weight) {
+                best = Some((coding, weight));
+            }
+        }
+
+        best.map(|(coding, _)| coding)
+    }
+
+    pub fn is_compressible(content_type: Option<&str>) -> bool {
+        let mime = match content_type {
+            Some(content_type) => content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase(),
+            None => return false,
+        };
+
+        //Event streams are flushed event by event and must not be buffered by an encoder
+        if mime == "text/event-stream" {
+            return false;
+        }
+
+        mime.starts_with("text/")
+            || mime.ends_with("+json")
+            || mime.ends_with("+xml")
+            || ["application/json", "application/javascript", "application/xml", "application/wasm", "image/svg+xml"].contains(&mime.as_str())
+    }
+
+    pub fn compress(&self, coding: ContentCoding, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut compressed: Vec<u8> = Vec::new();
+        {
+            let mut encoder = self.encoder(coding, &mut compressed);
+            encoder.write_all(data)?;
+            encoder.flush()?;
+        }
+        Ok(compressed)
+    }
+
+    // Encoder finishing the stream when dropped
+    fn encoder<'a>(&self, coding: ContentCoding, writer: &'a mut dyn Write) -> Box<dyn Write + 'a> {
+        match coding {
+            ContentCoding::Brotli => Box::new(brotli::CompressorWriter::new(writer, 4096, self.brotli_quality, 22)),
+            ContentCoding::Gzip => Box::new(GzEncoder::new(writer, Compression::new(self.level))),
+            ContentCoding::Deflate => Box::new(ZlibEncoder::new(writer, Compression::new(self.level))),
+        }
+    }
+
+    // Each flush of the producer is forwarded so live content is not held back by the encoder
+    fn compress_stream(&self, coding: ContentCoding, body_stream: BodyStream) -> BodyStream {
+        let config = self.clone();
+        Box::new(move |writer: &mut dyn Write| {
+            let mut encoder = config.encoder(coding, writer);
+            body_stream(&mut encoder)?;
+            encoder.flush()
+        })
+    }
+
+    // Decoding stops at max_size to protect against compression bombs
+    pub fn decompress(coding: ContentCoding, data: &[u8], max_size: usize) -> io::Result<Vec<u8>> {
+        let decoder: Box<dyn Read + '_> = match coding {
+            ContentCoding::Brotli => Box::new(brotli::Decompressor::new(data, 4096)),
+            ContentCoding::Gzip => Box::new(GzDecoder::new(data)),
+            ContentCoding::Deflate => Box::new(ZlibDecoder::new(data)),
+        };
+
+        let mut decompressed: Vec<u8> = Vec::new();
+        decoder.take(max_size as u64 + 1).read_to_end(&mut decompressed)?;
+        if decompressed.len() > max_size {
+            return Err(io::Error::new(io::ErrorKind::OutOfMemory, "Decompressed body too large"));
+        }
+        Ok(decompressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(raw: &str) -> HttpMessage {
+        HttpMessage::new(raw.as_bytes()).unwrap()
+    }
+
+    fn file_response() -> HttpResponse {
+        HttpResponse::sized_stream(200, "text/plain", 4096, |writer| writer.write_all(&[b'a'; 4096]))
+            .with_header("ETag", "\"abc\"")
+            .with_header("Accept-Ranges", "bytes")
+    }
+
+    #[test]
+    fn compresses_streamed_files() {
+        let config = CompressionConfig::default();
+        let mut response = config.compress_response(&request("GET /a.txt HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n"), file_response());
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.headers.get("ETag"), Some("\"abc-gzip\""));
+        assert_eq!(response.stream_length, None);
+
+        let mut compressed: Vec<u8> = Vec::new();
+        response.body_stream.take().unwrap()(&mut compressed).unwrap();
+        assert!(compressed.len() < 4096);
+        assert_eq!(CompressionConfig::decompress(ContentCoding::Gzip, &compressed, 8192).unwrap(), vec![b'a'; 4096]);
+    }
+
+    #[test]
+    fn sends_the_same_fields_for_head() {
+        let config = CompressionConfig::default();
+        let raw = "HEAD /a.txt HTTP/1.1\r\nAccept-Encoding: br\r\n\r\n";
+        let response = config.compress_response(&request(raw), file_response());
+        assert_eq!(response.headers.get("Content-Encoding"), Some("br"));
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.headers.get("ETag"), Some("\"abc-br\""));
+
+        let response = config.compress_response(&request(raw), HttpResponse::text(200, &"a".repeat(2048)));
+        assert_eq!(response.headers.get("Content-Encoding"), Some("br"));
+        assert!(response.body.len() < 2048);
+    }
+}
@@ -0,0 +1,394 @@
+use std::{io::{self, Write}, sync::mpsc::SyncSender, time::{Duration, Instant}};
+
+use crate::models::structs::http2_frame::PREFACE;
+use crate::models::structs::http_chunked::ChunkedDecoder;
+use crate::models::structs::http_error::HttpError;
+use crate::models::structs::http_headers::HeaderMap;
+use crate::models::structs::http_message::{HttpLimits, HttpMessage, MinDataRate};
+use crate::models::structs::http_parser::{HttpParser, StartLine, StreamedBodies, StreamedLength};
+use crate::models::structs::http_stream::HttpStream;
+use crate::models::structs::http_version::Version;
+
+const READ_CHUNK_SIZE: usize = 4 * 1024;
+
+// A client connection yielding successive requests (keep-alive and pipelining)
+pub struct HttpConnection {
+    pub stream: Box<dyn HttpStream>,
+    // Bytes received but not consumed yet, start of the next pipelined request
+    buffer: Vec<u8>,
+    pub requests_served: usize,
+    streamed_bodies: StreamedBodies,
+    // Body of the last request still to receive, see forward_body
+    streamed_body: Option<StreamedLength>,
+}
+
+impl HttpConnection {
+    pub fn new(stream: Box<dyn HttpStream>) -> Self {
+        HttpConnection {
+            stream,
+            buffer: Vec::new(),
+            requests_served: 0,
+            streamed_bodies: StreamedBodies::None,
+            streamed_body: None,
+        }
+    }
+
+    // Hand requests over at the end of their head, see HttpParser::with_streamed_bodies
+    pub fn with_streamed_bodies(mut self, streamed_bodies: StreamedBodies) -> Self {
+        self.streamed_bodies = streamed_bodies;
+        self
+    }
+
+    // Framing of the body to forward for the request just read, None when the request holds its body
+    pub fn take_streamed_body(&mut self) -> Option<StreamedLength> {
+        self.streamed_body.take()
+    }
+
+    // Wait at most idle_timeout for the next request to begin
+    // Returns None when the client closed the connection or stayed idle
+    // The head then the body must arrive within the deadlines of limits, Timeout otherwise
+    pub fn read_request(&mut self, limits: &HttpLimits, idle_timeout: Duration) -> Result<Option<HttpMessage>, HttpError> {
+        if !self.wait_for_data(idle_timeout)? {
+            return Ok(None)
+        }
+
+        let mut parser = HttpParser::new(limits).with_streamed_bodies(self.streamed_bodies);
+        let mut deadline = ReadDeadline::new(limits.header_timeout, None);
+        let mut reading_body = false;
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+
+        loop {
+            if let Some(request) = parser.parse(&mut self.buffer)? {
+                self.streamed_body = parser.take_streamed_body();
+                if self.streamed_body.is_some() {
+                    self.answer_expectation(request.version == Version::Http11, &request.headers)?;
+                }
+                self.requests_served += 1;
+                return Ok(Some(request))
+            }
+
+            if !reading_body {
+                if let Some((start_line, headers)) = parser.head() {
+                    reading_body = true;
+                    let is_http11 = matches!(start_line, StartLine::Request { version: Version::Http11, .. });
+                    self.answer_expectation(is_http11, headers)?;
+                    deadline = ReadDeadline::new(limits.body_timeout, limits.min_body_rate);
+                }
+            }
+
+            self.stream.set_read_timeout(deadline.read_timeout(limits.read_timeout)?)?;
+            let nb_bytes_read = self.stream.read(&mut chunk)?;
+            if nb_bytes_read == 0 {
+                return Err(HttpError::ConnectionClosed)
+            }
+            self.buffer.extend_from_slice(&chunk[..nb_bytes_read]);
+            deadline.received += nb_bytes_read as u64;
+        }
+    }
+
+    // A client sending Expect: 100-continue waits for it before sending the body (RFC 9110 10.1.1)
+    // Too large bodies are refused before, by the parser, so the client doesn't send them
+    fn answer_expectation(&mut self, is_http11: bool, headers: &HeaderMap) -> Result<(), HttpError> {
+        let expectation = match headers.get("Expect") {
+            Some(expectation) => expectation,
+            None => return Ok(()),
+        };
+        //HTTP/1.0 predates Expect, it is ignored
+        if !is_http11 {
+            return Ok(())
+        }
+        if !expectation.trim().eq_ignore_ascii_case("100-continue") {
+            return Err(HttpError::ExpectationFailed(expectation.to_string()))
+        }
+
+        //The client may have sent the body without waiting
+        if self.buffer.is_empty() {
+            self.stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+            self.stream.flush()?;
+        }
+        Ok(())
+    }
+
+    // Send the body of the request just read to the handler as it is received, in chunks
+    // Returns whether the whole body was read, the connection can't be reused otherwise
+    // Stops once the receiver is dropped, i.e. the handler returned, a read error is handed to the handler
+    pub fn forward_body(&mut self, length: StreamedLength, limits: &HttpLimits, sender: &SyncSender<Result<Vec<u8>, HttpError>>) -> bool {
+        match length {
+            StreamedLength::Fixed(length) => self.forward_fixed_body(length, limits, sender),
+            StreamedLength::Chunked => self.forward_chunked_body(limits, sender),
+        }
+    }
+
+    fn forward_fixed_body(&mut self, length: usize, limits: &HttpLimits, sender: &SyncSender<Result<Vec<u8>, HttpError>>) -> bool {
+        let mut deadline = ReadDeadline::new(limits.body_timeout, limits.min_body_rate);
+        let mut remaining = length;
+
+        while remaining > 0 {
+            if self.buffer.is_empty() {
+                if let Err(err) = self.read_body_bytes(&mut deadline, limits) {
+                    let _ = sender.send(Err(err));
+                    return false
+                }
+            }
+
+            let available = remaining.min(self.buffer.len());
+            let chunk: Vec<u8> = self.buffer.drain(..available).collect();
+            remaining -= available;
+            if sender.send(Ok(chunk)).is_err() {
+                return remaining == 0
+            }
+        }
+        true
+    }
+
+    // The chunks are decoded as they come, max_streamed_body_size applies to the decoded body
+    fn forward_chunked_body(&mut self, limits: &HttpLimits, sender: &SyncSender<Result<Vec<u8>, HttpError>>) -> bool {
+        let mut deadline = ReadDeadline::new(limits.body_timeout, limits.min_body_rate);
+        let decoder_limits = HttpLimits { max_body_size: limits.max_streamed_body_size, ..limits.clone() };
+        let mut decoder = ChunkedDecoder::new();
+        let mut forwarded: usize = 0;
+
+        loop {
+            let (chunk, finished) = match decoder.decode(&mut self.buffer, &decoder_limits) {
+                Ok(Some((chunk, _trailers))) => (chunk, true),
+                Ok(None) => (decoder.take_body(), false),
+                Err(err) => {
+                    let _ = sender.send(Err(err));
+                    return false
+                }
+            };
+
+            forwarded += chunk.len();
+            if forwarded > limits.max_streamed_body_size {
+                let _ = sender.send(Err(HttpError::PayloadTooLarge));
+                return false
+            }
+            if !chunk.is_empty() && sender.send(Ok(chunk)).is_err() {
+                return finished
+            }
+            if finished {
+                return true
+            }
+
+            if let Err(err) = self.read_body_bytes(&mut deadline, limits) {
+                let _ = sender.send(Err(err));
+                return false
+            }
+        }
+    }
+
+    fn read_body_bytes(&mut self, deadline: &mut ReadDeadline, limits: &HttpLimits) -> Result<(), HttpError> {
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        self.stream.set_read_timeout(deadline.read_timeout(limits.read_timeout)?)?;
+        let nb_bytes_read = self.stream.read(&mut chunk)?;
+        if nb_bytes_read == 0 {
+            return Err(HttpError::ConnectionClosed)
+        }
+        self.buffer.extend_from_slice(&chunk[..nb_bytes_read]);
+        deadline.received += nb_bytes_read as u64;
+        Ok(())
+    }
+
+    // Look whether the client starts with the HTTP/2 connection preface (prior knowledge, RFC 9113 3.3)
+    // Returns None when the client closed the connection or stayed idle
+    pub fn detect_http2_preface(&mut self, idle_timeout: Duration) -> Result<Option<bool>, HttpError> {
+        //One deadline for the whole preface, a client sending a byte at a time gets no more time
+        let deadline = ReadDeadline::new(Some(idle_timeout), None);
+        loop {
+            let length = self.buffer.len().min(PREFACE.len());
+            if self.buffer[..length] != PREFACE[..length] {
+                return Ok(Some(false))
+            }
+            if length == PREFACE.len() {
+                return Ok(Some(true))
+            }
+
+            //Nothing yet or the start of the preface, wait for the rest of it
+            match deadline.read_timeout(None) {
+                Ok(read_timeout) => self.stream.set_read_timeout(read_timeout)?,
+                Err(HttpError::Timeout) => return Ok(None),
+                Err(err) => return Err(err),
+            }
+            if !self.read_more()? {
+                return Ok(None)
+            }
+        }
+    }
+
+    // Wait at most idle_timeout for bytes when none are buffered, false when nothing came
+    fn wait_for_data(&mut self, idle_timeout: Duration) -> Result<bool, HttpError> {
+        if !self.buffer.is_empty() {
+            return Ok(true)
+        }
+
+        self.stream.set_read_timeout(Some(idle_timeout))?;
+        self.read_more()
+    }
+
+    fn read_more(&mut self) -> Result<bool, HttpError> {
+        let mut chunk = [0u8; 4 * 1024];
+        match self.stream.read(&mut chunk) {
+            Ok(0) => Ok(false),
+            Ok(nb_bytes_read) => {
+                self.buffer.extend_from_slice(&chunk[..nb_bytes_read]);
+                Ok(true)
+            },
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => Ok(false),
+            Err(err) if err.kind() == io::ErrorKind::ConnectionReset => Ok(false),
+            Err(err) => Err(HttpError::Io(err)),
+        }
+    }
+
+    // Stream and unread bytes, used to hand the connection over to another protocol
+    pub fn into_parts(self) -> (Box<dyn HttpStream>, Vec<u8>) {
+        (self.stream, self.buffer)
+    }
+}
+
+// Time left to receive a part of a request, from a deadline and a minimum rate
+struct ReadDeadline {
+    started: Instant,
+    timeout: Option<Duration>,
+    min_rate: Option<MinDataRate>,
+    received: u64,
+}
+
+impl ReadDeadline {
+    fn new(timeout: Option<Duration>, min_rate: Option<MinDataRate>) -> Self {
+        ReadDeadline {
+            started: Instant::now(),
+            timeout,
+            min_rate: min_rate.filter(|min_rate| min_rate.bytes_per_second > 0),
+            received: 0,
+        }
+    }
+
+    // Timeout for the next read, at most read_timeout, Timeout once the part is late
+    fn read_timeout(&self, read_timeout: Option<Duration>) -> Result<Option<Duration>, HttpError> {
+        //Below the minimum rate once the time elapsed is above received / rate
+        let rate_limit = self.min_rate.map(|min_rate| {
+            min_rate.grace_period.max(Duration::from_secs_f64(self.received as f64 / min_rate.bytes_per_second as f64))
+        });
+        let limit = match (self.timeout, rate_limit) {
+            (Some(timeout), Some(rate_limit)) => Some(timeout.min(rate_limit)),
+            (timeout, rate_limit) => timeout.or(rate_limit),
+        };
+
+        let limit = match limit {
+            Some(limit) => limit,
+            None => return Ok(read_timeout),
+        };
+        let remaining = limit.saturating_sub(self.started.elapsed());
+        if remaining.is_zero() {
+            return Err(HttpError::Timeout)
+        }
+        //A zero read timeout is refused by the sockets
+        let remaining = remaining.max(Duration::from_millis(1));
+        Ok(Some(read_timeout.map_or(remaining, |read_timeout| read_timeout.min(remaining))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Read, net::{TcpListener, TcpStream}, thread};
+
+    // Server side connection and client socket
+    fn connect() -> (HttpConnection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (HttpConnection::new(Box::new(server)), client)
+    }
+
+    #[test]
+    fn times_out_trickled_heads_and_slow_bodies() {
+        let limits = HttpLimits {
+            header_timeout: Some(Duration::from_millis(200)),
+            min_body_rate: Some(MinDataRate { bytes_per_second: 1000, grace_period: Duration::from_millis(200) }),
+            ..HttpLimits::default()
+        };
+
+        let (mut connection, mut client) = connect();
+        let trickle = thread::spawn(move || {
+            client.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+            for _ in 0..8 {
+                thread::sleep(Duration::from_millis(50));
+                let _ = client.write_all(b"X: 1\r\n");
+            }
+        });
+        assert!(matches!(connection.read_request(&limits, Duration::from_secs(1)), Err(HttpError::Timeout)));
+        trickle.join().unwrap();
+
+        let (mut connection, mut client) = connect();
+        client.write_all(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 10000\r\n\r\n0123456789").unwrap();
+        assert!(matches!(connection.read_request(&limits, Duration::from_secs(1)), Err(HttpError::Timeout)));
+    }
+
+    #[test]
+    fn answers_expect_100_continue() {
+        let (mut connection, mut client) = connect();
+        let upload = thread::spawn(move || {
+            client.write_all(b"PUT /file HTTP/1.1\r\nHost: a\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n").unwrap();
+            let mut interim = [0u8; 25];
+            client.read_exact(&mut interim).unwrap();
+            assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+            client.write_all(b"hello").unwrap();
+            client
+        });
+        let request = connection.read_request(&HttpLimits::default(), Duration::from_secs(1)).unwrap().unwrap();
+        assert_eq!(request.body, b"hello");
+        upload.join().unwrap();
+
+        //Refused before the body is sent
+        let (mut connection, mut client) = connect();
+        client.write_all(b"PUT /file HTTP/1.1\r\nHost: a\r\nExpect: 100-continue\r\nContent-Length: 99999999\r\n\r\n").unwrap();
+        assert!(matches!(connection.read_request(&HttpLimits::default(), Duration::from_secs(1)), Err(HttpError::PayloadTooLarge)));
+        let (mut connection, mut client) = connect();
+        client.write_all(b"PUT /file HTTP/1.1\r\nHost: a\r\nExpect: 200-ok\r\nContent-Length: 5\r\n\r\n").unwrap();
+        assert!(matches!(connection.read_request(&HttpLimits::default(), Duration::from_secs(1)), Err(HttpError::ExpectationFailed(_))));
+    }
+
+    #[test]
+    fn forwards_streamed_bodies() {
+        let (connection, mut client) = connect();
+        let mut connection = connection.with_streamed_bodies(StreamedBodies::Multipart);
+        client.write_all(b"POST /upload HTTP/1.1\r\nHost: a\r\nContent-Type: multipart/form-data; boundary=XyZ\r\nContent-Length: 10\r\n\r\n0123").unwrap();
+        let request = connection.read_request(&HttpLimits::default(), Duration::from_secs(1)).unwrap().unwrap();
+        assert!(request.body.is_empty());
+        let body_length = connection.take_streamed_body().unwrap();
+        assert_eq!(body_length, StreamedLength::Fixed(10));
+
+        let (sender, receiver) = std::sync::mpsc::sync_channel(1);
+        let send_rest = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            client.write_all(b"456789GET /next HTTP/1.1\r\n\r\n").unwrap();
+            client
+        });
+        let reader = thread::spawn(move || receiver.iter().map(|chunk: Result<Vec<u8>, HttpError>| chunk.unwrap()).collect::<Vec<Vec<u8>>>().concat());
+        assert!(connection.forward_body(body_length, &HttpLimits::default(), &sender));
+        drop(sender);
+        assert_eq!(reader.join().unwrap(), b"0123456789");
+        let _client = send_rest.join().unwrap();
+        //Bytes after the body are the next request
+        assert_eq!(connection.read_request(&HttpLimits::default(), Duration::from_secs(1)).unwrap().unwrap().target.path, "/next");
+
+        //Chunked bodies are decoded as they come, trailers included
+        let (connection, mut client) = connect();
+        let mut connection = connection.with_streamed_bodies(StreamedBodies::All);
+        client.write_all(b"PUT /file HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n4;ext=1\r\n0123\r\n").unwrap();
+        connection.read_request(&HttpLimits::default(), Duration::from_secs(1)).unwrap().unwrap();
+        assert_eq!(connection.take_streamed_body(), Some(StreamedLength::Chunked));
+        let (sender, receiver) = std::sync::mpsc::sync_channel(8);
+        let send_rest = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            client.write_all(b"6\r\n456789\r\n0\r\nX-Checksum: 1\r\n\r\nGET /next HTTP/1.1\r\n\r\n").unwrap();
+            client
+        });
+        assert!(connection.forward_body(StreamedLength::Chunked, &HttpLimits::default(), &sender));
+        drop(sender);
+        assert_eq!(receiver.iter().map(|chunk| chunk.unwrap()).collect::<Vec<Vec<u8>>>().concat(), b"0123456789");
+        let _client = send_rest.join().unwrap();
+        assert_eq!(connection.read_request(&HttpLimits::default(), Duration::from_secs(1)).unwrap().unwrap().target.path, "/next");
+    }
+}
@@ -0,0 +1,220 @@
+use std::{collections::HashMap, io::{self, Read, Write}, net::{IpAddr, SocketAddr}, sync::{Arc, Mutex}, time::Duration};
+
+use crate::models::structs::http_stream::HttpStream;
+
+#[derive(Default)]
+struct ConnectionCounts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    // Connections waiting for or held by a worker
+    in_flight_per_ip: HashMap<IpAddr, usize>,
+}
+
+// Counts the open connections, in total and per client address
+// A connection is counted from its accept until its permit is dropped, including while it waits for a worker
+// and after it is handed over to an upgrade, see PermittedStream
+// Connections waiting for or held by a worker are also counted per address, see WorkerSlot
+#[derive(Clone)]
+pub struct ConnectionLimiter {
+    max_connections: usize,
+    max_connections_per_ip: Option<usize>,
+    max_in_flight_per_ip: Option<usize>,
+    counts: Arc<Mutex<ConnectionCounts>>,
+}
+
+impl ConnectionLimiter {
+    // max_in_flight_per_ip below the number of workers keeps one address from holding all of them
+    pub fn new(max_connections: usize, max_connections_per_ip: Option<usize>, max_in_flight_per_ip: Option<usize>) -> Self {
+        ConnectionLimiter {
+            max_connections,
+            max_connections_per_ip,
+            max_in_flight_per_ip,
+            counts: Arc::new(Mutex::new(ConnectionCounts::default())),
+        }
+    }
+
+    // False once max_connections are open, the accept loop then waits and new clients queue in the listen backlog
+    pub fn has_capacity(&self) -> bool {
+        self.counts.lock().map(|counts| counts.total < self.max_connections).unwrap_or(false)
+    }
+
+    // Permit for a new connection from ip and its place among the worker connections
+    // None when the global, the per-address or the in-flight per-address limit is reached
+    pub fn acquire(&self, ip: IpAddr) -> Option<(ConnectionPermit, WorkerSlot)> {
+        let mut counts = self.counts.lock().ok()?;
+        if counts.total >= self.max_connections {
+            return None;
+        }
+        let ip_count = counts.per_ip.get(&ip).copied().unwrap_or(0);
+        if self.max_connections_per_ip.is_some_and(|max_connections_per_ip| ip_count >= max_connections_per_ip) {
+            return None;
+        }
+        let in_flight_count = counts.in_flight_per_ip.get(&ip).copied().unwrap_or(0);
+        if self.max_in_flight_per_ip.is_some_and(|max_in_flight_per_ip| in_flight_count >= max_in_flight_per_ip) {
+            return None;
+        }
+
+        counts.total += 1;
+        counts.per_ip.insert(ip, ip_count + 1);
+        counts.in_flight_per_ip.insert(ip, in_flight_count + 1);
+        Some((ConnectionPermit { ip, counts: self.counts.clone() }, WorkerSlot { ip, counts: self.counts.clone() }))
+    }
+
+    pub fn open_connections(&self) -> usize {
+        self.counts.lock().map(|counts| counts.total).unwrap_or(0)
+    }
+}
+
+fn decrement(counts: &mut HashMap<IpAddr, usize>, ip: &IpAddr) {
+    if let Some(count) = counts.get_mut(ip) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(ip);
+        }
+    }
+}
+
+// Slot of an open connection, released on drop
+pub struct ConnectionPermit {
+    ip: IpAddr,
+    counts: Arc<Mutex<ConnectionCounts>>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        if let Ok(mut counts) = self.counts.lock() {
+            counts.total = counts.total.saturating_sub(1);
+            decrement(&mut counts.per_ip, &self.ip);
+        }
+    }
+}
+
+// Place of a connection among the ones waiting for or held by a worker, released on drop
+// Dropped when the worker is done with the connection, an upgraded connection keeps only its permit
+pub struct WorkerSlot {
+    ip: IpAddr,
+    counts: Arc<Mutex<ConnectionCounts>>,
+}
+
+impl Drop for WorkerSlot {
+    fn drop(&mut self) {
+        if let Ok(mut counts) = self.counts.lock() {
+            decrement(&mut counts.in_flight_per_ip, &self.ip);
+        }
+    }
+}
+
+// Stream owning the permit of its connection, so the slot lasts as long as the socket
+// wherever the connection goes, e.g. to the thread of a WebSocket
+pub struct PermittedStream {
+    stream: Box<dyn HttpStream>,
+    _permit: ConnectionPermit,
+}
+
+impl PermittedStream {
+    pub fn new(stream: Box<dyn HttpStream>, permit: ConnectionPermit) -> Self {
+        PermittedStream {
+            stream,
+            _permit: permit,
+        }
+    }
+}
+
+impl Read for PermittedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for PermittedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl HttpStream for PermittedStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(timeout)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    // The clone doesn't hold the permit, the original handle must outlive it
+    fn try_clone_stream(&self) -> io::Result<Box<dyn HttpStream>> {
+        self.stream.try_clone_stream()
+    }
+
+    fn is_tls(&self) -> bool {
+        self.stream.is_tls()
+    }
+
+    fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.stream.alpn_protocol()
+    }
+
+    fn server_name(&self) -> Option<String> {
+        self.stream.server_name()
+    }
+
+    fn close(&mut self) {
+        self.stream.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_connections_in_total_and_per_address() {
+        let limiter = ConnectionLimiter::new(3, Some(2), None);
+        let first_ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let second_ip: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let first = limiter.acquire(first_ip).unwrap();
+        let _second = limiter.acquire(first_ip).unwrap();
+        assert!(limiter.acquire(first_ip).is_none());
+        let _third = limiter.acquire(second_ip).unwrap();
+        assert!(!limiter.has_capacity());
+        assert!(limiter.acquire(second_ip).is_none());
+
+        drop(first);
+        assert_eq!(limiter.open_connections(), 2);
+        assert!(limiter.has_capacity());
+        assert!(limiter.acquire(first_ip).is_some());
+    }
+
+    #[test]
+    fn limits_worker_connections_per_address() {
+        let limiter = ConnectionLimiter::new(10, Some(3), Some(1));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        let (first_permit, first_slot) = limiter.acquire(ip).unwrap();
+        assert!(limiter.acquire(ip).is_none());
+        //Handed over to an upgrade, the connection leaves the workers but stays open
+        drop(first_slot);
+        let (_second_permit, _second_slot) = limiter.acquire(ip).unwrap();
+        assert_eq!(limiter.open_connections(), 2);
+
+        //The permit follows the stream
+        let (client, server) = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            (client, listener.accept().unwrap().0)
+        };
+        let stream: Box<dyn HttpStream> = Box::new(PermittedStream::new(Box::new(server), first_permit));
+        assert_eq!(stream.peer_addr().unwrap(), client.local_addr().unwrap());
+        drop(stream);
+        assert_eq!(limiter.open_connections(), 1);
+    }
+}
@@ -0,0 +1,206 @@
+use std::{fmt::Display, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use crate::models::structs::http_date::format_http_date;
+use crate::models::structs::http_method::Method;
+
+// Parse a Cookie field value, e.g. "theme=dark; session=abc" (RFC 6265 4.2.1)
+// Pairs without '=' are ignored, double quotes around a value are removed
+pub fn parse_cookie_header(value: &str) -> Vec<(String, String)> {
+    let mut cookies: Vec<(String, String)> = Vec::new();
+
+    for pair in value.split(';') {
+        let (name, value) = match pair.split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => continue,
+        };
+        if name.is_empty() {
+            continue;
+        }
+
+        let value = value.strip_prefix('"').and_then(|value| value.strip_suffix('"')).unwrap_or(value);
+        cookies.push((name.to_string(), value.to_string()));
+    }
+
+    cookies
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    // Sent on cross-site requests too, browsers require Secure with it
+    None,
+}
+
+impl Display for SameSite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+// Value of a Set-Cookie field (RFC 6265 4.1)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SetCookie {
+    pub name: String,
+    pub value: String,
+    pub expires: Option<SystemTime>,
+    pub max_age: Option<Duration>,
+    pub domain: Option<String>,
+    pub path: Option<String>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<SameSite>,
+}
+
+impl SetCookie {
+    // Session cookie, kept by the browser until it is closed
+    // The name must be a token and the value made of cookie-octets, see is_valid
+    pub fn new(name: &str, value: &str) -> Self {
+        SetCookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            expires: None,
+            max_age: None,
+            domain: None,
+            path: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    // Cookie telling the browser to delete the one with this name
+    pub fn removal(name: &str) -> Self {
+        Self::new(name, "")
+            .with_expires(UNIX_EPOCH)
+            .with_max_age(Duration::ZERO)
+    }
+
+    pub fn with_expires(mut self, expires: SystemTime) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+
+    // Takes precedence over Expires for browsers supporting both
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn with_domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    // Only sent over HTTPS
+    pub fn with_secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    // Hidden from scripts
+    pub fn with_http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    // SameSite=None turns Secure on, browsers drop it otherwise
+    pub fn with_same_site(mut self, same_site: SameSite) -> Self {
+        if same_site == SameSite::None {
+            self.secure = true;
+        }
+        self.same_site = Some(same_site);
+        self
+    }
+
+    // Can the cookie be sent as is, attributes values must not hold ';' or control characters
+    pub fn is_valid(&self) -> bool {
+        //cookie-octet excludes whitespace, DQUOTE, comma, semicolon and backslash
+        let is_cookie_octet = |byte: u8| byte == 0x21 || (0x23..=0x2B).contains(&byte) || (0x2D..=0x3A).contains(&byte)
+            || (0x3C..=0x5B).contains(&byte) || (0x5D..=0x7E).contains(&byte);
+        let is_attribute_value = |value: &str| value.bytes().all(|byte| byte >= 0x20 && byte != 0x7F && byte != b';');
+
+        !self.name.is_empty()
+            && self.name.bytes().all(Method::is_tchar)
+            && self.value.bytes().all(is_cookie_octet)
+            && self.domain.as_deref().is_none_or(is_attribute_value)
+            && self.path.as_deref().is_none_or(is_attribute_value)
+    }
+}
+
+impl Display for SetCookie {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", format_http_date(expires))?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={}", same_site)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cookie_header() {
+        let cookies = parse_cookie_header("theme=dark;session=\"a.b\" ; flag; =x; empty=");
+        assert_eq!(cookies, vec![
+            ("theme".to_string(), "dark".to_string()),
+            ("session".to_string(), "a.b".to_string()),
+            ("empty".to_string(), String::new()),
+        ]);
+    }
+
+    #[test]
+    fn formats_set_cookie() {
+        let cookie = SetCookie::new("session", "abc")
+            .with_expires(UNIX_EPOCH + Duration::from_secs(784111777))
+            .with_max_age(Duration::from_secs(3600))
+            .with_path("/")
+            .with_http_only(true)
+            .with_same_site(SameSite::Lax);
+        assert!(cookie.is_valid());
+        assert_eq!(cookie.to_string(), "session=abc; Expires=Sun, 06 Nov 1994 08:49:37 GMT; Max-Age=3600; Path=/; HttpOnly; SameSite=Lax");
+
+        assert_eq!(SetCookie::removal("session").to_string(), "session=; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Max-Age=0");
+        assert!(SetCookie::new("embed", "x").with_same_site(SameSite::None).secure);
+    }
+
+    #[test]
+    fn rejects_invalid_cookies() {
+        assert!(!SetCookie::new("bad name", "x").is_valid());
+        assert!(!SetCookie::new("name", "a;b").is_valid());
+        assert!(!SetCookie::new("name", "x").with_path("/;Secure").is_valid());
+    }
+}
@@ -0,0 +1,276 @@
+use std::time::Duration;
+
+use crate::models::structs::http_message::HttpMessage;
+use crate::models::structs::http_method::Method;
+use crate::models::structs::http_middleware::{Middleware, Next};
+use crate::models::structs::http_response::HttpResponse;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AllowedOrigins {
+    // Answered with "*", never with credentials as any site could then act as the user
+    Any,
+    // Exact origins, e.g. "https://viewer.example.com"
+    List(Vec<String>),
+}
+
+// Cross-Origin Resource Sharing (Fetch Standard 3.2) as a middleware
+// Preflight requests are answered without calling the rest of the chain
+// Requests from origins not allowed go through without CORS fields, the browser then hides the response
+#[derive(Clone, Debug)]
+pub struct Cors {
+    pub allowed_origins: AllowedOrigins,
+    pub allowed_methods: Vec<Method>,
+    // Request fields the client may send, None to allow the ones the preflight asks for
+    pub allowed_headers: Option<Vec<String>>,
+    // Response fields the client may read besides the CORS-safelisted ones
+    pub exposed_headers: Vec<String>,
+    // Cookies and Authorization sent along with the requests
+    pub allow_credentials: bool,
+    // How long the browser may cache a preflight answer
+    pub max_age: Option<Duration>,
+}
+
+impl Cors {
+    // No origin allowed until with_origin or with_any_origin
+    pub fn new() -> Self {
+        Cors {
+            allowed_origins: AllowedOrigins::List(Vec::new()),
+            allowed_methods: vec![Method::Get, Method::Head, Method::Post, Method::Put, Method::Patch, Method::Delete],
+            allowed_headers: None,
+            exposed_headers: Vec::new(),
+            allow_credentials: false,
+            max_age: Some(Duration::from_secs(600)),
+        }
+    }
+
+    pub fn with_origin(mut self, origin: &str) -> Self {
+        let origin = Self::normalize_origin(origin);
+        match &mut self.allowed_origins {
+            AllowedOrigins::List(origins) => origins.push(origin),
+            AllowedOrigins::Any => self.allowed_origins = AllowedOrigins::List(vec![origin]),
+        }
+        self
+    }
+
+    // Refused along with credentials, the origins must then be listed with with_origin
+    pub fn with_any_origin(mut self) -> Self {
+        if self.allow_credentials {
+            println!("Invalid cors config, any origin can't be allowed with credentials");
+            return self;
+        }
+        self.allowed_origins = AllowedOrigins::Any;
+        self
+    }
+
+    pub fn with_methods(mut self, methods: &[Method]) -> Self {
+        self.allowed_methods = methods.to_vec();
+        self
+    }
+
+    pub fn with_headers(mut self, headers: &[&str]) -> Self {
+        self.allowed_headers = Some(headers.iter().map(|header| header.to_string()).collect());
+        self
+    }
+
+    pub fn with_exposed_headers(mut self, headers: &[&str]) -> Self {
+        self.exposed_headers = headers.iter().map(|header| header.to_string()).collect();
+        self
+    }
+
+    pub fn with_credentials(mut self, allow_credentials: bool) -> Self {
+        if allow_credentials && self.allowed_origins == AllowedOrigins::Any {
+            println!("Invalid cors config, credentials can't be allowed for any origin");
+            return self;
+        }
+        self.allow_credentials = allow_credentials;
+        self
+    }
+
+    pub fn with_max_age(mut self, max_age: Option<Duration>) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    // Scheme and host are case-insensitive, browsers never send a trailing slash
+    fn normalize_origin(origin: &str) -> String {
+        origin.trim().trim_end_matches('/').to_ascii_lowercase()
+    }
+
+    // Access-Control-Allow-Origin value for the request origin, None when it is not allowed
+    fn allow_origin(&self, origin: &str) -> Option<String> {
+        match &self.allowed_origins {
+            //Fields set without the builders, reflecting the origin would hand the user's cookies to any site
+            AllowedOrigins::Any if self.allow_credentials => None,
+            AllowedOrigins::Any => Some("*".to_string()),
+            AllowedOrigins::List(origins) => {
+                let normalized_origin = Self::normalize_origin(origin);
+                origins.contains(&normalized_origin).then(|| origin.to_string())
+            },
+        }
+    }
+
+    // The answer depends on Origin unless every origin gets "*"
+    fn varies_by_origin(&self) -> bool {
+        self.allowed_origins != AllowedOrigins::Any || self.allow_credentials
+    }
+
+    fn preflight(&self, request: &HttpMessage, origin: &str, requested_method: &str) -> HttpResponse {
+        let mut response = HttpResponse::new(204);
+        Self::add_vary(&mut response, &["Origin", "Access-Control-Request-Method", "Access-Control-Request-Headers"]);
+
+        let allow_origin = match self.allow_origin(origin) {
+            Some(allow_origin) => allow_origin,
+            None => return response,
+        };
+        //Methods and fields outside the allowed ones fail the preflight, the browser then doesn't send the request
+        let method_allowed = Method::parse(requested_method).is_ok_and(|method| self.allowed_methods.contains(&method));
+        let requested_headers = request.headers.get_list("Access-Control-Request-Headers");
+        let headers_allowed = match &self.allowed_headers {
+            Some(allowed_headers) => requested_headers.iter().all(|requested_header| {
+                allowed_headers.iter().any(|allowed_header| allowed_header.eq_ignore_ascii_case(requested_header))
+            }),
+            None => true,
+        };
+        if !method_allowed || !headers_allowed {
+            return response;
+        }
+
+        response.headers.insert("Access-Control-Allow-Origin", &allow_origin);
+        let methods: Vec<String> = self.allowed_methods.iter().map(|method| method.to_string()).collect();
+        response.headers.insert("Access-Control-Allow-Methods", &methods.join(", "));
+        match &self.allowed_headers {
+            Some(allowed_headers) if !allowed_headers.is_empty() => response.headers.insert("Access-Control-Allow-Headers", &allowed_headers.join(", ")),
+            Some(_) => (),
+            None if !requested_headers.is_empty() => response.headers.insert("Access-Control-Allow-Headers", &requested_headers.join(", ")),
+            None => (),
+        }
+        if self.allow_credentials {
+            response.headers.insert("Access-Control-Allow-Credentials", "true");
+        }
+        if let Some(max_age) = self.max_age {
+            response.headers.insert("Access-Control-Max-Age", &max_age.as_secs().to_string());
+        }
+        response
+    }
+
+    fn add_vary(response: &mut HttpResponse, names: &[&str]) {
+        for name in names {
+            if !response.headers.contains_token("Vary", name) && !response.headers.contains_token("Vary", "*") {
+                response.headers.append("Vary", name);
+            }
+        }
+    }
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Middleware for Cors {
+    fn handle(&self, request: &mut HttpMessage, next: Next<'_>) -> HttpResponse {
+        let origin = match request.headers.get("Origin") {
+            Some(origin) => origin.to_string(),
+            None => {
+                //Caches must not give a response without CORS fields to a cross-origin request
+                let mut response = next.run(request);
+                if self.varies_by_origin() {
+                    Self::add_vary(&mut response, &["Origin"]);
+                }
+                return response;
+            }
+        };
+
+        if request.method == Method::Options {
+            if let Some(requested_method) = request.headers.get("Access-Control-Request-Method") {
+                return self.preflight(request, &origin, requested_method);
+            }
+        }
+
+        let mut response = next.run(request);
+        if self.varies_by_origin() {
+            Self::add_vary(&mut response, &["Origin"]);
+        }
+        if let Some(allow_origin) = self.allow_origin(&origin) {
+            response.headers.insert("Access-Control-Allow-Origin", &allow_origin);
+            if self.allow_credentials {
+                response.headers.insert("Access-Control-Allow-Credentials", "true");
+            }
+            if !self.exposed_headers.is_empty() {
+                response.headers.insert("Access-Control-Expose-Headers", &self.exposed_headers.join(", "));
+            }
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::structs::http_middleware::MiddlewareChain;
+    use crate::models::structs::http_server::Handler;
+    use std::sync::Arc;
+
+    fn handler(cors: Cors) -> Handler {
+        MiddlewareChain::new().with(cors).into_handler(Arc::new(|request: &mut HttpMessage| {
+            HttpResponse::text(200, &request.method.to_string()).with_header("X-Total", "3")
+        }))
+    }
+
+    fn send(handler: &Handler, raw: &str) -> HttpResponse {
+        handler(&mut HttpMessage::new(raw.as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn answers_preflight_requests() {
+        let handler = handler(Cors::new().with_origin("https://Viewer.example.com/").with_headers(&["Content-Type", "Authorization"]).with_credentials(true));
+
+        let response = send(&handler, "OPTIONS /api HTTP/1.1\r\nOrigin: https://viewer.example.com\r\nAccess-Control-Request-Method: PUT\r\nAccess-Control-Request-Headers: content-type\r\n\r\n");
+        assert_eq!(response.status, 204);
+        assert_eq!(response.headers.get("Access-Control-Allow-Origin"), Some("https://viewer.example.com"));
+        assert_eq!(response.headers.get("Access-Control-Allow-Methods"), Some("GET, HEAD, POST, PUT, PATCH, DELETE"));
+        assert_eq!(response.headers.get("Access-Control-Allow-Headers"), Some("Content-Type, Authorization"));
+        assert_eq!(response.headers.get("Access-Control-Allow-Credentials"), Some("true"));
+        assert_eq!(response.headers.get("Access-Control-Max-Age"), Some("600"));
+        assert!(response.headers.contains_token("Vary", "Origin"));
+
+        //Unknown origin, method or field
+        for raw in ["OPTIONS /api HTTP/1.1\r\nOrigin: https://evil.example.com\r\nAccess-Control-Request-Method: GET\r\n\r\n",
+            "OPTIONS /api HTTP/1.1\r\nOrigin: https://viewer.example.com\r\nAccess-Control-Request-Method: TRACE\r\n\r\n",
+            "OPTIONS /api HTTP/1.1\r\nOrigin: https://viewer.example.com\r\nAccess-Control-Request-Method: GET\r\nAccess-Control-Request-Headers: X-Secret\r\n\r\n"] {
+            let response = send(&handler, raw);
+            assert_eq!(response.status, 204);
+            assert!(!response.headers.contains("Access-Control-Allow-Origin"));
+        }
+
+        //Without Access-Control-Request-Method it is a plain OPTIONS request
+        assert_eq!(send(&handler, "OPTIONS /api HTTP/1.1\r\nOrigin: https://viewer.example.com\r\n\r\n").body, b"OPTIONS");
+    }
+
+    #[test]
+    fn adds_fields_to_cross_origin_responses() {
+        let any_origin = handler(Cors::new().with_any_origin().with_exposed_headers(&["X-Total"]));
+        let response = send(&any_origin, "GET /api HTTP/1.1\r\nOrigin: https://a.example.com\r\n\r\n");
+        assert_eq!(response.headers.get("Access-Control-Allow-Origin"), Some("*"));
+        assert_eq!(response.headers.get("Access-Control-Expose-Headers"), Some("X-Total"));
+        assert!(!response.headers.contains("Vary"));
+
+        //Credentials need listed origins, whatever the order of the builders
+        assert!(!Cors::new().with_any_origin().with_credentials(true).allow_credentials);
+        assert_eq!(Cors::new().with_credentials(true).with_any_origin().allowed_origins, AllowedOrigins::List(Vec::new()));
+        let with_credentials = handler(Cors { allowed_origins: AllowedOrigins::Any, allow_credentials: true, ..Cors::new() });
+        for origin in ["https://a.example.com", "null"] {
+            let response = send(&with_credentials, &format!("GET /api HTTP/1.1\r\nOrigin: {}\r\n\r\n", origin));
+            assert!(!response.headers.contains("Access-Control-Allow-Origin"));
+            assert!(!response.headers.contains("Access-Control-Allow-Credentials"));
+        }
+
+        let listed_origin = handler(Cors::new().with_origin("https://a.example.com"));
+        let response = send(&listed_origin, "GET /api HTTP/1.1\r\nOrigin: https://b.example.com\r\n\r\n");
+        assert_eq!(response.status, 200);
+        assert!(!response.headers.contains("Access-Control-Allow-Origin"));
+        let response = send(&listed_origin, "GET /api HTTP/1.1\r\n\r\n");
+        assert_eq!(response.headers.get("Vary"), Some("Origin"));
+    }
+}
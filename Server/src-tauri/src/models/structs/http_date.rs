@@ -0,0 +1,103 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAY_NAMES: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTH_NAMES: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+// IMF-fixdate used by Date, Last-Modified, Expires... (RFC 9110 5.6.7)
+// Format : Sun, 06 Nov 1994 08:49:37 GMT
+pub fn format_http_date(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_secs();
+    let days = seconds / 86400;
+    let seconds_of_day = seconds % 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAY_NAMES[(days % 7) as usize],
+        day,
+        MONTH_NAMES[(month - 1) as usize],
+        year,
+        seconds_of_day / 3600,
+        (seconds_of_day % 3600) / 60,
+        seconds_of_day % 60)
+}
+
+// Date of access log lines in Common Log Format, always UTC
+// Format : 10/Oct/2000:13:55:36 +0000
+pub fn format_log_date(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_secs();
+    let seconds_of_day = seconds % 86400;
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+
+    format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTH_NAMES[(month - 1) as usize],
+        year,
+        seconds_of_day / 3600,
+        (seconds_of_day % 3600) / 60,
+        seconds_of_day % 60)
+}
+
+// RFC 3339 timestamp with milliseconds, always UTC
+// Format : 2000-10-10T13:55:36.123Z
+pub fn format_rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+    let seconds = since_epoch.as_secs();
+    let seconds_of_day = seconds % 86400;
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        (seconds_of_day % 3600) / 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_millis())
+}
+
+// Parse an IMF-fixdate, obsolete formats are not accepted
+pub fn parse_http_date(value: &str) -> Option<SystemTime> {
+    //Format : Sun, 06 Nov 1994 08:49:37 GMT
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    if parts.len() != 6 || parts[5] != "GMT" || !DAY_NAMES.iter().any(|day| parts[0] == format!("{},", day)) {
+        return None;
+    }
+
+    let day: u32 = parts[1].parse().ok()?;
+    let month = MONTH_NAMES.iter().position(|month| *month == parts[2])? as u32 + 1;
+    let year: i64 = parts[3].parse().ok()?;
+    let time: Vec<u64> = parts[4].split(':').map(|part| part.parse().ok()).collect::<Option<Vec<u64>>>()?;
+    if time.len() != 3 || time[0] > 23 || time[1] > 59 || time[2] > 60 || day == 0 || day > 31 || year < 1970 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day) as u64;
+    Some(UNIX_EPOCH + Duration::from_secs(days * 86400 + time[0] * 3600 + time[1] * 60 + time[2]))
+}
+
+// (year, month, day) to days since 1970-01-01
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month_position = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let day_of_year = (153 * month_position + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+// Days since 1970-01-01 to (year, month, day), see http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_position = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_position + 2) / 5 + 1) as u32;
+    let month = if month_position < 10 { month_position + 3 } else { month_position - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
@@ -0,0 +1,77 @@
+use std::{fmt::Display, io};
+
+// Errors raised while reading an HTTP message from a stream
+#[derive(Debug)]
+pub enum HttpError {
+    // Request-line longer than the configured limit
+    RequestLineTooLong,
+    // More header fields than the configured limit
+    TooManyHeaders,
+    // A header field or the whole header section is above the configured limit
+    HeaderTooLarge,
+    // Content-Length above the configured body limit
+    PayloadTooLarge,
+    // Expect field with another expectation than 100-continue
+    ExpectationFailed(String),
+    // Syntax error in the message, the reason is kept for logging
+    Malformed(String),
+    // Transfer coding other than chunked
+    UnsupportedTransferCoding(String),
+    // Body media type the handler can't parse, e.g. a form expected
+    UnsupportedMediaType(String),
+    // Byte outside of US-ASCII where only US-ASCII is allowed
+    InvalidEncoding,
+    // The peer did not send the message in time
+    Timeout,
+    // The peer closed the connection before a complete message was received
+    ConnectionClosed,
+    Io(io::Error),
+}
+
+impl HttpError {
+    // Status code the server should answer with for this error
+    pub fn status_code(&self) -> u16 {
+        match self {
+            HttpError::RequestLineTooLong => 414,
+            HttpError::TooManyHeaders | HttpError::HeaderTooLarge => 431,
+            HttpError::PayloadTooLarge => 413,
+            HttpError::ExpectationFailed(_) => 417,
+            HttpError::Malformed(_) | HttpError::InvalidEncoding => 400,
+            HttpError::UnsupportedTransferCoding(_) => 501,
+            HttpError::UnsupportedMediaType(_) => 415,
+            HttpError::Timeout => 408,
+            HttpError::ConnectionClosed | HttpError::Io(_) => 400,
+        }
+    }
+}
+
+impl Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpError::RequestLineTooLong => write!(f, "Request-line too long"),
+            HttpError::TooManyHeaders => write!(f, "Too many header fields"),
+            HttpError::HeaderTooLarge => write!(f, "Header fields too large"),
+            HttpError::PayloadTooLarge => write!(f, "Payload too large"),
+            HttpError::ExpectationFailed(expectation) => write!(f, "Unsupported expectation : {}", expectation),
+            HttpError::Malformed(reason) => write!(f, "Malformed message : {}", reason),
+            HttpError::UnsupportedTransferCoding(coding) => write!(f, "Unsupported transfer coding : {}", coding),
+            HttpError::UnsupportedMediaType(media_type) => write!(f, "Unsupported media type : {}", media_type),
+            HttpError::InvalidEncoding => write!(f, "Invalid encoding"),
+            HttpError::Timeout => write!(f, "Timeout while reading message"),
+            HttpError::ConnectionClosed => write!(f, "Connection closed by peer"),
+            HttpError::Io(err) => write!(f, "Io error : {}", err),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl From<io::Error> for HttpError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => HttpError::Timeout,
+            io::ErrorKind::UnexpectedEof => HttpError::ConnectionClosed,
+            _ => HttpError::Io(err),
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use crate::models::structs::http_error::HttpError;
+use crate::models::structs::http_target::percent_decode;
+
+// Name / value pairs of a query string or a form in reception order,
+// names are case-sensitive and a name may hold several values
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ParamMap {
+    params: Vec<(String, String)>,
+}
+
+impl ParamMap {
+    pub fn new() -> Self {
+        ParamMap {
+            params: Vec::new()
+        }
+    }
+
+    // Parse application/x-www-form-urlencoded content, also the format of query strings
+    // e.g. "name=a+b&tag=1&tag=%C3%A9&flag"
+    pub fn parse(input: &str) -> Result<Self, HttpError> {
+        let mut params = ParamMap::new();
+
+        for pair in input.split('&') {
+            if pair.is_empty() {
+                continue;
+            }
+            //A pair without '=' is a name with an empty value
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            params.append(&Self::decode(name)?, &Self::decode(value)?);
+        }
+
+        Ok(params)
+    }
+
+    // '+' is a space, then %XX sequences, the result must be UTF-8
+    fn decode(input: &str) -> Result<String, HttpError> {
+        let decoded = match percent_decode(&input.replace('+', " ")) {
+            Some(decoded) => decoded,
+            None => return Err(HttpError::Malformed(format!("Invalid percent-encoding {}", input))),
+        };
+
+        String::from_utf8(decoded).map_err(|_| HttpError::InvalidEncoding)
+    }
+
+    // First value for the name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(param_name, _)| param_name == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Every value for the name, in reception order
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.params
+            .iter()
+            .filter(|(param_name, _)| param_name == name)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.params.iter().any(|(param_name, _)| param_name == name)
+    }
+
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.params.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.params.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.params.is_empty()
+    }
+}
+
+// Media type of a Content-Type value without its parameters, lowercase
+pub fn media_type(content_type: &str) -> String {
+    content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase()
+}
+
+// Parameter of a Content-Type value, e.g. the boundary of multipart/form-data
+pub fn media_type_parameter(content_type: &str, name: &str) -> Option<String> {
+    parse_parameters(content_type)
+        .into_iter()
+        .find(|(parameter_name, _)| parameter_name.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value)
+}
+
+// ";"-separated name=value parameters following a value, e.g. of Content-Type or Content-Disposition
+// Values may be quoted strings (RFC 9110 5.6.6), parsing stops at the first invalid parameter
+pub fn parse_parameters(input: &str) -> Vec<(String, String)> {
+    let mut parameters: Vec<(String, String)> = Vec::new();
+    let mut rest = match input.split_once(';') {
+        Some((_, rest)) => rest,
+        None => return parameters,
+    };
+
+    while let Some((name, after_name)) = rest.split_once('=') {
+        let after_name = after_name.trim_start();
+        let (value, after_value) = match after_name.strip_prefix('"') {
+            Some(quoted) => match unquote(quoted) {
+                Some((value, length)) => (value, &quoted[length..]),
+                None => break,
+            },
+            None => {
+                let end = after_name.find(';').unwrap_or(after_name.len());
+                (after_name[..end].trim_end().to_string(), &after_name[end..])
+            }
+        };
+        parameters.push((name.trim().to_string(), value));
+
+        rest = match after_value.split_once(';') {
+            Some((_, rest)) => rest,
+            None => break,
+        };
+    }
+
+    parameters
+}
+
+// Content of a quoted-string whose opening quote is already consumed,
+// returns the value and the number of bytes read including the closing quote
+fn unquote(input: &str) -> Option<(String, usize)> {
+    let mut value = String::new();
+    let mut chars = input.char_indices();
+
+    while let Some((index, character)) = chars.next() {
+        match character {
+            '"' => return Some((value, index + 1)),
+            '\\' => value.push(chars.next()?.1),
+            _ => value.push(character),
+        }
+    }
+
+    //No closing quote
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_urlencoded_pairs() {
+        let params = ParamMap::parse("name=a+b&tag=1&tag=%C3%A9&flag&&empty=").unwrap();
+        assert_eq!(params.get("name"), Some("a b"));
+        assert_eq!(params.get_all("tag"), vec!["1", "é"]);
+        assert_eq!(params.get("flag"), Some(""));
+        assert_eq!(params.get("empty"), Some(""));
+        assert_eq!(params.len(), 5);
+        assert!(!params.contains("Name"));
+    }
+
+    #[test]
+    fn rejects_invalid_encoding() {
+        assert!(matches!(ParamMap::parse("a=%zz"), Err(HttpError::Malformed(_))));
+        assert!(matches!(ParamMap::parse("a=%ff"), Err(HttpError::InvalidEncoding)));
+    }
+
+    #[test]
+    fn reads_media_type_parameters() {
+        let content_type = "Multipart/Form-Data; charset=utf-8; boundary=\"a \\\"b\\\"; c\"";
+        assert_eq!(media_type(content_type), "multipart/form-data");
+        assert_eq!(media_type_parameter(content_type, "boundary"), Some("a \"b\"; c".to_string()));
+        assert_eq!(media_type_parameter(content_type, "Charset"), Some("utf-8".to_string()));
+        assert_eq!(media_type_parameter(content_type, "missing"), None);
+        assert_eq!(media_type_parameter("text/plain", "charset"), None);
+    }
+}
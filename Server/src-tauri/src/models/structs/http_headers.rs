@@ -0,0 +1,95 @@
+use crate::models::structs::http_error::HttpError;
+use crate::models::structs::http_method::Method;
+
+// Header fields in reception order, names are compared case-insensitively
+// and a name may hold several values
+#[derive(Clone, Debug, Default)]
+pub struct HeaderMap {
+    fields: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        HeaderMap {
+            fields: Vec::new()
+        }
+    }
+
+    // Parse a "Name: value" field line
+    pub fn parse_field(line: &str) -> Result<(String, String), HttpError> {
+        let (name, value) = match line.split_once(':') {
+            Some(field) => field,
+            None => return Err(HttpError::Malformed("Header field without ':'".to_string())),
+        };
+
+        //No whitespace allowed between the name and the colon (RFC 9112 5.1)
+        if name.is_empty() || !name.bytes().all(Method::is_tchar) {
+            return Err(HttpError::Malformed(format!("Invalid header name {}", name)));
+        }
+
+        Ok((name.to_string(), value.trim_matches([' ', '\t']).to_string()))
+    }
+
+    // First value for the name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field_name, _)| field_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Every value for the name, in reception order
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.fields
+            .iter()
+            .filter(|(field_name, _)| field_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    // Every comma separated element of a list-based field, "a, b" and two fields "a" / "b" are equal
+    pub fn get_list(&self, name: &str) -> Vec<&str> {
+        self.get_all(name)
+            .into_iter()
+            .flat_map(|value| value.split(','))
+            .map(|element| element.trim())
+            .filter(|element| !element.is_empty())
+            .collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    // Does the list-based field contains the token, case-insensitively
+    pub fn contains_token(&self, name: &str, token: &str) -> bool {
+        self.get_list(name).iter().any(|element| element.eq_ignore_ascii_case(token))
+    }
+
+    // Add a value, keeping the existing ones
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.fields.push((name.to_string(), value.to_string()));
+    }
+
+    // Replace every value for the name
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.fields.retain(|(field_name, _)| !field_name.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
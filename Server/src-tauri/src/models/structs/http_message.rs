@@ -1,146 +1,317 @@
-use std::{fmt::Error, io::Read, net::TcpStream};
+use std::{io::Read, net::SocketAddr, str::Utf8Error, sync::{mpsc::Receiver, Mutex}, time::Duration};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::models::structs::http_cookie::parse_cookie_header;
+use crate::models::structs::http_error::HttpError;
+use crate::models::structs::http_form::{media_type, media_type_parameter, ParamMap};
+use crate::models::structs::http_headers::HeaderMap;
+use crate::models::structs::http_method::Method;
+use crate::models::structs::http_middleware::Extensions;
+use crate::models::structs::http_multipart::{MultipartLimits, MultipartPart, MultipartStream};
+use crate::models::structs::http_parser::HttpParser;
+use crate::models::structs::http_target::RequestTarget;
+use crate::models::structs::http_version::Version;
+
+// Limits applied while reading a message, protects the server from huge or endless requests
+#[derive(Clone, Debug)]
+pub struct HttpLimits {
+    pub max_request_line: usize,
+    pub max_headers: usize,
+    pub max_header_size: usize,
+    pub max_body_size: usize,
+    // Body handed to the handler as it is received, see HttpParser::with_streamed_bodies
+    pub max_streamed_body_size: usize,
+    // Applied by the connection to its stream
+    pub read_timeout: Option<Duration>,
+    // Time allowed for the whole head, from its first byte, so a client can't trickle header fields
+    pub header_timeout: Option<Duration>,
+    // Time allowed for the whole body, from the end of the head
+    pub body_timeout: Option<Duration>,
+    // Slowest average rate accepted for the body
+    pub min_body_rate: Option<MinDataRate>,
+}
+
+// Minimum average transfer rate, checked once the grace period is over
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MinDataRate {
+    pub bytes_per_second: u64,
+    pub grace_period: Duration,
+}
+
+impl Default for HttpLimits {
+    fn default() -> Self {
+        HttpLimits {
+            max_request_line: 8 * 1024,
+            max_headers: 100,
+            max_header_size: 8 * 1024,
+            max_body_size: 10 * 1024 * 1024,
+            max_streamed_body_size: 1024 * 1024 * 1024,
+            read_timeout: Some(Duration::from_secs(30)),
+            header_timeout: Some(Duration::from_secs(20)),
+            body_timeout: Some(Duration::from_secs(300)),
+            min_body_rate: Some(MinDataRate {
+                bytes_per_second: 240,
+                grace_period: Duration::from_secs(5),
+            }),
+        }
+    }
+}
 
 pub struct HttpMessage {
-    pub start_line: String,
-    pub header_field: Vec<String>,
-    pub body: String
+    pub method: Method,
+    pub target: RequestTarget,
+    pub version: Version,
+    pub headers: HeaderMap,
+    // Raw bytes as received, see body_text() for text access
+    pub body: Vec<u8>,
+    // Trailer fields sent after a chunked body
+    pub trailers: HeaderMap,
+    // Typed values attached by the middlewares
+    pub extensions: Extensions,
+    // Client address, set by the server
+    pub peer_addr: Option<SocketAddr>,
+}
+
+const READ_CHUNK_SIZE: usize = 4 * 1024;
+
+// Body still being received while the handler runs, in the request extensions (see HttpParser::with_streamed_bodies)
+// request.body stays empty, multipart and multipart_stream read from here
+pub struct StreamedBody {
+    receiver: Mutex<Receiver<Result<Vec<u8>, HttpError>>>,
+    length: Option<usize>,
+}
+
+impl StreamedBody {
+    // length is None for a chunked body
+    pub fn new(receiver: Receiver<Result<Vec<u8>, HttpError>>, length: Option<usize>) -> Self {
+        StreamedBody {
+            receiver: Mutex::new(receiver),
+            length,
+        }
+    }
+
+    pub fn length(&self) -> Option<usize> {
+        self.length
+    }
+
+    // Next bytes of the body, None once it is all received
+    pub fn next_chunk(&self) -> Result<Option<Vec<u8>>, HttpError> {
+        let receiver = self.receiver.lock().map_err(|_| HttpError::ConnectionClosed)?;
+        match receiver.recv() {
+            Ok(chunk) => chunk.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
 }
 
 impl HttpMessage  {
-    pub fn new(mut tcp_stream: TcpStream) -> Result<Self, String> {
-        let mut buf = [0u8;100 * 1024];
-        let nb_bytes_read = tcp_stream.read(&mut buf);
-
-        println!("{}", String::from_utf8_lossy(&buf).to_string());
-        
-        //Checking encoding US-ASCII
-        let is_valid = buf
-        .iter()
-        .all(|byte| 
-            Self::is_usascii_byte(byte)
-        );
-        if !is_valid {
-            return Err("Invalid encoding".to_string());
-        } 
-
-        let mut count: usize = 0;
-
-        //Parse the first line IS Request-line or Status-line
-        //Until CRLF 
-        let crlf = b"\r\n";
-        let mut request_line: [u8; 1024] = [0; 1024]; 
-        for i in 0..buf.len()-1 {
-            if buf[i] == crlf[0] && buf[i+1] == crlf[1] {
-                count = i + 2;
-                break;
+    // Read one request from any stream (TCP, TLS, in-memory bytes...), timeouts are the stream's own
+    // The header and body deadlines of HttpLimits are applied by HttpConnection::read_request
+    pub fn new<R: Read>(stream: R) -> Result<Self, HttpError> {
+        Self::with_limits(stream, &HttpLimits::default())
+    }
+
+    pub fn with_limits<R: Read>(mut stream: R, limits: &HttpLimits) -> Result<Self, HttpError> {
+        let mut buf: Vec<u8> = Vec::new();
+        Self::read(&mut stream, &mut buf, limits)
+    }
+
+    // Read one request, buf holds bytes already received on the connection
+    // Bytes received after the request (pipelined requests) are left in buf
+    pub fn read<R: Read + ?Sized>(stream: &mut R, buf: &mut Vec<u8>, limits: &HttpLimits) -> Result<Self, HttpError> {
+        let mut parser = HttpParser::new(limits);
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+
+        loop {
+            if let Some(message) = parser.parse(buf)? {
+                return Ok(message);
             }
 
-            request_line[i] = buf[i];
+            let nb_bytes_read = stream.read(&mut chunk)?;
+            if nb_bytes_read == 0 {
+                return Err(HttpError::ConnectionClosed);
+            }
+            buf.extend_from_slice(&chunk[..nb_bytes_read]);
         }
+    }
+
+    // Same as new for tokio streams
+    pub async fn new_async<R: AsyncRead + Unpin>(stream: R) -> Result<Self, HttpError> {
+        Self::with_limits_async(stream, &HttpLimits::default()).await
+    }
 
-        //Parse X header 
-        //Format : Something CRLF
-        
-        let mut vec_header_field: Vec<[u8; 1024]> = Vec::new();
-        let mut header_field_buf: [u8; 1024] = [0; 1024];
-        let mut buf_counter: usize = 0;
-        let mut body_start: usize = 0;
+    pub async fn with_limits_async<R: AsyncRead + Unpin>(mut stream: R, limits: &HttpLimits) -> Result<Self, HttpError> {
+        let mut buf: Vec<u8> = Vec::new();
+        Self::read_async(&mut stream, &mut buf, limits).await
+    }
 
-        for mut i in count..buf.len()-1 {
+    // Same as read for tokio streams, timeouts are left to the caller (tokio::time::timeout)
+    pub async fn read_async<R: AsyncRead + Unpin + ?Sized>(stream: &mut R, buf: &mut Vec<u8>, limits: &HttpLimits) -> Result<Self, HttpError> {
+        let mut parser = HttpParser::new(limits);
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
 
-            if buf[i] == crlf[0] && buf[i + 1] != crlf[1] {
-                break; // Invalide message
+        loop {
+            if let Some(message) = parser.parse(buf)? {
+                return Ok(message);
             }
-            else if buf[i] == crlf[0] && buf[i + 1] == crlf[1] {
-                vec_header_field.push(header_field_buf);
-                header_field_buf.fill(0);
-                buf_counter = 0;
-                if buf[i + 2] == crlf[0] && buf[i + 3] == crlf[1]{
-                    body_start += 6;
-                    break;
-                }
-                i += 2;
-                body_start += 2;
+
+            let nb_bytes_read = stream.read(&mut chunk).await?;
+            if nb_bytes_read == 0 {
+                return Err(HttpError::ConnectionClosed);
             }
+            buf.extend_from_slice(&chunk[..nb_bytes_read]);
+        }
+    }
 
-            header_field_buf[buf_counter] = buf[i];
-            buf_counter += 1;
-            body_start += 1;
-        }   
-
-
-        //According to parse info 
-        //Parse body for BODY_LENGTH given in header information 
-        // Retriving body length information
-        let mut body_content: Vec<u8> = Vec::new();
-        let parsing_bytes = b"C\nContent-Length";
-        let mut body_length: usize = 0;
-        let split_char: u8 = b':';
-        if let Some(slice) = vec_header_field
-                .iter()
-                .find(|&slice| {
-                    slice.windows(parsing_bytes.len()).any(|window| window == parsing_bytes)
-                }) {
-
-            let mut split_char_index = slice.iter().position(|char| char == &split_char).unwrap();
-            split_char_index += 1;
-
-            let splited_slice: Vec<u8> = slice
-                .iter()
-                .skip(split_char_index)
-                .take(slice.len() - split_char_index)
-                .copied()
-                .collect();
-
-            let mut str: String = splited_slice.iter()
-            .filter(|&&char| char >= 0x30 && char <= 0x39)
-            .map(|&char| char as char)
-            .collect();
-            str = str.trim().to_string();
-
-            body_length = str.parse().unwrap();
+    // Body as UTF-8 text, fails for binary content
+    pub fn body_text(&self) -> Result<&str, Utf8Error> {
+        std::str::from_utf8(&self.body)
+    }
 
+    // Should the connection stay open after this request (RFC 9112 9.3)
+    pub fn keep_alive(&self) -> bool {
+        if self.headers.contains_token("Connection", "close") {
+            return false;
         }
 
-        for i in body_start..body_start+body_length {
-             if i >= buf.len()-1 {
-                break;
-            }
+        match self.version {
+            Version::Http11 | Version::Http2 => true,
+            Version::Http10 => self.headers.contains_token("Connection", "keep-alive"),
+        }
+    }
+
+    pub fn content_length(&self) -> Option<usize> {
+        self.headers.get("Content-Length").and_then(|value| value.trim().parse().ok())
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.get("Content-Type")
+    }
 
-            body_content.push(buf[i]);
+    pub fn host(&self) -> Option<&str> {
+        self.headers.get("Host")
+    }
+
+    // Cookies of every Cookie field, in reception order
+    pub fn cookies(&self) -> Vec<(String, String)> {
+        self.headers
+            .get_all("Cookie")
+            .into_iter()
+            .flat_map(parse_cookie_header)
+            .collect()
+    }
+
+    // First cookie with the name
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.cookies()
+            .into_iter()
+            .find(|(cookie_name, _)| cookie_name == name)
+            .map(|(_, value)| value)
+    }
+
+    // Parameters of the query string, empty without query
+    pub fn query_params(&self) -> Result<ParamMap, HttpError> {
+        match &self.target.query {
+            Some(query) => ParamMap::parse(query),
+            None => Ok(ParamMap::new()),
         }
-    
+    }
 
-        Ok(HttpMessage {
-            start_line : Self::byte_array_to_string(request_line).replace("\0", ""),
-            header_field : Self::vec_byte_array_to_string(vec_header_field).into_iter().map(|str| str.replace("\0", "")).collect(),
-            body: Self::byte_vec_to_string(body_content).replace("\0", "")
-        })
+    // Fields of an application/x-www-form-urlencoded body
+    pub fn form(&self) -> Result<ParamMap, HttpError> {
+        let content_type = self.content_type().unwrap_or_default();
+        if media_type(content_type) != "application/x-www-form-urlencoded" {
+            return Err(HttpError::UnsupportedMediaType(content_type.to_string()));
+        }
+
+        match std::str::from_utf8(&self.body) {
+            Ok(body) => ParamMap::parse(body),
+            Err(_) => Err(HttpError::Malformed("Form body is not UTF-8".to_string())),
+        }
+    }
+
+    // Whole parts of a multipart/form-data body, use multipart_stream for large files
+    pub fn multipart(&self, limits: &MultipartLimits) -> Result<Vec<MultipartPart>, HttpError> {
+        self.multipart_stream(limits)?.collect_parts()
     }
 
-    fn is_usascii_byte(byte: &u8) -> bool {
-        if *byte < 127 {
-            true
+    // Events of a multipart/form-data body as it is received, a file can be written out without being held in memory
+    // The body is read only once, a second stream of a streamed body finds it empty
+    pub fn multipart_stream(&self, limits: &MultipartLimits) -> Result<MultipartStream<'_>, HttpError> {
+        let content_type = self.content_type().unwrap_or_default();
+        if media_type(content_type) != "multipart/form-data" {
+            return Err(HttpError::UnsupportedMediaType(content_type.to_string()));
         }
-        else {
-            false
+
+        match media_type_parameter(content_type, "boundary") {
+            Some(boundary) => MultipartStream::new(&boundary, limits, self.body.clone(), self.extensions.get::<StreamedBody>()),
+            None => Err(HttpError::Malformed("Multipart body without boundary".to_string())),
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hands out the bytes a few at a time like a slow socket
+    struct SlowReader {
+        data: Vec<u8>,
+        position: usize,
+    }
 
-    fn vec_byte_array_to_string<const N: usize>(vec: Vec<[u8;N]>) -> Vec<String> {
-        let mut vec_string: Vec<String> = Vec::new();
-        for byte_array in vec {
-            vec_string.push(Self::byte_array_to_string(byte_array));
+    impl Read for SlowReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let length = 3.min(buf.len()).min(self.data.len() - self.position);
+            buf[..length].copy_from_slice(&self.data[self.position..self.position + length]);
+            self.position += length;
+            Ok(length)
         }
+    }
+
+    #[test]
+    fn reads_from_byte_slice() {
+        let request = HttpMessage::new(&b"POST /echo HTTP/1.1\r\nContent-Length: 4\r\n\r\nping"[..]).unwrap();
+        assert_eq!(request.method, Method::Post);
+        assert_eq!(request.body_text(), Ok("ping"));
+    }
 
-        vec_string
+    #[test]
+    fn reads_from_slow_stream() {
+        let mut stream = SlowReader {
+            data: b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nslow\r\n0\r\n\r\nGET /next HTTP/1.1\r\n\r\n".to_vec(),
+            position: 0,
+        };
+        let mut buf: Vec<u8> = Vec::new();
+        let first = HttpMessage::read(&mut stream, &mut buf, &HttpLimits::default()).unwrap();
+        assert_eq!(first.body_text(), Ok("slow"));
+        let second = HttpMessage::read(&mut stream, &mut buf, &HttpLimits::default()).unwrap();
+        assert_eq!(second.target.path, "/next");
     }
 
-    fn byte_array_to_string<const N: usize>(array: [u8; N]) -> String {
-        String::from_utf8_lossy(&array).to_string()
+    #[test]
+    fn truncated_stream_is_connection_closed() {
+        let result = HttpMessage::new(&b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc"[..]);
+        assert!(matches!(result, Err(HttpError::ConnectionClosed)));
     }
 
-    fn byte_vec_to_string(vec: Vec<u8>) -> String {
-        String::from_utf8_lossy(&vec).to_string()
+    #[test]
+    fn parses_query_and_form() {
+        let request = HttpMessage::new(&b"POST /search?q=rust+http&page=2 HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: 9\r\n\r\nname=a%20"[..]).unwrap();
+        assert_eq!(request.query_params().unwrap().get("q"), Some("rust http"));
+        assert_eq!(request.form().unwrap().get("name"), Some("a "));
+        assert!(matches!(request.multipart(&MultipartLimits::default()), Err(HttpError::UnsupportedMediaType(_))));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn reads_from_async_stream() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let request = runtime.block_on(HttpMessage::new_async(&b"GET /async HTTP/1.1\r\nHost: a\r\n\r\n"[..])).unwrap();
+        assert_eq!(request.target.path, "/async");
+        assert_eq!(request.host(), Some("a"));
+
+        let result = runtime.block_on(HttpMessage::new_async(&b"GET /async HTTP/1.1\r\nHost: a"[..]));
+        assert!(matches!(result, Err(HttpError::ConnectionClosed)));
+    }
+}
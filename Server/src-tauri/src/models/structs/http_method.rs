@@ -0,0 +1,69 @@
+use std::fmt::Display;
+
+use crate::models::structs::http_error::HttpError;
+
+// Request method (RFC 9110 9.1)
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+    // Any other token, methods are case-sensitive
+    Extension(String),
+}
+
+impl Method {
+    pub fn parse(token: &str) -> Result<Self, HttpError> {
+        let method = match token {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "CONNECT" => Method::Connect,
+            "OPTIONS" => Method::Options,
+            "TRACE" => Method::Trace,
+            "PATCH" => Method::Patch,
+            _ => {
+                if token.is_empty() || !token.bytes().all(Self::is_tchar) {
+                    return Err(HttpError::Malformed(format!("Invalid method {}", token)));
+                }
+                Method::Extension(token.to_string())
+            }
+        };
+
+        Ok(method)
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
+            Method::Extension(token) => token,
+        }
+    }
+
+    // Token characters allowed in a method or a header name (RFC 9110 5.6.2)
+    pub fn is_tchar(byte: u8) -> bool {
+        byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+    }
+}
+
+impl Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
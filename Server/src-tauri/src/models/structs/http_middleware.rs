@@ -0,0 +1,219 @@
+use std::{any::{Any, TypeId}, collections::HashMap, sync::Arc, time::Duration};
+
+use crate::models::structs::http_message::HttpMessage;
+use crate::models::structs::http_response::HttpResponse;
+use crate::models::structs::http_server::Handler;
+use crate::models::structs::stop_watch::StopWatch;
+
+// Values attached to a request by the middlewares, one per type
+// e.g. the authenticated user, read back by the handler with get::<User>()
+#[derive(Default)]
+pub struct Extensions {
+    values: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Extensions {
+            values: HashMap::new(),
+        }
+    }
+
+    // Returns the value of the same type previously attached
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) -> Option<T> {
+        self.values
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.downcast::<T>().ok())
+            .map(|previous| *previous)
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.values.get(&TypeId::of::<T>()).and_then(|value| value.downcast_ref::<T>())
+    }
+
+    pub fn get_mut<T: Any + Send + Sync>(&mut self) -> Option<&mut T> {
+        self.values.get_mut(&TypeId::of::<T>()).and_then(|value| value.downcast_mut::<T>())
+    }
+
+    pub fn remove<T: Any + Send + Sync>(&mut self) -> Option<T> {
+        self.values
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast::<T>().ok())
+            .map(|value| *value)
+    }
+
+    pub fn contains<T: Any + Send + Sync>(&self) -> bool {
+        self.values.contains_key(&TypeId::of::<T>())
+    }
+}
+
+impl std::fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Extensions({} values)", self.values.len())
+    }
+}
+
+// Code run around the handler, e.g. logging, authentication or CORS
+// A middleware calls next.run(request) to continue the chain, or answers by itself to short-circuit it
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: &mut HttpMessage, next: Next<'_>) -> HttpResponse;
+}
+
+impl<F> Middleware for F
+    where F: Fn(&mut HttpMessage, Next<'_>) -> HttpResponse + Send + Sync {
+    fn handle(&self, request: &mut HttpMessage, next: Next<'_>) -> HttpResponse {
+        self(request, next)
+    }
+}
+
+// Rest of the chain after the current middleware, ends with the handler
+pub struct Next<'a> {
+    middlewares: &'a [Arc<dyn Middleware>],
+    handler: &'a Handler,
+}
+
+impl Next<'_> {
+    pub fn run(self, request: &mut HttpMessage) -> HttpResponse {
+        match self.middlewares.split_first() {
+            Some((middleware, middlewares)) => middleware.handle(request, Next { middlewares, handler: self.handler }),
+            None => (self.handler)(request),
+        }
+    }
+}
+
+// Middlewares in the order they see the request, the response goes through them in reverse order
+#[derive(Clone, Default)]
+pub struct MiddlewareChain {
+    middlewares: Vec<Arc<dyn Middleware>>,
+}
+
+impl MiddlewareChain {
+    pub fn new() -> Self {
+        MiddlewareChain {
+            middlewares: Vec::new(),
+        }
+    }
+
+    pub fn with<M: Middleware + 'static>(mut self, middleware: M) -> Self {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    // Same as with, lets the compiler infer the closure's parameter types
+    pub fn with_fn<F>(self, middleware: F) -> Self
+        where F: Fn(&mut HttpMessage, Next<'_>) -> HttpResponse + Send + Sync + 'static {
+        self.with(middleware)
+    }
+
+    pub fn handle(&self, request: &mut HttpMessage, handler: &Handler) -> HttpResponse {
+        Next { middlewares: &self.middlewares, handler }.run(request)
+    }
+
+    // Handler running the chain then the given handler, e.g. Router::into_handler()
+    pub fn into_handler(self, handler: Handler) -> Handler {
+        Arc::new(move |request: &mut HttpMessage| self.handle(request, &handler))
+    }
+}
+
+// Time spent in the rest of the chain, attached to the request by Timing once the response is produced
+#[derive(Clone, Copy, Debug)]
+pub struct RequestDuration(pub Duration);
+
+// Measure how long the rest of the chain takes and announce it with Server-Timing (W3C Server Timing)
+// e.g. "Server-Timing: app;dur=12.345"
+#[derive(Clone, Debug, Default)]
+pub struct Timing {
+    // Log the method, target, status and duration of each request
+    pub log: bool,
+}
+
+impl Timing {
+    pub fn new() -> Self {
+        Timing {
+            log: false,
+        }
+    }
+
+    pub fn with_log(mut self, log: bool) -> Self {
+        self.log = log;
+        self
+    }
+}
+
+impl Middleware for Timing {
+    fn handle(&self, request: &mut HttpMessage, next: Next<'_>) -> HttpResponse {
+        let mut stop_watch = StopWatch::new();
+        stop_watch.start();
+        let mut response = next.run(request);
+        stop_watch.stop();
+
+        let duration = stop_watch.elapsed();
+        request.extensions.insert(RequestDuration(duration));
+        if self.log {
+            println!("{} {} {} {:?}", request.method, request.target, response.status, duration);
+        }
+        response.headers.append("Server-Timing", &format!("app;dur={:.3}", duration.as_secs_f64() * 1000.0));
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct User(String);
+
+    fn request(raw: &str) -> HttpMessage {
+        HttpMessage::new(raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn runs_in_order_and_attaches_extensions() {
+        let handler: Handler = Arc::new(|request: &mut HttpMessage| {
+            let user = request.extensions.get::<User>().map(|user| user.0.clone()).unwrap_or_default();
+            HttpResponse::text(200, &format!("hello {}", user))
+        });
+        let chain = MiddlewareChain::new()
+            .with_fn(|request, next| {
+                let mut response = next.run(request);
+                response.headers.append("X-Order", "outer");
+                response
+            })
+            .with_fn(|request, next| {
+                //Short-circuit without calling the handler
+                let user = match request.headers.get("Authorization") {
+                    Some(user) => user.to_string(),
+                    None => return HttpResponse::error(401),
+                };
+                request.extensions.insert(User(user));
+                let mut response = next.run(request);
+                response.headers.append("X-Order", "inner");
+                response
+            })
+            .with(Timing::new());
+        let handler = chain.into_handler(handler);
+
+        let mut authorized = request("GET / HTTP/1.1\r\nAuthorization: alice\r\n\r\n");
+        let response = handler(&mut authorized);
+        assert_eq!(response.body, b"hello alice");
+        assert_eq!(response.headers.get_all("X-Order"), vec!["inner", "outer"]);
+        assert!(response.headers.get("Server-Timing").unwrap().starts_with("app;dur="));
+        assert!(authorized.extensions.contains::<RequestDuration>());
+        assert_eq!(authorized.extensions.get::<User>(), Some(&User("alice".to_string())));
+
+        let response = handler(&mut request("GET / HTTP/1.1\r\n\r\n"));
+        assert_eq!(response.status, 401);
+        assert!(response.headers.get("Server-Timing").is_none());
+    }
+
+    #[test]
+    fn replaces_and_removes_extensions() {
+        let mut extensions = Extensions::new();
+        assert_eq!(extensions.insert(User("a".to_string())), None);
+        assert_eq!(extensions.insert(User("b".to_string())), Some(User("a".to_string())));
+        extensions.get_mut::<User>().unwrap().0.push('!');
+        assert_eq!(extensions.remove::<User>(), Some(User("b!".to_string())));
+        assert!(!extensions.contains::<User>());
+    }
+}
@@ -0,0 +1,469 @@
+use std::str::Utf8Error;
+
+use crate::models::structs::http_error::HttpError;
+use crate::models::structs::http_form::parse_parameters;
+use crate::models::structs::http_headers::HeaderMap;
+use crate::models::structs::http_message::StreamedBody;
+use crate::models::structs::http_target::percent_decode;
+
+#[derive(Clone, Debug)]
+pub struct MultipartLimits {
+    pub max_parts: usize,
+    // Header section of one part
+    pub max_part_header_size: usize,
+    // Content of a part without filename, e.g. a text input
+    pub max_field_size: usize,
+    // Content of a part with a filename
+    pub max_file_size: usize,
+}
+
+impl Default for MultipartLimits {
+    fn default() -> Self {
+        MultipartLimits {
+            max_parts: 100,
+            max_part_header_size: 8 * 1024,
+            max_field_size: 64 * 1024,
+            max_file_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+// Header section of a part, name and filename come from Content-Disposition
+#[derive(Clone, Debug)]
+pub struct PartHead {
+    pub headers: HeaderMap,
+    pub name: Option<String>,
+    pub filename: Option<String>,
+}
+
+impl PartHead {
+    fn new(headers: HeaderMap) -> Self {
+        let (name, filename) = match headers.get("Content-Disposition") {
+            Some(disposition) => parse_content_disposition(disposition),
+            None => (None, None),
+        };
+
+        PartHead {
+            headers,
+            name,
+            filename,
+        }
+    }
+
+    // text/plain when not given (RFC 7578 4.4)
+    pub fn content_type(&self) -> &str {
+        self.headers.get("Content-Type").unwrap_or("text/plain")
+    }
+}
+
+// A whole part, see MultipartParser to handle the content as it comes
+#[derive(Clone, Debug)]
+pub struct MultipartPart {
+    pub head: PartHead,
+    pub data: Vec<u8>,
+}
+
+impl MultipartPart {
+    pub fn name(&self) -> Option<&str> {
+        self.head.name.as_deref()
+    }
+
+    pub fn filename(&self) -> Option<&str> {
+        self.head.filename.as_deref()
+    }
+
+    // Content as UTF-8 text, fails for binary content
+    pub fn text(&self) -> Result<&str, Utf8Error> {
+        std::str::from_utf8(&self.data)
+    }
+}
+
+#[derive(Debug)]
+pub enum MultipartEvent {
+    // Start of a part
+    Part(PartHead),
+    // Next bytes of the current part's content
+    Data(Vec<u8>),
+    // End of the current part
+    PartEnd,
+    // Close delimiter reached, the epilogue is ignored
+    End,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Start,
+    Preamble,
+    // Just after a delimiter, in_part when it ends a part
+    Delimiter { in_part: bool },
+    Headers,
+    Body,
+    Done,
+}
+
+// Incremental multipart/form-data parser (RFC 7578, RFC 2046 5.1), does no I/O
+// The content of a part is handed out as it is found, only a delimiter's length is kept back
+pub struct MultipartParser {
+    // "\r\n--" followed by the boundary
+    delimiter: Vec<u8>,
+    limits: MultipartLimits,
+    state: State,
+    nb_parts: usize,
+    // Content size and limit of the current part
+    part_size: usize,
+    max_part_size: usize,
+}
+
+impl MultipartParser {
+    pub fn new(boundary: &str, limits: &MultipartLimits) -> Result<Self, HttpError> {
+        //1 to 70 characters (RFC 2046 5.1.1)
+        if boundary.is_empty() || boundary.len() > 70 || !boundary.is_ascii() {
+            return Err(HttpError::Malformed(format!("Invalid multipart boundary {}", boundary)));
+        }
+
+        Ok(MultipartParser {
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            limits: limits.clone(),
+            state: State::Start,
+            nb_parts: 0,
+            part_size: 0,
+            max_part_size: 0,
+        })
+    }
+
+    // Consume what buf allows and return the next event, None when more bytes are needed
+    // Bytes not consumed are left at the start of buf
+    pub fn parse(&mut self, buf: &mut Vec<u8>) -> Result<Option<MultipartEvent>, HttpError> {
+        loop {
+            match self.state {
+                State::Start => {
+                    //The first delimiter has no CRLF before it, add one so every delimiter looks the same
+                    buf.splice(0..0, b"\r\n".iter().copied());
+                    self.state = State::Preamble;
+                },
+                State::Preamble => {
+                    match find(buf, &self.delimiter) {
+                        Some(index) => {
+                            buf.drain(..index + self.delimiter.len());
+                            self.state = State::Delimiter { in_part: false };
+                        },
+                        None => {
+                            //Keep what may be the start of a delimiter
+                            let keep = buf.len().min(self.delimiter.len() - 1);
+                            buf.drain(..buf.len() - keep);
+                            return Ok(None)
+                        }
+                    }
+                },
+                State::Delimiter { in_part } => {
+                    if in_part {
+                        self.state = State::Delimiter { in_part: false };
+                        return Ok(Some(MultipartEvent::PartEnd))
+                    }
+
+                    if buf.len() < 2 {
+                        return Ok(None)
+                    }
+                    if buf.starts_with(b"--") {
+                        buf.clear();
+                        self.state = State::Done;
+                        return Ok(Some(MultipartEvent::End))
+                    }
+
+                    //Transport padding may follow the delimiter
+                    let padding = buf.iter().take_while(|byte| **byte == b' ' || **byte == b'\t').count();
+                    if buf.len() < padding + 2 {
+                        if padding > self.limits.max_part_header_size {
+                            return Err(HttpError::Malformed("Invalid multipart delimiter".to_string()));
+                        }
+                        return Ok(None)
+                    }
+                    if &buf[padding..padding + 2] != b"\r\n" {
+                        return Err(HttpError::Malformed("Invalid multipart delimiter".to_string()));
+                    }
+                    buf.drain(..padding + 2);
+                    self.state = State::Headers;
+                },
+                State::Headers => {
+                    //No header at all is allowed, the section is then a lone CRLF
+                    let end = if buf.starts_with(b"\r\n") { Some(0) } else { find(buf, b"\r\n\r\n").map(|index| index + 2) };
+                    let end = match end {
+                        Some(end) => end,
+                        None => {
+                            if buf.len() > self.limits.max_part_header_size {
+                                return Err(HttpError::PayloadTooLarge);
+                            }
+                            return Ok(None)
+                        }
+                    };
+                    if end > self.limits.max_part_header_size {
+                        return Err(HttpError::PayloadTooLarge);
+                    }
+
+                    let head = self.parse_part_head(&buf[..end])?;
+                    buf.drain(..end + 2);
+
+                    self.nb_parts += 1;
+                    if self.nb_parts > self.limits.max_parts {
+                        return Err(HttpError::PayloadTooLarge);
+                    }
+                    self.part_size = 0;
+                    self.max_part_size = if head.filename.is_some() { self.limits.max_file_size } else { self.limits.max_field_size };
+                    self.state = State::Body;
+                    return Ok(Some(MultipartEvent::Part(head)))
+                },
+                State::Body => {
+                    let (length, delimiter_found) = match find(buf, &self.delimiter) {
+                        Some(index) => (index, true),
+                        None => (buf.len().saturating_sub(self.delimiter.len() - 1), false),
+                    };
+
+                    self.part_size += length;
+                    if self.part_size > self.max_part_size {
+                        return Err(HttpError::PayloadTooLarge);
+                    }
+
+                    let data: Vec<u8> = buf.drain(..length).collect();
+                    if delimiter_found {
+                        buf.drain(..self.delimiter.len());
+                        self.state = State::Delimiter { in_part: true };
+                    }
+
+                    if !data.is_empty() {
+                        return Ok(Some(MultipartEvent::Data(data)))
+                    }
+                    if !delimiter_found {
+                        return Ok(None)
+                    }
+                },
+                State::Done => {
+                    buf.clear();
+                    return Ok(None)
+                },
+            }
+        }
+    }
+
+    // Is the close delimiter reached
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    fn parse_part_head(&self, section: &[u8]) -> Result<PartHead, HttpError> {
+        //Field values may hold UTF-8 filenames (RFC 7578 4.2)
+        let section = match std::str::from_utf8(section) {
+            Ok(section) => section,
+            Err(_) => return Err(HttpError::Malformed("Part header section is not UTF-8".to_string())),
+        };
+
+        let mut headers = HeaderMap::new();
+        for line in section.split("\r\n").filter(|line| !line.is_empty()) {
+            let (name, value) = HeaderMap::parse_field(line)?;
+            headers.append(&name, &value);
+        }
+
+        Ok(PartHead::new(headers))
+    }
+}
+
+// Events of a multipart body, from the bytes already received then from the rest of a streamed body
+pub struct MultipartStream<'a> {
+    parser: MultipartParser,
+    buf: Vec<u8>,
+    streamed_body: Option<&'a StreamedBody>,
+    finished: bool,
+}
+
+impl<'a> MultipartStream<'a> {
+    pub fn new(boundary: &str, limits: &MultipartLimits, body: Vec<u8>, streamed_body: Option<&'a StreamedBody>) -> Result<Self, HttpError> {
+        Ok(MultipartStream {
+            parser: MultipartParser::new(boundary, limits)?,
+            buf: body,
+            streamed_body,
+            finished: false,
+        })
+    }
+
+    fn next_event(&mut self) -> Result<Option<MultipartEvent>, HttpError> {
+        loop {
+            if let Some(event) = self.parser.parse(&mut self.buf)? {
+                return Ok(Some(event));
+            }
+            if self.parser.is_done() {
+                return Ok(None);
+            }
+
+            let chunk = match self.streamed_body {
+                Some(streamed_body) => streamed_body.next_chunk()?,
+                None => None,
+            };
+            match chunk {
+                Some(chunk) => self.buf.extend_from_slice(&chunk),
+                None => return Err(HttpError::Malformed("Multipart body without close delimiter".to_string())),
+            }
+        }
+    }
+
+    // Gather the parts in memory
+    pub fn collect_parts(self) -> Result<Vec<MultipartPart>, HttpError> {
+        let mut parts: Vec<MultipartPart> = Vec::new();
+        for event in self {
+            match event? {
+                MultipartEvent::Part(head) => parts.push(MultipartPart { head, data: Vec::new() }),
+                MultipartEvent::Data(data) => {
+                    if let Some(part) = parts.last_mut() {
+                        part.data.extend_from_slice(&data);
+                    }
+                },
+                MultipartEvent::PartEnd | MultipartEvent::End => {},
+            }
+        }
+        Ok(parts)
+    }
+}
+
+impl Iterator for MultipartStream<'_> {
+    type Item = Result<MultipartEvent, HttpError>;
+
+    // Ends after End or the first error
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let event = self.next_event();
+        if matches!(event, Ok(Some(MultipartEvent::End)) | Ok(None) | Err(_)) {
+            self.finished = true;
+        }
+        event.transpose()
+    }
+}
+
+// Parse a whole multipart/form-data body
+pub fn parse_multipart(body: &[u8], boundary: &str, limits: &MultipartLimits) -> Result<Vec<MultipartPart>, HttpError> {
+    MultipartStream::new(boundary, limits, body.to_vec(), None)?.collect_parts()
+}
+
+// name and filename parameters of a Content-Disposition value,
+// filename* (RFC 8187) is preferred over filename when both are sent
+fn parse_content_disposition(disposition: &str) -> (Option<String>, Option<String>) {
+    let mut name: Option<String> = None;
+    let mut filename: Option<String> = None;
+    let mut extended_filename: Option<String> = None;
+
+    for (parameter_name, value) in parse_parameters(disposition) {
+        match parameter_name.to_ascii_lowercase().as_str() {
+            "name" => name = Some(value),
+            "filename" => filename = Some(value),
+            //charset'language'percent-encoded value, only UTF-8 is supported
+            "filename*" => {
+                if let Some((charset, encoded)) = value.split_once('\'').and_then(|(charset, rest)| Some((charset, rest.split_once('\'')?.1))) {
+                    if charset.eq_ignore_ascii_case("utf-8") {
+                        extended_filename = percent_decode(encoded).and_then(|decoded| String::from_utf8(decoded).ok());
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+
+    (name, extended_filename.or(filename))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"preamble\r\n--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nMy stream\r\n--XyZ  \r\nContent-Disposition: form-data; name=\"overlay\"; filename=\"logo \\\"v2\\\".png\"\r\nContent-Type: image/png\r\n\r\n\x89PNG\r\n--Xy\r\n\r\n--XyZ--\r\nepilogue";
+
+    #[test]
+    fn parses_whole_body() {
+        let parts = parse_multipart(BODY, "XyZ", &MultipartLimits::default()).unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name(), Some("title"));
+        assert_eq!(parts[0].filename(), None);
+        assert_eq!(parts[0].head.content_type(), "text/plain");
+        assert_eq!(parts[0].text(), Ok("My stream"));
+        assert_eq!(parts[1].name(), Some("overlay"));
+        assert_eq!(parts[1].filename(), Some("logo \"v2\".png"));
+        assert_eq!(parts[1].head.content_type(), "image/png");
+        assert_eq!(parts[1].data, b"\x89PNG\r\n--Xy\r\n");
+    }
+
+    #[test]
+    fn parses_byte_by_byte() {
+        let mut parser = MultipartParser::new("XyZ", &MultipartLimits::default()).unwrap();
+        let mut buf: Vec<u8> = Vec::new();
+        let mut data: Vec<u8> = Vec::new();
+        let mut events: Vec<String> = Vec::new();
+
+        for byte in BODY {
+            buf.push(*byte);
+            while let Some(event) = parser.parse(&mut buf).unwrap() {
+                match event {
+                    MultipartEvent::Part(head) => events.push(format!("part {:?}", head.name)),
+                    MultipartEvent::Data(chunk) => data.extend_from_slice(&chunk),
+                    MultipartEvent::PartEnd => events.push("end".to_string()),
+                    MultipartEvent::End => events.push("done".to_string()),
+                }
+            }
+        }
+
+        assert_eq!(events, vec!["part Some(\"title\")", "end", "part Some(\"overlay\")", "end", "done"]);
+        assert_eq!(data, b"My stream\x89PNG\r\n--Xy\r\n");
+        assert!(parser.is_done());
+    }
+
+    #[test]
+    fn reads_streamed_bodies() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        for chunk in BODY.chunks(7) {
+            sender.send(Ok(chunk.to_vec())).unwrap();
+        }
+        drop(sender);
+        let streamed_body = StreamedBody::new(receiver, None);
+        let mut stream = MultipartStream::new("XyZ", &MultipartLimits::default(), b"pre".to_vec(), Some(&streamed_body)).unwrap();
+        //The bytes received with the head come first
+        assert!(matches!(stream.next(), Some(Ok(MultipartEvent::Part(head))) if head.name.as_deref() == Some("title")));
+        let parts = stream.collect_parts().unwrap();
+        assert_eq!(parts[0].data, b"\x89PNG\r\n--Xy\r\n");
+
+        //A body ending early, or failing to be received, ends the stream with the error
+        let (sender, receiver) = std::sync::mpsc::channel();
+        sender.send(Ok(BODY[..70].to_vec())).unwrap();
+        sender.send(Err(HttpError::Timeout)).unwrap();
+        let streamed_body = StreamedBody::new(receiver, None);
+        let mut stream = MultipartStream::new("XyZ", &MultipartLimits::default(), Vec::new(), Some(&streamed_body)).unwrap();
+        assert!(matches!(stream.next(), Some(Ok(MultipartEvent::Part(_)))));
+        assert!(matches!(stream.find(|event| event.is_err()), Some(Err(HttpError::Timeout))));
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let limits = MultipartLimits { max_field_size: 4, ..MultipartLimits::default() };
+        assert!(matches!(parse_multipart(BODY, "XyZ", &limits), Err(HttpError::PayloadTooLarge)));
+
+        let limits = MultipartLimits { max_parts: 1, ..MultipartLimits::default() };
+        assert!(matches!(parse_multipart(BODY, "XyZ", &limits), Err(HttpError::PayloadTooLarge)));
+
+        let limits = MultipartLimits { max_part_header_size: 16, ..MultipartLimits::default() };
+        assert!(matches!(parse_multipart(BODY, "XyZ", &limits), Err(HttpError::PayloadTooLarge)));
+    }
+
+    #[test]
+    fn rejects_truncated_body() {
+        let truncated = &BODY[..BODY.len() - 20];
+        assert!(matches!(parse_multipart(truncated, "XyZ", &MultipartLimits::default()), Err(HttpError::Malformed(_))));
+    }
+
+    #[test]
+    fn prefers_extended_filename() {
+        let (name, filename) = parse_content_disposition("form-data; name=config; filename=\"a.json\"; filename*=UTF-8''r%C3%A9glages.json");
+        assert_eq!(name.as_deref(), Some("config"));
+        assert_eq!(filename.as_deref(), Some("réglages.json"));
+    }
+}
@@ -0,0 +1,683 @@
+use crate::models::structs::http_chunked::ChunkedDecoder;
+use crate::models::structs::http_error::HttpError;
+use crate::models::structs::http_form::media_type;
+use crate::models::structs::http_headers::HeaderMap;
+use crate::models::structs::http_message::{HttpLimits, HttpMessage};
+use crate::models::structs::http_method::Method;
+use crate::models::structs::http_middleware::Extensions;
+use crate::models::structs::http_response::HttpResponse;
+use crate::models::structs::http_target::RequestTarget;
+use crate::models::structs::http_version::Version;
+
+const CRLF: &[u8; 2] = b"\r\n";
+// Body bytes reserved upfront, the rest grows with what is received so a Content-Length alone costs no memory
+const INITIAL_BODY_CAPACITY: usize = 64 * 1024;
+
+// Request bodies returned to the caller at the end of the head instead of whole, see HttpParser::with_streamed_bodies
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamedBodies {
+    None,
+    // multipart/form-data bodies, read with HttpMessage::multipart_stream
+    Multipart,
+    // Every body, e.g. relayed by a reverse proxy as it is received
+    All,
+}
+
+// Framing of a body left to the caller, see HttpParser::take_streamed_body
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamedLength {
+    Fixed(usize),
+    // Decoded as it is received, its trailers are dropped
+    Chunked,
+}
+
+// First line of a message (RFC 9112 2.1)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StartLine {
+    // Format : method SP request-target SP HTTP-version
+    Request { method: Method, target: RequestTarget, version: Version },
+    // Format : HTTP-version SP status-code SP [ reason-phrase ]
+    Status { version: Version, status: u16, reason: String },
+}
+
+impl StartLine {
+    // A line starting with the HTTP-version is a status-line, any other a request-line
+    pub fn parse(line: &str) -> Result<Self, HttpError> {
+        if line.starts_with("HTTP/") {
+            return Self::parse_status_line(line);
+        }
+
+        let mut parts = line.split(' ');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version), None) => Ok(StartLine::Request {
+                method: Method::parse(method)?,
+                target: RequestTarget::parse(target)?,
+                version: Version::parse(version)?,
+            }),
+            _ => Err(HttpError::Malformed(format!("Invalid request-line {}", line)))
+        }
+    }
+
+    fn parse_status_line(line: &str) -> Result<Self, HttpError> {
+        let invalid = || HttpError::Malformed(format!("Invalid status-line {}", line));
+        let mut parts = line.splitn(3, ' ');
+        let version = Version::parse(parts.next().unwrap_or_default())?;
+        let status = parts.next().unwrap_or_default();
+        if status.len() != 3 || !status.bytes().all(|char| char.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let status: u16 = status.parse().map_err(|_| invalid())?;
+        if !(100..600).contains(&status) {
+            return Err(invalid());
+        }
+
+        Ok(StartLine::Status {
+            version,
+            status,
+            //The reason phrase is optional, some servers also omit the space before it
+            reason: parts.next().unwrap_or_default().to_string(),
+        })
+    }
+}
+
+// Start-line and header fields of the message being parsed
+struct MessageHead {
+    start_line: StartLine,
+    headers: HeaderMap,
+}
+
+// How the end of the body is known (RFC 9112 6.3)
+enum BodyLength {
+    Fixed(usize),
+    Chunked,
+    // Response ending with the connection
+    UntilClose,
+}
+
+enum ParserState {
+    // Waiting for the end of the header section, bytes before scanned hold no CRLF CRLF
+    Head { scanned: usize },
+    // Content-Length body, remaining bytes to receive
+    Body { head: MessageHead, body: Vec<u8>, remaining: usize },
+    Chunked { head: MessageHead, decoder: ChunkedDecoder },
+    UntilClose { head: MessageHead, body: Vec<u8> },
+}
+
+// Message parser over byte buffers, does no I/O so it can be driven by blocking or async streams
+// Usage : append received bytes to buf and call parse (requests) or parse_response until it returns a message
+pub struct HttpParser {
+    limits: HttpLimits,
+    state: ParserState,
+    // Method of the request answered when parsing responses, None when parsing requests
+    request_method: Option<Method>,
+    streamed_bodies: StreamedBodies,
+    // Framing of the body left to the caller by the last request parsed
+    streamed_body: Option<StreamedLength>,
+}
+
+impl HttpParser {
+    pub fn new(limits: &HttpLimits) -> Self {
+        HttpParser {
+            limits: limits.clone(),
+            state: ParserState::Head { scanned: 0 },
+            request_method: None,
+            streamed_bodies: StreamedBodies::None,
+            streamed_body: None,
+        }
+    }
+
+    // A request whose body is streamed is returned at the end of its head, with an empty body
+    // Its body is left to the caller, see take_streamed_body, and is limited by max_streamed_body_size
+    pub fn with_streamed_bodies(mut self, streamed_bodies: StreamedBodies) -> Self {
+        self.streamed_bodies = streamed_bodies;
+        self
+    }
+
+    // Framing of the body still to read after the request just returned, None when the body came with it
+    pub fn take_streamed_body(&mut self) -> Option<StreamedLength> {
+        self.streamed_body.take()
+    }
+
+    // Parser for the responses to a request sent with request_method, e.g. a HEAD response has no body
+    pub fn for_response(limits: &HttpLimits, request_method: &Method) -> Self {
+        HttpParser {
+            request_method: Some(request_method.clone()),
+            ..Self::new(limits)
+        }
+    }
+
+    // Consume the bytes of the request at the start of buf, None when more bytes are needed
+    // Bytes after a complete request (pipelined requests) are left in buf, the parser is then ready for the next one
+    // Limits are checked as bytes arrive, an oversized request fails before it is fully received
+    pub fn parse(&mut self, buf: &mut Vec<u8>) -> Result<Option<HttpMessage>, HttpError> {
+        match self.parse_message(buf, false)? {
+            Some((head, body, trailers)) => Self::build_message(head, body, trailers).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Start-line and header fields while the body is being received, None before the end of the head
+    pub fn head(&self) -> Option<(&StartLine, &HeaderMap)> {
+        match &self.state {
+            ParserState::Head { .. } => None,
+            ParserState::Body { head, .. } | ParserState::Chunked { head, .. } | ParserState::UntilClose { head, .. } => {
+                Some((&head.start_line, &head.headers))
+            },
+        }
+    }
+
+    // Same as parse for a response, closed tells the peer closed the connection
+    // which ends a body without Content-Length nor chunked encoding
+    // Interim 1xx responses are returned like the others
+    pub fn parse_response(&mut self, buf: &mut Vec<u8>, closed: bool) -> Result<Option<HttpResponse>, HttpError> {
+        match self.parse_message(buf, closed)? {
+            Some((head, body, _)) => Self::build_response(head, body).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn parse_message(&mut self, buf: &mut Vec<u8>, closed: bool) -> Result<Option<(MessageHead, Vec<u8>, HeaderMap)>, HttpError> {
+        loop {
+            match std::mem::replace(&mut self.state, ParserState::Head { scanned: 0 }) {
+                ParserState::Head { scanned } => {
+                    let head_end = match self.find_head_end(buf, scanned)? {
+                        Ok(head_end) => head_end,
+                        Err(scanned) => {
+                            self.state = ParserState::Head { scanned };
+                            return Ok(None);
+                        }
+                    };
+
+                    let head = self.parse_head(&buf[..head_end])?;
+                    buf.drain(..head_end);
+
+                    //According to parse info
+                    //Parse body for BODY_LENGTH given in header information, or chunk by chunk
+                    self.state = match self.body_length(&head)? {
+                        BodyLength::Chunked if self.is_streamed(&head) => {
+                            self.streamed_body = Some(StreamedLength::Chunked);
+                            return Ok(Some((head, Vec::new(), HeaderMap::new())));
+                        },
+                        BodyLength::Chunked => ParserState::Chunked { head, decoder: ChunkedDecoder::new() },
+                        BodyLength::UntilClose => ParserState::UntilClose { head, body: Vec::new() },
+                        BodyLength::Fixed(body_length) if body_length > 0 && self.is_streamed(&head) => {
+                            if body_length > self.limits.max_streamed_body_size {
+                                return Err(HttpError::PayloadTooLarge);
+                            }
+                            self.streamed_body = Some(StreamedLength::Fixed(body_length));
+                            return Ok(Some((head, Vec::new(), HeaderMap::new())));
+                        },
+                        BodyLength::Fixed(body_length) => {
+                            if body_length > self.limits.max_body_size {
+                                return Err(HttpError::PayloadTooLarge);
+                            }
+                            ParserState::Body { head, body: Vec::with_capacity(body_length.min(INITIAL_BODY_CAPACITY)), remaining: body_length }
+                        },
+                    };
+                },
+                ParserState::Body { head, mut body, remaining } => {
+                    let available = remaining.min(buf.len());
+                    body.extend(buf.drain(..available));
+                    if available < remaining {
+                        self.state = ParserState::Body { head, body, remaining: remaining - available };
+                        return Ok(None);
+                    }
+                    return Ok(Some((head, body, HeaderMap::new())));
+                },
+                ParserState::Chunked { head, mut decoder } => {
+                    match decoder.decode(buf, &self.limits)? {
+                        Some((body, trailers)) => return Ok(Some((head, body, trailers))),
+                        None => {
+                            self.state = ParserState::Chunked { head, decoder };
+                            return Ok(None);
+                        }
+                    }
+                },
+                ParserState::UntilClose { head, mut body } => {
+                    if body.len() + buf.len() > self.limits.max_body_size {
+                        return Err(HttpError::PayloadTooLarge);
+                    }
+                    body.append(buf);
+                    if !closed {
+                        self.state = ParserState::UntilClose { head, body };
+                        return Ok(None);
+                    }
+                    return Ok(Some((head, body, HeaderMap::new())));
+                },
+            }
+        }
+    }
+
+    fn build_message(head: MessageHead, body: Vec<u8>, trailers: HeaderMap) -> Result<HttpMessage, HttpError> {
+        match head.start_line {
+            StartLine::Request { method, target, version } => Ok(HttpMessage {
+                method,
+                target,
+                version,
+                headers: head.headers,
+                body,
+                trailers,
+                extensions: Extensions::new(),
+                peer_addr: None,
+            }),
+            StartLine::Status { .. } => Err(HttpError::Malformed("Status-line where a request-line is expected".to_string())),
+        }
+    }
+
+    fn build_response(head: MessageHead, body: Vec<u8>) -> Result<HttpResponse, HttpError> {
+        let (version, status, reason) = match head.start_line {
+            StartLine::Status { version, status, reason } => (version, status, reason),
+            StartLine::Request { .. } => return Err(HttpError::Malformed("Request-line where a status-line is expected".to_string())),
+        };
+
+        let mut response = HttpResponse::new(status);
+        response.version = version;
+        response.reason = reason;
+        response.keep_alive = match version {
+            Version::Http10 => head.headers.contains_token("Connection", "keep-alive"),
+            _ => !head.headers.contains_token("Connection", "close"),
+        };
+        response.headers = head.headers;
+        response.body = body;
+        Ok(response)
+    }
+
+    // Body framing of a request, or of a response to request_method
+    fn body_length(&self, head: &MessageHead) -> Result<BodyLength, HttpError> {
+        let status = match (&head.start_line, &self.request_method) {
+            (StartLine::Request { .. }, None) if Self::is_chunked(&head.headers)? => return Ok(BodyLength::Chunked),
+            (StartLine::Request { .. }, None) => return Ok(BodyLength::Fixed(Self::parse_content_length(&head.headers)?)),
+            (StartLine::Status { .. }, Some(Method::Head)) => return Ok(BodyLength::Fixed(0)),
+            (StartLine::Status { status, .. }, Some(_)) => *status,
+            (StartLine::Status { .. }, None) => return Err(HttpError::Malformed("Status-line where a request-line is expected".to_string())),
+            (StartLine::Request { .. }, Some(_)) => return Err(HttpError::Malformed("Request-line where a status-line is expected".to_string())),
+        };
+
+        //No body for 1xx, 204 and 304 whatever the fields say
+        if status < 200 || status == 204 || status == 304 {
+            return Ok(BodyLength::Fixed(0));
+        }
+        //Transfer-Encoding wins over Content-Length, any coding but a final chunked ends with the connection
+        let codings = head.headers.get_list("Transfer-Encoding");
+        if let Some(last_coding) = codings.last() {
+            return Ok(if last_coding.eq_ignore_ascii_case("chunked") { BodyLength::Chunked } else { BodyLength::UntilClose });
+        }
+        if !head.headers.contains("Content-Length") {
+            return Ok(BodyLength::UntilClose);
+        }
+        Ok(BodyLength::Fixed(Self::parse_content_length(&head.headers)?))
+    }
+
+    // Index right after CRLF CRLF, or the index to resume scanning from once more bytes are received
+    fn find_head_end(&self, buf: &mut Vec<u8>, mut scanned: usize) -> Result<Result<usize, usize>, HttpError> {
+        let limits = &self.limits;
+        let max_head_size = limits.max_request_line + limits.max_headers * (limits.max_header_size + CRLF.len()) + 2 * CRLF.len();
+
+        //Empty lines before the request-line are ignored (RFC 9112 2.2)
+        while buf.starts_with(CRLF) {
+            buf.drain(..CRLF.len());
+            scanned = 0;
+        }
+
+        if let Some(position) = buf[scanned.min(buf.len())..].windows(4).position(|window| window == b"\r\n\r\n") {
+            let head_end = scanned + position + 4;
+            Self::check_request_line(&buf[..head_end], limits)?;
+            return Ok(Ok(head_end));
+        }
+
+        Self::check_request_line(buf, limits)?;
+        if buf.len() > max_head_size {
+            return Err(HttpError::HeaderTooLarge);
+        }
+        Ok(Err(buf.len().saturating_sub(3)))
+    }
+
+    fn parse_head(&self, head: &[u8]) -> Result<MessageHead, HttpError> {
+        //Parse the first line IS Request-line or Status-line
+        //Until CRLF
+        let mut lines = Self::split_crlf(&head[..head.len() - 2 * CRLF.len()])?.into_iter();
+        let request_line_bytes = lines.next().unwrap_or_default();
+        //Checking encoding US-ASCII
+        if !request_line_bytes.iter().all(Self::is_usascii_byte) {
+            return Err(HttpError::InvalidEncoding);
+        }
+        let request_line = Self::byte_vec_to_string(request_line_bytes.to_vec());
+        let start_line = StartLine::parse(&request_line)?;
+
+        //Parse X header
+        //Format : Something CRLF
+        let mut headers = HeaderMap::new();
+        for line in lines {
+            if line.len() > self.limits.max_header_size {
+                return Err(HttpError::HeaderTooLarge);
+            }
+            if headers.len() == self.limits.max_headers {
+                return Err(HttpError::TooManyHeaders);
+            }
+            //Checking encoding US-ASCII for the name, values may hold opaque obs-text bytes
+            let name_length = line.iter().position(|byte| *byte == b':').unwrap_or(line.len());
+            if !line[..name_length].iter().all(Self::is_usascii_byte) {
+                return Err(HttpError::InvalidEncoding);
+            }
+            let (name, value) = HeaderMap::parse_field(&Self::byte_vec_to_string(line.to_vec()))?;
+            headers.append(&name, &value);
+        }
+
+        Ok(MessageHead {
+            start_line,
+            headers,
+        })
+    }
+
+    fn check_request_line(buf: &[u8], limits: &HttpLimits) -> Result<(), HttpError> {
+        let request_line_length = buf
+            .windows(CRLF.len())
+            .position(|window| window == CRLF)
+            .unwrap_or(buf.len());
+
+        if request_line_length > limits.max_request_line {
+            return Err(HttpError::RequestLineTooLong);
+        }
+        Ok(())
+    }
+
+    // Request body handed over undecoded, a Content-Encoding or a protocol switch needs the whole body
+    fn is_streamed(&self, head: &MessageHead) -> bool {
+        let streamed = match self.streamed_bodies {
+            StreamedBodies::None => false,
+            StreamedBodies::Multipart => media_type(head.headers.get("Content-Type").unwrap_or_default()) == "multipart/form-data",
+            StreamedBodies::All => true,
+        };
+        streamed
+            && self.request_method.is_none()
+            && matches!(head.start_line, StartLine::Request { .. })
+            && !head.headers.contains("Content-Encoding")
+            && !head.headers.contains("Upgrade")
+    }
+
+    // Transfer-Encoding wins over Content-Length, chunked must be the final coding (RFC 9112 6.1)
+    fn is_chunked(headers: &HeaderMap) -> Result<bool, HttpError> {
+        let codings = headers.get_list("Transfer-Encoding");
+        if codings.is_empty() {
+            return Ok(false);
+        }
+
+        //Both fields is a request smuggling attempt or a broken client
+        if headers.contains("Content-Length") {
+            return Err(HttpError::Malformed("Both Transfer-Encoding and Content-Length".to_string()));
+        }
+        if !codings.last().is_some_and(|coding| coding.eq_ignore_ascii_case("chunked")) {
+            return Err(HttpError::Malformed("Chunked is not the final transfer coding".to_string()));
+        }
+        if let Some(coding) = codings.iter().rev().nth(1) {
+            return Err(HttpError::UnsupportedTransferCoding(coding.to_string()));
+        }
+
+        Ok(true)
+    }
+
+    fn parse_content_length(headers: &HeaderMap) -> Result<usize, HttpError> {
+        let mut body_length: Option<usize> = None;
+        for value in headers.get_list("Content-Length") {
+            if !value.bytes().all(|char| char.is_ascii_digit()) {
+                return Err(HttpError::Malformed("Invalid Content-Length".to_string()));
+            }
+            let length: usize = value.parse().map_err(|_| HttpError::PayloadTooLarge)?;
+
+            //Several Content-Length values must agree
+            if body_length.is_some_and(|previous| previous != length) {
+                return Err(HttpError::Malformed("Conflicting Content-Length".to_string()));
+            }
+            body_length = Some(length);
+        }
+
+        Ok(body_length.unwrap_or(0))
+    }
+
+    // Split the header section on CRLF, a lone CR or LF is refused
+    fn split_crlf(head: &[u8]) -> Result<Vec<&[u8]>, HttpError> {
+        let mut lines: Vec<&[u8]> = Vec::new();
+        let mut line_start: usize = 0;
+        let mut i: usize = 0;
+        while i < head.len() {
+            if head[i] == CRLF[0] && head.get(i + 1) == Some(&CRLF[1]) {
+                lines.push(&head[line_start..i]);
+                i += CRLF.len();
+                line_start = i;
+                continue;
+            }
+            if head[i] == CRLF[0] || head[i] == CRLF[1] {
+                return Err(HttpError::Malformed("Bare CR or LF in header section".to_string()));
+            }
+            i += 1;
+        }
+        lines.push(&head[line_start..]);
+
+        Ok(lines)
+    }
+
+    fn is_usascii_byte(byte: &u8) -> bool {
+        *byte < 127
+    }
+
+    fn byte_vec_to_string(vec: Vec<u8>) -> String {
+        String::from_utf8_lossy(&vec).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(bytes: &[u8]) -> Result<Option<HttpMessage>, HttpError> {
+        let mut buf = bytes.to_vec();
+        HttpParser::new(&HttpLimits::default()).parse(&mut buf)
+    }
+
+    fn parse_error(bytes: &[u8]) -> HttpError {
+        match parse_all(bytes) {
+            Err(err) => err,
+            Ok(Some(_)) => panic!("request parsed, error expected"),
+            Ok(None) => panic!("request incomplete, error expected"),
+        }
+    }
+
+    #[test]
+    fn parses_simple_get() {
+        let request = parse_all(b"GET /index.html?lang=en HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\n").unwrap().unwrap();
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.target.path, "/index.html");
+        assert_eq!(request.target.query.as_deref(), Some("lang=en"));
+        assert_eq!(request.version, Version::Http11);
+        assert_eq!(request.host(), Some("example.com"));
+        assert_eq!(request.headers.get("accept"), Some("*/*"));
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn parses_content_length_body() {
+        let request = parse_all(b"POST /submit HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello world").unwrap().unwrap();
+        assert_eq!(request.method, Method::Post);
+        assert_eq!(request.body_text(), Ok("hello world"));
+    }
+
+    #[test]
+    fn keeps_binary_body() {
+        let request = parse_all(b"PUT /blob HTTP/1.1\r\nContent-Length: 4\r\n\r\n\x00\xff\xfe\x01").unwrap().unwrap();
+        assert_eq!(request.body, vec![0x00, 0xff, 0xfe, 0x01]);
+        assert!(request.body_text().is_err());
+    }
+
+    #[test]
+    fn parses_chunked_body_with_trailers() {
+        let request = parse_all(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nChecksum: abc\r\n\r\n").unwrap().unwrap();
+        assert_eq!(request.body_text(), Ok("hello world"));
+        assert_eq!(request.trailers.get("checksum"), Some("abc"));
+    }
+
+    #[test]
+    fn ignores_leading_empty_lines() {
+        let request = parse_all(b"\r\n\r\nGET / HTTP/1.0\r\n\r\n").unwrap().unwrap();
+        assert_eq!(request.version, Version::Http10);
+        assert!(!request.keep_alive());
+    }
+
+    #[test]
+    fn accepts_obs_text_in_values() {
+        let request = parse_all(b"GET / HTTP/1.1\r\nX-Name: caf\xe9\r\n\r\n").unwrap().unwrap();
+        assert!(request.headers.contains("X-Name"));
+    }
+
+    #[test]
+    fn waits_for_more_bytes() {
+        assert!(parse_all(b"GET / HTTP/1.1\r\nHost: a").unwrap().is_none());
+        assert!(parse_all(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc").unwrap().is_none());
+        assert!(parse_all(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel").unwrap().is_none());
+    }
+
+    #[test]
+    fn parses_byte_by_byte() {
+        let raw = b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n";
+        let mut parser = HttpParser::new(&HttpLimits::default());
+        let mut buf: Vec<u8> = Vec::new();
+        let mut parsed: Option<HttpMessage> = None;
+        for (index, byte) in raw.iter().enumerate() {
+            buf.push(*byte);
+            parsed = parser.parse(&mut buf).unwrap();
+            if parsed.is_some() {
+                assert_eq!(index, raw.len() - 1);
+            }
+        }
+        assert_eq!(parsed.unwrap().body_text(), Ok("abc"));
+    }
+
+    #[test]
+    fn leaves_pipelined_requests_in_buffer() {
+        let mut buf = b"GET /first HTTP/1.1\r\n\r\nPOST /second HTTP/1.1\r\nContent-Length: 2\r\n\r\nokGET /third".to_vec();
+        let mut parser = HttpParser::new(&HttpLimits::default());
+        assert_eq!(parser.parse(&mut buf).unwrap().unwrap().target.path, "/first");
+        let second = parser.parse(&mut buf).unwrap().unwrap();
+        assert_eq!(second.target.path, "/second");
+        assert_eq!(second.body_text(), Ok("ok"));
+        assert!(parser.parse(&mut buf).unwrap().is_none());
+        assert_eq!(buf, b"GET /third");
+    }
+
+    #[test]
+    fn leaves_streamed_bodies_in_buffer() {
+        let mut buf = b"POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=XyZ\r\nContent-Length: 5\r\n\r\nabc".to_vec();
+        let mut parser = HttpParser::new(&HttpLimits::default()).with_streamed_bodies(StreamedBodies::Multipart);
+        let request = parser.parse(&mut buf).unwrap().unwrap();
+        assert!(request.body.is_empty());
+        assert_eq!(parser.take_streamed_body(), Some(StreamedLength::Fixed(5)));
+        assert_eq!(buf, b"abc");
+        let mut buf = b"POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=XyZ\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc".to_vec();
+        assert!(parser.parse(&mut buf).unwrap().unwrap().body.is_empty());
+        assert_eq!(parser.take_streamed_body(), Some(StreamedLength::Chunked));
+        assert_eq!(buf, b"3\r\nabc");
+
+        //Other media types and compressed bodies are received whole
+        let mut buf = b"POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=XyZ\r\nContent-Encoding: gzip\r\nContent-Length: 2\r\n\r\nab".to_vec();
+        assert_eq!(parser.parse(&mut buf).unwrap().unwrap().body, b"ab");
+        assert_eq!(parser.take_streamed_body(), None);
+        let mut buf = b"PUT /file HTTP/1.1\r\nContent-Length: 2\r\n\r\nab".to_vec();
+        assert_eq!(parser.parse(&mut buf).unwrap().unwrap().body, b"ab");
+        let mut parser = HttpParser::new(&HttpLimits::default()).with_streamed_bodies(StreamedBodies::All);
+        let mut buf = b"PUT /file HTTP/1.1\r\nContent-Length: 2\r\n\r\nab".to_vec();
+        assert!(parser.parse(&mut buf).unwrap().unwrap().body.is_empty());
+        assert_eq!(parser.take_streamed_body(), Some(StreamedLength::Fixed(2)));
+
+        let limits = HttpLimits { max_streamed_body_size: 4, ..HttpLimits::default() };
+        let mut buf = b"POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=XyZ\r\nContent-Length: 5\r\n\r\n".to_vec();
+        assert!(matches!(HttpParser::new(&limits).with_streamed_bodies(StreamedBodies::Multipart).parse(&mut buf), Err(HttpError::PayloadTooLarge)));
+    }
+
+    #[test]
+    fn rejects_malformed_request_lines() {
+        assert!(matches!(parse_error(b"GET /\r\n\r\n"), HttpError::Malformed(_)));
+        assert!(matches!(parse_error(b"GET  / HTTP/1.1\r\n\r\n"), HttpError::Malformed(_)));
+        assert!(matches!(parse_error(b"GET / HTTP/1.1 extra\r\n\r\n"), HttpError::Malformed(_)));
+        assert_eq!(parse_error(b"G@T / HTTP/1.1\r\n\r\n").status_code(), 400);
+        assert!(matches!(parse_error(b"GET / HTTP/3.0\r\n\r\n"), HttpError::Malformed(_)));
+        assert!(matches!(parse_error(b"GET /caf\xe9 HTTP/1.1\r\n\r\n"), HttpError::InvalidEncoding));
+    }
+
+    #[test]
+    fn rejects_malformed_header_fields() {
+        assert!(matches!(parse_error(b"GET / HTTP/1.1\r\nNo-Colon\r\n\r\n"), HttpError::Malformed(_)));
+        assert!(matches!(parse_error(b"GET / HTTP/1.1\r\nName : value\r\n\r\n"), HttpError::Malformed(_)));
+        assert!(matches!(parse_error(b"GET / HTTP/1.1\nHost: a\r\n\r\n"), HttpError::Malformed(_)));
+        assert!(matches!(parse_error(b"GET / HTTP/1.1\r\nN\xe4me: a\r\n\r\n"), HttpError::InvalidEncoding));
+    }
+
+    #[test]
+    fn rejects_ambiguous_body_length() {
+        assert!(matches!(parse_error(b"POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab"), HttpError::Malformed(_)));
+        assert!(matches!(parse_error(b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n"), HttpError::Malformed(_)));
+        assert!(matches!(parse_error(b"POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n"), HttpError::Malformed(_)));
+        assert!(matches!(parse_error(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n"), HttpError::Malformed(_)));
+        assert_eq!(parse_error(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n").status_code(), 501);
+    }
+
+    #[test]
+    fn rejects_malformed_chunks() {
+        assert!(matches!(parse_error(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n"), HttpError::Malformed(_)));
+        assert!(matches!(parse_error(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabcd\r\n"), HttpError::Malformed(_)));
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let limits = HttpLimits {
+            max_request_line: 32,
+            max_headers: 2,
+            max_header_size: 64,
+            max_body_size: 8,
+            ..HttpLimits::default()
+        };
+        let parse_limited = |bytes: &[u8]| HttpParser::new(&limits).parse(&mut bytes.to_vec()).err().map(|err| err.status_code());
+
+        //Checked before the request-line is complete
+        assert_eq!(parse_limited(format!("GET /{}", "a".repeat(40)).as_bytes()), Some(414));
+        assert_eq!(parse_limited(b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n"), Some(431));
+        assert_eq!(parse_limited(format!("GET / HTTP/1.1\r\nA: {}\r\n\r\n", "a".repeat(70)).as_bytes()), Some(431));
+        assert_eq!(parse_limited(b"POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\n"), Some(413));
+        assert_eq!(parse_limited(b"POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n"), Some(413));
+        assert_eq!(parse_limited(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nabcde\r\n5\r\n"), Some(413));
+        assert_eq!(parse_limited(b"POST / HTTP/1.1\r\nContent-Length: 8\r\n\r\n12345678"), None);
+    }
+
+    #[test]
+    fn parses_status_lines() {
+        assert_eq!(StartLine::parse("HTTP/1.1 404 Not Found").unwrap(), StartLine::Status { version: Version::Http11, status: 404, reason: "Not Found".to_string() });
+        assert_eq!(StartLine::parse("HTTP/1.0 204").unwrap(), StartLine::Status { version: Version::Http10, status: 204, reason: String::new() });
+        assert!(matches!(StartLine::parse("GET / HTTP/1.1").unwrap(), StartLine::Request { method: Method::Get, .. }));
+        assert!(StartLine::parse("HTTP/1.1 20 OK").is_err());
+        assert!(StartLine::parse("HTTP/1.1 099 Low").is_err());
+        assert!(StartLine::parse("HTTP/1.1 abc OK").is_err());
+        //A request parser rejects responses and the other way around
+        assert!(matches!(parse_error(b"HTTP/1.1 200 OK\r\n\r\n"), HttpError::Malformed(_)));
+        assert!(HttpParser::for_response(&HttpLimits::default(), &Method::Get).parse_response(&mut b"GET / HTTP/1.1\r\n\r\n".to_vec(), false).is_err());
+    }
+
+    #[test]
+    fn frames_response_bodies() {
+        let parse_response = |method: Method, bytes: &[u8], closed: bool| {
+            HttpParser::for_response(&HttpLimits::default(), &method).parse_response(&mut bytes.to_vec(), closed).unwrap()
+        };
+
+        let response = parse_response(Method::Get, b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello", false).unwrap();
+        assert_eq!((response.status, response.reason.as_str(), response.body.as_slice(), response.keep_alive), (200, "OK", &b"hello"[..], true));
+        let response = parse_response(Method::Get, b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n5\r\nhello\r\n0\r\n\r\n", false).unwrap();
+        assert_eq!((response.body.as_slice(), response.keep_alive), (&b"hello"[..], false));
+
+        //Content-Length of a HEAD response tells the size of the GET one
+        assert!(parse_response(Method::Head, b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n", false).unwrap().body.is_empty());
+        assert!(parse_response(Method::Get, b"HTTP/1.1 304 Not Modified\r\nContent-Length: 5\r\n\r\n", false).unwrap().body.is_empty());
+        assert_eq!(parse_response(Method::Get, b"HTTP/1.1 100 Continue\r\n\r\n", false).unwrap().status, 100);
+
+        //Without length the body ends with the connection
+        assert!(parse_response(Method::Get, b"HTTP/1.0 200 OK\r\n\r\nuntil close", false).is_none());
+        assert_eq!(parse_response(Method::Get, b"HTTP/1.0 200 OK\r\n\r\nuntil close", true).unwrap().body, b"until close");
+        assert_eq!(parse_response(Method::Get, b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip\r\n\r\nzz", true).unwrap().body, b"zz");
+    }
+}
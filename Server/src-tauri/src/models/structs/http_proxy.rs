@@ -0,0 +1,739 @@
+use std::{io::{self, Read, Write}, net::{IpAddr, TcpStream, ToSocketAddrs}, sync::{atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering}, Arc, Weak}, thread, time::Duration};
+
+use crate::models::structs::http_chunked::{ChunkedDecoder, ChunkedWriter};
+use crate::models::structs::http_error::HttpError;
+use crate::models::structs::http_headers::HeaderMap;
+use crate::models::structs::http_message::{HttpLimits, HttpMessage, StreamedBody};
+use crate::models::structs::http_method::Method;
+use crate::models::structs::http_parser::StartLine;
+use crate::models::structs::http_response::HttpResponse;
+use crate::models::structs::http_server::Handler;
+
+const CRLF: &[u8; 2] = b"\r\n";
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+// Fields describing a single connection, never forwarded (RFC 9110 7.6.1)
+const HOP_BY_HOP_FIELDS: [&str; 9] = ["connection", "keep-alive", "proxy-connection", "proxy-authenticate", "proxy-authorization", "te", "trailer", "transfer-encoding", "upgrade"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadBalancing {
+    RoundRobin,
+    // Upstream with the fewest requests in progress, round-robin between equals
+    LeastConnections,
+}
+
+// Request sent to every upstream at each interval
+// An upstream leaves the rotation after unhealthy_threshold failures in a row, and comes back after healthy_threshold successes
+#[derive(Clone, Debug)]
+pub struct HealthCheck {
+    pub path: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub unhealthy_threshold: u32,
+    pub healthy_threshold: u32,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck {
+            path: "/health".to_string(),
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(2),
+            unhealthy_threshold: 2,
+            healthy_threshold: 2,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ProxyConfig {
+    // "host:port" of each upstream server
+    pub upstreams: Vec<String>,
+    pub load_balancing: LoadBalancing,
+    // Without it an upstream is only skipped for the request its connection failed
+    pub health_check: Option<HealthCheck>,
+    pub connect_timeout: Duration,
+    // Applied to each read and write on the upstream connection
+    pub timeout: Duration,
+    // Removed from the start of the path before forwarding, e.g. "/api"
+    pub strip_prefix: Option<String>,
+    // Forward the client's Host instead of the upstream address
+    pub preserve_host: bool,
+    // Limits on the upstream response header section
+    pub limits: HttpLimits,
+}
+
+impl ProxyConfig {
+    pub fn new(upstreams: Vec<String>) -> Self {
+        ProxyConfig {
+            upstreams,
+            load_balancing: LoadBalancing::RoundRobin,
+            health_check: None,
+            connect_timeout: Duration::from_secs(5),
+            timeout: Duration::from_secs(60),
+            strip_prefix: None,
+            preserve_host: false,
+            limits: HttpLimits::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Upstream {
+    address: String,
+    healthy: AtomicBool,
+    // Requests in progress, the response body being relayed included
+    active: AtomicUsize,
+    // Health checks failed or passed in a row
+    failures: AtomicU32,
+    successes: AtomicU32,
+}
+
+// Marks a request in progress on an upstream until dropped
+struct ActiveRequest(Arc<Upstream>);
+
+impl ActiveRequest {
+    fn new(upstream: &Arc<Upstream>) -> Self {
+        upstream.active.fetch_add(1, Ordering::Relaxed);
+        ActiveRequest(upstream.clone())
+    }
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Status-line and header fields of an upstream response
+struct ResponseHead {
+    status: u16,
+    reason: String,
+    headers: HeaderMap,
+}
+
+struct ProxyState {
+    config: ProxyConfig,
+    upstreams: Vec<Arc<Upstream>>,
+    // Round-robin position
+    next: AtomicUsize,
+}
+
+// Forward requests to upstream servers and relay their responses
+// Response bodies are streamed to the client as they are received
+// Request bodies too when the server streams them (StreamedBodies::All), otherwise they are sent once read whole
+// Each request opens its own upstream connection
+#[derive(Clone)]
+pub struct ReverseProxy {
+    state: Arc<ProxyState>,
+}
+
+impl ReverseProxy {
+    // Starts the health check thread when configured, it ends once the proxy is dropped
+    pub fn new(config: ProxyConfig) -> Self {
+        let upstreams = config.upstreams.iter().map(|address| Arc::new(Upstream {
+            address: address.clone(),
+            healthy: AtomicBool::new(true),
+            active: AtomicUsize::new(0),
+            failures: AtomicU32::new(0),
+            successes: AtomicU32::new(0),
+        })).collect();
+
+        let state = Arc::new(ProxyState {
+            config,
+            upstreams,
+            next: AtomicUsize::new(0),
+        });
+        if let Some(health_check) = state.config.health_check.clone() {
+            let state = Arc::downgrade(&state);
+            thread::spawn(move || Self::run_health_checks(state, health_check));
+        }
+
+        ReverseProxy {
+            state,
+        }
+    }
+
+    // Handler for HttpServer::start, every request is forwarded
+    pub fn into_handler(self) -> Handler {
+        Arc::new(move |request: &mut HttpMessage| self.handle(request))
+    }
+
+    // Addresses of the upstreams currently in rotation
+    pub fn healthy_upstreams(&self) -> Vec<String> {
+        self.state.upstreams.iter()
+            .filter(|upstream| upstream.healthy.load(Ordering::Relaxed))
+            .map(|upstream| upstream.address.clone())
+            .collect()
+    }
+
+    // 502 when no upstream could be reached or its response is invalid, 503 when none is healthy, 504 on timeout
+    pub fn handle(&self, request: &HttpMessage) -> HttpResponse {
+        let config = &self.state.config;
+        let mut tried: Vec<usize> = Vec::new();
+
+        //A connection refused is retried on the next upstream, nothing was sent yet
+        while let Some(index) = self.pick(&tried) {
+            let upstream = &self.state.upstreams[index];
+            let active_request = ActiveRequest::new(upstream);
+            let stream = match Self::connect(&upstream.address, config.connect_timeout, config.timeout) {
+                Ok(stream) => stream,
+                Err(err) => {
+                    println!("Error while connecting to upstream {} {:?}", upstream.address, err);
+                    //The health checks put it back in rotation
+                    if config.health_check.is_some() && upstream.healthy.swap(false, Ordering::Relaxed) {
+                        upstream.successes.store(0, Ordering::Relaxed);
+                    }
+                    tried.push(index);
+                    continue;
+                }
+            };
+
+            return match self.forward(stream, &upstream.address, request, active_request) {
+                Ok(response) => response,
+                Err(HttpError::Timeout) => HttpResponse::error(504),
+                Err(err) => {
+                    println!("Error while proxying to upstream {} {}", upstream.address, err);
+                    HttpResponse::error(502)
+                }
+            };
+        }
+
+        HttpResponse::error(if tried.is_empty() { 503 } else { 502 })
+    }
+
+    // Index of the upstream for the next request, among the healthy ones not tried yet
+    fn pick(&self, tried: &[usize]) -> Option<usize> {
+        let upstreams = &self.state.upstreams;
+        let start = self.state.next.fetch_add(1, Ordering::Relaxed);
+        let mut candidates = (0..upstreams.len())
+            .map(|offset| (start + offset) % upstreams.len())
+            .filter(|index| !tried.contains(index) && upstreams[*index].healthy.load(Ordering::Relaxed));
+
+        match self.state.config.load_balancing {
+            LoadBalancing::RoundRobin => candidates.next(),
+            LoadBalancing::LeastConnections => candidates.min_by_key(|index| upstreams[*index].active.load(Ordering::Relaxed)),
+        }
+    }
+
+    fn connect(address: &str, connect_timeout: Duration, timeout: Duration) -> io::Result<TcpStream> {
+        let mut last_err = io::Error::new(io::ErrorKind::NotFound, "Upstream address not resolved");
+        for socket_addr in address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&socket_addr, connect_timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(timeout))?;
+                    stream.set_write_timeout(Some(timeout))?;
+                    stream.set_nodelay(true)?;
+                    return Ok(stream);
+                },
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+
+    fn forward(&self, mut stream: TcpStream, address: &str, request: &HttpMessage, active_request: ActiveRequest) -> Result<HttpResponse, HttpError> {
+        stream.write_all(&self.upstream_request(address, request))?;
+        match request.extensions.get::<StreamedBody>() {
+            Some(streamed_body) => {
+                if let Some(err) = send_streamed_body(streamed_body, &mut stream)? {
+                    //The upstream got an incomplete body, its answer would be about another request
+                    println!("Error while receiving the body to proxy {}", err);
+                    return Ok(HttpResponse::error(err.status_code()));
+                }
+            },
+            None => stream.write_all(&request.body)?,
+        }
+        stream.flush()?;
+
+        let limits = &self.state.config.limits;
+        let mut buf: Vec<u8> = Vec::new();
+        let head = loop {
+            let head = read_response_head(&mut stream, &mut buf, limits)?;
+            //Interim responses, e.g. 100 Continue, are not relayed
+            if head.status >= 200 || head.status == 101 {
+                break head;
+            }
+        };
+        if head.status == 101 {
+            return Err(HttpError::Malformed("Upstream switched protocols".to_string()));
+        }
+
+        let mut response = HttpResponse::new(head.status);
+        response.reason = head.reason;
+        let connection_fields: Vec<String> = head.headers.get_list("Connection").iter().map(|name| name.to_ascii_lowercase()).collect();
+        for (name, value) in head.headers.iter() {
+            let lowercase_name = name.to_ascii_lowercase();
+            if HOP_BY_HOP_FIELDS.contains(&lowercase_name.as_str()) || connection_fields.contains(&lowercase_name) || lowercase_name == "content-length" {
+                continue;
+            }
+            response.headers.append(name, value);
+        }
+
+        //Framing of the upstream body (RFC 9112 6.3)
+        let content_length = parse_content_length(&head.headers)?;
+        if request.method == Method::Head || !HttpResponse::allows_body(head.status) {
+            //A HEAD response announces the length of the body it doesn't send
+            if let (Method::Head, Some(content_length)) = (&request.method, content_length) {
+                response.body_stream = Some(Box::new(|_| Ok(())));
+                response.stream_length = Some(content_length);
+            }
+            return Ok(response);
+        }
+
+        let chunked = head.headers.get_list("Transfer-Encoding").last().is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"));
+        let limits = HttpLimits { max_body_size: usize::MAX, ..limits.clone() };
+        response.stream_length = if chunked { None } else { content_length };
+        response.body_stream = Some(Box::new(move |writer| {
+            //Keeps the request counted for least-connections until the body is relayed
+            let _active_request = active_request;
+            if chunked {
+                relay_chunked(&mut stream, buf, writer, &limits)
+            }
+            else {
+                relay(&mut stream, buf, writer, content_length)
+            }
+        }));
+        Ok(response)
+    }
+
+    // Request-line and header section sent upstream
+    fn upstream_request(&self, address: &str, request: &HttpMessage) -> Vec<u8> {
+        let config = &self.state.config;
+        let mut path = request.target.path.as_str();
+        if let Some(prefix) = config.strip_prefix.as_deref().map(|prefix| prefix.trim_end_matches('/')) {
+            if let Some(rest) = path.strip_prefix(prefix).filter(|rest| rest.is_empty() || rest.starts_with('/')) {
+                path = if rest.is_empty() { "/" } else { rest };
+            }
+        }
+        let target = match &request.target.query {
+            Some(query) => format!("{}?{}", path, query),
+            None => path.to_string(),
+        };
+
+        let client_host = request.host().unwrap_or_default();
+        let host = if config.preserve_host && !client_host.is_empty() { client_host } else { address };
+        let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", request.method, target, host);
+
+        let connection_fields: Vec<String> = request.headers.get_list("Connection").iter().map(|name| name.to_ascii_lowercase()).collect();
+        for (name, value) in request.headers.iter() {
+            let lowercase_name = name.to_ascii_lowercase();
+            if HOP_BY_HOP_FIELDS.contains(&lowercase_name.as_str())
+                || connection_fields.contains(&lowercase_name)
+                || ["host", "content-length", "expect", "x-forwarded-for", "x-forwarded-host", "forwarded"].contains(&lowercase_name.as_str()) {
+                continue;
+            }
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+
+        //Client address appended to the ones added by proxies before us
+        let client_ip = request.peer_addr.map(|peer_addr| peer_addr.ip());
+        let mut forwarded_for: Vec<&str> = request.headers.get_all("X-Forwarded-For");
+        let client_ip_text = client_ip.map(|client_ip| client_ip.to_string()).unwrap_or_else(|| "unknown".to_string());
+        forwarded_for.push(&client_ip_text);
+        head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for.join(", ")));
+        if !client_host.is_empty() {
+            head.push_str(&format!("X-Forwarded-Host: {}\r\n", client_host));
+        }
+
+        //Same information in the standard field (RFC 7239)
+        let mut forwarded: Vec<String> = request.headers.get_all("Forwarded").iter().map(|value| value.to_string()).collect();
+        let mut element = format!("for={}", forwarded_node(client_ip));
+        if !client_host.is_empty() {
+            element.push_str(&format!(";host=\"{}\"", client_host.replace(['"', '\\'], "")));
+        }
+        forwarded.push(element);
+        head.push_str(&format!("Forwarded: {}\r\n", forwarded.join(", ")));
+
+        //Bodies were decoded by the server, they are sent with their length when it is known
+        match request.extensions.get::<StreamedBody>().map(|streamed_body| streamed_body.length()) {
+            Some(Some(length)) => head.push_str(&format!("Content-Length: {}\r\n", length)),
+            Some(None) => head.push_str("Transfer-Encoding: chunked\r\n"),
+            None if !request.body.is_empty() || [Method::Post, Method::Put, Method::Patch].contains(&request.method) => {
+                head.push_str(&format!("Content-Length: {}\r\n", request.body.len()));
+            },
+            None => {},
+        }
+        head.push_str("Connection: close\r\n\r\n");
+
+        head.into_bytes()
+    }
+
+    fn run_health_checks(state: Weak<ProxyState>, health_check: HealthCheck) {
+        loop {
+            thread::sleep(health_check.interval);
+            let state = match state.upgrade() {
+                Some(state) => state,
+                None => return,
+            };
+
+            for upstream in &state.upstreams {
+                let passed = Self::check_health(&upstream.address, &health_check, &state.config.limits);
+                if passed {
+                    upstream.failures.store(0, Ordering::Relaxed);
+                    let successes = upstream.successes.fetch_add(1, Ordering::Relaxed) + 1;
+                    if successes >= health_check.healthy_threshold && !upstream.healthy.swap(true, Ordering::Relaxed) {
+                        println!("Upstream {} back in rotation", upstream.address);
+                    }
+                }
+                else {
+                    upstream.successes.store(0, Ordering::Relaxed);
+                    let failures = upstream.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                    if failures >= health_check.unhealthy_threshold && upstream.healthy.swap(false, Ordering::Relaxed) {
+                        println!("Upstream {} out of rotation", upstream.address);
+                    }
+                }
+            }
+        }
+    }
+
+    // Passed when the upstream answers with a 2xx or 3xx status
+    fn check_health(address: &str, health_check: &HealthCheck, limits: &HttpLimits) -> bool {
+        let result = Self::connect(address, health_check.timeout, health_check.timeout).and_then(|mut stream| {
+            let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", health_check.path, address);
+            stream.write_all(request.as_bytes())?;
+            read_response_head(&mut stream, &mut Vec::new(), limits).map_err(|err| io::Error::other(err.to_string()))
+        });
+
+        match result {
+            Ok(head) => (200..400).contains(&head.status),
+            Err(_) => false,
+        }
+    }
+}
+
+// Node of a Forwarded element, IPv6 addresses are bracketed and quoted
+fn forwarded_node(ip: Option<IpAddr>) -> String {
+    match ip {
+        Some(IpAddr::V4(ip)) => ip.to_string(),
+        Some(IpAddr::V6(ip)) => format!("\"[{}]\"", ip),
+        None => "unknown".to_string(),
+    }
+}
+
+// Read up to the end of the header section, bytes received after it are left in buf
+// The body is not read, it is relayed as it arrives
+fn read_response_head<R: Read>(stream: &mut R, buf: &mut Vec<u8>, limits: &HttpLimits) -> Result<ResponseHead, HttpError> {
+    let max_head_size = limits.max_request_line + limits.max_headers * (limits.max_header_size + CRLF.len()) + 2 * CRLF.len();
+    let mut chunk = [0u8; READ_CHUNK_SIZE];
+    let head_end = loop {
+        if let Some(position) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position + 4;
+        }
+        if buf.len() > max_head_size {
+            return Err(HttpError::HeaderTooLarge);
+        }
+        let nb_bytes_read = stream.read(&mut chunk)?;
+        if nb_bytes_read == 0 {
+            return Err(HttpError::ConnectionClosed);
+        }
+        buf.extend_from_slice(&chunk[..nb_bytes_read]);
+    };
+
+    let head: Vec<u8> = buf.drain(..head_end).collect();
+    let head = String::from_utf8_lossy(&head[..head_end - 2 * CRLF.len()]).to_string();
+    let mut lines = head.split("\r\n");
+
+    let status_line = lines.next().unwrap_or_default();
+    let (status, reason) = match StartLine::parse(status_line)? {
+        StartLine::Status { status, reason, .. } => (status, reason),
+        StartLine::Request { .. } => return Err(HttpError::Malformed(format!("Invalid status-line {}", status_line))),
+    };
+
+    let mut headers = HeaderMap::new();
+    for line in lines {
+        if headers.len() == limits.max_headers {
+            return Err(HttpError::TooManyHeaders);
+        }
+        let (name, value) = HeaderMap::parse_field(line)?;
+        headers.append(&name, &value);
+    }
+
+    Ok(ResponseHead {
+        status,
+        reason,
+        headers,
+    })
+}
+
+fn parse_content_length(headers: &HeaderMap) -> Result<Option<u64>, HttpError> {
+    match headers.get_list("Content-Length").last() {
+        Some(value) => value.parse().map(Some).map_err(|_| HttpError::Malformed("Invalid Content-Length".to_string())),
+        None => Ok(None),
+    }
+}
+
+// Copy the body, up to length bytes or until the upstream closes the connection when the length is unknown
+fn relay(stream: &mut TcpStream, mut buf: Vec<u8>, writer: &mut dyn Write, length: Option<u64>) -> io::Result<()> {
+    let length = match length {
+        Some(length) => length,
+        None => {
+            writer.write_all(&buf)?;
+            io::copy(stream, writer)?;
+            return Ok(());
+        }
+    };
+
+    buf.truncate(length.min(buf.len() as u64) as usize);
+    writer.write_all(&buf)?;
+    let remaining = length - buf.len() as u64;
+    if io::copy(&mut stream.take(remaining), writer)? < remaining {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Upstream closed before the end of the body"));
+    }
+    Ok(())
+}
+
+// Decode the chunked body and write its data as it arrives, the trailer fields are dropped
+fn relay_chunked(stream: &mut TcpStream, mut buf: Vec<u8>, writer: &mut dyn Write, limits: &HttpLimits) -> io::Result<()> {
+    let mut decoder = ChunkedDecoder::new();
+    let mut chunk = [0u8; READ_CHUNK_SIZE];
+    loop {
+        let decoded = decoder.decode(&mut buf, limits).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        let finished = decoded.is_some();
+        let data = match decoded {
+            Some((body, _)) => body,
+            None => decoder.take_body(),
+        };
+        if !data.is_empty() {
+            writer.write_all(&data)?;
+        }
+        if finished {
+            return Ok(());
+        }
+
+        let nb_bytes_read = stream.read(&mut chunk)?;
+        if nb_bytes_read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Upstream closed before the end of the body"));
+        }
+        buf.extend_from_slice(&chunk[..nb_bytes_read]);
+    }
+}
+
+// Copy the client's body to the upstream as it is received, with chunked encoding when its length is unknown
+// Returns the error when the client's body couldn't be received whole, the upstream then got part of it
+fn send_streamed_body(streamed_body: &StreamedBody, stream: &mut TcpStream) -> io::Result<Option<HttpError>> {
+    if streamed_body.length().is_some() {
+        return copy_streamed_body(streamed_body, stream);
+    }
+
+    let mut chunked_writer = ChunkedWriter::new(&mut *stream);
+    let client_err = copy_streamed_body(streamed_body, &mut chunked_writer)?;
+    if client_err.is_none() {
+        chunked_writer.finish()?;
+    }
+    Ok(client_err)
+}
+
+fn copy_streamed_body(streamed_body: &StreamedBody, writer: &mut dyn Write) -> io::Result<Option<HttpError>> {
+    loop {
+        match streamed_body.next_chunk() {
+            Ok(Some(chunk)) => writer.write_all(&chunk)?,
+            Ok(None) => return Ok(None),
+            Err(err) => return Ok(Some(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::{Ipv4Addr, TcpListener}, sync::mpsc};
+    use crate::models::structs::http_parser::StreamedBodies;
+    use crate::models::structs::http_server::{HttpServer, HttpServerConfig};
+
+    // Upstream answering with its name, the request line and the fields received
+    fn upstream(name: &'static str) -> HttpServer {
+        upstream_on(name, 0)
+    }
+
+    fn upstream_on(name: &'static str, port: u16) -> HttpServer {
+        let config = HttpServerConfig { address: IpAddr::V4(Ipv4Addr::LOCALHOST), port, workers: 2, ..HttpServerConfig::default() };
+        HttpServer::start(config, Arc::new(move |request: &mut HttpMessage| {
+            if request.target.path == "/stream" {
+                return HttpResponse::stream(200, "text/plain", |writer| {
+                    for i in 0..3 {
+                        write!(writer, "part{} ", i)?;
+                    }
+                    Ok(())
+                });
+            }
+            let fields: Vec<String> = request.headers.iter().map(|(name, value)| format!("{}: {}", name, value)).collect();
+            HttpResponse::text(200, &format!("{}\n{} {}\n{}\n{}", name, request.method, request.target, fields.join("\n"), request.body_text().unwrap_or_default()))
+                .with_header("Keep-Alive", "timeout=5")
+                .with_header("X-Upstream", name)
+        })).unwrap()
+    }
+
+    // Address nothing listens on
+    fn closed_address() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    fn request(raw: &str) -> HttpMessage {
+        let mut request = HttpMessage::new(raw.as_bytes()).unwrap();
+        request.peer_addr = Some("192.0.2.7:40000".parse().unwrap());
+        request
+    }
+
+    // Body as received by the client
+    fn body(mut response: HttpResponse) -> String {
+        let mut body = response.body.clone();
+        if let Some(body_stream) = response.body_stream.take() {
+            body_stream(&mut body).unwrap();
+        }
+        String::from_utf8(body).unwrap()
+    }
+
+    #[test]
+    fn forwards_request_with_forwarded_fields() {
+        let server = upstream("a");
+        let config = ProxyConfig { strip_prefix: Some("/api".to_string()), ..ProxyConfig::new(vec![server.local_addr.to_string()]) };
+        let proxy = ReverseProxy::new(config);
+
+        let response = proxy.handle(&request("POST /api/items?x=1 HTTP/1.1\r\nHost: example.com\r\nConnection: keep-alive, X-Secret\r\nX-Secret: 1\r\nX-Forwarded-For: 10.0.0.1\r\nContent-Length: 5\r\n\r\nhello"));
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers.get("X-Upstream"), Some("a"));
+        assert!(!response.headers.contains("Keep-Alive"));
+
+        let body = body(response);
+        assert!(body.contains("POST /items?x=1\n"), "{}", body);
+        assert!(body.contains(&format!("Host: {}", server.local_addr)));
+        assert!(body.contains("X-Forwarded-For: 10.0.0.1, 192.0.2.7"));
+        assert!(body.contains("X-Forwarded-Host: example.com"));
+        assert!(body.contains("Forwarded: for=192.0.2.7;host=\"example.com\""));
+        assert!(!body.contains("X-Secret"));
+        assert!(body.ends_with("hello"));
+    }
+
+    #[test]
+    fn relays_chunked_body() {
+        let server = upstream("a");
+        let proxy = ReverseProxy::new(ProxyConfig::new(vec![server.local_addr.to_string()]));
+
+        let response = proxy.handle(&request("GET /stream HTTP/1.1\r\nHost: example.com\r\n\r\n"));
+        assert_eq!(response.stream_length, None);
+        assert_eq!(body(response), "part0 part1 part2 ");
+    }
+
+    #[test]
+    fn balances_and_skips_unreachable_upstreams() {
+        let (a, b) = (upstream("a"), upstream("b"));
+        let config = ProxyConfig {
+            health_check: Some(HealthCheck { interval: Duration::from_secs(60), ..HealthCheck::default() }),
+            ..ProxyConfig::new(vec![a.local_addr.to_string(), closed_address(), b.local_addr.to_string()])
+        };
+        let proxy = ReverseProxy::new(config);
+
+        let names: Vec<String> = (0..4).map(|_| {
+            let response = proxy.handle(&request("GET / HTTP/1.1\r\n\r\n"));
+            response.headers.get("X-Upstream").unwrap_or_default().to_string()
+        }).collect();
+        assert_eq!(names.iter().filter(|name| *name == "a").count(), 2);
+        assert_eq!(names.iter().filter(|name| *name == "b").count(), 2);
+        assert_eq!(proxy.healthy_upstreams(), vec![a.local_addr.to_string(), b.local_addr.to_string()]);
+
+        let proxy = ReverseProxy::new(ProxyConfig::new(vec![closed_address()]));
+        assert_eq!(proxy.handle(&request("GET / HTTP/1.1\r\n\r\n")).status, 502);
+    }
+
+    #[test]
+    fn picks_least_connections() {
+        let config = ProxyConfig { load_balancing: LoadBalancing::LeastConnections, ..ProxyConfig::new(vec!["a:80".to_string(), "b:80".to_string(), "c:80".to_string()]) };
+        let proxy = ReverseProxy::new(config);
+        let upstreams = &proxy.state.upstreams;
+        upstreams[0].active.store(2, Ordering::Relaxed);
+        upstreams[1].active.store(1, Ordering::Relaxed);
+        upstreams[2].active.store(3, Ordering::Relaxed);
+        assert_eq!(proxy.pick(&[]), Some(1));
+        assert_eq!(proxy.pick(&[1]), Some(0));
+
+        upstreams[0].healthy.store(false, Ordering::Relaxed);
+        upstreams[1].healthy.store(false, Ordering::Relaxed);
+        assert_eq!(proxy.pick(&[]), Some(2));
+        assert_eq!(proxy.pick(&[2]), None);
+    }
+
+    #[test]
+    fn health_checks_remove_and_restore_upstreams() {
+        let mut server = upstream("a");
+        let address = server.local_addr.to_string();
+        let health_check = HealthCheck { interval: Duration::from_millis(20), timeout: Duration::from_millis(200), unhealthy_threshold: 1, healthy_threshold: 1, ..HealthCheck::default() };
+        let proxy = ReverseProxy::new(ProxyConfig { health_check: Some(health_check), ..ProxyConfig::new(vec![address.clone()]) });
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(proxy.healthy_upstreams(), vec![address.clone()]);
+
+        server.stop().unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert!(proxy.healthy_upstreams().is_empty());
+        assert_eq!(proxy.handle(&request("GET / HTTP/1.1\r\n\r\n")).status, 503);
+
+        let _server = upstream_on("a", server.local_addr.port());
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(proxy.healthy_upstreams(), vec![address]);
+        assert_eq!(proxy.handle(&request("GET / HTTP/1.1\r\n\r\n")).status, 200);
+    }
+
+    fn read_until(stream: &mut TcpStream, received: &mut Vec<u8>, done: impl Fn(&[u8]) -> bool) {
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        while !done(received) {
+            let nb_bytes_read = stream.read(&mut chunk).unwrap();
+            assert!(nb_bytes_read > 0);
+            received.extend_from_slice(&chunk[..nb_bytes_read]);
+        }
+    }
+
+    #[test]
+    fn streams_request_bodies_to_the_upstream() {
+        //Upstream telling when it received the first half of the body, the client only sends the second half then
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_address = listener.local_addr().unwrap().to_string();
+        let half = 64 * 1024;
+        let (half_received, wait_half) = mpsc::channel();
+        let raw_upstream = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut received: Vec<u8> = Vec::new();
+            read_until(&mut stream, &mut received, |received| received.windows(4).any(|window| window == b"\r\n\r\n"));
+            let head_end = received.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+            read_until(&mut stream, &mut received, |received| received.len() >= head_end + half);
+            half_received.send(()).unwrap();
+            read_until(&mut stream, &mut received, |received| received.len() >= head_end + 2 * half);
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\nreceived").unwrap();
+            String::from_utf8_lossy(&received[..head_end]).to_string()
+        });
+
+        let config = HttpServerConfig { address: IpAddr::V4(Ipv4Addr::LOCALHOST), port: 0, workers: 2, streamed_bodies: StreamedBodies::All, ..HttpServerConfig::default() };
+        let proxy = HttpServer::start(config, ReverseProxy::new(ProxyConfig::new(vec![upstream_address])).into_handler()).unwrap();
+        let mut client = TcpStream::connect(proxy.local_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(format!("PUT /video HTTP/1.1\r\nHost: a\r\nContent-Length: {}\r\n\r\n", 2 * half).as_bytes()).unwrap();
+        client.write_all(&vec![b'a'; half]).unwrap();
+        assert!(wait_half.recv_timeout(Duration::from_secs(5)).is_ok(), "The proxy waited for the whole body");
+        client.write_all(&vec![b'b'; half]).unwrap();
+
+        let mut response = Vec::new();
+        read_until(&mut client, &mut response, |response| response.ends_with(b"received"));
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(raw_upstream.join().unwrap().contains(&format!("Content-Length: {}\r\n", 2 * half)));
+
+        //A chunked upload is sent on chunked as well
+        let server = upstream("a");
+        let config = HttpServerConfig { address: IpAddr::V4(Ipv4Addr::LOCALHOST), port: 0, workers: 2, streamed_bodies: StreamedBodies::All, ..HttpServerConfig::default() };
+        let proxy = HttpServer::start(config, ReverseProxy::new(ProxyConfig::new(vec![server.local_addr.to_string()])).into_handler()).unwrap();
+        let mut client = TcpStream::connect(proxy.local_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(b"POST /upload HTTP/1.1\r\nHost: a\r\nConnection: close\r\nTransfer-Encoding: chunked\r\n\r\n").unwrap();
+        let data = "0123456789".repeat(4 * 1024);
+        for part in data.as_bytes().chunks(7 * 1024) {
+            client.write_all(format!("{:X}\r\n", part.len()).as_bytes()).unwrap();
+            client.write_all(part).unwrap();
+            client.write_all(b"\r\n").unwrap();
+        }
+        client.write_all(b"0\r\n\r\n").unwrap();
+        let mut response = String::new();
+        let _ = client.read_to_string(&mut response);
+        assert!(response.contains("Transfer-Encoding: chunked"), "{}", response);
+        assert!(response.contains(&data));
+    }
+}
@@ -0,0 +1,296 @@
+use std::{io::{self, Write}, time::SystemTime};
+
+use serde::Serialize;
+
+use crate::models::structs::http_chunked::ChunkedWriter;
+use crate::models::structs::http_cookie::SetCookie;
+use crate::models::structs::http_date::format_http_date;
+use crate::models::structs::http_headers::HeaderMap;
+use crate::models::structs::http_stream::HttpStream;
+use crate::models::structs::http_version::Version;
+
+// Producer of a body of unknown length, each write is sent as soon as it is made
+pub type BodyStream = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()> + Send>;
+
+// Takes over the connection after a 101 response, with the bytes already received after the request
+pub type UpgradeHandler = Box<dyn FnOnce(Box<dyn HttpStream>, Vec<u8>) + Send>;
+
+pub struct HttpResponse {
+    pub version: Version,
+    pub status: u16,
+    pub reason: String,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+    // Replaces body when set, sent with chunked encoding unless stream_length is known
+    pub body_stream: Option<BodyStream>,
+    pub stream_length: Option<u64>,
+    // Should the connection be kept open after this response
+    pub keep_alive: bool,
+    // Protocol switched to once a 101 response is sent
+    pub upgrade: Option<UpgradeHandler>,
+    // Body stream written on its own thread over HTTP/1.x, see detached
+    pub detached: bool,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16) -> Self {
+        HttpResponse {
+            version: Version::Http11,
+            status,
+            reason: Self::reason_phrase(status).to_string(),
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+            body_stream: None,
+            stream_length: None,
+            keep_alive: true,
+            upgrade: None,
+            detached: false,
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    // Add a Set-Cookie field, an invalid cookie is dropped
+    pub fn with_cookie(mut self, cookie: &SetCookie) -> Self {
+        if !cookie.is_valid() {
+            println!("Invalid cookie {} not sent", cookie.name);
+            return self;
+        }
+        self.headers.append("Set-Cookie", &cookie.to_string());
+        self
+    }
+
+    pub fn with_body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+
+    pub fn text(status: u16, text: &str) -> Self {
+        Self::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(text.as_bytes().to_vec())
+    }
+
+    pub fn html(status: u16, html: &str) -> Self {
+        Self::new(status)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(html.as_bytes().to_vec())
+    }
+
+    pub fn json<T: Serialize>(status: u16, value: &T) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => {
+                Self::new(status)
+                    .with_header("Content-Type", "application/json")
+                    .with_body(body)
+            },
+            Err(err) => {
+                println!("Error while serializing json response {:?}", err);
+                Self::error(500)
+            }
+        }
+    }
+
+    // Body produced while sending, e.g. live statistics
+    pub fn stream<F>(status: u16, content_type: &str, producer: F) -> Self
+        where F: FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static {
+        let mut response = Self::new(status).with_header("Content-Type", content_type);
+        response.body_stream = Some(Box::new(producer));
+        response
+    }
+
+    // The body stream runs as long as the client stays, e.g. server-sent events
+    // Over HTTP/1.x the connection is handed to a thread of its own like an upgrade, so it doesn't hold a worker,
+    // and closes once the stream ends. HTTP/2 streams already have their own thread
+    pub fn detached(mut self) -> Self {
+        self.detached = true;
+        self
+    }
+
+    // Body produced while sending with a length known upfront, e.g. a file read from disk
+    pub fn sized_stream<F>(status: u16, content_type: &str, length: u64, producer: F) -> Self
+        where F: FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static {
+        let mut response = Self::stream(status, content_type, producer);
+        response.stream_length = Some(length);
+        response
+    }
+
+    // 301, 302, 303, 307 or 308 to the location
+    pub fn redirect(status: u16, location: &str) -> Self {
+        Self::new(status).with_header("Location", location)
+    }
+
+    // Plain text response holding the reason phrase
+    pub fn error(status: u16) -> Self {
+        Self::text(status, Self::reason_phrase(status))
+    }
+
+    pub fn not_found() -> Self {
+        Self::error(404)
+    }
+
+    // Status-line, header section and body as sent on the wire
+    pub fn to_bytes(&self, include_body: bool) -> Vec<u8> {
+        let mut bytes = self.head_bytes(false);
+        if include_body && Self::allows_body(self.status) {
+            bytes.extend_from_slice(&self.body);
+        }
+
+        bytes
+    }
+
+    // Returns the number of body bytes sent
+    pub fn write_to<W: Write + ?Sized>(&mut self, stream: &mut W, include_body: bool) -> io::Result<u64> {
+        let body_stream = match self.body_stream.take() {
+            Some(body_stream) => body_stream,
+            None => {
+                stream.write_all(&self.to_bytes(include_body))?;
+                stream.flush()?;
+                return Ok(if include_body && Self::allows_body(self.status) { self.body.len() as u64 } else { 0 })
+            }
+        };
+
+        //HTTP/1.0 has no chunked encoding, the end of the body is the end of the connection
+        let chunked = self.stream_length.is_none() && self.version != Version::Http10;
+        if self.stream_length.is_none() && self.version == Version::Http10 {
+            self.keep_alive = false;
+        }
+        stream.write_all(&self.head_bytes(true))?;
+        stream.flush()?;
+
+        if !include_body || !Self::allows_body(self.status) {
+            return Ok(0)
+        }
+
+        let bytes_sent = if chunked {
+            let mut chunked_writer = ChunkedWriter::new(&mut *stream);
+            let mut counting_writer = CountingWriter { inner: &mut chunked_writer, count: 0 };
+            body_stream(&mut counting_writer)?;
+            let count = counting_writer.count;
+            chunked_writer.finish()?;
+            count
+        }
+        else {
+            let mut counting_writer = CountingWriter { inner: &mut *stream, count: 0 };
+            body_stream(&mut counting_writer)?;
+            counting_writer.count
+        };
+        stream.flush()?;
+        Ok(bytes_sent)
+    }
+
+    fn head_bytes(&self, streamed: bool) -> Vec<u8> {
+        let mut head = format!("{} {} {}\r\n", self.version, self.status, self.reason);
+
+        for (name, value) in self.headers.iter() {
+            if ["Content-Length", "Transfer-Encoding", "Date", "Connection"].iter().any(|generated| generated.eq_ignore_ascii_case(name)) {
+                continue;
+            }
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+
+        head.push_str(&format!("Date: {}\r\n", format_http_date(SystemTime::now())));
+        //No body allowed for 1xx and 204 (RFC 9110 8.6), the length of a 304 would be taken as the one of the representation
+        if Self::allows_body(self.status) {
+            if let (true, Some(stream_length)) = (streamed, self.stream_length) {
+                head.push_str(&format!("Content-Length: {}\r\n", stream_length));
+            }
+            else if streamed && self.version != Version::Http10 {
+                head.push_str("Transfer-Encoding: chunked\r\n");
+            }
+            else if !streamed {
+                head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+            }
+        }
+        if self.status == 101 && self.upgrade.is_some() {
+            head.push_str("Connection: Upgrade\r\n");
+        }
+        else {
+            head.push_str(if self.keep_alive { "Connection: keep-alive\r\n" } else { "Connection: close\r\n" });
+        }
+        head.push_str("\r\n");
+
+        head.into_bytes()
+    }
+
+    pub fn allows_body(status: u16) -> bool {
+        status >= 200 && status != 204 && status != 304
+    }
+
+    pub fn reason_phrase(status: u16) -> &'static str {
+        match status {
+            100 => "Continue",
+            101 => "Switching Protocols",
+            200 => "OK",
+            201 => "Created",
+            202 => "Accepted",
+            204 => "No Content",
+            206 => "Partial Content",
+            301 => "Moved Permanently",
+            302 => "Found",
+            303 => "See Other",
+            304 => "Not Modified",
+            307 => "Temporary Redirect",
+            308 => "Permanent Redirect",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            411 => "Length Required",
+            412 => "Precondition Failed",
+            413 => "Content Too Large",
+            414 => "URI Too Long",
+            415 => "Unsupported Media Type",
+            416 => "Range Not Satisfiable",
+            417 => "Expectation Failed",
+            421 => "Misdirected Request",
+            426 => "Upgrade Required",
+            429 => "Too Many Requests",
+            431 => "Request Header Fields Too Large",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            505 => "HTTP Version Not Supported",
+            _ => "Unknown",
+        }
+    }
+}
+
+// Counts the bytes written through it, e.g. the body bytes of a streamed response
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let nb_bytes_written = self.inner.write(data)?;
+        self.count += nb_bytes_written as u64;
+        Ok(nb_bytes_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_out_the_length_of_responses_without_body() {
+        let head = |response: HttpResponse| String::from_utf8(response.to_bytes(false)).unwrap();
+        assert!(head(HttpResponse::text(200, "hello")).contains("Content-Length: 5\r\n"));
+        for status in [101, 204, 304] {
+            assert!(!head(HttpResponse::new(status).with_header("Content-Length", "5")).contains("Content-Length"));
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::models::structs::http_message::HttpMessage;
+use crate::models::structs::http_method::Method;
+use crate::models::structs::http_response::HttpResponse;
+use crate::models::structs::http_server::Handler;
+
+pub type RouteHandler = Arc<dyn Fn(&HttpMessage, &PathParams) -> HttpResponse + Send + Sync>;
+
+// Values captured by ":name" and "*name" segments of the matched pattern
+#[derive(Clone, Debug, Default)]
+pub struct PathParams {
+    values: HashMap<String, String>,
+}
+
+impl PathParams {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(|value| value.as_str())
+    }
+}
+
+// How "/streams/" is treated when "/streams" is registered, and the other way around
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrailingSlash {
+    // Both paths match the route
+    Ignore,
+    // Answer 308 to the registered form
+    Redirect,
+    // Only the registered form matches
+    Strict,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Static(String),
+    Param(String),
+    // Matches the rest of the path, must be the last segment
+    Wildcard(String),
+}
+
+struct Route {
+    method: Method,
+    pattern: Vec<Segment>,
+    trailing_slash: bool,
+    handler: RouteHandler,
+}
+
+pub struct Router {
+    routes: Vec<Route>,
+    trailing_slash: TrailingSlash,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router {
+            routes: Vec::new(),
+            trailing_slash: TrailingSlash::Ignore,
+        }
+    }
+
+    pub fn with_trailing_slash(mut self, trailing_slash: TrailingSlash) -> Self {
+        self.trailing_slash = trailing_slash;
+        self
+    }
+
+    // Register a handler, routes are tried in registration order
+    // Pattern format : "/streams/:id", "/static/*path" or "/static/*"
+    pub fn route<F>(mut self, method: Method, pattern: &str, handler: F) -> Self
+        where F: Fn(&HttpMessage, &PathParams) -> HttpResponse + Send + Sync + 'static {
+
+        let mut segments: Vec<Segment> = Vec::new();
+        for segment in Self::split_path(pattern) {
+            if let Some(Segment::Wildcard(_)) = segments.last() {
+                panic!("Wildcard must be the last segment of route {}", pattern);
+            }
+
+            segments.push(if let Some(name) = segment.strip_prefix(':') {
+                Segment::Param(name.to_string())
+            }
+            else if let Some(name) = segment.strip_prefix('*') {
+                Segment::Wildcard(name.to_string())
+            }
+            else {
+                Segment::Static(segment.to_string())
+            });
+        }
+
+        self.routes.push(Route {
+            method,
+            pattern: segments,
+            trailing_slash: pattern.len() > 1 && pattern.ends_with('/'),
+            handler: Arc::new(handler),
+        });
+        self
+    }
+
+    pub fn get<F>(self, pattern: &str, handler: F) -> Self
+        where F: Fn(&HttpMessage, &PathParams) -> HttpResponse + Send + Sync + 'static {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post<F>(self, pattern: &str, handler: F) -> Self
+        where F: Fn(&HttpMessage, &PathParams) -> HttpResponse + Send + Sync + 'static {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    pub fn put<F>(self, pattern: &str, handler: F) -> Self
+        where F: Fn(&HttpMessage, &PathParams) -> HttpResponse + Send + Sync + 'static {
+        self.route(Method::Put, pattern, handler)
+    }
+
+    pub fn patch<F>(self, pattern: &str, handler: F) -> Self
+        where F: Fn(&HttpMessage, &PathParams) -> HttpResponse + Send + Sync + 'static {
+        self.route(Method::Patch, pattern, handler)
+    }
+
+    pub fn delete<F>(self, pattern: &str, handler: F) -> Self
+        where F: Fn(&HttpMessage, &PathParams) -> HttpResponse + Send + Sync + 'static {
+        self.route(Method::Delete, pattern, handler)
+    }
+
+    // Dispatch the request, answers 404 when no pattern matches
+    // and 405 with an Allow header when the pattern matches for other methods only
+    pub fn handle(&self, request: &HttpMessage) -> HttpResponse {
+        self.try_handle(request).unwrap_or_else(HttpResponse::not_found)
+    }
+
+    // Same as handle, None instead of the 404 when no pattern matches
+    // so a 404 returned by a handler can be told apart, e.g. by a fallback
+    pub fn try_handle(&self, request: &HttpMessage) -> Option<HttpResponse> {
+        let path = &request.target.path;
+        let segments = Self::split_path(path);
+        let has_trailing_slash = path.len() > 1 && path.ends_with('/');
+
+        let mut allowed: Vec<Method> = Vec::new();
+        for route in &self.routes {
+            let params = match Self::match_pattern(&route.pattern, &segments) {
+                Some(params) => params,
+                None => continue,
+            };
+
+            let ends_with_wildcard = matches!(route.pattern.last(), Some(Segment::Wildcard(_)));
+            let slash_differs = route.trailing_slash != has_trailing_slash && !ends_with_wildcard;
+            if slash_differs && self.trailing_slash == TrailingSlash::Strict {
+                continue;
+            }
+
+            //HEAD is answered by the GET handler, the body is dropped when writing
+            if route.method != request.method && !(request.method == Method::Head && route.method == Method::Get) {
+                if !allowed.contains(&route.method) {
+                    allowed.push(route.method.clone());
+                }
+                continue;
+            }
+
+            //Only redirected when the method matches, otherwise the 405 would come after a useless redirect
+            if slash_differs && self.trailing_slash == TrailingSlash::Redirect {
+                let mut location = if has_trailing_slash {
+                    path.trim_end_matches('/').to_string()
+                }
+                else {
+                    format!("{}/", path)
+                };
+                if let Some(query) = &request.target.query {
+                    location.push('?');
+                    location.push_str(query);
+                }
+                return Some(HttpResponse::redirect(308, &location));
+            }
+            return Some((route.handler)(request, &params));
+        }
+
+        if allowed.is_empty() {
+            return None;
+        }
+
+        if allowed.contains(&Method::Get) && !allowed.contains(&Method::Head) {
+            allowed.push(Method::Head);
+        }
+        let allow_value = allowed.iter().map(|method| method.as_str()).collect::<Vec<&str>>().join(", ");
+        Some(HttpResponse::error(405).with_header("Allow", &allow_value))
+    }
+
+    pub fn into_handler(self) -> Handler {
+        let router = Arc::new(self);
+        Arc::new(move |request: &mut HttpMessage| router.handle(request))
+    }
+
+    fn match_pattern(pattern: &[Segment], segments: &[&str]) -> Option<PathParams> {
+        let mut params = PathParams::default();
+
+        for (index, pattern_segment) in pattern.iter().enumerate() {
+            match pattern_segment {
+                Segment::Wildcard(name) => {
+                    if !name.is_empty() {
+                        params.values.insert(name.clone(), segments.get(index..).unwrap_or_default().join("/"));
+                    }
+                    return Some(params);
+                },
+                Segment::Static(expected) => {
+                    if segments.get(index) != Some(&expected.as_str()) {
+                        return None;
+                    }
+                },
+                Segment::Param(name) => {
+                    match segments.get(index) {
+                        Some(value) => {
+                            params.values.insert(name.clone(), value.to_string());
+                        },
+                        None => return None,
+                    }
+                }
+            }
+        }
+
+        if segments.len() == pattern.len() {
+            Some(params)
+        }
+        else {
+            None
+        }
+    }
+
+    fn split_path(path: &str) -> Vec<&str> {
+        path.split('/').filter(|segment| !segment.is_empty()).collect()
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send(router: &Router, raw: &str) -> HttpResponse {
+        router.handle(&HttpMessage::new(raw.as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn checks_the_method_before_redirecting() {
+        let router = Router::new()
+            .with_trailing_slash(TrailingSlash::Redirect)
+            .get("/streams", |_request, _params| HttpResponse::text(200, "streams"));
+
+        let response = send(&router, "GET /streams/?page=2 HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert_eq!(response.status, 308);
+        assert_eq!(response.headers.get("Location"), Some("/streams?page=2"));
+
+        let response = send(&router, "POST /streams/ HTTP/1.1\r\nHost: a\r\nContent-Length: 0\r\n\r\n");
+        assert_eq!(response.status, 405);
+        assert_eq!(response.headers.get("Allow"), Some("GET, HEAD"));
+
+        assert_eq!(send(&router, "GET /other/ HTTP/1.1\r\nHost: a\r\n\r\n").status, 404);
+    }
+
+    #[test]
+    fn captures_parameters_and_wildcards() {
+        let router = Router::new()
+            .get("/streams/:id/frames/:frame", |_request, params| {
+                HttpResponse::text(200, &format!("{} {}", params.get("id").unwrap(), params.get("frame").unwrap()))
+            })
+            .get("/static/*path", |_request, params| HttpResponse::text(200, params.get("path").unwrap()))
+            .get("/any/*", |_request, params| HttpResponse::text(200, &format!("{:?}", params.get("path"))));
+
+        assert_eq!(send(&router, "GET /streams/42/frames/7 HTTP/1.1\r\nHost: a\r\n\r\n").body, b"42 7");
+        assert_eq!(send(&router, "GET /streams/42/frames HTTP/1.1\r\nHost: a\r\n\r\n").status, 404);
+        assert_eq!(send(&router, "GET /streams/42/frames/7/8 HTTP/1.1\r\nHost: a\r\n\r\n").status, 404);
+
+        //The wildcard takes the rest of the path, possibly nothing
+        assert_eq!(send(&router, "GET /static/css/site.css HTTP/1.1\r\nHost: a\r\n\r\n").body, b"css/site.css");
+        assert_eq!(send(&router, "GET /static HTTP/1.1\r\nHost: a\r\n\r\n").body, b"");
+        assert_eq!(send(&router, "GET /any/thing HTTP/1.1\r\nHost: a\r\n\r\n").body, b"None");
+    }
+
+    #[test]
+    fn answers_404_and_405_with_the_allowed_methods() {
+        let router = Router::new()
+            .get("/streams", |_request, _params| HttpResponse::text(200, "list"))
+            .post("/streams", |_request, _params| HttpResponse::text(201, "created"))
+            .delete("/streams/:id", |_request, _params| HttpResponse::new(204));
+
+        assert_eq!(send(&router, "POST /streams HTTP/1.1\r\nHost: a\r\nContent-Length: 0\r\n\r\n").status, 201);
+        //HEAD is answered by the GET handler
+        assert_eq!(send(&router, "HEAD /streams HTTP/1.1\r\nHost: a\r\n\r\n").status, 200);
+
+        let response = send(&router, "PUT /streams HTTP/1.1\r\nHost: a\r\nContent-Length: 0\r\n\r\n");
+        assert_eq!(response.status, 405);
+        assert_eq!(response.headers.get("Allow"), Some("GET, POST, HEAD"));
+        let response = send(&router, "GET /streams/1 HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert_eq!(response.status, 405);
+        assert_eq!(response.headers.get("Allow"), Some("DELETE"));
+
+        let response = send(&router, "GET /missing HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert_eq!(response.status, 404);
+        assert!(!response.headers.contains("Allow"));
+        assert!(router.try_handle(&HttpMessage::new(&b"GET /missing HTTP/1.1\r\nHost: a\r\n\r\n"[..]).unwrap()).is_none());
+    }
+}
@@ -0,0 +1,669 @@
+use std::{io::{self, Write}, net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream}, sync::{atomic::{AtomicBool, Ordering}, mpsc, Arc, Mutex}, thread::{self, JoinHandle}, time::{Duration, Instant}};
+
+use crate::models::structs::http2_connection::{Http2Config, Http2Connection};
+use crate::models::structs::http_access_log::{AccessLog, AccessLogEntry};
+use crate::models::structs::http_compression::CompressionConfig;
+use crate::models::structs::http_connection::HttpConnection;
+use crate::models::structs::http_connection_limiter::{ConnectionLimiter, ConnectionPermit, PermittedStream, WorkerSlot};
+use crate::models::structs::http_error::HttpError;
+use crate::models::structs::http_message::{HttpLimits, HttpMessage, StreamedBody};
+use crate::models::structs::http_method::Method;
+use crate::models::structs::http_parser::{StreamedBodies, StreamedLength};
+use crate::models::structs::http_response::{HttpResponse, UpgradeHandler};
+use crate::models::structs::http_stream::HttpStream;
+use crate::models::structs::http_tls::{TlsAcceptor, TlsConfig, TlsServerName};
+use crate::models::structs::http_version::Version;
+
+// Body chunks read ahead of a streamed request's handler
+const STREAMED_BODY_BOUND: usize = 8;
+
+// The request is mutable so middlewares can attach extensions, see MiddlewareChain
+pub type Handler = Arc<dyn Fn(&mut HttpMessage) -> HttpResponse + Send + Sync>;
+
+#[derive(Clone, Debug)]
+pub struct HttpServerConfig {
+    pub address: IpAddr,
+    pub port: u16,
+    // Number of threads handling connections
+    pub workers: usize,
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+    // How long a kept-alive connection may wait for its next request
+    pub idle_timeout: Duration,
+    pub max_requests_per_connection: usize,
+    // Open connections, accepting pauses at the limit and clients wait in the listen backlog
+    pub max_connections: usize,
+    // Open connections per client address, more are answered 503 and closed, None for no limit
+    // Upgraded connections, e.g. WebSockets and event streams, count here but no longer against the workers
+    pub max_connections_per_ip: Option<usize>,
+    // Connections per client address waiting for or held by a worker, more are answered 503 and closed, None for no limit
+    // Below workers, one address can't hold all of them. Browsers open about 6 connections per host
+    // and clients behind a NAT or a proxy share an address, so keep it well above what one client needs
+    pub max_in_flight_per_ip: Option<usize>,
+    // Overall time allowed for the TLS handshake, whatever the pace of the client
+    pub tls_handshake_timeout: Duration,
+    pub limits: HttpLimits,
+    // Request bodies reaching the handler as they are received instead of whole, HTTP/1.x only
+    // The handler then runs on its own thread while the worker reads the body, see StreamedBody
+    // None by default, request.body stays empty for these requests so every handler must read them from the StreamedBody,
+    // with HttpMessage::multipart_stream for Multipart, and All suits a ReverseProxy
+    pub streamed_bodies: StreamedBodies,
+    // Response compression negotiated with Accept-Encoding, None to always send identity
+    pub compression: Option<CompressionConfig>,
+    // HTTPS when set
+    pub tls: Option<TlsConfig>,
+    // HTTP/2 next to HTTP/1.1, None to only serve HTTP/1.x
+    pub http2: Option<Http2Config>,
+    // One line per response written, None to disable
+    pub access_log: Option<Arc<AccessLog>>,
+}
+
+impl Default for HttpServerConfig {
+    fn default() -> Self {
+        HttpServerConfig {
+            address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 8000,
+            workers: 4,
+            read_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(5),
+            max_requests_per_connection: 100,
+            max_connections: 1024,
+            max_connections_per_ip: Some(32),
+            max_in_flight_per_ip: None,
+            tls_handshake_timeout: Duration::from_secs(10),
+            limits: HttpLimits::default(),
+            streamed_bodies: StreamedBodies::None,
+            compression: Some(CompressionConfig::default()),
+            tls: None,
+            http2: Some(Http2Config::default()),
+            access_log: None,
+        }
+    }
+}
+
+pub struct HttpServer {
+    pub local_addr: SocketAddr,
+    should_stop: Arc<AtomicBool>,
+    accept_thread: Option<JoinHandle<()>>,
+    worker_threads: Vec<JoinHandle<()>>,
+    tls_acceptor: Option<Arc<TlsAcceptor>>,
+    tls_reload_thread: Option<JoinHandle<()>>,
+}
+
+impl HttpServer {
+    // Bind the listener and spawn the accept thread and the workers
+    pub fn start(config: HttpServerConfig, handler: Handler) -> io::Result<Self> {
+        let listener = TcpListener::bind(SocketAddr::new(config.address, config.port))?;
+        //Non blocking so the accept loop can look at should_stop
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+
+        let should_stop = Arc::new(AtomicBool::new(false));
+
+        //Certificates are loaded before accepting anything so a bad file fails the start
+        //HTTP/2 is offered first with ALPN, clients without it stay on HTTP/1.1
+        let alpn_protocols = match &config.http2 {
+            Some(_) => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            None => Vec::new(),
+        };
+        let tls_acceptor = match &config.tls {
+            Some(tls_config) => Some(Arc::new(TlsAcceptor::new(tls_config.clone(), alpn_protocols)?)),
+            None => None,
+        };
+        let tls_reload_thread = match (&tls_acceptor, config.tls.as_ref().and_then(|tls_config| tls_config.reload_interval)) {
+            (Some(tls_acceptor), Some(reload_interval)) => Some(tls_acceptor.new_reload_thread(reload_interval, should_stop.clone())),
+            _ => None,
+        };
+
+        let (sender, receiver) = mpsc::channel::<(TcpStream, ConnectionPermit, WorkerSlot)>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let config = Arc::new(config);
+        let mut worker_threads = Vec::new();
+        for worker_id in 0..config.workers.max(1) {
+            worker_threads.push(Self::new_worker_thread(worker_id, receiver.clone(), config.clone(),
+                handler.clone(), should_stop.clone(), tls_acceptor.clone()));
+        }
+
+        let connection_limiter = ConnectionLimiter::new(config.max_connections.max(1), config.max_connections_per_ip, config.max_in_flight_per_ip);
+        let tls = config.tls.is_some();
+        let accept_should_stop = should_stop.clone();
+        let accept_thread = thread::spawn(move || {
+            println!("Http server listening on {}", local_addr);
+            loop {
+                if accept_should_stop.load(Ordering::Relaxed) {
+                    break
+                }
+                //Backpressure, connections wait in the listen backlog until one closes
+                if !connection_limiter.has_capacity() {
+                    thread::sleep(Duration::from_millis(10));
+                    continue
+                }
+
+                match listener.accept() {
+                    Ok((tcp_stream, peer_addr)) => {
+                        let (permit, worker_slot) = match connection_limiter.acquire(peer_addr.ip()) {
+                            Some(acquired) => acquired,
+                            None => {
+                                Self::refuse(tcp_stream, tls);
+                                continue
+                            },
+                        };
+                        if sender.send((tcp_stream, permit, worker_slot)).is_err() {
+                            break
+                        }
+                    },
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(10));
+                    },
+                    Err(err) => {
+                        println!("Error while accepting connection {:?}", err);
+                    }
+                }
+            }
+            //Dropping the sender stops the workers once the queue is empty
+        });
+
+        Ok(HttpServer {
+            local_addr,
+            should_stop,
+            accept_thread: Some(accept_thread),
+            worker_threads,
+            tls_acceptor,
+            tls_reload_thread,
+        })
+    }
+
+    pub fn stop(&mut self) -> Result<(), String> {
+        self.should_stop.store(true, Ordering::Relaxed);
+
+        if let Some(accept_thread) = self.accept_thread.take() {
+            if let Err(err) = accept_thread.join() {
+                println!("Error while stoping accept thread {:?}", err);
+                return Err("Error while stoping accept thread".to_string())
+            }
+        }
+
+        for worker_thread in self.worker_threads.drain(..) {
+            if let Err(err) = worker_thread.join() {
+                println!("Error while stoping http worker thread {:?}", err);
+                return Err("Error while stoping http worker thread".to_string())
+            }
+        }
+
+        if let Some(tls_reload_thread) = self.tls_reload_thread.take() {
+            if let Err(err) = tls_reload_thread.join() {
+                println!("Error while stoping tls reload thread {:?}", err);
+                return Err("Error while stoping tls reload thread".to_string())
+            }
+        }
+
+        println!("Http server properly stopped");
+        Ok(())
+    }
+
+    // Read the certificate files again without restarting
+    pub fn reload_certificates(&self) -> Result<(), String> {
+        match &self.tls_acceptor {
+            Some(tls_acceptor) => tls_acceptor.store.reload().map_err(|err| {
+                println!("Error while reloading tls certificates {:?}", err);
+                "Error while reloading tls certificates".to_string()
+            }),
+            None => Err("Http server is not using tls".to_string())
+        }
+    }
+
+    // Answer 503 to a connection above the per address limits, from the accept thread without reading the request
+    // A TLS client expects a handshake first, its connection is only closed
+    fn refuse(mut tcp_stream: TcpStream, tls: bool) {
+        if tls {
+            return
+        }
+        let mut response = HttpResponse::error(503).with_header("Retry-After", "1");
+        response.keep_alive = false;
+        //A new socket has room for it in its send buffer, the write doesn't block the accept loop
+        let _ = tcp_stream.write_all(&response.to_bytes(true));
+    }
+
+    fn new_worker_thread(worker_id: usize,
+        receiver: Arc<Mutex<mpsc::Receiver<(TcpStream, ConnectionPermit, WorkerSlot)>>>,
+        config: Arc<HttpServerConfig>,
+        handler: Handler,
+        should_stop: Arc<AtomicBool>,
+        tls_acceptor: Option<Arc<TlsAcceptor>>) -> JoinHandle<()> {
+
+        thread::spawn(move || {
+            loop {
+                let tcp_stream = {
+                    match receiver.lock() {
+                        Ok(receiver) => receiver.recv(),
+                        Err(_) => break,
+                    }
+                };
+
+                match tcp_stream {
+                    //The worker slot is released once the connection is handled, the permit goes with the stream
+                    Ok((tcp_stream, permit, _worker_slot)) => {
+                        if let Err(err) = Self::handle_connection(tcp_stream, permit, &config, &handler, &should_stop, tls_acceptor.as_deref()) {
+                            println!("Worker {} - error while handling connection {:?}", worker_id, err);
+                        }
+                    },
+                    Err(_) => break,
+                }
+            }
+        })
+    }
+
+    fn handle_connection(tcp_stream: TcpStream,
+        permit: ConnectionPermit,
+        config: &Arc<HttpServerConfig>,
+        handler: &Handler,
+        should_stop: &Arc<AtomicBool>,
+        tls_acceptor: Option<&TlsAcceptor>) -> io::Result<()> {
+        //Accepted sockets inherit the non blocking mode of the listener on some platforms
+        tcp_stream.set_nonblocking(false)?;
+        tcp_stream.set_read_timeout(Some(config.read_timeout))?;
+        tcp_stream.set_write_timeout(Some(config.write_timeout))?;
+
+        let stream: Box<dyn HttpStream> = match tls_acceptor {
+            Some(tls_acceptor) => tls_acceptor.accept(tcp_stream, config.tls_handshake_timeout)?,
+            None => Box::new(tcp_stream),
+        };
+        //Upgraded and detached connections outlive this worker, the permit stays with the socket
+        let stream: Box<dyn HttpStream> = Box::new(PermittedStream::new(stream, permit));
+
+        if stream.alpn_protocol().as_deref() == Some(b"h2") {
+            return Http2Connection::new(stream, Vec::new(), config.clone(), handler.clone(), should_stop.clone()).serve()
+        }
+
+        let mut connection = HttpConnection::new(stream).with_streamed_bodies(config.streamed_bodies);
+        //Cleartext clients may start with HTTP/2 directly when they know the server supports it
+        if config.http2.as_ref().is_some_and(|http2| http2.h2c) && !connection.stream.is_tls() {
+            match connection.detect_http2_preface(config.idle_timeout) {
+                Ok(Some(true)) => {
+                    let (stream, buffer) = connection.into_parts();
+                    return Http2Connection::new(stream, buffer, config.clone(), handler.clone(), should_stop.clone()).serve()
+                },
+                Ok(Some(false)) => {},
+                Ok(None) | Err(HttpError::ConnectionClosed) => {
+                    connection.stream.close();
+                    return Ok(())
+                },
+                Err(HttpError::Io(err)) => return Err(err),
+                Err(err) => {
+                    println!("Error while detecting http2 preface {}", err);
+                    return Ok(())
+                }
+            }
+        }
+
+        let limits = HttpLimits {
+            read_timeout: Some(config.read_timeout),
+            ..config.limits.clone()
+        };
+
+        //Requests are answered in order, which keeps pipelined responses ordered
+        match Self::serve_connection(&mut connection, config, handler, should_stop, &limits) {
+            Ok(Some(upgrade)) => {
+                let (stream, buffer) = connection.into_parts();
+                stream.set_read_timeout(None)?;
+                stream.set_write_timeout(None)?;
+                upgrade(stream, buffer);
+                Ok(())
+            },
+            Ok(None) => {
+                connection.stream.close();
+                Ok(())
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    // Answer requests until the connection should close, returns the protocol to switch to if any
+    fn serve_connection(connection: &mut HttpConnection,
+        config: &Arc<HttpServerConfig>,
+        handler: &Handler,
+        should_stop: &Arc<AtomicBool>,
+        limits: &HttpLimits) -> io::Result<Option<UpgradeHandler>> {
+        loop {
+            let mut request = match connection.read_request(limits, config.idle_timeout) {
+                Ok(Some(request)) => request,
+                Ok(None) | Err(HttpError::ConnectionClosed) => return Ok(None),
+                Err(HttpError::Io(err)) => return Err(err),
+                Err(err) => return Self::reject(connection, err),
+            };
+            //A HTTP/1.1 request carries exactly one Host (RFC 9112 3.2)
+            if request.version == Version::Http11 && request.headers.get_all("Host").len() != 1 {
+                return Self::reject(connection, HttpError::Malformed("Missing or repeated Host".to_string()))
+            }
+            request.peer_addr = connection.stream.peer_addr().ok();
+            if let Some(server_name) = connection.stream.server_name() {
+                request.extensions.insert(TlsServerName(server_name));
+            }
+            let started = Instant::now();
+
+            if let Some(http2_settings) = Self::h2c_upgrade_settings(&request, config, connection) {
+                //The request is answered on stream 1 once the connection is upgraded
+                let config = config.clone();
+                let handler = handler.clone();
+                let should_stop = should_stop.clone();
+                let mut response = HttpResponse::new(101).with_header("Upgrade", "h2c");
+                response.upgrade = Some(Box::new(move |stream, buffer| {
+                    if let Err(err) = Http2Connection::new(stream, buffer, config, handler, should_stop).serve_upgraded(request, &http2_settings) {
+                        println!("Error while serving upgraded http2 connection {:?}", err);
+                    }
+                }));
+                response.write_to(&mut connection.stream, false)?;
+                return Ok(response.upgrade.take())
+            }
+
+            let (mut response, body_read) = match connection.take_streamed_body() {
+                Some(body_length) => Self::respond_streamed(connection, handler, &mut request, config, limits, body_length),
+                None => (Self::respond(handler, &mut request, config), true),
+            };
+            //A HTTP/1.0 client may not understand HTTP/1.1 framing like chunked bodies
+            if request.version == Version::Http10 {
+                response.version = Version::Http10;
+            }
+            response.keep_alive = response.keep_alive
+                && body_read
+                && request.keep_alive()
+                && connection.requests_served < config.max_requests_per_connection
+                && !should_stop.load(Ordering::Relaxed);
+            if response.detached && response.body_stream.is_some() && request.method != Method::Head {
+                return Ok(Some(Self::detach(response, request, config)))
+            }
+            let bytes_sent = response.write_to(&mut connection.stream, request.method != Method::Head)?;
+            if let Some(access_log) = &config.access_log {
+                access_log.log(&AccessLogEntry::new(&request, response.status, bytes_sent, started.elapsed()));
+            }
+
+            if response.status == 101 && response.upgrade.is_some() {
+                return Ok(response.upgrade.take())
+            }
+
+            if !response.keep_alive {
+                return Ok(None)
+            }
+        }
+    }
+
+    // Write a long-lived body stream on its own thread, the worker moves on to other connections
+    fn detach(mut response: HttpResponse, request: HttpMessage, config: &Arc<HttpServerConfig>) -> UpgradeHandler {
+        let write_timeout = config.write_timeout;
+        let access_log = config.access_log.clone();
+        let started = Instant::now();
+        response.keep_alive = false;
+
+        Box::new(move |mut stream, _buffer| {
+            thread::spawn(move || {
+                let result = stream.set_write_timeout(Some(write_timeout)).and_then(|_| response.write_to(&mut stream, true));
+                match result {
+                    Ok(bytes_sent) => {
+                        if let Some(access_log) = &access_log {
+                            access_log.log(&AccessLogEntry::new(&request, response.status, bytes_sent, started.elapsed()));
+                        }
+                    },
+                    Err(err) => println!("Error while writing detached response {:?}", err),
+                }
+                stream.close();
+            });
+        })
+    }
+
+    // Answer an invalid request then close, the rest of the stream can't be trusted
+    fn reject(connection: &mut HttpConnection, err: HttpError) -> io::Result<Option<UpgradeHandler>> {
+        //Not in the access log, there may be no request line to log
+        println!("Invalid request {}", err);
+        let mut response = HttpResponse::error(err.status_code());
+        response.keep_alive = false;
+        response.write_to(&mut connection.stream, true)?;
+        Ok(None)
+    }
+
+    // HTTP2-Settings of a request asking to switch to HTTP/2 over cleartext (RFC 7540 3.2)
+    fn h2c_upgrade_settings(request: &HttpMessage, config: &HttpServerConfig, connection: &HttpConnection) -> Option<String> {
+        if !config.http2.as_ref().is_some_and(|http2| http2.h2c) || connection.stream.is_tls() || request.version != Version::Http11 {
+            return None
+        }
+        if !request.headers.contains_token("Upgrade", "h2c") || !request.headers.contains_token("Connection", "HTTP2-Settings") {
+            return None
+        }
+
+        match request.headers.get_all("HTTP2-Settings").as_slice() {
+            [http2_settings] => Some(http2_settings.to_string()),
+            _ => None,
+        }
+    }
+
+    // Call the handler on its own thread while the body is forwarded to it, returns whether the body was read whole
+    fn respond_streamed(connection: &mut HttpConnection,
+        handler: &Handler,
+        request: &mut HttpMessage,
+        config: &HttpServerConfig,
+        limits: &HttpLimits,
+        body_length: StreamedLength) -> (HttpResponse, bool) {
+        let (sender, receiver) = mpsc::sync_channel(STREAMED_BODY_BOUND);
+        let length = match body_length {
+            StreamedLength::Fixed(length) => Some(length),
+            StreamedLength::Chunked => None,
+        };
+        request.extensions.insert(StreamedBody::new(receiver, length));
+
+        thread::scope(|scope| {
+            let responder = scope.spawn(|| {
+                let response = Self::respond(handler, request, config);
+                //The rest of the body is of no use once the handler returned, the worker stops forwarding it
+                drop(request.extensions.remove::<StreamedBody>());
+                response
+            });
+            let body_read = connection.forward_body(body_length, limits, &sender);
+            //The handler sees the end of the body
+            drop(sender);
+            match responder.join() {
+                Ok(response) => (response, body_read),
+                Err(_) => {
+                    println!("Error while handling streamed request, the handler panicked");
+                    (HttpResponse::error(500), body_read)
+                },
+            }
+        })
+    }
+
+    // Handlers are kept-alive by default, they can opt out with keep_alive = false
+    pub fn respond(handler: &Handler, request: &mut HttpMessage, config: &HttpServerConfig) -> HttpResponse {
+        if let Some(compression) = &config.compression {
+            if let Err(status) = compression.decompress_request(request, config.limits.max_body_size) {
+                return HttpResponse::error(status);
+            }
+        }
+
+        let mut response = handler(request);
+        if let Some(compression) = &config.compression {
+            response = compression.compress_response(request, response);
+        }
+        if response.headers.contains_token("Connection", "close") {
+            response.keep_alive = false;
+        }
+        response
+    }
+}
+
+impl Drop for HttpServer {
+    fn drop(&mut self) {
+        if self.accept_thread.is_some() {
+            let _ = self.stop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::{Read, Write}, net::TcpStream};
+    use crate::models::structs::http_parser::HttpParser;
+    use crate::models::structs::http_router::Router;
+
+    fn start(config: HttpServerConfig, handler: Handler) -> HttpServer {
+        HttpServer::start(HttpServerConfig { address: IpAddr::V4(Ipv4Addr::LOCALHOST), port: 0, ..config }, handler).unwrap()
+    }
+
+    fn connect(server: &HttpServer) -> TcpStream {
+        let stream = TcpStream::connect(server.local_addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream
+    }
+
+    // Everything received until the server closes the connection
+    fn read_to_close(stream: &mut TcpStream) -> String {
+        let mut received = Vec::new();
+        let _ = stream.read_to_end(&mut received);
+        String::from_utf8_lossy(&received).to_string()
+    }
+
+    // Next response on a kept-alive connection, buf holds the bytes received after it
+    fn read_response(stream: &mut TcpStream, buf: &mut Vec<u8>) -> HttpResponse {
+        let mut parser = HttpParser::for_response(&HttpLimits::default(), &Method::Get);
+        loop {
+            if let Some(response) = parser.parse_response(buf, false).unwrap() {
+                return response
+            }
+            let mut chunk = [0u8; 1024];
+            let nb_bytes_read = stream.read(&mut chunk).unwrap();
+            assert!(nb_bytes_read > 0, "Connection closed before the response");
+            buf.extend_from_slice(&chunk[..nb_bytes_read]);
+        }
+    }
+
+    fn echo_path() -> Handler {
+        Arc::new(|request: &mut HttpMessage| HttpResponse::text(200, &request.target.path))
+    }
+
+    #[test]
+    fn stops_forwarding_bodies_left_by_the_handler() {
+        let config = HttpServerConfig { workers: 1, streamed_bodies: StreamedBodies::Multipart, ..HttpServerConfig::default() };
+        let server = start(config, Router::new().get("/", |_request, _params| HttpResponse::text(200, "index")).into_handler());
+
+        //Far more chunks than the channel holds, the route doesn't exist so nobody reads them
+        let mut body = b"--b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.bin\"\r\n\r\n".to_vec();
+        body.extend_from_slice(&[b'x'; 64 * 1024]);
+        body.extend_from_slice(b"\r\n--b--\r\n");
+        let head = format!("POST /upload HTTP/1.1\r\nHost: a\r\nContent-Type: multipart/form-data; boundary=b\r\nContent-Length: {}\r\n\r\n", body.len());
+        let mut stream = connect(&server);
+        let mut writer = stream.try_clone().unwrap();
+        let upload = thread::spawn(move || {
+            let _ = writer.write_all(head.as_bytes()).and_then(|_| writer.write_all(&body));
+        });
+        assert!(read_to_close(&mut stream).starts_with("HTTP/1.1 404 "));
+        upload.join().unwrap();
+
+        //The worker is free for the next connection
+        let mut stream = connect(&server);
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(read_to_close(&mut stream).ends_with("index"));
+    }
+
+    #[test]
+    fn answers_connections_above_the_address_limit() {
+        let config = HttpServerConfig { workers: 2, max_in_flight_per_ip: Some(1), ..HttpServerConfig::default() };
+        let server = start(config, Arc::new(|_request: &mut HttpMessage| HttpResponse::text(200, "ok")));
+
+        //Kept alive, the first connection holds a worker
+        let mut first = connect(&server);
+        first.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        let mut response = [0u8; 1024];
+        assert!(first.read(&mut response).unwrap() > 0);
+
+        let mut second = connect(&server);
+        let response = read_to_close(&mut second);
+        assert!(response.starts_with("HTTP/1.1 503 "), "{}", response);
+        assert!(response.contains("Retry-After: 1\r\n"));
+
+        //Served again once the first one is closed
+        drop(first);
+        thread::sleep(Duration::from_millis(100));
+        let mut third = connect(&server);
+        third.write_all(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(read_to_close(&mut third).ends_with("ok"));
+    }
+
+    #[test]
+    fn stops_after_answering_requests_in_progress() {
+        let config = HttpServerConfig { workers: 2, idle_timeout: Duration::from_millis(200), ..HttpServerConfig::default() };
+        let mut server = start(config, Arc::new(|_request: &mut HttpMessage| {
+            thread::sleep(Duration::from_millis(300));
+            HttpResponse::text(200, "done")
+        }));
+        let address = server.local_addr;
+
+        let mut stream = connect(&server);
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let stopping = thread::spawn(move || {
+            server.stop().unwrap();
+            server
+        });
+
+        //Answered, then closed instead of kept alive
+        let response = read_to_close(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(response.ends_with("done"));
+        let _server = stopping.join().unwrap();
+        //The listener is closed with the accept thread
+        assert!(TcpStream::connect(address).is_err());
+    }
+
+    #[test]
+    fn answers_pipelined_requests_in_order_on_kept_alive_connections() {
+        let config = HttpServerConfig { workers: 1, max_requests_per_connection: 4, ..HttpServerConfig::default() };
+        let server = start(config, echo_path());
+        let mut stream = connect(&server);
+        let mut buf = Vec::new();
+
+        stream.write_all(b"GET /first HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        let response = read_response(&mut stream, &mut buf);
+        assert!(response.keep_alive);
+        assert_eq!(response.body, b"/first");
+
+        //Sent at once, answered one after the other on the same connection
+        stream.write_all(b"GET /second HTTP/1.1\r\nHost: a\r\n\r\nPOST /third HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\n\r\nabcGET /fourth HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        assert_eq!(read_response(&mut stream, &mut buf).body, b"/second");
+        assert_eq!(read_response(&mut stream, &mut buf).body, b"/third");
+        //The last request allowed on the connection
+        let response = read_response(&mut stream, &mut buf);
+        assert_eq!(response.body, b"/fourth");
+        assert!(!response.keep_alive);
+        assert!(buf.is_empty());
+        assert_eq!(read_to_close(&mut stream), "");
+    }
+
+    #[test]
+    fn closes_connections_when_asked() {
+        let server = start(HttpServerConfig { workers: 2, ..HttpServerConfig::default() }, echo_path());
+
+        let mut stream = connect(&server);
+        stream.write_all(b"GET /close HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\nGET /ignored HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        let response = read_to_close(&mut stream);
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(response.ends_with("/close"), "{}", response);
+
+        //HTTP/1.0 closes unless the client asks for keep-alive
+        let mut stream = connect(&server);
+        stream.write_all(b"GET /old HTTP/1.0\r\n\r\n").unwrap();
+        let response = read_to_close(&mut stream);
+        assert!(response.starts_with("HTTP/1.0 200 "));
+        assert!(response.ends_with("/old"));
+
+        let mut stream = connect(&server);
+        let mut buf = Vec::new();
+        stream.write_all(b"GET /old HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream, &mut buf).keep_alive);
+        stream.write_all(b"GET /last HTTP/1.0\r\n\r\n").unwrap();
+        let response = read_response(&mut stream, &mut buf);
+        assert_eq!(response.body, b"/last");
+        assert!(!response.keep_alive);
+        assert_eq!(read_to_close(&mut stream), "");
+    }
+}
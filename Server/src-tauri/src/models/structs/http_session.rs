@@ -0,0 +1,481 @@
+use std::{collections::{BTreeMap, HashMap}, fs, io, path::PathBuf, sync::{Arc, Mutex}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::models::structs::http_cookie::{SameSite, SetCookie};
+use crate::models::structs::http_message::HttpMessage;
+use crate::models::structs::http_response::HttpResponse;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Identifiers are 32 random bytes in hex, also used as file names
+const SESSION_ID_LENGTH: usize = 32;
+const MIN_SECRET_LENGTH: usize = 32;
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+// A temporary file left this long is from a write that was interrupted, a save takes far less
+const STALE_TEMPORARY_FILE_AGE: Duration = Duration::from_secs(60);
+
+// What a store keeps for a session
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub values: BTreeMap<String, String>,
+    // Seconds since the Unix epoch
+    pub expires_at: u64,
+}
+
+impl SessionRecord {
+    pub fn is_expired(&self) -> bool {
+        unix_seconds(SystemTime::now()) >= self.expires_at
+    }
+}
+
+// Server side storage of the sessions, shared by the worker threads
+pub trait SessionStore: Send + Sync {
+    fn load(&self, id: &str) -> io::Result<Option<SessionRecord>>;
+
+    fn save(&self, id: &str, record: &SessionRecord) -> io::Result<()>;
+
+    fn remove(&self, id: &str) -> io::Result<()>;
+
+    // Drop the expired sessions
+    fn purge_expired(&self) -> io::Result<()>;
+}
+
+// Sessions kept in memory, lost when the server restarts
+#[derive(Default)]
+pub struct MemorySessionStore {
+    records: Mutex<HashMap<String, SessionRecord>>,
+}
+
+impl MemorySessionStore {
+    pub fn new() -> Self {
+        MemorySessionStore {
+            records: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn lock(&self) -> io::Result<std::sync::MutexGuard<'_, HashMap<String, SessionRecord>>> {
+        self.records.lock().map_err(|_| io::Error::other("Session store lock poisoned"))
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self, id: &str) -> io::Result<Option<SessionRecord>> {
+        Ok(self.lock()?.get(id).cloned())
+    }
+
+    fn save(&self, id: &str, record: &SessionRecord) -> io::Result<()> {
+        self.lock()?.insert(id.to_string(), record.clone());
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        self.lock()?.remove(id);
+        Ok(())
+    }
+
+    fn purge_expired(&self) -> io::Result<()> {
+        self.lock()?.retain(|_, record| !record.is_expired());
+        Ok(())
+    }
+}
+
+// Sessions kept as one JSON file per session, survive restarts
+pub struct FileSessionStore {
+    directory: PathBuf,
+}
+
+impl FileSessionStore {
+    // The directory is created if missing
+    pub fn new(directory: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&directory)?;
+        Ok(FileSessionStore {
+            directory,
+        })
+    }
+
+    fn path(&self, id: &str) -> io::Result<PathBuf> {
+        //Identifiers come from cookies, never let one escape the directory
+        if id.is_empty() || !id.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid session id"));
+        }
+        Ok(self.directory.join(format!("{}.json", id)))
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    fn load(&self, id: &str) -> io::Result<Option<SessionRecord>> {
+        let content = match fs::read(self.path(id)?) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        match serde_json::from_slice(&content) {
+            Ok(record) => Ok(Some(record)),
+            Err(err) => {
+                println!("Invalid session file {} {:?}", id, err);
+                Ok(None)
+            }
+        }
+    }
+
+    fn save(&self, id: &str, record: &SessionRecord) -> io::Result<()> {
+        let path = self.path(id)?;
+        let content = serde_json::to_vec(record).map_err(io::Error::other)?;
+
+        //Written aside then renamed, a concurrent load never sees half a file
+        let temporary_path = path.with_extension("json.tmp");
+        fs::write(&temporary_path, content)?;
+        fs::rename(&temporary_path, &path)
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(id)?) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    fn purge_expired(&self) -> io::Result<()> {
+        for entry in fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            if path.to_string_lossy().ends_with(".json.tmp") {
+                let stale = fs::metadata(&path)
+                    .and_then(|metadata| metadata.modified())
+                    .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age >= STALE_TEMPORARY_FILE_AGE));
+                if stale {
+                    let _ = fs::remove_file(&path);
+                }
+                continue;
+            }
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+
+            let expired = fs::read(&path)
+                .ok()
+                .and_then(|content| serde_json::from_slice::<SessionRecord>(&content).ok())
+                .is_none_or(|record| record.is_expired());
+            if expired {
+                let _ = fs::remove_file(&path);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SessionConfig {
+    pub cookie_name: String,
+    // Lifetime of a session since its last save
+    pub ttl: Duration,
+    pub cookie_path: String,
+    // Should be true when served over HTTPS
+    pub secure: bool,
+    pub same_site: SameSite,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            cookie_name: "session".to_string(),
+            ttl: Duration::from_secs(24 * 3600),
+            cookie_path: "/".to_string(),
+            secure: false,
+            same_site: SameSite::Lax,
+        }
+    }
+}
+
+// Values of one client's session, see SessionManager::load and SessionManager::save
+#[derive(Clone, Debug, Default)]
+pub struct Session {
+    // None until the session is saved for the first time
+    id: Option<String>,
+    values: BTreeMap<String, String>,
+    modified: bool,
+    destroyed: bool,
+    // The previous identifier to remove from the store, see regenerate
+    replaced_id: Option<String>,
+}
+
+impl Session {
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|value| value.as_str())
+    }
+
+    pub fn insert(&mut self, key: &str, value: &str) {
+        self.values.insert(key.to_string(), value.to_string());
+        self.modified = true;
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let value = self.values.remove(key);
+        self.modified |= value.is_some();
+        value
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    // Delete the session from the store and the client, e.g. on logout
+    pub fn destroy(&mut self) {
+        self.values.clear();
+        self.destroyed = true;
+    }
+
+    // Move the values to a new identifier, to call when the privilege level changes (login)
+    // so an identifier known before by someone else becomes useless (session fixation)
+    pub fn regenerate(&mut self) {
+        if let Some(id) = self.id.take() {
+            self.replaced_id = Some(id);
+        }
+        self.modified = true;
+    }
+}
+
+// Sessions identified by a cookie holding the session identifier and its HMAC-SHA256 signature
+// The values stay on the server, a forged or altered cookie only yields an empty session
+pub struct SessionManager {
+    store: Arc<dyn SessionStore>,
+    key: Vec<u8>,
+    config: SessionConfig,
+    last_purge: Mutex<Instant>,
+}
+
+impl SessionManager {
+    // The secret signs the cookies, it must be random and at least 32 bytes long
+    pub fn new(store: Arc<dyn SessionStore>, secret: &[u8], config: SessionConfig) -> Result<Self, String> {
+        if secret.len() < MIN_SECRET_LENGTH {
+            return Err(format!("Session secret must be at least {} bytes", MIN_SECRET_LENGTH));
+        }
+
+        Ok(SessionManager {
+            store,
+            key: secret.to_vec(),
+            config,
+            last_purge: Mutex::new(Instant::now()),
+        })
+    }
+
+    // Session of the request, a new empty one when the cookie is missing, invalid or expired
+    pub fn load(&self, request: &HttpMessage) -> Session {
+        let id = request.cookies()
+            .into_iter()
+            .filter(|(name, _)| *name == self.config.cookie_name)
+            .find_map(|(_, value)| self.verify(&value));
+        let id = match id {
+            Some(id) => id,
+            None => return Session::default(),
+        };
+
+        match self.store.load(&id) {
+            Ok(Some(record)) if !record.is_expired() => Session {
+                id: Some(id),
+                values: record.values,
+                ..Session::default()
+            },
+            Ok(_) => Session::default(),
+            Err(err) => {
+                println!("Error while loading session {:?}", err);
+                Session::default()
+            }
+        }
+    }
+
+    // Store the session and set its cookie on the response when needed
+    // A session left untouched is not saved, its expiry is then not pushed back
+    pub fn save(&self, mut session: Session, response: &mut HttpResponse) -> io::Result<()> {
+        self.purge_if_due();
+
+        if let Some(replaced_id) = session.replaced_id.take() {
+            self.store.remove(&replaced_id)?;
+        }
+
+        if session.destroyed {
+            if let Some(id) = &session.id {
+                self.store.remove(id)?;
+            }
+            let cookie = SetCookie::removal(&self.config.cookie_name).with_path(&self.config.cookie_path);
+            response.headers.append("Set-Cookie", &cookie.to_string());
+            return Ok(());
+        }
+
+        if !session.modified {
+            return Ok(());
+        }
+
+        let id = match session.id {
+            Some(id) => id,
+            None => generate_session_id()?,
+        };
+        let record = SessionRecord {
+            values: session.values,
+            expires_at: unix_seconds(SystemTime::now() + self.config.ttl),
+        };
+        self.store.save(&id, &record)?;
+
+        let cookie = SetCookie::new(&self.config.cookie_name, &self.sign(&id))
+            .with_max_age(self.config.ttl)
+            .with_path(&self.config.cookie_path)
+            .with_secure(self.config.secure)
+            .with_http_only(true)
+            .with_same_site(self.config.same_site);
+        response.headers.append("Set-Cookie", &cookie.to_string());
+        Ok(())
+    }
+
+    // Cookie value : identifier "." base64url(HMAC-SHA256(identifier))
+    fn sign(&self, id: &str) -> String {
+        format!("{}.{}", id, BASE64_URL.encode(self.mac(id).finalize().into_bytes()))
+    }
+
+    // Identifier of a signed cookie value, None when the signature does not match
+    fn verify(&self, value: &str) -> Option<String> {
+        let (id, signature) = value.split_once('.')?;
+        let signature = BASE64_URL.decode(signature).ok()?;
+
+        //verify_slice compares in constant time
+        self.mac(id).verify_slice(&signature).ok()?;
+        Some(id.to_string())
+    }
+
+    fn mac(&self, id: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(id.as_bytes());
+        mac
+    }
+
+    fn purge_if_due(&self) {
+        let mut last_purge = match self.last_purge.lock() {
+            Ok(last_purge) => last_purge,
+            Err(_) => return,
+        };
+        if last_purge.elapsed() < PURGE_INTERVAL {
+            return;
+        }
+
+        *last_purge = Instant::now();
+        if let Err(err) = self.store.purge_expired() {
+            println!("Error while purging sessions {:?}", err);
+        }
+    }
+}
+
+fn generate_session_id() -> io::Result<String> {
+    let mut bytes = [0u8; SESSION_ID_LENGTH];
+    getrandom::getrandom(&mut bytes).map_err(io::Error::other)?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn request_with_cookie(cookie: &str) -> HttpMessage {
+        HttpMessage::new(format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", cookie).as_bytes()).unwrap()
+    }
+
+    // Value of the session cookie set on the response
+    fn cookie_value(response: &HttpResponse) -> String {
+        let set_cookie = response.headers.get("Set-Cookie").unwrap();
+        set_cookie.split(';').next().unwrap().split_once('=').unwrap().1.to_string()
+    }
+
+    #[test]
+    fn round_trips_signed_session() {
+        let manager = SessionManager::new(Arc::new(MemorySessionStore::new()), SECRET, SessionConfig::default()).unwrap();
+
+        let mut session = manager.load(&request_with_cookie("theme=dark"));
+        assert!(session.id().is_none());
+        session.insert("user", "admin");
+        let mut response = HttpResponse::new(200);
+        manager.save(session, &mut response).unwrap();
+        let value = cookie_value(&response);
+        assert!(response.headers.get("Set-Cookie").unwrap().contains("HttpOnly; SameSite=Lax"));
+
+        let session = manager.load(&request_with_cookie(&format!("theme=dark; session={}", value)));
+        assert_eq!(session.get("user"), Some("admin"));
+
+        //Same identifier with a forged signature
+        let (id, _) = value.split_once('.').unwrap();
+        let forged = manager.load(&request_with_cookie(&format!("session={}.{}", id, BASE64_URL.encode([0u8; 32]))));
+        assert!(forged.get("user").is_none());
+
+        //Signed with another secret
+        let other = SessionManager::new(Arc::new(MemorySessionStore::new()), b"another secret of at least 32 bytes", SessionConfig::default()).unwrap();
+        assert!(other.verify(&value).is_none());
+    }
+
+    #[test]
+    fn regenerates_and_destroys() {
+        let store = Arc::new(MemorySessionStore::new());
+        let manager = SessionManager::new(store.clone(), SECRET, SessionConfig::default()).unwrap();
+
+        let mut session = Session::default();
+        session.insert("step", "1");
+        let mut response = HttpResponse::new(200);
+        manager.save(session, &mut response).unwrap();
+        let first_value = cookie_value(&response);
+
+        let mut session = manager.load(&request_with_cookie(&format!("session={}", first_value)));
+        let first_id = session.id().unwrap().to_string();
+        session.regenerate();
+        let mut response = HttpResponse::new(200);
+        manager.save(session, &mut response).unwrap();
+        let second_value = cookie_value(&response);
+        assert!(store.load(&first_id).unwrap().is_none());
+
+        let mut session = manager.load(&request_with_cookie(&format!("session={}", second_value)));
+        assert_eq!(session.get("step"), Some("1"));
+        session.destroy();
+        let mut response = HttpResponse::new(200);
+        manager.save(session, &mut response).unwrap();
+        assert!(response.headers.get("Set-Cookie").unwrap().contains("Max-Age=0"));
+        assert!(manager.load(&request_with_cookie(&format!("session={}", second_value))).is_empty());
+    }
+
+    #[test]
+    fn file_store_persists_and_expires() {
+        let directory = std::env::temp_dir().join(format!("sessions_test_{}", std::process::id()));
+        let store = FileSessionStore::new(directory.clone()).unwrap();
+
+        let record = SessionRecord { values: BTreeMap::from([("user".to_string(), "admin".to_string())]), expires_at: u64::MAX };
+        store.save("00ff", &record).unwrap();
+        store.save("0aa0", &SessionRecord { values: BTreeMap::new(), expires_at: 0 }).unwrap();
+        assert_eq!(FileSessionStore::new(directory.clone()).unwrap().load("00ff").unwrap().unwrap().values["user"], "admin");
+
+        store.purge_expired().unwrap();
+        assert!(store.load("0aa0").unwrap().is_none());
+        assert!(store.load("00ff").unwrap().is_some());
+        assert!(store.load("../secret").is_err());
+
+        //Temporary files of interrupted writes go once stale, a write in progress keeps its own
+        let interrupted = directory.join("0bb0.json.tmp");
+        let in_progress = directory.join("0cc0.json.tmp");
+        fs::write(&interrupted, b"{").unwrap();
+        fs::write(&in_progress, b"{").unwrap();
+        fs::File::options().write(true).open(&interrupted).unwrap()
+            .set_modified(SystemTime::now() - STALE_TEMPORARY_FILE_AGE * 2).unwrap();
+        store.purge_expired().unwrap();
+        assert!(!interrupted.exists());
+        assert!(in_progress.exists());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
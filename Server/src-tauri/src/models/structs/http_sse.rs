@@ -0,0 +1,258 @@
+use std::{collections::VecDeque, sync::{mpsc::{self, RecvTimeoutError}, Arc, Mutex}, time::Duration};
+
+use crate::models::structs::http_message::HttpMessage;
+use crate::models::structs::http_response::HttpResponse;
+
+// One event of a text/event-stream (HTML Living Standard 9.2)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SseEvent {
+    pub id: Option<String>,
+    // Type listened to with addEventListener, "message" when None
+    pub event: Option<String>,
+    pub data: String,
+    // Reconnection delay the client should use from now on
+    pub retry: Option<Duration>,
+}
+
+impl SseEvent {
+    pub fn new(data: &str) -> Self {
+        SseEvent {
+            id: None,
+            event: None,
+            data: data.to_string(),
+            retry: None,
+        }
+    }
+
+    pub fn with_id(mut self, id: &str) -> Self {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    pub fn with_event(mut self, event: &str) -> Self {
+        self.event = Some(event.to_string());
+        self
+    }
+
+    pub fn with_retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    // Field lines followed by the empty line dispatching the event
+    pub fn encode(&self) -> String {
+        //A line break in id or event would start another field
+        let single_line = |value: &str| value.replace(['\r', '\n', '\0'], "");
+
+        let mut encoded = String::new();
+        if let Some(event) = &self.event {
+            encoded.push_str(&format!("event: {}\n", single_line(event)));
+        }
+        if let Some(id) = &self.id {
+            encoded.push_str(&format!("id: {}\n", single_line(id)));
+        }
+        if let Some(retry) = self.retry {
+            encoded.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        //Each line is its own data field, the client joins them back with \n
+        for line in self.data.replace("\r\n", "\n").split(['\r', '\n']) {
+            encoded.push_str(&format!("data: {}\n", line));
+        }
+        encoded.push('\n');
+        encoded
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SseConfig {
+    // Sent first so clients wait this long before reconnecting, None to keep their default
+    pub retry: Option<Duration>,
+    // Comment sent when no event was for this long, keeps proxies from closing the connection
+    pub heartbeat_interval: Duration,
+}
+
+impl Default for SseConfig {
+    fn default() -> Self {
+        SseConfig {
+            retry: Some(Duration::from_secs(3)),
+            heartbeat_interval: Duration::from_secs(15),
+        }
+    }
+}
+
+struct BroadcasterState {
+    next_id: u64,
+    // Latest events, replayed to clients resuming with Last-Event-ID
+    history: VecDeque<SseEvent>,
+    subscribers: Vec<mpsc::Sender<SseEvent>>,
+}
+
+// Events published to every open event stream, numbered in publication order
+#[derive(Clone)]
+pub struct EventBroadcaster {
+    state: Arc<Mutex<BroadcasterState>>,
+    history_size: usize,
+}
+
+impl EventBroadcaster {
+    pub fn new(history_size: usize) -> Self {
+        EventBroadcaster {
+            state: Arc::new(Mutex::new(BroadcasterState {
+                next_id: 1,
+                history: VecDeque::with_capacity(history_size),
+                subscribers: Vec::new(),
+            })),
+            history_size,
+        }
+    }
+
+    // Returns the id given to the event
+    pub fn publish(&self, event: &str, data: &str) -> u64 {
+        self.send(SseEvent::new(data).with_event(event))
+    }
+
+    // The id of the event is replaced by the next one of the broadcaster
+    pub fn send(&self, mut event: SseEvent) -> u64 {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return 0,
+        };
+        let id = state.next_id;
+        state.next_id += 1;
+        event.id = Some(id.to_string());
+
+        //Streams that ended dropped their receiver
+        state.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        if self.history_size > 0 {
+            if state.history.len() == self.history_size {
+                state.history.pop_front();
+            }
+            state.history.push_back(event);
+        }
+        id
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.state.lock().map(|state| state.subscribers.len()).unwrap_or(0)
+    }
+
+    // End every open event stream, e.g. before stopping the server
+    pub fn disconnect_all(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.subscribers.clear();
+        }
+    }
+
+    // Events to replay after last_event_id, and the receiver of the next ones
+    // Every kept event is replayed for an id the broadcaster did not give, e.g. before a restart
+    fn subscribe(&self, last_event_id: Option<&str>) -> (Vec<SseEvent>, mpsc::Receiver<SseEvent>) {
+        let (sender, receiver) = mpsc::channel();
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return (Vec::new(), receiver),
+        };
+        state.subscribers.push(sender);
+
+        let backlog = match last_event_id.map(|last_event_id| last_event_id.trim().parse::<u64>()) {
+            None => Vec::new(),
+            Some(Ok(last_id)) if last_id < state.next_id => state.history.iter()
+                .filter(|event| event.id.as_deref().and_then(|id| id.parse::<u64>().ok()).is_some_and(|id| id > last_id))
+                .cloned()
+                .collect(),
+            Some(_) => state.history.iter().cloned().collect(),
+        };
+        (backlog, receiver)
+    }
+
+    // text/event-stream response sending the events published from now on
+    // A client reconnecting with Last-Event-ID first receives the kept events it missed
+    pub fn stream(&self, request: &HttpMessage, config: &SseConfig) -> HttpResponse {
+        let (backlog, receiver) = self.subscribe(request.headers.get("Last-Event-ID"));
+        let config = config.clone();
+
+        HttpResponse::stream(200, "text/event-stream", move |writer| {
+            //Something is sent at once so the client sees the stream open
+            match config.retry {
+                Some(retry) => write!(writer, "retry: {}\n\n", retry.as_millis())?,
+                None => writer.write_all(b": connected\n\n")?,
+            }
+            for event in backlog {
+                writer.write_all(event.encode().as_bytes())?;
+            }
+            writer.flush()?;
+
+            //Ends when the client is gone, the write failing, or when the broadcaster disconnects it
+            loop {
+                match receiver.recv_timeout(config.heartbeat_interval) {
+                    Ok(event) => writer.write_all(event.encode().as_bytes())?,
+                    Err(RecvTimeoutError::Timeout) => writer.write_all(b": heartbeat\n\n")?,
+                    Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                }
+                writer.flush()?;
+            }
+        })
+        .with_header("Cache-Control", "no-cache")
+        //Tells nginx not to buffer the stream
+        .with_header("X-Accel-Buffering", "no")
+        //A stream per dashboard would otherwise keep a worker each
+        .detached()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn encodes_events() {
+        assert_eq!(SseEvent::new("hello").encode(), "data: hello\n\n");
+        let event = SseEvent::new("line 1\r\nline 2\n").with_event("up\ndate").with_id("7").with_retry(Duration::from_secs(2));
+        assert_eq!(event.encode(), "event: update\nid: 7\nretry: 2000\ndata: line 1\ndata: line 2\ndata: \n\n");
+    }
+
+    #[test]
+    fn replays_missed_events() {
+        let broadcaster = EventBroadcaster::new(3);
+        for index in 1..=5 {
+            assert_eq!(broadcaster.publish("tick", &index.to_string()), index);
+        }
+
+        let replayed = |last_event_id: Option<&str>| -> Vec<String> {
+            broadcaster.subscribe(last_event_id).0.into_iter().map(|event| event.data).collect()
+        };
+        assert!(replayed(None).is_empty());
+        assert_eq!(replayed(Some("3")), vec!["4", "5"]);
+        assert!(replayed(Some("5")).is_empty());
+        //Older than the history, or unknown
+        assert_eq!(replayed(Some("1")), vec!["3", "4", "5"]);
+        assert_eq!(replayed(Some("99")), vec!["3", "4", "5"]);
+        assert_eq!(replayed(Some("abc")), vec!["3", "4", "5"]);
+    }
+
+    #[test]
+    fn streams_events_and_heartbeats() {
+        let broadcaster = EventBroadcaster::new(10);
+        broadcaster.publish("tick", "missed");
+        let request = HttpMessage::new(&b"GET /events HTTP/1.1\r\nHost: a\r\nLast-Event-ID: 0\r\n\r\n"[..]).unwrap();
+        let config = SseConfig { heartbeat_interval: Duration::from_millis(50), ..SseConfig::default() };
+        let mut response = broadcaster.stream(&request, &config);
+        assert_eq!(response.headers.get("Content-Type"), Some("text/event-stream"));
+        assert!(response.detached);
+
+        let publisher = broadcaster.clone();
+        let publish_thread = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(120));
+            publisher.publish("tick", "live");
+            publisher.disconnect_all();
+        });
+        let mut body: Vec<u8> = Vec::new();
+        response.body_stream.take().unwrap()(&mut body).unwrap();
+        publish_thread.join().unwrap();
+
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.starts_with("retry: 3000\n\nevent: tick\nid: 1\ndata: missed\n\n: heartbeat\n\n"));
+        assert!(body.ends_with("event: tick\nid: 2\ndata: live\n\n"));
+        assert_eq!(broadcaster.subscriber_count(), 0);
+    }
+}
@@ -0,0 +1,426 @@
+use std::{fs::{self, File, Metadata}, io::{self, Read, Seek, SeekFrom}, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+use crate::models::structs::http_date::{format_http_date, parse_http_date};
+use crate::models::structs::http_message::HttpMessage;
+use crate::models::structs::http_method::Method;
+use crate::models::structs::http_response::HttpResponse;
+use crate::models::structs::http_target::percent_decode;
+
+// Serve the files of a directory
+// Usage : router.get("/files/*path", move |request, params| static_files.serve(request, params.get("path").unwrap_or_default()))
+pub struct StaticFiles {
+    root: PathBuf,
+    pub index_file: Option<String>,
+    pub directory_listing: bool,
+}
+
+impl StaticFiles {
+    pub fn new(root: &Path) -> io::Result<Self> {
+        Ok(StaticFiles {
+            //Canonical so the traversal check can compare prefixes
+            root: root.canonicalize()?,
+            index_file: Some("index.html".to_string()),
+            directory_listing: false,
+        })
+    }
+
+    pub fn with_directory_listing(mut self, directory_listing: bool) -> Self {
+        self.directory_listing = directory_listing;
+        self
+    }
+
+    pub fn with_index_file(mut self, index_file: Option<&str>) -> Self {
+        self.index_file = index_file.map(|index_file| index_file.to_string());
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // relative_path is the part of the request path below the served directory, still percent-encoded
+    pub fn serve(&self, request: &HttpMessage, relative_path: &str) -> HttpResponse {
+        if request.method != Method::Get && request.method != Method::Head {
+            return HttpResponse::error(405).with_header("Allow", "GET, HEAD");
+        }
+
+        let mut path = match self.resolve(relative_path) {
+            Some(path) => path,
+            None => return HttpResponse::not_found(),
+        };
+
+        let mut metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => return HttpResponse::not_found(),
+        };
+
+        if metadata.is_dir() {
+            //Relative links of the listing or the index need the trailing slash
+            if !request.target.path.ends_with('/') {
+                return HttpResponse::redirect(301, &format!("{}/", request.target.path));
+            }
+
+            let index = self.index_file.as_ref().map(|index_file| path.join(index_file));
+            match index.and_then(|index| fs::metadata(&index).ok().filter(|metadata| metadata.is_file()).map(|metadata| (index, metadata))) {
+                Some((index, index_metadata)) => {
+                    path = index;
+                    metadata = index_metadata;
+                },
+                None if self.directory_listing => return self.list_directory(&path, &request.target.path),
+                None => return HttpResponse::not_found(),
+            }
+        }
+
+        self.serve_file(request, &path, &metadata)
+    }
+
+    // Map the request path below root, None for anything escaping root
+    fn resolve(&self, relative_path: &str) -> Option<PathBuf> {
+        let decoded = String::from_utf8(percent_decode(relative_path)?).ok()?;
+
+        let mut path = self.root.clone();
+        for segment in decoded.split('/') {
+            if segment.is_empty() || segment == "." {
+                continue;
+            }
+            //No parent, no windows separator or drive, no hidden files
+            if segment == ".." || segment.starts_with('.') || segment.contains(['\\', ':', '\0']) {
+                return None;
+            }
+            path.push(segment);
+        }
+
+        //Symbolic links may still point outside of root
+        let canonical = path.canonicalize().ok()?;
+        if canonical.starts_with(&self.root) {
+            Some(canonical)
+        }
+        else {
+            None
+        }
+    }
+
+    fn serve_file(&self, request: &HttpMessage, path: &Path, metadata: &Metadata) -> HttpResponse {
+        let file_length = metadata.len();
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        let modified_seconds = modified.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+        let etag = format!("\"{:x}-{:x}\"", file_length, modified_seconds);
+        let last_modified = format_http_date(modified);
+
+        if Self::is_not_modified(request, &etag, modified_seconds) {
+            return HttpResponse::new(304)
+                .with_header("ETag", &etag)
+                .with_header("Last-Modified", &last_modified);
+        }
+
+        let content_type = Self::mime_type(path);
+        let range = match request.headers.get("Range") {
+            Some(range) if Self::if_range_matches(request, &etag, &last_modified) => Self::parse_range(range, file_length),
+            _ => RangeRequest::Full,
+        };
+
+        let (status, start, length) = match range {
+            RangeRequest::Full => (200, 0, file_length),
+            RangeRequest::Partial(start, end) => (206, start, end - start + 1),
+            RangeRequest::Unsatisfiable => {
+                return HttpResponse::error(416).with_header("Content-Range", &format!("bytes */{}", file_length));
+            }
+        };
+
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) => {
+                println!("Error while opening {:?} {:?}", path, err);
+                return HttpResponse::error(500);
+            }
+        };
+
+        let mut response = HttpResponse::sized_stream(status, content_type, length, move |writer| {
+            file.seek(SeekFrom::Start(start))?;
+            io::copy(&mut file.take(length), writer)?;
+            Ok(())
+        })
+        .with_header("ETag", &etag)
+        .with_header("Last-Modified", &last_modified)
+        .with_header("Accept-Ranges", "bytes");
+
+        if status == 206 {
+            response = response.with_header("Content-Range", &format!("bytes {}-{}/{}", start, start + length - 1, file_length));
+        }
+        response
+    }
+
+    // If-None-Match wins over If-Modified-Since (RFC 9110 13.2.2)
+    fn is_not_modified(request: &HttpMessage, etag: &str, modified_seconds: u64) -> bool {
+        let if_none_match = request.headers.get_list("If-None-Match");
+        if !if_none_match.is_empty() {
+            //Weak comparison
+            return if_none_match.iter().any(|tag| *tag == "*" || tag.trim_start_matches("W/") == etag);
+        }
+
+        match request.headers.get("If-Modified-Since").and_then(parse_http_date) {
+            Some(since) => {
+                let since_seconds = since.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+                modified_seconds <= since_seconds
+            },
+            None => false,
+        }
+    }
+
+    // A Range is only honored when If-Range still designates the current representation
+    fn if_range_matches(request: &HttpMessage, etag: &str, last_modified: &str) -> bool {
+        match request.headers.get("If-Range") {
+            Some(if_range) if if_range.starts_with('"') => if_range == etag,
+            Some(if_range) => if_range == last_modified,
+            None => true,
+        }
+    }
+
+    // Single byte range only, several ranges are answered with the full content
+    fn parse_range(range: &str, file_length: u64) -> RangeRequest {
+        let spec = match range.trim().strip_prefix("bytes=") {
+            Some(spec) if !spec.contains(',') => spec.trim(),
+            _ => return RangeRequest::Full,
+        };
+        let (first, last) = match spec.split_once('-') {
+            Some(bounds) => bounds,
+            None => return RangeRequest::Full,
+        };
+
+        let (start, end) = if first.is_empty() {
+            //Suffix : the last N bytes
+            match last.parse::<u64>() {
+                Ok(0) => return RangeRequest::Unsatisfiable,
+                Ok(suffix) => (file_length.saturating_sub(suffix), file_length.saturating_sub(1)),
+                Err(_) => return RangeRequest::Full,
+            }
+        }
+        else {
+            let start = match first.parse::<u64>() {
+                Ok(start) => start,
+                Err(_) => return RangeRequest::Full,
+            };
+            let end = if last.is_empty() {
+                file_length.saturating_sub(1)
+            }
+            else {
+                match last.parse::<u64>() {
+                    Ok(end) if end >= start => end.min(file_length.saturating_sub(1)),
+                    _ => return RangeRequest::Full,
+                }
+            };
+            (start, end)
+        };
+
+        if file_length == 0 || start >= file_length {
+            return RangeRequest::Unsatisfiable;
+        }
+        RangeRequest::Partial(start, end)
+    }
+
+    fn list_directory(&self, path: &Path, request_path: &str) -> HttpResponse {
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(err) => {
+                println!("Error while listing {:?} {:?}", path, err);
+                return HttpResponse::error(500);
+            }
+        };
+
+        let mut names: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if name.starts_with('.') {
+                    return None;
+                }
+                let is_dir = entry.file_type().map(|file_type| file_type.is_dir()).unwrap_or(false);
+                Some(if is_dir { format!("{}/", name) } else { name })
+            })
+            .collect();
+        names.sort();
+
+        let title = Self::escape_html(request_path);
+        let mut html = format!("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Index of {}</title></head><body>\n<h1>Index of {}</h1>\n<ul>\n", title, title);
+        if request_path != "/" {
+            html.push_str("<li><a href=\"../\">../</a></li>\n");
+        }
+        for name in names {
+            html.push_str(&format!("<li><a href=\"{}\">{}</a></li>\n", Self::escape_html(&Self::percent_encode(&name)), Self::escape_html(&name)));
+        }
+        html.push_str("</ul>\n</body></html>\n");
+
+        HttpResponse::html(200, &html).with_header("Last-Modified", &format_http_date(SystemTime::now()))
+    }
+
+    pub fn mime_type(path: &Path) -> &'static str {
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+
+        match extension.as_str() {
+            "html" | "htm" => "text/html; charset=utf-8",
+            "css" => "text/css; charset=utf-8",
+            "js" | "mjs" => "text/javascript; charset=utf-8",
+            "json" => "application/json",
+            "txt" | "log" => "text/plain; charset=utf-8",
+            "csv" => "text/csv; charset=utf-8",
+            "xml" => "application/xml",
+            "svg" => "image/svg+xml",
+            "png" => "image/png",
+            "jpg" | "jpeg" => "image/jpeg",
+            "gif" => "image/gif",
+            "webp" => "image/webp",
+            "ico" => "image/x-icon",
+            "mp4" | "m4v" => "video/mp4",
+            "webm" => "video/webm",
+            "mkv" => "video/x-matroska",
+            "ts" => "video/mp2t",
+            "m3u8" => "application/vnd.apple.mpegurl",
+            "h264" | "264" => "video/h264",
+            "mp3" => "audio/mpeg",
+            "ogg" => "audio/ogg",
+            "wav" => "audio/wav",
+            "pdf" => "application/pdf",
+            "zip" => "application/zip",
+            "gz" => "application/gzip",
+            "wasm" => "application/wasm",
+            "woff" => "font/woff",
+            "woff2" => "font/woff2",
+            "ttf" => "font/ttf",
+            _ => "application/octet-stream",
+        }
+    }
+
+    fn escape_html(text: &str) -> String {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+            .replace('\'', "&#39;")
+    }
+
+    fn percent_encode(text: &str) -> String {
+        text.bytes()
+            .map(|byte| {
+                if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+                    (byte as char).to_string()
+                }
+                else {
+                    format!("%{:02X}", byte)
+                }
+            })
+            .collect()
+    }
+}
+
+enum RangeRequest {
+    Full,
+    // First and last byte, inclusive
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // root/ holding video.mp4, sub/a.txt and sub/.hidden, next to outside/secret.txt
+    fn create_tree(name: &str) -> (PathBuf, StaticFiles) {
+        let directory = std::env::temp_dir().join(format!("static_{}_{}", name, std::process::id()));
+        let root = directory.join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::create_dir_all(directory.join("outside")).unwrap();
+        fs::write(root.join("video.mp4"), (0..100).collect::<Vec<u8>>()).unwrap();
+        fs::write(root.join("sub").join("a.txt"), b"a").unwrap();
+        fs::write(root.join("sub").join(".hidden"), b"hidden").unwrap();
+        fs::write(directory.join("outside").join("secret.txt"), b"secret").unwrap();
+        let static_files = StaticFiles::new(&root).unwrap();
+        (directory, static_files)
+    }
+
+    fn get(static_files: &StaticFiles, relative_path: &str, fields: &str) -> HttpResponse {
+        let raw = format!("GET /files/{} HTTP/1.1\r\nHost: a\r\n{}\r\n", relative_path, fields);
+        static_files.serve(&HttpMessage::new(raw.as_bytes()).unwrap(), relative_path)
+    }
+
+    fn body(mut response: HttpResponse) -> Vec<u8> {
+        let mut body = response.body.clone();
+        if let Some(body_stream) = response.body_stream.take() {
+            body_stream(&mut body).unwrap();
+        }
+        body
+    }
+
+    #[test]
+    fn refuses_paths_escaping_the_root() {
+        let (directory, static_files) = create_tree("traversal");
+        assert_eq!(get(&static_files, "video.mp4", "").status, 200);
+        for relative_path in ["../outside/secret.txt", "%2e%2e/outside/secret.txt", "sub/%2E%2e/%2e%2E/outside/secret.txt", "%2e%2e%2foutside%2fsecret.txt", "sub/.hidden", "sub%2f%2ehidden"] {
+            assert_eq!(get(&static_files, relative_path, "").status, 404, "{}", relative_path);
+        }
+        //"%+1" would be decoded by a parser taking a sign
+        assert_eq!(get(&static_files, "video.mp%+4", "").status, 404);
+        assert_eq!(get(&static_files, "video.mp%-4", "").status, 404);
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(directory.join("outside").join("secret.txt"), directory.join("root").join("escape.txt")).unwrap();
+            std::os::unix::fs::symlink(directory.join("root").join("video.mp4"), directory.join("root").join("link.mp4")).unwrap();
+            assert_eq!(get(&static_files, "escape.txt", "").status, 404);
+            assert_eq!(get(&static_files, "link.mp4", "").status, 200);
+        }
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn answers_conditional_and_range_requests() {
+        let (directory, static_files) = create_tree("range");
+        let response = get(&static_files, "video.mp4", "");
+        assert_eq!(response.headers.get("Content-Type"), Some("video/mp4"));
+        let etag = response.headers.get("ETag").unwrap().to_string();
+
+        let response = get(&static_files, "video.mp4", &format!("If-None-Match: W/{}\r\n", etag));
+        assert_eq!(response.status, 304);
+        assert!(!String::from_utf8(response.to_bytes(true)).unwrap().contains("Content-Length"));
+        assert_eq!(get(&static_files, "video.mp4", "If-None-Match: \"other\"\r\n").status, 200);
+
+        let response = get(&static_files, "video.mp4", "Range: bytes=10-19\r\n");
+        assert_eq!(response.status, 206);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes 10-19/100"));
+        assert_eq!(response.stream_length, Some(10));
+        assert_eq!(body(response), (10..20).collect::<Vec<u8>>());
+        assert_eq!(body(get(&static_files, "video.mp4", "Range: bytes=-5\r\n")), (95..100).collect::<Vec<u8>>());
+
+        let response = get(&static_files, "video.mp4", "Range: bytes=200-\r\n");
+        assert_eq!(response.status, 416);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes */100"));
+        //A Range for an older representation gets the whole file
+        assert_eq!(get(&static_files, "video.mp4", "Range: bytes=10-19\r\nIf-Range: \"old\"\r\n").status, 200);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn lists_directories() {
+        let (directory, static_files) = create_tree("listing");
+        assert_eq!(get(&static_files, "sub/", "").status, 404);
+
+        let static_files = static_files.with_directory_listing(true).with_index_file(None);
+        let response = get(&static_files, "sub", "");
+        assert_eq!(response.status, 301);
+        assert_eq!(response.headers.get("Location"), Some("/files/sub/"));
+
+        let response = get(&static_files, "sub/", "");
+        assert_eq!(response.status, 200);
+        let html = String::from_utf8(body(response)).unwrap();
+        assert!(html.contains("<title>Index of /files/sub/</title>"));
+        assert!(html.contains("<a href=\"../\">../</a>"));
+        assert!(html.contains("<a href=\"a.txt\">a.txt</a>"));
+        assert!(!html.contains(".hidden"));
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
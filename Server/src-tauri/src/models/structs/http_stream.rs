@@ -0,0 +1,50 @@
+use std::{io::{self, Read, Write}, net::{SocketAddr, TcpStream}, time::Duration};
+
+// Byte stream a HTTP connection runs on, plain TCP or TLS
+pub trait HttpStream: Read + Write + Send {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    // Second handle on the same connection, not available for every stream
+    fn try_clone_stream(&self) -> io::Result<Box<dyn HttpStream>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Stream can't be cloned"))
+    }
+
+    fn is_tls(&self) -> bool {
+        false
+    }
+
+    // Application protocol negotiated with ALPN, e.g. "h2"
+    fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    // Name the client asked for with SNI, None without TLS or when it sent none
+    fn server_name(&self) -> Option<String> {
+        None
+    }
+
+    // Orderly close, e.g. TLS close_notify
+    fn close(&mut self) {}
+}
+
+impl HttpStream for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn try_clone_stream(&self) -> io::Result<Box<dyn HttpStream>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+}
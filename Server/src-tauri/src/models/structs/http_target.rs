@@ -0,0 +1,100 @@
+use std::fmt::Display;
+
+use crate::models::structs::http_error::HttpError;
+
+// Request-target of the request-line (RFC 9112 3.2)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestTarget {
+    // Target as received
+    pub raw: String,
+    // Path component, "*" for asterisk-form, "host:port" for authority-form
+    pub path: String,
+    // Query component without the leading '?'
+    pub query: Option<String>,
+}
+
+impl RequestTarget {
+    pub fn parse(raw: &str) -> Result<Self, HttpError> {
+        if raw.is_empty() || raw.bytes().any(|byte| byte <= b' ' || byte >= 127) {
+            return Err(HttpError::Malformed(format!("Invalid request-target {}", raw)));
+        }
+
+        //Fragment is never sent, drop it if a client does
+        let without_fragment = raw.split('#').next().unwrap_or_default();
+
+        let path_and_query = if without_fragment.starts_with('/') || without_fragment == "*" {
+            //origin-form or asterisk-form
+            without_fragment
+        }
+        else if let Some((_, rest)) = without_fragment.split_once("://") {
+            //absolute-form, keep what follows the authority
+            match rest.find(['/', '?']) {
+                Some(index) => &rest[index..],
+                None => "/",
+            }
+        }
+        else {
+            //authority-form, only used by CONNECT
+            return Ok(RequestTarget {
+                raw: raw.to_string(),
+                path: without_fragment.to_string(),
+                query: None,
+            });
+        };
+
+        let (path, query) = match path_and_query.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_string())),
+            None => (path_and_query, None),
+        };
+
+        Ok(RequestTarget {
+            raw: raw.to_string(),
+            path: if path.is_empty() { "/".to_string() } else { path.to_string() },
+            query,
+        })
+    }
+}
+
+impl Display for RequestTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.raw)
+    }
+}
+
+// Decode %XX sequences, None when a sequence is not valid hex
+pub fn percent_decode(input: &str) -> Option<Vec<u8>> {
+    let bytes = input.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i: usize = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            //from_str_radix would take a sign, "%+F" is not a valid sequence
+            if !hex.iter().all(|byte| byte.is_ascii_hexdigit()) {
+                return None;
+            }
+            let value = u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?;
+            decoded.push(value);
+            i += 3;
+        }
+        else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_only_hex_sequences() {
+        assert_eq!(percent_decode("a%20b%2f"), Some(b"a b/".to_vec()));
+        for invalid in ["%+F", "%-1", "%2", "%zz"] {
+            assert_eq!(percent_decode(invalid), None);
+        }
+    }
+}
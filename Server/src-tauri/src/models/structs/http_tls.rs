@@ -0,0 +1,470 @@
+use std::{collections::HashMap, fs::{self, File}, io::{self, BufReader, Write}, net::{SocketAddr, TcpStream}, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, Arc}, thread::{self, JoinHandle}, time::{Duration, Instant, SystemTime}};
+
+use arc_swap::ArcSwap;
+use rustls::{crypto::CryptoProvider, pki_types::ServerName, server::{ClientHello, ResolvesServerCert}, sign::CertifiedKey, ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+
+use crate::models::structs::http_stream::HttpStream;
+
+// A certificate chain and its key, both PEM files
+#[derive(Clone, Debug)]
+pub struct TlsCertificate {
+    // SNI names served by the certificate, "*.example.com" matches one subdomain level
+    pub server_names: Vec<String>,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    // The first certificate is used when the client sends no SNI or an unknown name
+    pub certificates: Vec<TlsCertificate>,
+    // How often the PEM files are checked for changes, None to disable hot reload
+    pub reload_interval: Option<Duration>,
+}
+
+struct LoadedCertificates {
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+    modified: Vec<Option<SystemTime>>,
+}
+
+// Certificates selected by SNI, swapped atomically on reload so handshakes in progress are not disturbed
+#[derive(Debug)]
+pub struct TlsCertificateStore {
+    config: TlsConfig,
+    provider: Arc<CryptoProvider>,
+    loaded: ArcSwap<LoadedCertificates>,
+}
+
+impl std::fmt::Debug for LoadedCertificates {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LoadedCertificates({:?})", self.by_name.keys().collect::<Vec<&String>>())
+    }
+}
+
+impl TlsCertificateStore {
+    pub fn new(config: TlsConfig, provider: Arc<CryptoProvider>) -> io::Result<Self> {
+        let loaded = Self::load(&config, &provider)?;
+        Ok(TlsCertificateStore {
+            config,
+            provider,
+            loaded: ArcSwap::from_pointee(loaded),
+        })
+    }
+
+    // Read the PEM files again, the previous certificates stay in use if they are invalid
+    pub fn reload(&self) -> io::Result<()> {
+        let loaded = Self::load(&self.config, &self.provider)?;
+        self.loaded.store(Arc::new(loaded));
+        println!("Tls certificates reloaded");
+        Ok(())
+    }
+
+    // Reload when a PEM file modification time changed
+    pub fn reload_if_modified(&self) -> io::Result<bool> {
+        let modified = Self::modification_times(&self.config);
+        if modified == self.loaded.load().modified {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+
+    fn load(config: &TlsConfig, provider: &CryptoProvider) -> io::Result<LoadedCertificates> {
+        let mut by_name: HashMap<String, Arc<CertifiedKey>> = HashMap::new();
+        let mut default: Option<Arc<CertifiedKey>> = None;
+
+        for certificate in &config.certificates {
+            let certified_key = Arc::new(Self::load_certified_key(certificate, provider)?);
+            for server_name in &certificate.server_names {
+                by_name.insert(server_name.to_ascii_lowercase(), certified_key.clone());
+            }
+            if default.is_none() {
+                default = Some(certified_key);
+            }
+        }
+
+        Ok(LoadedCertificates {
+            by_name,
+            default,
+            modified: Self::modification_times(config),
+        })
+    }
+
+    fn load_certified_key(certificate: &TlsCertificate, provider: &CryptoProvider) -> io::Result<CertifiedKey> {
+        let mut cert_reader = BufReader::new(File::open(&certificate.cert_path)?);
+        let chain = rustls_pemfile::certs(&mut cert_reader).collect::<Result<Vec<_>, _>>()?;
+        if chain.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("No certificate in {:?}", certificate.cert_path)));
+        }
+
+        let mut key_reader = BufReader::new(File::open(&certificate.key_path)?);
+        let key = match rustls_pemfile::private_key(&mut key_reader)? {
+            Some(key) => key,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("No private key in {:?}", certificate.key_path))),
+        };
+
+        let signing_key = provider
+            .key_provider
+            .load_private_key(key)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        Ok(CertifiedKey::new(chain, signing_key))
+    }
+
+    fn modification_times(config: &TlsConfig) -> Vec<Option<SystemTime>> {
+        config.certificates
+            .iter()
+            .flat_map(|certificate| [&certificate.cert_path, &certificate.key_path])
+            .map(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+            .collect()
+    }
+
+    fn find(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let loaded = self.loaded.load();
+        if let Some(server_name) = server_name.map(|server_name| server_name.to_ascii_lowercase()) {
+            if let Some(certified_key) = loaded.by_name.get(&server_name) {
+                return Some(certified_key.clone());
+            }
+            if let Some((_, parent)) = server_name.split_once('.') {
+                if let Some(certified_key) = loaded.by_name.get(&format!("*.{}", parent)) {
+                    return Some(certified_key.clone());
+                }
+            }
+        }
+        loaded.default.clone()
+    }
+}
+
+impl ResolvesServerCert for TlsCertificateStore {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.find(client_hello.server_name())
+    }
+}
+
+// SNI name of the connection a request came on, in the request extensions
+// Lets a handler check it against Host, see VirtualHosts
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsServerName(pub String);
+
+// Wraps accepted sockets in TLS sessions
+pub struct TlsAcceptor {
+    server_config: Arc<ServerConfig>,
+    pub store: Arc<TlsCertificateStore>,
+}
+
+impl TlsAcceptor {
+    // alpn_protocols in order of preference, empty to skip ALPN
+    pub fn new(config: TlsConfig, alpn_protocols: Vec<Vec<u8>>) -> io::Result<Self> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let store = Arc::new(TlsCertificateStore::new(config, provider.clone())?);
+
+        let mut server_config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?
+            .with_no_client_auth()
+            .with_cert_resolver(store.clone());
+        server_config.alpn_protocols = alpn_protocols;
+
+        Ok(TlsAcceptor {
+            server_config: Arc::new(server_config),
+            store,
+        })
+    }
+
+    // The handshake is completed within the socket timeouts so the protocol negotiated with ALPN is known
+    // The handshake must complete within handshake_timeout, a client sending a byte at a time can't hold the worker longer
+    pub fn accept(&self, tcp_stream: TcpStream, handshake_timeout: Duration) -> io::Result<Box<dyn HttpStream>> {
+        let connection = ServerConnection::new(self.server_config.clone())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let mut stream = StreamOwned::new(connection, tcp_stream);
+        let read_timeout = stream.sock.read_timeout()?;
+        let write_timeout = stream.sock.write_timeout()?;
+        let started = Instant::now();
+
+        while stream.conn.is_handshaking() {
+            let remaining = handshake_timeout.saturating_sub(started.elapsed());
+            if remaining.is_zero() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "Tls handshake timed out"));
+            }
+            //Each read and write gets at most what is left, and never more than the configured timeouts
+            let step_timeout = remaining.max(Duration::from_millis(1));
+            stream.sock.set_read_timeout(Some(read_timeout.map_or(step_timeout, |read_timeout| read_timeout.min(step_timeout))))?;
+            stream.sock.set_write_timeout(Some(write_timeout.map_or(step_timeout, |write_timeout| write_timeout.min(step_timeout))))?;
+
+            let (nb_bytes_read, nb_bytes_written) = stream.conn.complete_io(&mut stream.sock)?;
+            if nb_bytes_read == 0 && nb_bytes_written == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed during the tls handshake"));
+            }
+        }
+        stream.sock.set_read_timeout(read_timeout)?;
+        stream.sock.set_write_timeout(write_timeout)?;
+        Ok(Box::new(stream))
+    }
+
+    // Poll the PEM files, same stop flag pattern as the other threads of the server
+    pub fn new_reload_thread(&self, interval: Duration, should_stop: Arc<AtomicBool>) -> JoinHandle<()> {
+        let store = self.store.clone();
+        thread::spawn(move || {
+            let mut elapsed = Duration::ZERO;
+            let step = Duration::from_millis(100);
+            loop {
+                if should_stop.load(Ordering::Relaxed) {
+                    break
+                }
+
+                thread::sleep(step);
+                elapsed += step;
+                if elapsed < interval {
+                    continue;
+                }
+                elapsed = Duration::ZERO;
+
+                if let Err(err) = store.reload_if_modified() {
+                    println!("Error while reloading tls certificates {:?}", err);
+                }
+            }
+        })
+    }
+}
+
+// Opens TLS sessions to servers, used by the HTTP client
+pub struct TlsConnector {
+    client_config: Arc<ClientConfig>,
+}
+
+impl TlsConnector {
+    // root_certificates is a PEM file of the authorities to trust, the Mozilla root program's when None
+    pub fn new(root_certificates: Option<&Path>, alpn_protocols: Vec<Vec<u8>>) -> io::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        match root_certificates {
+            Some(path) => {
+                let mut reader = BufReader::new(File::open(path)?);
+                for certificate in rustls_pemfile::certs(&mut reader) {
+                    roots.add(certificate?).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                }
+            },
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut client_config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_config.alpn_protocols = alpn_protocols;
+
+        Ok(TlsConnector {
+            client_config: Arc::new(client_config),
+        })
+    }
+
+    // The certificate must be valid for server_name, the handshake is completed within the socket timeouts
+    pub fn connect(&self, server_name: &str, tcp_stream: TcpStream) -> io::Result<Box<dyn HttpStream>> {
+        let server_name = ServerName::try_from(server_name.to_string())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let connection = ClientConnection::new(self.client_config.clone(), server_name)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let mut stream = StreamOwned::new(connection, tcp_stream);
+
+        while stream.conn.is_handshaking() {
+            let (nb_bytes_read, nb_bytes_written) = stream.conn.complete_io(&mut stream.sock)?;
+            if nb_bytes_read == 0 && nb_bytes_written == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed during the tls handshake"));
+            }
+        }
+        Ok(Box::new(stream))
+    }
+}
+
+impl HttpStream for StreamOwned<ServerConnection, TcpStream> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_write_timeout(timeout)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.sock.peer_addr()
+    }
+
+    fn is_tls(&self) -> bool {
+        true
+    }
+
+    fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.conn.alpn_protocol().map(|protocol| protocol.to_vec())
+    }
+
+    fn server_name(&self) -> Option<String> {
+        self.conn.server_name().map(|server_name| server_name.to_string())
+    }
+
+    fn close(&mut self) {
+        self.conn.send_close_notify();
+        let _ = self.flush();
+    }
+}
+
+impl HttpStream for StreamOwned<ClientConnection, TcpStream> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_write_timeout(timeout)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.sock.peer_addr()
+    }
+
+    fn is_tls(&self) -> bool {
+        true
+    }
+
+    fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.conn.alpn_protocol().map(|protocol| protocol.to_vec())
+    }
+
+    fn close(&mut self) {
+        self.conn.send_close_notify();
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Read, net::TcpListener};
+
+    // Self-signed certificate for server_name written as PEM files
+    fn write_certificate(directory: &Path, file_name: &str, server_name: &str) -> TlsCertificate {
+        let generated = rcgen::generate_simple_self_signed(vec![server_name.to_string()]).unwrap();
+        let certificate = TlsCertificate {
+            server_names: vec![server_name.to_string()],
+            cert_path: directory.join(format!("{}.crt", file_name)),
+            key_path: directory.join(format!("{}.key", file_name)),
+        };
+        fs::write(&certificate.cert_path, generated.cert.pem()).unwrap();
+        fs::write(&certificate.key_path, generated.key_pair.serialize_pem()).unwrap();
+        certificate
+    }
+
+    // Handshake over loopback trusting only root, returns the message echoed by the server
+    fn handshake(acceptor: &Arc<TlsAcceptor>, server_name: &str, root: &Path) -> io::Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let acceptor = acceptor.clone();
+        let server = thread::spawn(move || -> io::Result<()> {
+            let (tcp_stream, _) = listener.accept()?;
+            tcp_stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+            let mut stream = acceptor.accept(tcp_stream, Duration::from_secs(5))?;
+            stream.write_all(b"hello")?;
+            stream.flush()
+        });
+
+        let tcp_stream = TcpStream::connect(address)?;
+        tcp_stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+        let connector = TlsConnector::new(Some(root), Vec::new())?;
+        let result = connector.connect(server_name, tcp_stream).and_then(|mut stream| {
+            let mut message = [0u8; 5];
+            stream.read_exact(&mut message)?;
+            Ok(String::from_utf8_lossy(&message).to_string())
+        });
+        let _ = server.join();
+        result
+    }
+
+    #[test]
+    fn selects_certificates_by_server_name() {
+        let directory = std::env::temp_dir().join(format!("tls_sni_test_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let first = write_certificate(&directory, "first", "first.test");
+        let mut wildcard = write_certificate(&directory, "wildcard", "*.streams.test");
+        //Names are matched without case
+        wildcard.server_names.push("Other.Test".to_string());
+        let config = TlsConfig { certificates: vec![first.clone(), wildcard.clone()], reload_interval: None };
+        let acceptor = Arc::new(TlsAcceptor::new(config, Vec::new()).unwrap());
+
+        assert_eq!(handshake(&acceptor, "first.test", &first.cert_path).unwrap(), "hello");
+        assert_eq!(handshake(&acceptor, "live.streams.test", &wildcard.cert_path).unwrap(), "hello");
+        //The wildcard covers one level only, unknown names get the first certificate
+        assert!(handshake(&acceptor, "a.live.streams.test", &wildcard.cert_path).is_err());
+        assert!(handshake(&acceptor, "live.streams.test", &first.cert_path).is_err());
+
+        let store = &acceptor.store;
+        let first_key = store.find(Some("first.test")).unwrap();
+        assert!(Arc::ptr_eq(&store.find(Some("unknown.test")).unwrap(), &first_key));
+        assert!(Arc::ptr_eq(&store.find(None).unwrap(), &first_key));
+        assert!(Arc::ptr_eq(&store.find(Some("LIVE.streams.test")).unwrap(), &store.find(Some("other.test")).unwrap()));
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn times_out_trickled_handshakes() {
+        let directory = std::env::temp_dir().join(format!("tls_handshake_test_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let certificate = write_certificate(&directory, "site", "site.test");
+        let config = TlsConfig { certificates: vec![certificate], reload_interval: None };
+        let acceptor = TlsAcceptor::new(config, Vec::new()).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (tcp_stream, _) = listener.accept().unwrap();
+        tcp_stream.set_read_timeout(Some(Duration::from_secs(30))).unwrap();
+        let should_stop = Arc::new(AtomicBool::new(false));
+        let trickle_should_stop = should_stop.clone();
+        let trickle = thread::spawn(move || {
+            //Start of a ClientHello record, then a byte at a time well within the read timeout
+            let _ = client.write_all(&[0x16, 0x03, 0x01, 0x02, 0x00, 0x01]);
+            while !trickle_should_stop.load(Ordering::Relaxed) && client.write_all(&[0]).is_ok() {
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+
+        assert!(acceptor.accept(tcp_stream, Duration::from_millis(300)).is_err());
+        should_stop.store(true, Ordering::Relaxed);
+        trickle.join().unwrap();
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn reloads_modified_certificates() {
+        let directory = std::env::temp_dir().join(format!("tls_reload_test_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let certificate = write_certificate(&directory, "site", "site.test");
+        let previous_root = directory.join("previous.crt");
+        fs::copy(&certificate.cert_path, &previous_root).unwrap();
+        let config = TlsConfig { certificates: vec![certificate.clone()], reload_interval: Some(Duration::from_millis(100)) };
+        let acceptor = Arc::new(TlsAcceptor::new(config, Vec::new()).unwrap());
+        assert!(!acceptor.store.reload_if_modified().unwrap());
+
+        //Renewed certificate, with a later modification time whatever the file system resolution
+        write_certificate(&directory, "site", "site.test");
+        let later = SystemTime::now() + Duration::from_secs(10);
+        for path in [&certificate.cert_path, &certificate.key_path] {
+            File::options().write(true).open(path).unwrap().set_modified(later).unwrap();
+        }
+        let should_stop = Arc::new(AtomicBool::new(false));
+        let reload_thread = acceptor.new_reload_thread(Duration::from_millis(100), should_stop.clone());
+        thread::sleep(Duration::from_millis(500));
+        should_stop.store(true, Ordering::Relaxed);
+        reload_thread.join().unwrap();
+
+        assert_eq!(handshake(&acceptor, "site.test", &certificate.cert_path).unwrap(), "hello");
+        assert!(handshake(&acceptor, "site.test", &previous_root).is_err());
+
+        //An invalid file keeps the certificates in use
+        fs::write(&certificate.key_path, b"not a key").unwrap();
+        File::options().write(true).open(&certificate.key_path).unwrap().set_modified(later + Duration::from_secs(10)).unwrap();
+        assert!(acceptor.store.reload_if_modified().is_err());
+        assert_eq!(handshake(&acceptor, "site.test", &certificate.cert_path).unwrap(), "hello");
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
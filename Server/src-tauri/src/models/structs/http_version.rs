@@ -0,0 +1,35 @@
+use std::fmt::Display;
+
+use crate::models::structs::http_error::HttpError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Version {
+    Http10,
+    Http11,
+    // Requests received on a HTTP/2 connection, never parsed from a request-line
+    Http2,
+}
+
+impl Version {
+    pub fn parse(token: &str) -> Result<Self, HttpError> {
+        match token {
+            "HTTP/1.0" => Ok(Version::Http10),
+            "HTTP/1.1" => Ok(Version::Http11),
+            _ => Err(HttpError::Malformed(format!("Unsupported version {}", token))),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+            Version::Http2 => "HTTP/2",
+        }
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
@@ -0,0 +1,263 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+
+use crate::models::structs::http_message::HttpMessage;
+use crate::models::structs::http_method::Method;
+use crate::models::structs::http_response::HttpResponse;
+use crate::models::structs::http_router::Router;
+use crate::models::structs::http_server::Handler;
+use crate::models::structs::http_static::StaticFiles;
+use crate::models::structs::http_tls::{TlsCertificate, TlsConfig, TlsServerName};
+
+// A site served on the shared listener, selected by the Host of the request
+pub struct VirtualHost {
+    // "example.com", or "*.example.com" for every subdomain of example.com
+    pub names: Vec<String>,
+    router: Router,
+    // Files answered for the GET and HEAD requests no route matches
+    static_files: Option<StaticFiles>,
+    // Certificate presented for the names, selected with SNI
+    pub tls: Option<TlsCertificate>,
+}
+
+impl VirtualHost {
+    pub fn new(names: &[&str], router: Router) -> Self {
+        VirtualHost {
+            names: names.iter().map(|name| name.to_ascii_lowercase()).collect(),
+            router,
+            static_files: None,
+            tls: None,
+        }
+    }
+
+    pub fn with_static_files(mut self, static_files: StaticFiles) -> Self {
+        self.static_files = Some(static_files);
+        self
+    }
+
+    pub fn with_tls(mut self, cert_path: PathBuf, key_path: PathBuf) -> Self {
+        self.tls = Some(TlsCertificate {
+            server_names: self.names.clone(),
+            cert_path,
+            key_path,
+        });
+        self
+    }
+
+    // A 404 returned by a route is kept, files are only looked for when no route matches
+    pub fn handle(&self, request: &HttpMessage) -> HttpResponse {
+        match (self.router.try_handle(request), &self.static_files) {
+            (Some(response), _) => response,
+            (None, Some(static_files)) if request.method == Method::Get || request.method == Method::Head => {
+                static_files.serve(request, request.target.path.trim_start_matches('/'))
+            },
+            (None, _) => HttpResponse::not_found(),
+        }
+    }
+}
+
+// Dispatch requests to the virtual host matching their Host
+// Exact names are tried first, then wildcards from the longest, then the default host
+// Without a default host, unknown names are answered with 421
+// So are TLS requests whose SNI name selects another host than their Host, e.g. on a connection reused for another site
+#[derive(Default)]
+pub struct VirtualHosts {
+    hosts: Vec<VirtualHost>,
+    exact: HashMap<String, usize>,
+    // Suffix with its leading dot, e.g. ".example.com"
+    wildcards: Vec<(String, usize)>,
+    default: Option<usize>,
+}
+
+impl VirtualHosts {
+    pub fn new() -> Self {
+        VirtualHosts {
+            hosts: Vec::new(),
+            exact: HashMap::new(),
+            wildcards: Vec::new(),
+            default: None,
+        }
+    }
+
+    pub fn with_host(mut self, host: VirtualHost) -> Self {
+        let index = self.hosts.len();
+        for name in &host.names {
+            match name.strip_prefix('*') {
+                Some(suffix) => self.wildcards.push((suffix.to_string(), index)),
+                None => {
+                    self.exact.insert(name.clone(), index);
+                },
+            }
+        }
+        self.wildcards.sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+        self.hosts.push(host);
+        self
+    }
+
+    // Host answering requests for names no host declares, and HTTP/1.0 requests without Host
+    // Its certificate is the one presented to clients without SNI
+    pub fn with_default(mut self, host: VirtualHost) -> Self {
+        self.default = Some(self.hosts.len());
+        self.with_host(host)
+    }
+
+    // host as found in the Host field, the port is ignored
+    pub fn find(&self, host: Option<&str>) -> Option<&VirtualHost> {
+        let name = host.map(normalize_host).unwrap_or_default();
+        let index = self.exact.get(&name).copied()
+            .or_else(|| self.wildcards.iter().find(|(suffix, _)| name.len() > suffix.len() && name.ends_with(suffix.as_str())).map(|(_, index)| *index))
+            .or(self.default)?;
+        self.hosts.get(index)
+    }
+
+    pub fn handle(&self, request: &HttpMessage) -> HttpResponse {
+        let host = match self.find(request.host()) {
+            Some(host) => host,
+            None => return HttpResponse::error(421),
+        };
+        //The certificate was chosen for the SNI name, the Host must be served by the same host (RFC 9110 7.4)
+        if let Some(TlsServerName(server_name)) = request.extensions.get::<TlsServerName>() {
+            if !self.find(Some(server_name)).is_some_and(|sni_host| std::ptr::eq(sni_host, host)) {
+                return HttpResponse::error(421);
+            }
+        }
+        host.handle(request)
+    }
+
+    pub fn into_handler(self) -> Handler {
+        let virtual_hosts = Arc::new(self);
+        Arc::new(move |request: &mut HttpMessage| virtual_hosts.handle(request))
+    }
+
+    // Certificates of the hosts for HttpServerConfig::tls, None when no host has one
+    pub fn tls_config(&self, reload_interval: Option<Duration>) -> Option<TlsConfig> {
+        //The first certificate is the default one
+        let default = self.default.and_then(|index| self.hosts[index].tls.clone());
+        let others = self.hosts.iter().enumerate()
+            .filter(|(index, _)| Some(*index) != self.default)
+            .filter_map(|(_, host)| host.tls.clone());
+        let certificates: Vec<TlsCertificate> = default.into_iter().chain(others).collect();
+        if certificates.is_empty() {
+            return None;
+        }
+
+        Some(TlsConfig {
+            certificates,
+            reload_interval,
+        })
+    }
+}
+
+// Lowercase name without the port nor a trailing dot, IPv6 literals keep their brackets
+fn normalize_host(host: &str) -> String {
+    let host = host.trim();
+    let name = if host.starts_with('[') {
+        host.split_inclusive(']').next().unwrap_or(host)
+    }
+    else {
+        host.split(':').next().unwrap_or(host)
+    };
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn named_router(name: &'static str) -> Router {
+        Router::new().get("/", move |_request, _params| HttpResponse::text(200, name))
+    }
+
+    fn request(raw: &str) -> HttpMessage {
+        HttpMessage::new(raw.as_bytes()).unwrap()
+    }
+
+    fn served_by(virtual_hosts: &VirtualHosts, host: Option<&str>) -> String {
+        let raw = match host {
+            Some(host) => format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", host),
+            None => "GET / HTTP/1.0\r\n\r\n".to_string(),
+        };
+        let response = virtual_hosts.handle(&request(&raw));
+        format!("{} {}", response.status, String::from_utf8_lossy(&response.body))
+    }
+
+    #[test]
+    fn matches_exact_wildcard_and_default_hosts() {
+        let virtual_hosts = VirtualHosts::new()
+            .with_host(VirtualHost::new(&["api.example.com"], named_router("api")))
+            .with_host(VirtualHost::new(&["*.example.com"], named_router("sites")))
+            .with_host(VirtualHost::new(&["*.eu.example.com", "Example.org"], named_router("eu")));
+
+        assert_eq!(served_by(&virtual_hosts, Some("API.example.com:8443")), "200 api");
+        assert_eq!(served_by(&virtual_hosts, Some("blog.example.com.")), "200 sites");
+        assert_eq!(served_by(&virtual_hosts, Some("a.b.example.com")), "200 sites");
+        assert_eq!(served_by(&virtual_hosts, Some("paris.eu.example.com")), "200 eu");
+        assert_eq!(served_by(&virtual_hosts, Some("example.org")), "200 eu");
+        assert_eq!(served_by(&virtual_hosts, Some("example.com")), "421 Misdirected Request");
+        assert_eq!(served_by(&virtual_hosts, None), "421 Misdirected Request");
+
+        let virtual_hosts = virtual_hosts.with_default(VirtualHost::new(&["localhost"], named_router("default")));
+        assert_eq!(served_by(&virtual_hosts, Some("example.com")), "200 default");
+        assert_eq!(served_by(&virtual_hosts, Some("[::1]:8000")), "200 default");
+        assert_eq!(served_by(&virtual_hosts, None), "200 default");
+    }
+
+    #[test]
+    fn serves_static_files_when_no_route_matches() {
+        let root = std::env::temp_dir().join(format!("virtual_host_test_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("page.html"), "<p>page</p>").unwrap();
+
+        let virtual_host = VirtualHost::new(&["example.com"], named_router("home"))
+            .with_static_files(StaticFiles::new(&root).unwrap());
+        assert_eq!(virtual_host.handle(&request("GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")).body, b"home");
+        let mut response = virtual_host.handle(&request("GET /page.html HTTP/1.1\r\nHost: example.com\r\n\r\n"));
+        let mut body: Vec<u8> = Vec::new();
+        response.body_stream.take().unwrap()(&mut body).unwrap();
+        assert_eq!(body, b"<p>page</p>");
+        assert_eq!(virtual_host.handle(&request("GET /missing.html HTTP/1.1\r\nHost: example.com\r\n\r\n")).status, 404);
+        assert_eq!(virtual_host.handle(&request("POST /page.html HTTP/1.1\r\nHost: example.com\r\n\r\n")).status, 404);
+
+        //A 404 from a route is not replaced by a file
+        let virtual_host = VirtualHost::new(&["example.com"], Router::new().get("/page.html", |_request, _params| HttpResponse::not_found()))
+            .with_static_files(StaticFiles::new(&root).unwrap());
+        let response = virtual_host.handle(&request("GET /page.html HTTP/1.1\r\nHost: example.com\r\n\r\n"));
+        assert_eq!(response.status, 404);
+        assert!(response.body_stream.is_none());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn refuses_hosts_other_than_the_sni_name() {
+        let virtual_hosts = VirtualHosts::new()
+            .with_host(VirtualHost::new(&["a.com", "www.a.com"], named_router("a")))
+            .with_host(VirtualHost::new(&["b.com"], named_router("b")))
+            .with_default(VirtualHost::new(&["localhost"], named_router("default")));
+        let served_with_sni = |server_name: &str, host: &str| {
+            let mut request = request(&format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", host));
+            request.extensions.insert(TlsServerName(server_name.to_string()));
+            let response = virtual_hosts.handle(&request);
+            format!("{} {}", response.status, String::from_utf8_lossy(&response.body))
+        };
+
+        assert_eq!(served_with_sni("a.com", "a.com:443"), "200 a");
+        assert_eq!(served_with_sni("a.com", "www.a.com"), "200 a");
+        assert_eq!(served_with_sni("a.com", "b.com"), "421 Misdirected Request");
+        assert_eq!(served_with_sni("unknown.com", "b.com"), "421 Misdirected Request");
+        assert_eq!(served_with_sni("unknown.com", "other.com"), "200 default");
+    }
+
+    #[test]
+    fn collects_certificates_default_first() {
+        let virtual_hosts = VirtualHosts::new()
+            .with_host(VirtualHost::new(&["a.com"], Router::new()).with_tls("a.pem".into(), "a.key".into()))
+            .with_host(VirtualHost::new(&["b.com"], Router::new()))
+            .with_default(VirtualHost::new(&["c.com", "*.c.com"], Router::new()).with_tls("c.pem".into(), "c.key".into()));
+
+        let tls_config = virtual_hosts.tls_config(None).unwrap();
+        let names: Vec<Vec<String>> = tls_config.certificates.iter().map(|certificate| certificate.server_names.clone()).collect();
+        assert_eq!(names, vec![vec!["c.com".to_string(), "*.c.com".to_string()], vec!["a.com".to_string()]]);
+        assert!(VirtualHosts::new().with_host(VirtualHost::new(&["b.com"], Router::new())).tls_config(None).is_none());
+    }
+}
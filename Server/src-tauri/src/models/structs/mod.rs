@@ -1,4 +1,40 @@
 pub mod http_message;
+pub mod http_parser;
+pub mod http_error;
+pub mod http_method;
+pub mod http_version;
+pub mod http_target;
+pub mod http_headers;
+pub mod http_form;
+pub mod http_multipart;
+pub mod http_cookie;
+pub mod http_session;
+pub mod http_middleware;
+pub mod http_cors;
+pub mod http_auth;
+pub mod http_access_log;
+pub mod http_response;
+pub mod http_date;
+pub mod http_server;
+pub mod http_connection;
+pub mod http_connection_limiter;
+pub mod http_chunked;
+pub mod http_router;
+pub mod http_virtual_host;
+pub mod http_static;
+pub mod http_proxy;
+pub mod http_client;
+pub mod http_sse;
+pub mod http_compression;
+pub mod http_stream;
+pub mod http_tls;
+pub mod http2_error;
+pub mod http2_frame;
+pub mod http2_hpack;
+pub mod http2_connection;
+pub mod websocket;
+pub mod stream_stats;
+pub mod stream_events;
 pub mod screen_capture;
 pub mod stop_watch;
 pub mod gpu_encoder;
@@ -8,7 +8,9 @@ use windows_capture::graphics_capture_api::InternalCaptureControl;
 
 use crate::models::structs::stop_watch::StopWatch;
 use crate::models::structs::gpu_encoder::GpuEncoder;
+use crate::models::structs::stream_events::StreamEvent;
 use crate::CLIENT_NUMBER_RECEIVER;
+use crate::ENCODED_FRAME_COUNTER;
 use crate::GLOBAL_QUEUE;
 
 
@@ -39,7 +41,10 @@ impl ScreenCapture {
                     let nal = &data[start..pos];
                     if start + 4 < pos {
                         let nal_type = data[start + 4] & 0x1F;
-                        if nal_type == 1 { self.frame_counter += 1; }
+                        if nal_type == 1 { 
+                            self.frame_counter += 1;
+                            ENCODED_FRAME_COUNTER.fetch_add(1, Ordering::Relaxed);
+                        }
                             // enqueue nal
                             GLOBAL_QUEUE.lock().unwrap().push_back(nal.to_vec());
                     }
@@ -108,6 +113,7 @@ impl GraphicsCaptureApiHandler for ScreenCapture {
     
                                 println!("");
                                 println!("Encoder recreation");
+                                StreamEvent::EncoderRestarted { subscribers: client_number }.publish();
                          }
                     } 
                 }
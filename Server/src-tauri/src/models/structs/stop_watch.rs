@@ -29,6 +29,15 @@ impl StopWatch {
         }
     }
 
+    // Time between start and stop, or since start while running
+    pub fn elapsed(&self) -> Duration {
+        match (self.is_finished, self.start_time) {
+            (true, _) => self.time_elapsed,
+            (false, Some(start_time)) => start_time.elapsed(),
+            (false, None) => Duration::ZERO,
+        }
+    }
+
     pub fn reset(&mut self) {
         self.start_time = None;
         self.time_elapsed = Duration::ZERO;
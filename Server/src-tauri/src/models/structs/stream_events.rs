@@ -0,0 +1,37 @@
+use serde::Serialize;
+
+use crate::STREAM_EVENTS;
+
+// Telemetry of the streaming side, published to the dashboards on the http server event stream
+// The SSE event type is the variant name, the data its fields as JSON
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+pub enum StreamEvent {
+    // Counts only, the event stream may be public and the subscribers' addresses must not leak
+    SubscriberJoined { subscribers: usize },
+    SubscriberLeft { subscribers: usize },
+    // The encoder is recreated so the new subscriber starts on a key frame
+    EncoderRestarted { subscribers: usize },
+    // Sent every second by the emit thread
+    Throughput { bytes_per_second: u64, packets_per_second: u64, frames_per_second: u64, subscribers: usize },
+}
+
+impl StreamEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamEvent::SubscriberJoined { .. } => "subscriber_joined",
+            StreamEvent::SubscriberLeft { .. } => "subscriber_left",
+            StreamEvent::EncoderRestarted { .. } => "encoder_restarted",
+            StreamEvent::Throughput { .. } => "throughput",
+        }
+    }
+
+    pub fn publish(&self) {
+        match serde_json::to_string(self) {
+            Ok(data) => {
+                STREAM_EVENTS.publish(self.name(), &data);
+            },
+            Err(err) => println!("Error while serializing stream event {:?}", err),
+        }
+    }
+}
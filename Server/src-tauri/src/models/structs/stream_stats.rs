@@ -0,0 +1,58 @@
+use std::{io, net::SocketAddr, sync::{atomic::Ordering, Arc}, time::{Duration, Instant}};
+
+use arc_swap::ArcSwapAny;
+use serde::Serialize;
+
+use crate::models::structs::websocket::{Message, WebSocket};
+use crate::ENCODED_FRAME_COUNTER;
+use crate::GLOBAL_QUEUE;
+
+// Snapshot of the streaming side, sent to the dashboards
+#[derive(Clone, Debug, Serialize)]
+pub struct StreamStats {
+    pub subscribers: usize,
+    pub encoded_frames: usize,
+    pub queued_packets: usize,
+}
+
+impl StreamStats {
+    pub fn collect(clients: &ArcSwapAny<Arc<Vec<SocketAddr>>>) -> Self {
+        StreamStats {
+            subscribers: clients.load().len(),
+            encoded_frames: ENCODED_FRAME_COUNTER.load(Ordering::Relaxed),
+            queued_packets: GLOBAL_QUEUE.lock().map(|queue| queue.len()).unwrap_or(0),
+        }
+    }
+
+    // Send a snapshot every second until the dashboard closes the socket
+    // Works over TLS too, reads are interleaved with the sends thanks to a read timeout
+    pub fn push_to_websocket(mut websocket: WebSocket, clients: Arc<ArcSwapAny<Arc<Vec<SocketAddr>>>>) {
+        let interval = Duration::from_secs(1);
+        let mut last_sent: Option<Instant> = None;
+
+        loop {
+            if last_sent.is_none_or(|last_sent| last_sent.elapsed() >= interval) {
+                let text = match serde_json::to_string(&Self::collect(&clients)) {
+                    Ok(text) => text,
+                    Err(_) => break,
+                };
+                if websocket.send_text(&text).is_err() {
+                    break;
+                }
+                last_sent = Some(Instant::now());
+            }
+
+            //Reading is needed to answer pings and to see the close frame
+            let remaining = last_sent.map(|last_sent| interval.saturating_sub(last_sent.elapsed())).unwrap_or(interval);
+            if websocket.set_read_timeout(Some(remaining.max(Duration::from_millis(10)))).is_err() {
+                break;
+            }
+            match websocket.read_message() {
+                Ok(Message::Close(_)) => break,
+                Ok(_) => (),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => (),
+                Err(_) => break,
+            }
+        }
+    }
+}
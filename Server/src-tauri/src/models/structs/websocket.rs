@@ -0,0 +1,434 @@
+use std::{io::{self, Read, Write}, thread, time::Duration};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use sha1::{Digest, Sha1};
+
+use crate::models::structs::http_message::HttpMessage;
+use crate::models::structs::http_method::Method;
+use crate::models::structs::http_response::HttpResponse;
+use crate::models::structs::http_stream::HttpStream;
+
+// Appended to Sec-WebSocket-Key before hashing (RFC 6455 1.3)
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    // Already answered with a pong when returned by read_message
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    // Status code and reason, None when the peer sent no status
+    Close(Option<(u16, String)>),
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// Server side of a WebSocket connection
+pub struct WebSocket {
+    stream: Box<dyn HttpStream>,
+    buffer: Vec<u8>,
+    // Opcode and payload of a fragmented message being received
+    fragments: Option<(u8, Vec<u8>)>,
+    pub max_message_size: usize,
+    close_sent: bool,
+}
+
+impl WebSocket {
+    // Answer the opening handshake, on_open runs on its own thread once the 101 response is sent
+    // Usage : router.get("/ws", |request, _params| WebSocket::upgrade(request, |websocket| { ... }))
+    pub fn upgrade<F>(request: &HttpMessage, on_open: F) -> HttpResponse
+        where F: FnOnce(WebSocket) + Send + 'static {
+
+        if request.method != Method::Get
+            || !request.headers.contains_token("Upgrade", "websocket")
+            || !request.headers.contains_token("Connection", "upgrade") {
+            return HttpResponse::text(400, "WebSocket upgrade expected");
+        }
+
+        if request.headers.get("Sec-WebSocket-Version") != Some("13") {
+            return HttpResponse::error(426).with_header("Sec-WebSocket-Version", "13");
+        }
+
+        //Key is 16 random bytes in base64
+        let key = match request.headers.get("Sec-WebSocket-Key") {
+            Some(key) if BASE64.decode(key).map(|decoded| decoded.len() == 16).unwrap_or(false) => key,
+            _ => return HttpResponse::text(400, "Invalid Sec-WebSocket-Key"),
+        };
+
+        let mut response = HttpResponse::new(101)
+            .with_header("Upgrade", "websocket")
+            .with_header("Sec-WebSocket-Accept", &Self::accept_key(key));
+        response.upgrade = Some(Box::new(move |stream: Box<dyn HttpStream>, buffer: Vec<u8>| {
+            let websocket = WebSocket {
+                stream,
+                buffer,
+                fragments: None,
+                max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+                close_sent: false,
+            };
+            thread::spawn(move || on_open(websocket));
+        }));
+
+        response
+    }
+
+    // base64(SHA-1(key + GUID))
+    pub fn accept_key(key: &str) -> String {
+        let mut hasher = Sha1::new();
+        hasher.update(key.as_bytes());
+        hasher.update(WEBSOCKET_GUID.as_bytes());
+        BASE64.encode(hasher.finalize())
+    }
+
+    // Second handle on the same connection, e.g. one thread reading while another one sends
+    // Only one of the handles should read, not available over TLS, see set_read_timeout instead
+    pub fn try_clone(&self) -> io::Result<WebSocket> {
+        Ok(WebSocket {
+            stream: self.stream.try_clone_stream()?,
+            buffer: Vec::new(),
+            fragments: None,
+            max_message_size: self.max_message_size,
+            close_sent: self.close_sent,
+        })
+    }
+
+    // read_message fails with WouldBlock or TimedOut after the timeout, it can be called again
+    // as a partially received frame stays buffered
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    // Next complete message, fragments are reassembled
+    // Pings are answered and close frames echoed before being returned, they may arrive between fragments
+    pub fn read_message(&mut self) -> io::Result<Message> {
+        loop {
+            let frame = self.read_frame()?;
+
+            match frame.opcode {
+                OPCODE_PING => {
+                    self.write_frame(OPCODE_PONG, &frame.payload)?;
+                    return Ok(Message::Ping(frame.payload));
+                },
+                OPCODE_PONG => return Ok(Message::Pong(frame.payload)),
+                OPCODE_CLOSE => {
+                    let status = Self::parse_close_payload(&frame.payload);
+                    if !self.close_sent {
+                        let code = status.as_ref().map(|(code, _)| *code).unwrap_or(CLOSE_NORMAL);
+                        let _ = self.close(code, "");
+                    }
+                    return Ok(Message::Close(status));
+                },
+                OPCODE_TEXT | OPCODE_BINARY => {
+                    if self.fragments.is_some() {
+                        return Err(self.fail(CLOSE_PROTOCOL_ERROR, "New message inside a fragmented message"));
+                    }
+                    if frame.fin {
+                        return self.build_message(frame.opcode, frame.payload);
+                    }
+                    self.fragments = Some((frame.opcode, frame.payload));
+                },
+                OPCODE_CONTINUATION => {
+                    let (opcode, mut payload) = match self.fragments.take() {
+                        Some(fragments) => fragments,
+                        None => return Err(self.fail(CLOSE_PROTOCOL_ERROR, "Continuation without a message")),
+                    };
+                    if payload.len() + frame.payload.len() > self.max_message_size {
+                        return Err(self.fail(CLOSE_TOO_BIG, "Message too big"));
+                    }
+                    payload.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        return self.build_message(opcode, payload);
+                    }
+                    self.fragments = Some((opcode, payload));
+                },
+                _ => return Err(self.fail(CLOSE_PROTOCOL_ERROR, "Unknown opcode")),
+            }
+        }
+    }
+
+    pub fn send(&mut self, message: &Message) -> io::Result<()> {
+        match message {
+            Message::Text(text) => self.write_frame(OPCODE_TEXT, text.as_bytes()),
+            Message::Binary(data) => self.write_frame(OPCODE_BINARY, data),
+            Message::Ping(data) => self.write_frame(OPCODE_PING, data),
+            Message::Pong(data) => self.write_frame(OPCODE_PONG, data),
+            Message::Close(Some((code, reason))) => self.close(*code, reason),
+            Message::Close(None) => self.close(CLOSE_NORMAL, ""),
+        }
+    }
+
+    pub fn send_text(&mut self, text: &str) -> io::Result<()> {
+        self.write_frame(OPCODE_TEXT, text.as_bytes())
+    }
+
+    pub fn send_binary(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write_frame(OPCODE_BINARY, data)
+    }
+
+    // Start the closing handshake, read_message returns Close once the peer answers
+    pub fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        if self.close_sent {
+            return Ok(());
+        }
+        self.close_sent = true;
+
+        let mut payload = code.to_be_bytes().to_vec();
+        //Control frames payload is at most 125 bytes
+        payload.extend(reason.as_bytes().iter().take(123));
+        self.write_frame(OPCODE_CLOSE, &payload)
+    }
+
+    fn read_frame(&mut self) -> io::Result<Frame> {
+        self.fill(2)?;
+        let fin = self.buffer[0] & 0x80 != 0;
+        let reserved = self.buffer[0] & 0x70;
+        let opcode = self.buffer[0] & 0x0F;
+        let masked = self.buffer[1] & 0x80 != 0;
+        let length_code = (self.buffer[1] & 0x7F) as usize;
+
+        //No extension negotiated, client frames are always masked (RFC 6455 5.1)
+        if reserved != 0 {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "Reserved bits set"));
+        }
+        if !masked {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "Unmasked client frame"));
+        }
+        let is_control = opcode & 0x8 != 0;
+        if is_control && (!fin || length_code > 125) {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "Invalid control frame"));
+        }
+
+        let (header_length, payload_length) = match length_code {
+            126 => {
+                self.fill(4)?;
+                (4, u16::from_be_bytes([self.buffer[2], self.buffer[3]]) as u64)
+            },
+            127 => {
+                self.fill(10)?;
+                let mut length_bytes = [0u8; 8];
+                length_bytes.copy_from_slice(&self.buffer[2..10]);
+                (10, u64::from_be_bytes(length_bytes))
+            },
+            _ => (2, length_code as u64),
+        };
+        if payload_length > self.max_message_size as u64 {
+            return Err(self.fail(CLOSE_TOO_BIG, "Frame too big"));
+        }
+        let payload_length = payload_length as usize;
+
+        self.fill(header_length + 4 + payload_length)?;
+        let mut mask = [0u8; 4];
+        mask.copy_from_slice(&self.buffer[header_length..header_length + 4]);
+        let mut payload: Vec<u8> = self.buffer.drain(..header_length + 4 + payload_length).skip(header_length + 4).collect();
+        for (index, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[index % 4];
+        }
+
+        Ok(Frame { fin, opcode, payload })
+    }
+
+    // Server frames are never masked
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame: Vec<u8> = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        if payload.len() < 126 {
+            frame.push(payload.len() as u8);
+        }
+        else if payload.len() <= u16::MAX as usize {
+            frame.push(126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        else {
+            frame.push(127);
+            frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        }
+        frame.extend_from_slice(payload);
+
+        self.stream.write_all(&frame)?;
+        self.stream.flush()
+    }
+
+    fn build_message(&mut self, opcode: u8, payload: Vec<u8>) -> io::Result<Message> {
+        if opcode == OPCODE_BINARY {
+            return Ok(Message::Binary(payload));
+        }
+
+        match String::from_utf8(payload) {
+            Ok(text) => Ok(Message::Text(text)),
+            Err(_) => Err(self.fail(CLOSE_INVALID_DATA, "Text message is not UTF-8")),
+        }
+    }
+
+    fn parse_close_payload(payload: &[u8]) -> Option<(u16, String)> {
+        if payload.len() < 2 {
+            return None;
+        }
+        let code = u16::from_be_bytes([payload[0], payload[1]]);
+        Some((code, String::from_utf8_lossy(&payload[2..]).to_string()))
+    }
+
+    // Close with the status and build the error returned to the caller
+    fn fail(&mut self, code: u16, reason: &str) -> io::Error {
+        let _ = self.close(code, reason);
+        io::Error::new(io::ErrorKind::InvalidData, reason)
+    }
+
+    fn fill(&mut self, min_length: usize) -> io::Result<()> {
+        let mut chunk = [0u8; 4 * 1024];
+        while self.buffer.len() < min_length {
+            let nb_bytes_read = self.stream.read(&mut chunk)?;
+            if nb_bytes_read == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "WebSocket closed by peer"));
+            }
+            self.buffer.extend_from_slice(&chunk[..nb_bytes_read]);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::{TcpListener, TcpStream}, sync::mpsc};
+
+    // Server side WebSocket opened through upgrade, and the client socket
+    fn open() -> (WebSocket, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let request = HttpMessage::new(&b"GET /ws HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"[..]).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let mut response = WebSocket::upgrade(&request, move |websocket| sender.send(websocket).unwrap());
+        assert_eq!(response.status, 101);
+        assert_eq!(response.headers.get("Sec-WebSocket-Accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        response.upgrade.take().unwrap()(Box::new(server), Vec::new());
+        (receiver.recv().unwrap(), client)
+    }
+
+    // Client frames are masked (RFC 6455 5.3)
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![if fin { 0x80 | opcode } else { opcode }];
+        match payload.len() {
+            length if length < 126 => frame.push(0x80 | length as u8),
+            length => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            },
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(index, byte)| byte ^ mask[index % 4]));
+        frame
+    }
+
+    fn read_server_frame(client: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0u8; 2];
+        client.read_exact(&mut head).unwrap();
+        //Server frames are final and unmasked
+        assert_eq!(head[0] & 0xf0, 0x80);
+        assert_eq!(head[1] & 0x80, 0);
+        let length = match head[1] {
+            126 => {
+                let mut length = [0u8; 2];
+                client.read_exact(&mut length).unwrap();
+                u16::from_be_bytes(length) as usize
+            },
+            length => length as usize,
+        };
+        let mut payload = vec![0u8; length];
+        client.read_exact(&mut payload).unwrap();
+        (head[0] & 0x0f, payload)
+    }
+
+    #[test]
+    fn unmasks_and_reassembles_messages() {
+        let (mut websocket, mut client) = open();
+        let long_text = "x".repeat(300);
+        client.write_all(&client_frame(true, OPCODE_TEXT, long_text.as_bytes())).unwrap();
+        //A ping may come between the fragments of a message
+        client.write_all(&client_frame(false, OPCODE_BINARY, b"\x00\x01")).unwrap();
+        client.write_all(&client_frame(true, OPCODE_PING, b"hi")).unwrap();
+        client.write_all(&client_frame(false, OPCODE_CONTINUATION, b"\x02")).unwrap();
+        client.write_all(&client_frame(true, OPCODE_CONTINUATION, b"\x03")).unwrap();
+
+        assert_eq!(websocket.read_message().unwrap(), Message::Text(long_text));
+        assert_eq!(websocket.read_message().unwrap(), Message::Ping(b"hi".to_vec()));
+        assert_eq!(read_server_frame(&mut client), (OPCODE_PONG, b"hi".to_vec()));
+        assert_eq!(websocket.read_message().unwrap(), Message::Binary(vec![0, 1, 2, 3]));
+
+        websocket.send_text(&"y".repeat(200)).unwrap();
+        assert_eq!(read_server_frame(&mut client), (OPCODE_TEXT, "y".repeat(200).into_bytes()));
+    }
+
+    #[test]
+    fn rejects_invalid_frames() {
+        //Unmasked client frame
+        let (mut websocket, mut client) = open();
+        client.write_all(&[0x81, 0x02, b'h', b'i']).unwrap();
+        assert_eq!(websocket.read_message().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read_server_frame(&mut client), (OPCODE_CLOSE, [&CLOSE_PROTOCOL_ERROR.to_be_bytes()[..], b"Unmasked client frame"].concat()));
+
+        //Continuation without a message, fragmented control frame, invalid UTF-8
+        for (frame, code) in [(client_frame(true, OPCODE_CONTINUATION, b"a"), CLOSE_PROTOCOL_ERROR),
+            (client_frame(false, OPCODE_PING, b"a"), CLOSE_PROTOCOL_ERROR),
+            (client_frame(true, OPCODE_TEXT, b"\xff\xfe"), CLOSE_INVALID_DATA)] {
+            let (mut websocket, mut client) = open();
+            client.write_all(&frame).unwrap();
+            assert!(websocket.read_message().is_err());
+            assert_eq!(read_server_frame(&mut client).1[..2], code.to_be_bytes());
+        }
+
+        let (mut websocket, mut client) = open();
+        websocket.max_message_size = 4;
+        client.write_all(&client_frame(false, OPCODE_TEXT, b"abc")).unwrap();
+        client.write_all(&client_frame(true, OPCODE_CONTINUATION, b"de")).unwrap();
+        assert!(websocket.read_message().is_err());
+        assert_eq!(read_server_frame(&mut client).1[..2], CLOSE_TOO_BIG.to_be_bytes());
+    }
+
+    #[test]
+    fn completes_closing_handshakes() {
+        //Close from the client is echoed with its status
+        let (mut websocket, mut client) = open();
+        client.write_all(&client_frame(true, OPCODE_CLOSE, &[&1001u16.to_be_bytes()[..], b"bye"].concat())).unwrap();
+        assert_eq!(websocket.read_message().unwrap(), Message::Close(Some((1001, "bye".to_string()))));
+        assert_eq!(read_server_frame(&mut client), (OPCODE_CLOSE, 1001u16.to_be_bytes().to_vec()));
+
+        //Close from the server is sent once, the answer ends the handshake
+        let (mut websocket, mut client) = open();
+        websocket.close(CLOSE_NORMAL, "done").unwrap();
+        websocket.close(CLOSE_NORMAL, "again").unwrap();
+        assert_eq!(read_server_frame(&mut client), (OPCODE_CLOSE, [&CLOSE_NORMAL.to_be_bytes()[..], b"done"].concat()));
+        client.write_all(&client_frame(true, OPCODE_CLOSE, &CLOSE_NORMAL.to_be_bytes())).unwrap();
+        assert_eq!(websocket.read_message().unwrap(), Message::Close(Some((CLOSE_NORMAL, String::new()))));
+        client.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        assert!(client.read(&mut [0u8; 1]).is_err());
+    }
+
+    #[test]
+    fn refuses_invalid_handshakes() {
+        let upgrade = |raw: &str| WebSocket::upgrade(&HttpMessage::new(raw.as_bytes()).unwrap(), |_| {}).status;
+        assert_eq!(upgrade("GET /ws HTTP/1.1\r\nHost: a\r\n\r\n"), 400);
+        assert_eq!(upgrade("GET /ws HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 8\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"), 426);
+        assert_eq!(upgrade("GET /ws HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: c2hvcnQ=\r\n\r\n"), 400);
+    }
+}